@@ -0,0 +1,87 @@
+use std::time::Duration;
+
+/// Values below are counted exactly, above them each power of two is split in `SUB_BUCKETS / 2` buckets
+const SUB_BUCKETS: u64 = 64;
+const SUB_BUCKET_BITS: u32 = SUB_BUCKETS.trailing_zeros();
+/// Enough for any `u64`
+const BUCKETS: usize =
+    SUB_BUCKETS as usize + (64 - SUB_BUCKET_BITS as usize) * SUB_BUCKETS as usize / 2;
+
+/// Latencies in nanoseconds, bucketed HDR-style so that the reported ones are within about 3% of the recorded ones.
+///
+/// Recording is a couple of instructions and never allocates, each thread keeps its own and they're merged at the end.
+#[derive(Clone)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    total: u64,
+    max: u64,
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Histogram {
+            counts: vec![0; BUCKETS],
+            total: 0,
+            max: 0,
+        }
+    }
+
+    pub fn record(&mut self, latency: Duration) {
+        let nanos = u64::try_from(latency.as_nanos()).unwrap_or(u64::MAX);
+        self.counts[bucket_of(nanos)] += 1;
+        self.total += 1;
+        self.max = self.max.max(nanos);
+    }
+
+    pub fn merge(&mut self, other: &Histogram) {
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+        self.total += other.total;
+        self.max = self.max.max(other.max);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_nanos(self.max)
+    }
+
+    /// Highest latency of the bucket holding the `p` (0 to 1) fraction of the recorded ones
+    pub fn percentile(&self, p: f64) -> Duration {
+        let rank = ((self.total as f64 * p).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_nanos(bucket_max(bucket).min(self.max));
+            }
+        }
+
+        Duration::ZERO
+    }
+}
+
+fn bucket_of(value: u64) -> usize {
+    if value < SUB_BUCKETS {
+        return value as usize;
+    }
+
+    // Keeps the `SUB_BUCKET_BITS` highest bits, the top one always set
+    let shift = 64 - value.leading_zeros() - SUB_BUCKET_BITS;
+    let half = SUB_BUCKETS / 2;
+    SUB_BUCKETS as usize + (shift as usize - 1) * half as usize + ((value >> shift) - half) as usize
+}
+
+fn bucket_max(bucket: usize) -> u64 {
+    if bucket < SUB_BUCKETS as usize {
+        return bucket as u64;
+    }
+
+    let half = SUB_BUCKETS as usize / 2;
+    let shift = (bucket - SUB_BUCKETS as usize) / half + 1;
+    let top = ((bucket - SUB_BUCKETS as usize) % half + half) as u64;
+    ((top + 1) << shift).wrapping_sub(1)
+}
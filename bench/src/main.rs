@@ -1,12 +1,19 @@
-use key_value_store::KVStorage;
+mod histogram;
+
+use histogram::Histogram;
+use key_value_store::{CompactionPolicy, EventListener, FlushInfo, KVStorage, Options};
 use std::collections::HashMap;
 use std::fs;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::thread::{self};
+use std::time::{Duration, Instant};
 
 const NUM_THREADS: usize = 10;
 const KNOWN_KEY_SPACE: u64 = 100;
 const KEY_SPACE_SIZE: u64 = 1000000000;
+/// Keys of each thread read again by the read-only phase
+const READ_SAMPLE: usize = 100000;
 
 fn gen_random_key(thread_id: usize) -> u64 {
     const TOTAL_KNOWN_SPACE: u64 = NUM_THREADS as u64 * KNOWN_KEY_SPACE;
@@ -52,54 +59,733 @@ fn verify_and_update_known_value(
     expected.insert(known_key, new_value);
 }
 
-fn main() {
-    env_logger::init();
+/// How long the default workload runs, from the command line: `[--duration <secs> | --ops <per thread>] [--value-verify]`
+struct Config {
+    duration: Duration,
+    /// Replaces the duration when set
+    ops_per_thread: Option<u64>,
+    /// Asserts that every read returns the value last written, which adds the known keys to the workload
+    value_verify: bool,
+}
 
-    let location = "./test-dbs";
-    let _ = fs::remove_dir_all(location);
-    fs::create_dir_all(location).unwrap();
+impl Config {
+    fn from_args() -> Self {
+        let mut config = Config {
+            duration: Duration::from_secs(10),
+            ops_per_thread: None,
+            value_verify: false,
+        };
 
-    let kv = Arc::new(KVStorage::new(location).unwrap());
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let mut number = |name: &str| -> u64 {
+                args.next()
+                    .and_then(|value| value.parse().ok())
+                    .unwrap_or_else(|| panic!("{name} takes a number"))
+            };
+            match arg.as_str() {
+                "--duration" => config.duration = Duration::from_secs(number("--duration")),
+                "--ops" => config.ops_per_thread = Some(number("--ops")),
+                "--value-verify" => config.value_verify = true,
+                _ => panic!("unknown argument {arg}"),
+            }
+        }
 
-    let handles: Vec<_> = (0..NUM_THREADS)
-        .map(|thread_id| {
-            let kv_clone = Arc::clone(&kv);
+        config
+    }
+
+    fn running(&self, start: Instant, ops: u64) -> bool {
+        match self.ops_per_thread {
+            Some(limit) => ops < limit,
+            None => start.elapsed() < self.duration,
+        }
+    }
+}
+
+/// Flushes begun so far, on whichever thread runs them
+static FLUSHES: AtomicU64 = AtomicU64::new(0);
+
+/// Counts the flushes, a write during which one began is reported as rotated: with inline flushing the write that
+/// filled the log and the ones waiting behind it, with a flush thread (`Options::max_immutable_memtables`) the writes
+/// made while it picked up a memtable
+struct FlushCounter;
+
+impl EventListener for FlushCounter {
+    fn on_flush_begin(&self, _info: &FlushInfo) {
+        FLUSHES.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Latencies of the operations of one thread, or of all of them once merged
+#[derive(Clone)]
+struct OpLatencies {
+    read_hits: Histogram,
+    read_misses: Histogram,
+    writes: Histogram,
+    rotating_writes: Histogram,
+}
+
+impl OpLatencies {
+    fn new() -> Self {
+        OpLatencies {
+            read_hits: Histogram::new(),
+            read_misses: Histogram::new(),
+            writes: Histogram::new(),
+            rotating_writes: Histogram::new(),
+        }
+    }
+
+    fn write(&mut self, kv: &KVStorage, key: u64, value: Option<u64>) {
+        let flushes = FLUSHES.load(Ordering::Relaxed);
+        let start = Instant::now();
+        kv.write(key, value).unwrap();
+        let elapsed = start.elapsed();
+        if FLUSHES.load(Ordering::Relaxed) != flushes {
+            self.rotating_writes.record(elapsed);
+        } else {
+            self.writes.record(elapsed);
+        }
+    }
+
+    fn read(&mut self, kv: &KVStorage, key: u64) -> Option<u64> {
+        let start = Instant::now();
+        let value = kv.read(&key).unwrap();
+        let elapsed = start.elapsed();
+        match value {
+            Some(_) => self.read_hits.record(elapsed),
+            None => self.read_misses.record(elapsed),
+        }
+        value
+    }
+
+    fn merge(&mut self, other: &OpLatencies) {
+        self.read_hits.merge(&other.read_hits);
+        self.read_misses.merge(&other.read_misses);
+        self.writes.merge(&other.writes);
+        self.rotating_writes.merge(&other.rotating_writes);
+    }
+
+    fn report(&self, phase: &str, elapsed: Duration) {
+        let histograms = [
+            ("read hit", &self.read_hits),
+            ("read miss", &self.read_misses),
+            ("write", &self.writes),
+            ("write (rotated)", &self.rotating_writes),
+        ];
+        let total: u64 = histograms.iter().map(|(_, h)| h.count()).sum();
+        println!(
+            "{phase}: {total} ops in {elapsed:?} ({:.0} ops/sec)",
+            total as f64 / elapsed.as_secs_f64()
+        );
+        for (name, histogram) in histograms {
+            if histogram.count() == 0 {
+                continue;
+            }
+            println!(
+                "  {name:<16} {:>10} ops  p50 {:>10?}  p95 {:>10?}  p99 {:>10?}  max {:>10?}",
+                histogram.count(),
+                histogram.percentile(0.5),
+                histogram.percentile(0.95),
+                histogram.percentile(0.99),
+                histogram.max()
+            );
+        }
+    }
+}
+
+/// Runs `work` on every thread, reporting their merged latencies and their throughput
+fn run_phase<I: Send + 'static, O: Send + 'static>(
+    phase: &str,
+    inputs: Vec<I>,
+    work: impl Fn(usize, I, &mut OpLatencies) -> O + Send + Sync + 'static,
+) -> Vec<O> {
+    let work = Arc::new(work);
+    let start = Instant::now();
+    let handles: Vec<_> = inputs
+        .into_iter()
+        .enumerate()
+        .map(|(thread_id, input)| {
+            let work = Arc::clone(&work);
             thread::spawn(move || {
-                let mut expected_values = HashMap::new();
-                let thread_key_offset = (thread_id as u64) * KNOWN_KEY_SPACE;
+                let mut latencies = OpLatencies::new();
+                let output = work(thread_id, input, &mut latencies);
+                (output, latencies)
+            })
+        })
+        .collect();
 
-                initialize_known_values(&kv_clone, &mut expected_values, thread_key_offset);
+    let mut merged = OpLatencies::new();
+    let outputs = handles
+        .into_iter()
+        .map(|handle| {
+            let (output, latencies) = handle.join().unwrap();
+            merged.merge(&latencies);
+            output
+        })
+        .collect();
+    merged.report(phase, start.elapsed());
+    outputs
+}
+
+/// Random writes each read back, with a read of a missing key every 10 and of a known key every 100 (with
+/// `--value-verify`), then a read-only phase over a sample of the keys written
+fn bench_mixed(kv: &KVStorage, config: Config) {
+    let config = Arc::new(config);
+
+    let written = run_phase("mixed", vec![(); NUM_THREADS], {
+        let kv = kv.clone();
+        let config = Arc::clone(&config);
+        move |thread_id, (), latencies| {
+            let mut expected_values = HashMap::new();
+            let thread_key_offset = (thread_id as u64) * KNOWN_KEY_SPACE;
+            if config.value_verify {
+                initialize_known_values(&kv, &mut expected_values, thread_key_offset);
+            }
+
+            let mut sample = HashMap::new();
+            let start = Instant::now();
+            let mut i = 0;
+            while config.running(start, i) {
+                let key = gen_random_key(thread_id);
+                let value = random_value(i * 2);
+
+                latencies.write(&kv, key, value);
+                let read = latencies.read(&kv, key);
+                if config.value_verify {
+                    assert_eq!(read, value);
+                }
+                if sample.len() < READ_SAMPLE || sample.contains_key(&key) {
+                    sample.insert(key, value);
+                }
+
+                if i.is_multiple_of(10) {
+                    latencies.read(&kv, gen_random_key(thread_id));
+                }
+
+                if config.value_verify && i.is_multiple_of(100) {
+                    let known_key = thread_key_offset + (rand::random::<u64>() % KNOWN_KEY_SPACE);
+                    verify_and_update_known_value(
+                        &kv,
+                        &mut expected_values,
+                        known_key,
+                        thread_id,
+                        i * 3 + known_key,
+                    );
+                }
+                i += 1;
+            }
+
+            sample.into_iter().collect::<Vec<_>>()
+        }
+    });
+
+    run_phase("read-only", written, {
+        let kv = kv.clone();
+        move |_, sample: Vec<(u64, Option<u64>)>, latencies| {
+            let start = Instant::now();
+            let mut i = 0;
+            while !sample.is_empty() && config.running(start, i) {
+                let (key, value) = sample[rand::random::<u64>() as usize % sample.len()];
+                let read = latencies.read(&kv, key);
+                if config.value_verify {
+                    assert_eq!(read, value);
+                }
+                i += 1;
+            }
+        }
+    });
+}
+
+/// Compares 1000 single reads against one `multi_get` of the same keys
+fn bench_multi_get(kv: &KVStorage) {
+    const ENTRIES: u64 = 100000;
+    const READS: usize = 1000;
+
+    for key in 0..ENTRIES {
+        kv.write(key, Some(key)).unwrap();
+    }
+
+    let keys: Vec<u64> = (0..READS)
+        .map(|_| rand::random::<u64>() % (ENTRIES * 2))
+        .collect();
+
+    let start = Instant::now();
+    let single: Vec<_> = keys.iter().map(|key| kv.read(key).unwrap()).collect();
+    let single_elapsed = start.elapsed();
+
+    let start = Instant::now();
+    let multi = kv.multi_get(&keys).unwrap();
+    let multi_elapsed = start.elapsed();
+
+    assert_eq!(single, multi);
+    println!("{READS} single reads: {single_elapsed:?}, one multi_get: {multi_elapsed:?}");
+}
+
+/// Single threaded write throughput, dominated by the append log write path
+fn bench_writes(kv: &KVStorage) {
+    const WRITES: u64 = 200000;
+
+    let start = Instant::now();
+    for key in 0..WRITES {
+        kv.write(key, Some(key)).unwrap();
+    }
+    let elapsed = start.elapsed();
+
+    println!(
+        "{WRITES} writes: {elapsed:?} ({:.0} writes/sec)",
+        WRITES as f64 / elapsed.as_secs_f64()
+    );
+}
+
+/// Write and read latency across many rotations, the SSTables being built while both go on
+fn bench_rotation_latency(location: &str) {
+    const THREADS: u64 = 4;
+    const WRITES_PER_THREAD: u64 = 100000;
+
+    let location = format!("{location}/rotation");
+    fs::create_dir_all(&location).unwrap();
+    let kv = KVStorage::new_with_options(&location, Options::new().write_shards(1)).unwrap();
+    let done = Arc::new(AtomicBool::new(false));
+
+    // Keys follow the sequence numbers closely, these were just written and most are still in the append log
+    let probe = {
+        let kv = kv.clone();
+        let done = Arc::clone(&done);
+        thread::spawn(move || {
+            let mut latencies = Vec::new();
+            while !done.load(Ordering::Relaxed) {
+                let key = kv
+                    .last_sequence()
+                    .saturating_sub(rand::random::<u64>() % 100);
+                let start = Instant::now();
+                kv.read(&key).unwrap();
+                latencies.push(start.elapsed());
+            }
+            latencies
+        })
+    };
+    let writers: Vec<_> = (0..THREADS)
+        .map(|thread_id| {
+            let kv = kv.clone();
+            thread::spawn(move || {
+                let mut latencies = Vec::with_capacity(WRITES_PER_THREAD as usize);
+                for i in 0..WRITES_PER_THREAD {
+                    let start = Instant::now();
+                    kv.write(i * THREADS + thread_id, Some(i)).unwrap();
+                    latencies.push(start.elapsed());
+                }
+                latencies
+            })
+        })
+        .collect();
+    let mut write_latencies: Vec<_> = writers
+        .into_iter()
+        .flat_map(|writer| writer.join().unwrap())
+        .collect();
+    done.store(true, Ordering::Relaxed);
+    let mut read_latencies = probe.join().unwrap();
 
-                for i in 0..100000000 {
-                    // println!("thread_id: {thread_id}, i: {i}");
+    for (name, latencies) in [
+        ("writes", &mut write_latencies),
+        ("reads", &mut read_latencies),
+    ] {
+        latencies.sort();
+        let percentile = |p: f64| latencies[((latencies.len() - 1) as f64 * p) as usize];
+        println!(
+            "{} {name}: p50 {:?}, p99 {:?}, max {:?}",
+            latencies.len(),
+            percentile(0.5),
+            percentile(0.99),
+            percentile(1.0)
+        );
+    }
+}
 
-                    let key = gen_random_key(thread_id);
-                    let value = random_value(i * 2);
+/// Multi-threaded write throughput with a single append log against one log per thread, without and with
+/// [`Options::write_batching`]
+fn bench_sharded_writes(location: &str) {
+    const THREADS: u64 = 8;
+    const WRITES_PER_THREAD: u64 = 50000;
 
-                    kv_clone.write(key, value).unwrap();
-                    assert_eq!(kv_clone.read(&key).unwrap(), value);
+    for (shards, batching) in [
+        (1, false),
+        (1, true),
+        (THREADS as usize, false),
+        (THREADS as usize, true),
+    ] {
+        let shard_location = format!("{location}/shards-{shards}-{batching}");
+        fs::create_dir_all(&shard_location).unwrap();
+        let options = Options::new().write_shards(shards).write_batching(batching);
+        let kv = KVStorage::new_with_options(&shard_location, options).unwrap();
 
-                    if i.is_multiple_of(10) {
-                        let _ = kv_clone.read(&gen_random_key(thread_id)).unwrap();
+        let start = Instant::now();
+        let handles: Vec<_> = (0..THREADS)
+            .map(|thread_id| {
+                let kv = kv.clone();
+                thread::spawn(move || {
+                    for i in 0..WRITES_PER_THREAD {
+                        kv.write(i * THREADS + thread_id, Some(i)).unwrap();
                     }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        let elapsed = start.elapsed();
 
-                    if i.is_multiple_of(100) {
-                        let known_key =
-                            thread_key_offset + (rand::random::<u64>() % KNOWN_KEY_SPACE);
-                        verify_and_update_known_value(
-                            &kv_clone,
-                            &mut expected_values,
-                            known_key,
-                            thread_id,
-                            i * 3 + known_key,
-                        );
+        let writes = THREADS * WRITES_PER_THREAD;
+        println!(
+            "{shards} shard(s), batching {batching}, {THREADS} threads, {writes} writes: {elapsed:?} ({:.0} writes/sec)",
+            writes as f64 / elapsed.as_secs_f64()
+        );
+    }
+}
+
+/// Synchronous write throughput as the number of writers grows, concurrent writers share their syncs
+fn bench_sync_writes(location: &str) {
+    const WRITES: u64 = 4000;
+
+    for threads in [1, 2, 4, 8] {
+        let thread_location = format!("{location}/sync-{threads}");
+        fs::create_dir_all(&thread_location).unwrap();
+        let options = Options::new()
+            .sync_writes(true)
+            .group_commit_delay(Duration::from_millis(1));
+        let kv = KVStorage::new_with_options(&thread_location, options).unwrap();
+
+        let start = Instant::now();
+        let handles: Vec<_> = (0..threads)
+            .map(|thread_id| {
+                let kv = kv.clone();
+                thread::spawn(move || {
+                    for i in 0..WRITES / threads {
+                        kv.write(i * threads + thread_id, Some(i)).unwrap();
                     }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        let elapsed = start.elapsed();
+
+        println!(
+            "{threads} thread(s), {WRITES} synchronous writes: {elapsed:?} ({:.0} writes/sec)",
+            WRITES as f64 / elapsed.as_secs_f64()
+        );
+    }
+}
+
+/// Read latency while heavy compaction runs, without and with [`Options::compaction_rate_limit`]
+fn bench_compaction_latency(location: &str) {
+    const ENTRIES: u64 = 2_000_000;
+
+    for rate_limit in [None, Some(16 * 1024 * 1024)] {
+        let limit_location = format!("{location}/compaction-{}", rate_limit.unwrap_or(0));
+        fs::create_dir_all(&limit_location).unwrap();
+        let mut options = Options::new().compaction_policy(CompactionPolicy::SizeTiered {
+            ratio: 2.0,
+            min_merge: 2,
+        });
+        if let Some(rate_limit) = rate_limit {
+            options = options.compaction_rate_limit(rate_limit);
+        }
+        let kv = KVStorage::new_with_options(&limit_location, options).unwrap();
+        let done = Arc::new(AtomicBool::new(false));
+
+        // Probes reads of already written keys while the writer keeps compaction busy
+        let probe = {
+            let kv = kv.clone();
+            let done = Arc::clone(&done);
+            thread::spawn(move || {
+                let mut latencies = Vec::new();
+                while !done.load(Ordering::Relaxed) {
+                    let key = rand::random::<u64>() % 1000;
+                    let start = Instant::now();
+                    kv.read(&key).unwrap();
+                    latencies.push(start.elapsed());
+                    thread::sleep(Duration::from_micros(100));
                 }
+                latencies
             })
+        };
+        for key in 0..ENTRIES {
+            kv.write(key, Some(key)).unwrap();
+        }
+        done.store(true, Ordering::Relaxed);
+        let mut latencies = probe.join().unwrap();
+        latencies.sort();
+
+        let percentile = |p: f64| latencies[((latencies.len() - 1) as f64 * p) as usize];
+        println!(
+            "rate limit {rate_limit:?}: {} reads, p50 {:?}, p99 {:?}, max {:?}",
+            latencies.len(),
+            percentile(0.5),
+            percentile(0.99),
+            percentile(1.0)
+        );
+    }
+}
+
+/// Read latency of keys still in the append log, without and with [`Options::spill_values`]
+fn bench_spill_reads(location: &str) {
+    const ENTRIES: u64 = 20000;
+    const READS: u64 = 200000;
+
+    for spill in [false, true] {
+        let spill_location = format!("{location}/spill-{spill}");
+        fs::create_dir_all(&spill_location).unwrap();
+        let options = Options::new().write_shards(1).spill_values(spill);
+        let kv = KVStorage::new_with_options(&spill_location, options).unwrap();
+        for key in 0..ENTRIES {
+            kv.write(key, Some(key)).unwrap();
+        }
+
+        let mut latencies = Vec::with_capacity(READS as usize);
+        for _ in 0..READS {
+            let key = rand::random::<u64>() % ENTRIES;
+            let start = Instant::now();
+            assert_eq!(kv.read(&key).unwrap(), Some(key));
+            latencies.push(start.elapsed());
+        }
+        latencies.sort();
+
+        let percentile = |p: f64| latencies[((latencies.len() - 1) as f64 * p) as usize];
+        println!(
+            "spill {spill}: memtable {} bytes, {READS} reads, p50 {:?}, p99 {:?}",
+            kv.stats().memtable_bytes,
+            percentile(0.5),
+            percentile(0.99)
+        );
+    }
+}
+
+/// Read latency over a deep stack of tables, without and with [`Options::parallel_probe_threads`]
+fn bench_deep_stack(location: &str) {
+    const TABLES: u64 = 40;
+    const ENTRIES_PER_TABLE: u64 = 5000;
+    const READS: u64 = 100000;
+
+    for threads in [0, 4] {
+        let probe_location = format!("{location}/probe-{threads}");
+        fs::create_dir_all(&probe_location).unwrap();
+        // No merges, the stack stays as deep as written
+        let options = Options::new()
+            .write_shards(1)
+            .parallel_probe_threads(threads)
+            .compaction_policy(CompactionPolicy::SizeTiered {
+                ratio: 2.0,
+                min_merge: usize::MAX,
+            });
+        let kv = KVStorage::new_with_options(&probe_location, options).unwrap();
+        // Every table overwrites a share of the keys of the older ones
+        for table in 0..TABLES {
+            for i in 0..ENTRIES_PER_TABLE {
+                let key = (table * ENTRIES_PER_TABLE / 2 + i) % (TABLES * ENTRIES_PER_TABLE / 4);
+                kv.write(key, Some(table)).unwrap();
+            }
+            kv.flush().unwrap();
+        }
+
+        let mut latencies = Vec::with_capacity(READS as usize);
+        for _ in 0..READS {
+            let key = rand::random::<u64>() % (TABLES * ENTRIES_PER_TABLE / 4);
+            let start = Instant::now();
+            kv.read(&key).unwrap();
+            latencies.push(start.elapsed());
+        }
+        latencies.sort();
+
+        let percentile = |p: f64| latencies[((latencies.len() - 1) as f64 * p) as usize];
+        println!(
+            "{threads} probe threads, {TABLES} tables: p50 {:?}, p99 {:?}",
+            percentile(0.5),
+            percentile(0.99)
+        );
+    }
+}
+
+/// Read tail latency while another thread commits changes to a long list of tables. Every flush adds a table and
+/// writes the whole list to the manifest, which reads don't wait for
+fn bench_table_commits(location: &str) {
+    const TABLES: u64 = 1000;
+    const ENTRIES_PER_TABLE: u64 = 100;
+    const COMMITS: u64 = 2000;
+
+    let location = format!("{location}/table-commits");
+    fs::create_dir_all(&location).unwrap();
+    // No merges, the list only grows
+    let options = Options::new()
+        .write_shards(1)
+        .compaction_policy(CompactionPolicy::SizeTiered {
+            ratio: 2.0,
+            min_merge: usize::MAX,
+        });
+    let kv = KVStorage::new_with_options(&location, options).unwrap();
+    for table in 0..TABLES {
+        for i in 0..ENTRIES_PER_TABLE {
+            let key = table * ENTRIES_PER_TABLE + i;
+            kv.write(key, Some(key)).unwrap();
+        }
+        kv.flush().unwrap();
+    }
+
+    let done = Arc::new(AtomicBool::new(false));
+    let reader = {
+        let kv = kv.clone();
+        let done = Arc::clone(&done);
+        thread::spawn(move || {
+            let mut latencies = Histogram::new();
+            while !done.load(Ordering::Relaxed) {
+                let key = rand::random::<u64>() % (TABLES * ENTRIES_PER_TABLE);
+                let start = Instant::now();
+                assert_eq!(kv.read(&key).unwrap(), Some(key));
+                latencies.record(start.elapsed());
+            }
+            latencies
         })
-        .collect();
+    };
+
+    let start = Instant::now();
+    for commit in 0..COMMITS {
+        kv.write(TABLES * ENTRIES_PER_TABLE + commit, Some(commit))
+            .unwrap();
+        kv.flush().unwrap();
+    }
+    let elapsed = start.elapsed();
+    done.store(true, Ordering::Relaxed);
+    let latencies = reader.join().unwrap();
+
+    println!(
+        "{COMMITS} commits over {TABLES}+ tables in {elapsed:?}, {} reads: p50 {:?}, p99 {:?}, p99.9 {:?}, max {:?}",
+        latencies.count(),
+        latencies.percentile(0.5),
+        latencies.percentile(0.99),
+        latencies.percentile(0.999),
+        latencies.max()
+    );
+}
+
+/// Repeated reads of a small set of keys that are in no table, with and without the negative cache
+fn bench_missing_reads(location: &str) {
+    const TABLES: u64 = 20;
+    const ENTRIES_PER_TABLE: u64 = 5000;
+    const MISSING: u64 = 1000;
+    const READS: u64 = 200000;
+
+    for slots in [0, 4096] {
+        let cache_location = format!("{location}/negative-{slots}");
+        fs::create_dir_all(&cache_location).unwrap();
+        let options = Options::new()
+            .negative_cache_slots(slots as usize)
+            .compaction_policy(CompactionPolicy::SizeTiered {
+                ratio: 2.0,
+                min_merge: usize::MAX,
+            });
+        let kv = KVStorage::new_with_options(&cache_location, options).unwrap();
+        // Even keys only, the odd ones are missing but within the key range of every table
+        for table in 0..TABLES {
+            for i in 0..ENTRIES_PER_TABLE {
+                kv.write((table * ENTRIES_PER_TABLE + i) * 2, Some(table))
+                    .unwrap();
+            }
+            kv.flush().unwrap();
+        }
+
+        let start = Instant::now();
+        for _ in 0..READS {
+            let key = (rand::random::<u64>() % MISSING) * 2 + 1;
+            assert_eq!(kv.read(&key).unwrap(), None);
+        }
+        let elapsed = start.elapsed();
 
-    for handle in handles {
-        handle.join().unwrap();
+        println!(
+            "{slots} negative cache slots, {READS} reads of missing keys: {elapsed:?} ({:.0} reads/sec)",
+            READS as f64 / elapsed.as_secs_f64()
+        );
     }
 }
+
+/// Multi-threaded read throughput of the SSTables with `pread` against io_uring
+#[cfg(feature = "uring")]
+fn bench_uring_reads(location: &str) {
+    use key_value_store::IoBackend;
+
+    const ENTRIES: u64 = 200000;
+    const THREADS: u64 = 8;
+    const READS_PER_THREAD: u64 = 50000;
+
+    for backend in [IoBackend::Std, IoBackend::Uring] {
+        let backend_location = format!("{location}/io-{backend:?}");
+        fs::create_dir_all(&backend_location).unwrap();
+        let options = Options::new().io_backend(backend);
+        let kv = KVStorage::new_with_options(&backend_location, options).unwrap();
+        for key in 0..ENTRIES {
+            kv.write(key, Some(key)).unwrap();
+        }
+        kv.flush().unwrap();
+
+        let start = Instant::now();
+        let handles: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let kv = kv.clone();
+                thread::spawn(move || {
+                    for _ in 0..READS_PER_THREAD {
+                        let key = rand::random::<u64>() % ENTRIES;
+                        assert_eq!(kv.read(&key).unwrap(), Some(key));
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        let elapsed = start.elapsed();
+
+        let reads = THREADS * READS_PER_THREAD;
+        println!(
+            "{backend:?}, {THREADS} threads, {reads} reads: {elapsed:?} ({:.0} reads/sec)",
+            reads as f64 / elapsed.as_secs_f64()
+        );
+    }
+}
+
+fn main() {
+    // The spans of the store, each logged with its duration once closed. The `log` records go through it too
+    #[cfg(feature = "tracing")]
+    tracing_subscriber::fmt()
+        .with_max_level(tracing_subscriber::filter::LevelFilter::TRACE)
+        .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
+        .init();
+    #[cfg(not(feature = "tracing"))]
+    env_logger::init();
+
+    let location = "./test-dbs";
+    let _ = fs::remove_dir_all(location);
+    fs::create_dir_all(location).unwrap();
+
+    let kv = KVStorage::new_with_options(
+        location,
+        Options::new().event_listener(Arc::new(FlushCounter)),
+    )
+    .unwrap();
+
+    match std::env::args().nth(1).as_deref() {
+        Some("multi-get") => return bench_multi_get(&kv),
+        Some("writes") => return bench_writes(&kv),
+        Some("rotation-latency") => return bench_rotation_latency(location),
+        Some("sharded-writes") => return bench_sharded_writes(location),
+        Some("sync-writes") => return bench_sync_writes(location),
+        Some("compaction-latency") => return bench_compaction_latency(location),
+        Some("spill-reads") => return bench_spill_reads(location),
+        Some("deep-stack") => return bench_deep_stack(location),
+        Some("missing-reads") => return bench_missing_reads(location),
+        Some("table-commits") => return bench_table_commits(location),
+        #[cfg(feature = "uring")]
+        Some("uring-reads") => return bench_uring_reads(location),
+        _ => {}
+    }
+
+    bench_mixed(&kv, Config::from_args());
+}
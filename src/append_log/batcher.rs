@@ -0,0 +1,278 @@
+use crate::{errors::Error, options::Durability, options::Options, serialization::KVMemoryRepr};
+use std::{
+    any::Any,
+    collections::HashMap,
+    io, mem,
+    panic::{self, AssertUnwindSafe},
+    sync::{Condvar, Mutex, MutexGuard},
+};
+
+/// Entries combined into a single write, in the order they were queued
+pub type Batch = Vec<(KVMemoryRepr, Durability)>;
+
+/// Combines the point writes of the concurrent writers of a shard, see [`Options::write_batching`].
+///
+/// Writers queue their entry, then the first one finding no combiner at work becomes it: it takes every queued entry,
+/// writes them at once and hands each writer its result. The others wait for theirs, or for their turn to combine.
+pub struct WriteBatcher {
+    state: Mutex<BatchState>,
+    /// Notified when a combiner is done
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct BatchState {
+    queue: Vec<QueuedWrite>,
+    /// Of the next queued write
+    next_ticket: u64,
+    /// Results of the combined writes, until their writers take them
+    done: HashMap<u64, Result<(), Error>>,
+    combining: bool,
+}
+
+struct QueuedWrite {
+    ticket: u64,
+    entry: KVMemoryRepr,
+    durability: Durability,
+}
+
+impl WriteBatcher {
+    /// `None` unless [`Options::write_batching`] is set
+    pub fn from_options(options: &Options) -> Option<Self> {
+        options.write_batching.then(|| Self {
+            state: Default::default(),
+            changed: Condvar::new(),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BatchState> {
+        self.state.lock().expect("poisoned write batcher lock")
+    }
+
+    /// Queues `entry` and returns once it's written, by this writer or another one. `combine` writes a batch and
+    /// returns the result of each of its entries, it's only run if this writer becomes the combiner.
+    ///
+    /// When `combine` panics, the other writers of the batch get [`Error::WritePanicked`] and the panic goes on in
+    /// the combiner
+    pub fn write(
+        &self,
+        entry: KVMemoryRepr,
+        durability: Durability,
+        combine: impl FnOnce(Batch) -> Vec<Result<(), Error>>,
+    ) -> Result<(), Error> {
+        let mut state = self.lock();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.queue.push(QueuedWrite {
+            ticket,
+            entry,
+            durability,
+        });
+
+        let mut combine = Some(combine);
+        loop {
+            if let Some(result) = state.done.remove(&ticket) {
+                return result;
+            }
+            if state.combining {
+                state = self
+                    .changed
+                    .wait(state)
+                    .expect("poisoned write batcher lock");
+                continue;
+            }
+
+            // Combine: the entry of this writer is still queued, so it's part of the batch
+            state.combining = true;
+            let queued = mem::take(&mut state.queue);
+            drop(state);
+
+            let tickets: Vec<_> = queued.iter().map(|write| write.ticket).collect();
+            let batch = queued
+                .into_iter()
+                .map(|write| (write.entry, write.durability))
+                .collect();
+            let combine = combine.take().expect("a writer combines at most once");
+            // Every ticket gets a result whatever happens, or its writer would wait forever
+            let results = panic::catch_unwind(AssertUnwindSafe(|| combine(batch)));
+
+            state = self.lock();
+            state.combining = false;
+            let panicked = match results {
+                Ok(results) => {
+                    if results.len() != tickets.len() {
+                        log::error!(
+                            "{} results for a batch of {} writes",
+                            results.len(),
+                            tickets.len()
+                        );
+                    }
+                    let mut results = results.into_iter();
+                    for ticket in tickets {
+                        let result = results.next().unwrap_or_else(|| {
+                            Err(Error::IO(io::Error::other("no result for the write")))
+                        });
+                        state.done.insert(ticket, result);
+                    }
+                    None
+                }
+                Err(panic) => {
+                    let message = panic_message(&*panic);
+                    for ticket in tickets.into_iter().filter(|t| *t != ticket) {
+                        state
+                            .done
+                            .insert(ticket, Err(Error::WritePanicked(message.clone())));
+                    }
+                    Some(panic)
+                }
+            };
+            self.changed.notify_all();
+
+            if let Some(panic) = panicked {
+                drop(state);
+                panic::resume_unwind(panic);
+            }
+        }
+    }
+}
+
+/// The message a combiner panicked with
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    panic
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::{
+            Arc,
+            atomic::{AtomicU64, Ordering},
+        },
+        thread,
+        time::Duration,
+    };
+
+    /// Keeps the entries of `batch`, slowly enough for the other writers to queue theirs meanwhile
+    fn write_slowly(written: &Mutex<Vec<KVMemoryRepr>>, batch: Batch) -> Vec<Result<(), Error>> {
+        thread::sleep(Duration::from_millis(1));
+        let mut written = written.lock().unwrap();
+        batch
+            .into_iter()
+            .map(|(entry, _)| written.push(entry))
+            .map(Ok)
+            .collect()
+    }
+
+    #[test]
+    fn test_writers_share_batches() {
+        const THREADS: u64 = 8;
+        const WRITES: u64 = 50;
+
+        let batcher = Arc::new(WriteBatcher::from_options(&Options::new()).unwrap());
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let batches = Arc::new(AtomicU64::new(0));
+
+        let writers: Vec<_> = (0..THREADS)
+            .map(|writer| {
+                let (batcher, written, batches) =
+                    (batcher.clone(), written.clone(), batches.clone());
+                thread::spawn(move || {
+                    for i in 0..WRITES {
+                        let entry = KVMemoryRepr::new(writer, Some(i), 0);
+                        let combine = |batch| {
+                            batches.fetch_add(1, Ordering::SeqCst);
+                            write_slowly(&written, batch)
+                        };
+                        batcher.write(entry, Durability::Buffered, combine).unwrap();
+
+                        // Returned once written
+                        let written = written.lock().unwrap();
+                        assert_eq!(
+                            written
+                                .iter()
+                                .filter(|entry| *entry.key() == writer)
+                                .count() as u64,
+                            i + 1
+                        );
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        // In the order of every writer
+        let written = written.lock().unwrap();
+        for writer in 0..THREADS {
+            let values: Vec<_> = written
+                .iter()
+                .filter(|entry| *entry.key() == writer)
+                .map(|entry| entry.value().unwrap())
+                .collect();
+            assert_eq!(values, (0..WRITES).collect::<Vec<_>>());
+        }
+        let batches = batches.load(Ordering::SeqCst);
+        assert!(batches < THREADS * WRITES / 2, "{batches} batches");
+    }
+
+    #[test]
+    fn test_every_writer_gets_a_result() {
+        let batcher = Arc::new(WriteBatcher::from_options(&Options::new()).unwrap());
+        let entry = |key| KVMemoryRepr::new(key, Some(0), 0);
+
+        // Combines until the two writers below are queued, they're then batched together
+        let first = {
+            let batcher = batcher.clone();
+            thread::spawn(move || {
+                batcher.write(entry(0), Durability::Buffered, |batch| {
+                    while batcher.lock().queue.len() < 2 {
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                    batch.into_iter().map(|_| Ok(())).collect()
+                })
+            })
+        };
+        while !batcher.lock().combining {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let panicking: Vec<_> = (1..3)
+            .map(|key| {
+                let batcher = batcher.clone();
+                thread::spawn(move || {
+                    batcher.write(entry(key), Durability::Buffered, |_| {
+                        panic!("combine failed")
+                    })
+                })
+            })
+            .collect();
+        first.join().unwrap().unwrap();
+
+        // The combiner panics, the other writer gets the panic message
+        let outcomes: Vec<_> = panicking.into_iter().map(|writer| writer.join()).collect();
+        assert_eq!(
+            outcomes.iter().filter(|outcome| outcome.is_err()).count(),
+            1
+        );
+        assert!(outcomes.into_iter().flatten().all(
+            |result| matches!(result, Err(Error::WritePanicked(message)) if message == "combine failed")
+        ));
+
+        // Not stuck, and a write left without a result fails
+        assert!(
+            batcher
+                .write(entry(3), Durability::Buffered, |_| Vec::new())
+                .is_err()
+        );
+        batcher
+            .write(entry(4), Durability::Buffered, |batch| {
+                batch.into_iter().map(|_| Ok(())).collect()
+            })
+            .unwrap();
+    }
+}
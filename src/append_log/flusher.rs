@@ -0,0 +1,222 @@
+use super::AppendLog;
+use crate::{
+    clock::Clock,
+    manifest::Manifest,
+    sstables::{TableList, compactor::CompactorManager},
+};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Condvar, Mutex},
+    thread::{JoinHandle, spawn},
+    time::Duration,
+};
+
+/// How long the flush thread waits before trying again a memtable it failed to flush
+const FLUSH_RETRY_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Wakes the flush thread, shared with the shards queuing memtables
+#[derive(Default)]
+pub struct FlushSignal {
+    state: Mutex<SignalState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct SignalState {
+    /// A memtable was queued since the thread last looked
+    pending: bool,
+    stopping: bool,
+}
+
+impl FlushSignal {
+    /// A memtable was queued
+    pub fn notify(&self) {
+        self.state.lock().expect("poisoned flush signal").pending = true;
+        self.changed.notify_all();
+    }
+
+    fn stop(&self) {
+        self.state.lock().expect("poisoned flush signal").stopping = true;
+        self.changed.notify_all();
+    }
+
+    /// Blocks until a memtable is queued, false once the thread must stop
+    fn wait(&self) -> bool {
+        let mut state = self.state.lock().expect("poisoned flush signal");
+        while !state.pending && !state.stopping {
+            state = self.changed.wait(state).expect("poisoned flush signal");
+        }
+        state.pending = false;
+
+        !state.stopping
+    }
+
+    fn is_stopping(&self) -> bool {
+        self.state.lock().expect("poisoned flush signal").stopping
+    }
+}
+
+/// The thread turning the memtables queued by the shards into SSTables, see
+/// [`crate::Options::max_immutable_memtables`]. Shared by a store and its namespaces, each registered with
+/// [`Flusher::register`]
+pub struct Flusher {
+    signal: Arc<FlushSignal>,
+    stores: Arc<Mutex<FlushedStores>>,
+    worker: Mutex<Option<JoinHandle<()>>>,
+}
+
+#[derive(Default)]
+struct FlushedStores {
+    next_id: u64,
+    stores: BTreeMap<u64, FlushedStore>,
+}
+
+/// What the thread needs to flush the memtables of a store
+struct FlushedStore {
+    shards: Vec<Arc<AppendLog>>,
+    sstables: Arc<Mutex<TableList>>,
+    manifest: Arc<Manifest>,
+    compaction_manager: Arc<CompactorManager>,
+}
+
+/// A store flushed by a [`Flusher`] until this is dropped
+pub struct FlushRegistration {
+    stores: Arc<Mutex<FlushedStores>>,
+    id: u64,
+}
+
+impl Flusher {
+    pub fn start(clock: Arc<dyn Clock>) -> Self {
+        let signal: Arc<FlushSignal> = Default::default();
+        let stores: Arc<Mutex<FlushedStores>> = Default::default();
+
+        let worker = {
+            let signal = signal.clone();
+            let stores = stores.clone();
+            spawn(move || {
+                while signal.wait() {
+                    // Until every queue is empty, or the database closes. Holding the lock makes
+                    // `FlushRegistration::drop` wait for the flush in progress
+                    while !flush_stores(&stores.lock().expect("poisoned flusher stores")) {
+                        if signal.is_stopping() {
+                            return;
+                        }
+                        clock.sleep(FLUSH_RETRY_INTERVAL);
+                    }
+                }
+            })
+        };
+
+        Self {
+            signal,
+            stores,
+            worker: Mutex::new(Some(worker)),
+        }
+    }
+
+    /// Wakes the thread, to be shared with the shards queuing memtables
+    pub fn signal(&self) -> &Arc<FlushSignal> {
+        &self.signal
+    }
+
+    /// Flushes the memtables of `shards`, which must share [`Flusher::signal`], until the registration is dropped
+    pub fn register(
+        &self,
+        shards: Vec<Arc<AppendLog>>,
+        sstables: Arc<Mutex<TableList>>,
+        manifest: Arc<Manifest>,
+        compaction_manager: Arc<CompactorManager>,
+    ) -> FlushRegistration {
+        let mut stores = self.stores.lock().expect("poisoned flusher stores");
+        let id = stores.next_id;
+        stores.next_id += 1;
+        stores.stores.insert(
+            id,
+            FlushedStore {
+                shards,
+                sstables,
+                manifest,
+                compaction_manager,
+            },
+        );
+        drop(stores);
+
+        // Memtables queued before a crash are replayed from their log files into the queue
+        self.signal.notify();
+
+        FlushRegistration {
+            stores: self.stores.clone(),
+            id,
+        }
+    }
+
+    /// Stops the thread once the flush in progress, if any, is over. The memtables still queued are flushed by the
+    /// next [`AppendLog::flush`], or replayed from their log files on open
+    pub fn stop(&self) {
+        self.signal.stop();
+        let worker = self.worker.lock().expect("poisoned flusher worker").take();
+        if let Some(worker) = worker
+            && worker.join().is_err()
+        {
+            log::error!("flush thread panicked");
+        }
+    }
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl Drop for FlushRegistration {
+    /// Waits for the flush in progress, if any
+    fn drop(&mut self) {
+        self.stores
+            .lock()
+            .expect("poisoned flusher stores")
+            .stores
+            .remove(&self.id);
+    }
+}
+
+/// Flushes the queued memtables of every store. Returns false when one failed
+fn flush_stores(stores: &FlushedStores) -> bool {
+    let mut flushed_all = true;
+    for store in stores.stores.values() {
+        flushed_all &= flush_queues(
+            &store.shards,
+            &store.sstables,
+            &store.manifest,
+            &store.compaction_manager,
+        );
+    }
+
+    flushed_all
+}
+
+/// Flushes the queued memtables of every shard, oldest first. Returns false when one failed, the others of its shard
+/// then wait for the next try
+fn flush_queues(
+    shards: &[Arc<AppendLog>],
+    sstables: &Mutex<TableList>,
+    manifest: &Manifest,
+    compaction_manager: &CompactorManager,
+) -> bool {
+    let mut flushed_all = true;
+    for shard in shards {
+        loop {
+            match shard.flush_frozen(sstables, manifest) {
+                Ok(true) => compaction_manager.signal_sstable_inserted(),
+                Ok(false) => break,
+                Err(e) => {
+                    shard.flush_failed(&e);
+                    flushed_all = false;
+                    break;
+                }
+            }
+        }
+    }
+
+    flushed_all
+}
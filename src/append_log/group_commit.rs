@@ -0,0 +1,226 @@
+use crate::options::Options;
+use std::{
+    io,
+    sync::{Condvar, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+/// Batches the syncs of concurrent writers, so that one `sync_data` makes a whole group durable.
+///
+/// Writers register with [`GroupCommit::begin`] before writing their record, then wait in [`PendingWrite::commit`].
+/// The first waiter becomes the leader: it waits (up to the group delay) for the other writers in flight, syncs once
+/// for everyone and wakes them up.
+pub struct GroupCommit {
+    /// Longest time a leader waits for the writers still in flight
+    delay: Duration,
+    state: Mutex<CommitState>,
+    /// Notified when a writer joins the group and when a sync completes
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct CommitState {
+    /// Writers that called `begin` but didn't join the group yet
+    in_flight: u64,
+    /// Ticket of the latest writer waiting for a sync
+    requested: u64,
+    /// Every ticket up to this one is durable
+    synced: u64,
+    /// Tickets up to this one were part of a failed sync, a later successful one doesn't make them durable
+    failed: Option<(u64, io::ErrorKind)>,
+    leader_active: bool,
+}
+
+/// A write registered with [`GroupCommit::begin`], dropping it without committing unregisters it
+pub struct PendingWrite<'a> {
+    group: &'a GroupCommit,
+    committed: bool,
+}
+
+impl GroupCommit {
+    pub fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            state: Default::default(),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// `None` unless [`Options::sync_writes`] is set
+    pub fn from_options(options: &Options) -> Option<Self> {
+        options
+            .sync_writes
+            .then(|| Self::new(options.group_commit_delay))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, CommitState> {
+        self.state.lock().expect("poisoned group commit lock")
+    }
+
+    /// Must be called before writing the record, lets the leader wait for it
+    pub fn begin(&self) -> PendingWrite<'_> {
+        self.lock().in_flight += 1;
+
+        PendingWrite {
+            group: self,
+            committed: false,
+        }
+    }
+}
+
+impl CommitState {
+    /// `None` until a sync covered `ticket`. A failed sync wins over any later one: the pages it failed to write back
+    /// may be gone, so a successful sync right after it doesn't mean they reached the disk
+    fn outcome(&self, ticket: u64) -> Option<io::Result<()>> {
+        if let Some((failed, kind)) = self.failed
+            && ticket <= failed
+        {
+            return Some(Err(io::Error::new(kind, "group commit sync failed")));
+        }
+        (self.synced >= ticket).then_some(Ok(()))
+    }
+}
+
+impl PendingWrite<'_> {
+    /// Waits until the record is durable, running `sync` on behalf of the group when leading it
+    pub fn commit(mut self, sync: impl FnOnce() -> io::Result<()>) -> io::Result<()> {
+        self.committed = true;
+        let group = self.group;
+
+        let mut state = group.lock();
+        state.in_flight -= 1;
+        state.requested += 1;
+        let ticket = state.requested;
+        group.changed.notify_all();
+
+        let mut sync = Some(sync);
+        loop {
+            if let Some(result) = state.outcome(ticket) {
+                return result;
+            }
+
+            if state.leader_active {
+                state = group
+                    .changed
+                    .wait(state)
+                    .expect("poisoned group commit lock");
+                continue;
+            }
+
+            // Lead the group: a leader never waits for itself, so `sync` is still there
+            state.leader_active = true;
+            let deadline = Instant::now() + group.delay;
+            while state.in_flight > 0 {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    break;
+                }
+                state = group
+                    .changed
+                    .wait_timeout(state, remaining)
+                    .expect("poisoned group commit lock")
+                    .0;
+            }
+
+            let target = state.requested;
+            drop(state);
+            let result = (sync.take().expect("a writer leads at most once"))();
+
+            state = group.lock();
+            state.leader_active = false;
+            match &result {
+                Ok(()) => state.synced = target,
+                Err(e) => state.failed = Some((target, e.kind())),
+            }
+            group.changed.notify_all();
+
+            return result;
+        }
+    }
+}
+
+impl Drop for PendingWrite<'_> {
+    fn drop(&mut self) {
+        if !self.committed {
+            self.group.lock().in_flight -= 1;
+            self.group.changed.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::{
+            Arc,
+            atomic::{AtomicU64, Ordering},
+        },
+        thread,
+    };
+
+    #[test]
+    fn test_group_commit_batches_syncs() {
+        const THREADS: u64 = 8;
+        const WRITES: u64 = 50;
+
+        let group = Arc::new(GroupCommit::new(Duration::from_millis(1)));
+        let syncs = Arc::new(AtomicU64::new(0));
+
+        let writers: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let (group, syncs) = (group.clone(), syncs.clone());
+                thread::spawn(move || {
+                    for _ in 0..WRITES {
+                        group
+                            .begin()
+                            .commit(|| {
+                                syncs.fetch_add(1, Ordering::SeqCst);
+                                thread::sleep(Duration::from_millis(2));
+                                Ok(())
+                            })
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let syncs = syncs.load(Ordering::SeqCst);
+        assert!(syncs < THREADS * WRITES / 2, "{syncs} syncs");
+        assert_eq!(group.lock().synced, THREADS * WRITES);
+    }
+
+    #[test]
+    fn test_failed_sync_not_hidden_by_later_sync() {
+        const WRITERS: u64 = 3;
+
+        // The leader waits for every writer, so they all share its failing sync
+        let group = GroupCommit::new(Duration::from_secs(5));
+        let pending: Vec<_> = (0..WRITERS).map(|_| group.begin()).collect();
+        thread::scope(|scope| {
+            let writers: Vec<_> = pending
+                .into_iter()
+                .map(|write| scope.spawn(|| write.commit(|| Err(io::ErrorKind::Other.into()))))
+                .collect();
+            for writer in writers {
+                assert!(writer.join().unwrap().is_err());
+            }
+        });
+        assert_eq!(group.lock().failed.map(|(ticket, _)| ticket), Some(WRITERS));
+
+        // A writer of the failed group that only re-takes the lock after the next leader synced
+        group.begin().commit(|| Ok(())).unwrap();
+        let state = group.lock();
+        assert_eq!(state.synced, WRITERS + 1);
+        for ticket in 1..=WRITERS {
+            assert!(
+                matches!(state.outcome(ticket), Some(Err(_))),
+                "ticket {ticket}"
+            );
+        }
+        assert!(matches!(state.outcome(WRITERS + 1), Some(Ok(()))));
+    }
+}
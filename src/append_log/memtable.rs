@@ -0,0 +1,288 @@
+use crate::{
+    Key,
+    comparator::KeyOrder,
+    errors::Error,
+    files::FileWithPath,
+    functions::{self, ReadOutcome},
+    serialization::{self, KVMemoryRepr, RecordFormat},
+    stats::RangeEstimate,
+};
+use std::{collections::HashMap, mem};
+
+/// Entries of the current log file, with their offset in it.
+///
+/// With [`crate::Options::spill_values`] only the keys of the point entries and where they are in the file are kept,
+/// the entries are read back from the file when needed.
+pub enum Memtable {
+    /// Sorted by sequence
+    Full(Vec<(u64, KVMemoryRepr)>),
+    Spilled {
+        /// Sorted by sequence
+        points: Vec<SpilledEntry>,
+        /// Range tombstones are kept whole, they're few and any of them can cover the key being read. Sorted by
+        /// sequence
+        ranges: Vec<(u64, KVMemoryRepr)>,
+    },
+}
+
+/// A point entry left in the log file
+pub struct SpilledEntry {
+    offset: u64,
+    key: Key,
+    sequence: u64,
+    /// Of the record in the file
+    len: u32,
+}
+
+/// Newest entry of a key in a [`Memtable`], not read back yet
+enum Hit<'a> {
+    Entry(&'a KVMemoryRepr),
+    Spilled(&'a SpilledEntry),
+}
+
+impl Memtable {
+    pub fn new(spill: bool) -> Self {
+        if spill {
+            Memtable::Spilled {
+                points: Vec::new(),
+                ranges: Vec::new(),
+            }
+        } else {
+            Memtable::Full(Vec::new())
+        }
+    }
+
+    /// `entries` must be sorted by sequence, written as records of `format`
+    pub fn from_entries(
+        entries: Vec<(u64, KVMemoryRepr)>,
+        spill: bool,
+        format: RecordFormat,
+    ) -> Result<Self, Error> {
+        if !spill {
+            return Ok(Memtable::Full(entries));
+        }
+
+        let mut memtable = Self::new(true);
+        for (offset, entry) in entries {
+            let len = serialization::serialize(&entry, format)?.len();
+            memtable.push(offset, entry, len);
+        }
+
+        Ok(memtable)
+    }
+
+    /// Adds `entry`, written at `offset` as a record of `len` bytes
+    pub fn push(&mut self, offset: u64, entry: KVMemoryRepr, len: usize) {
+        match self {
+            Memtable::Full(entries) => {
+                entries.push((offset, entry));
+                // Insertion sort since it's almost sorted
+                functions::insertion_sort_by_key(entries, |e| e.1.sequence());
+            }
+            Memtable::Spilled { ranges, .. } if entry.is_range_tombstone() => {
+                ranges.push((offset, entry));
+                functions::insertion_sort_by_key(ranges, |e| e.1.sequence());
+            }
+            Memtable::Spilled { points, .. } => {
+                points.push(SpilledEntry {
+                    offset,
+                    key: *entry.key(),
+                    sequence: entry.sequence(),
+                    len: len as u32,
+                });
+                functions::insertion_sort_by_key(points, |e| e.sequence);
+            }
+        }
+    }
+
+    /// Number of entries, overwritten ones included
+    pub fn len(&self) -> usize {
+        match self {
+            Memtable::Full(entries) => entries.len(),
+            Memtable::Spilled { points, ranges } => points.len() + ranges.len(),
+        }
+    }
+
+    /// Highest sequence number, 0 if empty
+    pub fn max_sequence(&self) -> u64 {
+        match self {
+            Memtable::Full(entries) => entries.last().map_or(0, |(_, e)| e.sequence()),
+            Memtable::Spilled { points, ranges } => {
+                let point = points.last().map_or(0, |e| e.sequence);
+                ranges
+                    .last()
+                    .map_or(point, |(_, e)| point.max(e.sequence()))
+            }
+        }
+    }
+
+    /// Memory held by the entries
+    pub fn memory_bytes(&self) -> u64 {
+        let full = mem::size_of::<(u64, KVMemoryRepr)>();
+        let bytes = match self {
+            Memtable::Full(entries) => entries.capacity() * full,
+            Memtable::Spilled { points, ranges } => {
+                points.capacity() * mem::size_of::<SpilledEntry>() + ranges.capacity() * full
+            }
+        };
+
+        bytes as u64
+    }
+
+    /// Newest entry of `key`, a range tombstone covering it included. `file` is the log file holding the entries,
+    /// and `order` tells which keys the range tombstones cover
+    pub fn find(
+        &self,
+        file: &FileWithPath,
+        key: &Key,
+        order: &KeyOrder,
+    ) -> Result<Option<KVMemoryRepr>, Error> {
+        self.newest(key, order)
+            .map(|hit| resolve(file, hit))
+            .transpose()
+    }
+
+    /// Same as [`Memtable::find`] for many keys, scanning the entries only once
+    pub fn find_keys(
+        &self,
+        file: &FileWithPath,
+        keys: &[Key],
+        now: u64,
+        order: &KeyOrder,
+    ) -> Result<Vec<ReadOutcome>, Error> {
+        let mut results: Vec<_> = keys.iter().map(|_| ReadOutcome::NotFound).collect();
+
+        let mut positions: HashMap<Key, Vec<usize>> = HashMap::new();
+        for (i, key) in keys.iter().enumerate() {
+            positions.entry(*key).or_default().push(i);
+        }
+
+        let Memtable::Full(entries) = self else {
+            for (key, found_at) in positions {
+                let Some(hit) = self.newest(&key, order) else {
+                    continue;
+                };
+                let result = resolve(file, hit)?.read_outcome(now);
+                for i in found_at {
+                    results[i] = result;
+                }
+            }
+            return Ok(results);
+        };
+
+        // Search from the end, so the first match is the most recent value
+        for (_, entry) in entries.iter().rev() {
+            if positions.is_empty() {
+                break;
+            }
+
+            if entry.is_range_tombstone() {
+                positions.retain(|key, found_at| {
+                    if !entry.covers(key, order) {
+                        return true;
+                    }
+                    for i in found_at {
+                        results[*i] = ReadOutcome::Deleted;
+                    }
+                    false
+                });
+            } else if let Some(found_at) = positions.remove(entry.key()) {
+                for i in found_at {
+                    results[i] = entry.read_outcome(now);
+                }
+            }
+        }
+
+        Ok(results)
+    }
+
+    /// Every entry with its offset, sorted by sequence. Spilled entries are read back from `file`
+    pub fn entries(&self, file: &FileWithPath) -> Result<Vec<(u64, KVMemoryRepr)>, Error> {
+        let (points, ranges) = match self {
+            Memtable::Full(entries) => return Ok(entries.clone()),
+            Memtable::Spilled { points, ranges } => (points, ranges),
+        };
+
+        // A single read of the written part of the file
+        let end = points.iter().map(|e| e.offset + e.len as u64).max();
+        let content = functions::read_file(&file.file, end.unwrap_or(0))?;
+
+        let mut entries = ranges.clone();
+        for spilled in points {
+            let start = spilled.offset as usize;
+            let (entry, _) = serialization::deserialize(
+                &content[start..start + spilled.len as usize],
+                file.record_format,
+            )?;
+            entries.push((spilled.offset, entry));
+        }
+        entries.sort_by_key(|(_, entry)| entry.sequence());
+
+        Ok(entries)
+    }
+
+    /// Point entries in `start..end`
+    pub fn approximate_range(&self, start: Key, end: Key, order: &KeyOrder) -> RangeEstimate {
+        let mut estimate = RangeEstimate::default();
+        match self {
+            Memtable::Full(entries) => {
+                for (_, entry) in entries {
+                    if !entry.is_range_tombstone() && order.in_range(&start, &end, entry.key()) {
+                        estimate.entries += 1;
+                        estimate.bytes += serialization::serialize(entry, RecordFormat::CURRENT)
+                            .map_or(0, |r| r.len() as u64);
+                    }
+                }
+            }
+            Memtable::Spilled { points, .. } => {
+                for spilled in points
+                    .iter()
+                    .filter(|e| order.in_range(&start, &end, &e.key))
+                {
+                    estimate.entries += 1;
+                    estimate.bytes += spilled.len as u64;
+                }
+            }
+        }
+
+        estimate
+    }
+
+    /// Most recent entry for `key`, a point entry or a range tombstone covering it
+    fn newest(&self, key: &Key, order: &KeyOrder) -> Option<Hit<'_>> {
+        match self {
+            Memtable::Full(entries) => entries
+                .iter()
+                .rev()
+                .find(|(_, e)| (e.key() == key && !e.is_range_tombstone()) || e.covers(key, order))
+                .map(|(_, e)| Hit::Entry(e)),
+            Memtable::Spilled { points, ranges } => {
+                let point = points.iter().rev().find(|e| e.key == *key);
+                let range =
+                    serialization::newest_covering(ranges.iter().map(|(_, e)| e), key, order);
+
+                match (point, range) {
+                    (Some(point), Some(range)) if range.sequence() > point.sequence => {
+                        Some(Hit::Entry(range))
+                    }
+                    (Some(point), _) => Some(Hit::Spilled(point)),
+                    (None, range) => range.map(Hit::Entry),
+                }
+            }
+        }
+    }
+}
+
+/// The entry of `hit`, reading a spilled one back from `file`
+fn resolve(file: &FileWithPath, hit: Hit) -> Result<KVMemoryRepr, Error> {
+    let spilled = match hit {
+        Hit::Entry(entry) => return Ok(entry.clone()),
+        Hit::Spilled(spilled) => spilled,
+    };
+
+    let mut buffer = vec![0u8; spilled.len as usize];
+    functions::read_data_at_offset(&file.file, &mut buffer, spilled.offset)?;
+    let (entry, _) = serialization::deserialize(&buffer, file.record_format)?;
+
+    Ok(entry)
+}
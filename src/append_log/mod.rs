@@ -1,170 +1,1432 @@
 use crate::{
-    FILE_SIZE_BYTES, Key, Value, cleanup,
+    FILE_SIZE_BYTES, Key,
+    changes::{Change, ChangeHub},
+    cleanup,
+    clock::Clock,
+    debug::LogDump,
+    env::RandomSource,
     errors::Error,
+    events::{EventListener, FlushInfo, FlushReason},
+    file_header::{self, FileHeader, FileKind, HEADER_BYTES},
     files::FileWithPath,
-    functions::{self, FindResult},
-    serialization::{self, KVMemoryRepr},
-    sstables::{self, SSTable, compactor::CompactorManager},
+    functions::{self, ReadOutcome},
+    instrumentation::{increment_counter, record, record_histogram, span},
+    manifest::Manifest,
+    options::{Durability, Options},
+    quota::DiskQuota,
+    serialization::{self, KVMemoryRepr, RecordFormat},
+    sstables::{self, TableFiles, TableList, TableOptions, compactor::CompactorManager},
+    stats::RangeEstimate,
+    storage::Storage,
 };
 use std::{
-    mem,
+    collections::VecDeque,
+    hash::{DefaultHasher, Hash, Hasher},
+    io, mem,
     path::{Path, PathBuf},
-    sync::{Arc, Mutex, RwLock, RwLockReadGuard},
+    sync::{
+        Arc, Condvar, Mutex, MutexGuard, OnceLock, RwLock, RwLockReadGuard,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::Instant,
 };
 
-/// The file's offset is added to prevent this vector having the wrong order
-type InMemoryAppendLog = Vec<(u64, KVMemoryRepr)>;
+mod batcher;
+mod flusher;
+mod group_commit;
+mod memtable;
+mod shards;
+
+use batcher::{Batch, WriteBatcher};
+use flusher::FlushSignal;
+pub use flusher::Flusher;
+use group_commit::{GroupCommit, PendingWrite};
+use memtable::Memtable;
+pub use shards::ShardedAppendLog;
+
+pub const LOG_FILE_PREFIX: &str = "log_";
+/// Number of locks shared by all keys for read-modify-write operations
+const KEY_LOCK_STRIPES: usize = 64;
+/// Smallest block of [`Options::align_log_records`], any record fits in one
+const MIN_RECORD_ALIGNMENT: u64 = serialization::MAX_RECORD_BYTES as u64;
 
 /// Represents the log file, the current available write location and the in-memory copy
-type InnerState = (FileWithPath, Mutex<u64>, RwLock<InMemoryAppendLog>);
+type InnerState = (FileWithPath, Mutex<u64>, RwLock<Memtable>);
+/// Offset and size of a record with the padding before it (see [`Options::align_log_records`]), and the state it
+/// belongs to
+type Slot<'a> = (u64, usize, u64, RwLockReadGuard<'a, InnerState>);
+/// Offset of the first of consecutive slots (padding included), the offset and size of each record and the state they
+/// belong to
+type Slots<'a> = (u64, Vec<(u64, usize)>, RwLockReadGuard<'a, InnerState>);
 
 pub struct AppendLog {
     state: RwLock<InnerState>,
     file_rotation_lock: Mutex<()>,
     db_dir: PathBuf,
+    /// Where rotated log files are turned into SSTables
+    table_files: Arc<TableFiles>,
+    /// Position in [`ShardedAppendLog`], also the slot of the log file in the manifest
+    shard: usize,
+    /// Striped per-key locks, see [`AppendLog::lock_key`]
+    key_locks: [Mutex<()>; KEY_LOCK_STRIPES],
+    /// Notified on every rotation
+    listener: Option<Arc<dyn EventListener>>,
+    /// Set with [`Options::sync_writes`]
+    group_commit: Option<GroupCommit>,
+    /// Set with [`Options::write_batching`]
+    batcher: Option<WriteBatcher>,
+    /// Retired log files, zeroed and ready to receive writes again
+    recycled: Mutex<Vec<FileWithPath>>,
+    /// See [`Options::recycled_log_files`]
+    max_recycled: usize,
+    /// Of the SSTables created by rotations
+    table_options: TableOptions,
+    quota: Arc<DiskQuota>,
+    /// See [`Options::spill_values`]
+    spill_values: bool,
+    clock: Arc<dyn Clock>,
+    /// Draws the names of new log files
+    random: Arc<dyn RandomSource>,
+    /// Clock time (in ms) of the latest write, shared with the compactor for [`Options::idle_compaction_after`]
+    last_write: Arc<AtomicU64>,
+    /// Sequence number of the latest write, shared by all shards. Set by [`ShardedAppendLog`]
+    last_sequence: Arc<AtomicU64>,
+    /// Oldest first. Pushed under the state write lock and read after the memtable under the state read lock, see
+    /// [`FrozenLog`]
+    frozen: RwLock<VecDeque<Arc<FrozenLog>>>,
+    /// Held while the oldest frozen log turns into an SSTable, so that it's only done once
+    flush_lock: Mutex<()>,
+    /// See [`Options::max_immutable_memtables`], frozen logs are flushed by the rotation itself with 0
+    max_immutable_memtables: usize,
+    /// Wakes the flush thread, set by [`ShardedAppendLog::start_flusher`]
+    flush_signal: OnceLock<Arc<FlushSignal>>,
+    /// Notified with `queue_lock` whenever a frozen log left the queue, or the flush thread failed
+    queue_freed: Condvar,
+    queue_lock: Mutex<()>,
+    /// The flush thread failed on the oldest frozen log, see [`AppendLog::flush_failed`]
+    flush_failed: AtomicBool,
+    /// See [`Options::memtable_max_entries`]
+    memtable_max_entries: Option<usize>,
+    /// See [`Options::memtable_max_bytes`]
+    memtable_max_bytes: Option<u64>,
+    /// Block size of [`Options::align_log_records`]
+    record_alignment: Option<u64>,
+}
+
+/// A record written to its slot by [`AppendLog::stage_entry`], either published with [`AppendLog::publish_staged`] or
+/// taken back with [`AppendLog::abandon_staged`]. Holds the state it belongs to, so no rotation happens meanwhile
+pub struct StagedWrite<'a> {
+    data: KVMemoryRepr,
+    slot: u64,
+    serialized_len: usize,
+    /// Written before `slot`, see [`Options::align_log_records`]
+    padding: u64,
+    /// The log was rotated to make room for the record
+    rotated: bool,
+    /// Registered before the write with [`Options::sync_writes`], dropped when abandoned
+    pending: Option<PendingWrite<'a>>,
+    read_lock: RwLockReadGuard<'a, InnerState>,
+}
+
+/// A log file replaced by a rotation, still read until its entries are in a registered SSTable.
+///
+/// The SSTable is built outside of the state lock, reads and writes go on meanwhile. Several can wait for the flush
+/// thread, see [`Options::max_immutable_memtables`]
+struct FrozenLog {
+    file: FileWithPath,
+    /// Written bytes, header included
+    used: u64,
+    memtable: Memtable,
+    reason: FlushReason,
+}
+
+impl FrozenLog {
+    /// Every entry, sorted by sequence
+    fn entries(&self) -> Result<Vec<KVMemoryRepr>, Error> {
+        let entries = self.memtable.entries(&self.file)?;
+
+        Ok(entries.into_iter().map(|(_, entry)| entry).collect())
+    }
 }
 
 impl AppendLog {
-    pub fn new(db_dir: &Path) -> Result<Self, Error> {
-        let file = create_append_log_file(db_dir)?;
+    pub fn new(
+        db_dir: &Path,
+        table_files: &Arc<TableFiles>,
+        shard: usize,
+        options: &Options,
+        quota: &Arc<DiskQuota>,
+    ) -> Result<Self, Error> {
+        let file = create_append_log_file(&**table_files.storage(), db_dir, &*options.random)?;
+        quota.add_log_file(FILE_SIZE_BYTES);
 
         Ok(Self {
-            state: RwLock::new((file, Mutex::new(0), Default::default())),
+            state: RwLock::new((
+                file,
+                Mutex::new(HEADER_BYTES),
+                RwLock::new(Memtable::new(options.spill_values)),
+            )),
             file_rotation_lock: Default::default(),
             db_dir: db_dir.to_owned(),
+            table_files: table_files.clone(),
+            shard,
+            key_locks: std::array::from_fn(|_| Mutex::new(())),
+            listener: options.event_listener.clone(),
+            group_commit: GroupCommit::from_options(options),
+            batcher: WriteBatcher::from_options(options),
+            recycled: Default::default(),
+            max_recycled: options.recycled_log_files,
+            table_options: TableOptions::from(options),
+            quota: quota.clone(),
+            spill_values: options.spill_values,
+            clock: options.clock.clone(),
+            random: options.random.clone(),
+            last_write: Arc::new(AtomicU64::new(options.clock.now_millis())),
+            last_sequence: Default::default(),
+            frozen: Default::default(),
+            flush_lock: Default::default(),
+            max_immutable_memtables: options.max_immutable_memtables,
+            flush_signal: Default::default(),
+            queue_freed: Default::default(),
+            queue_lock: Default::default(),
+            flush_failed: Default::default(),
+            memtable_max_entries: options.memtable_max_entries,
+            memtable_max_bytes: options.memtable_max_bytes,
+            record_alignment: options
+                .log_record_alignment
+                .map(|block| block.max(MIN_RECORD_ALIGNMENT)),
+        })
+    }
+
+    /// Reopens an existing log file, replaying its content into memory.
+    ///
+    /// With `read_only` the file is opened without write access, writing to the log will then fail.
+    pub fn open(
+        db_dir: &Path,
+        table_files: &Arc<TableFiles>,
+        shard: usize,
+        log_file: &str,
+        read_only: bool,
+        options: &Options,
+        quota: &Arc<DiskQuota>,
+    ) -> Result<Self, Error> {
+        let (file, used, memtable) = replay_log_file(
+            &**table_files.storage(),
+            &db_dir.join(log_file),
+            read_only,
+            options.spill_values,
+        )?;
+        quota.add_log(used - HEADER_BYTES);
+        quota.add_log_file(FILE_SIZE_BYTES);
+
+        Ok(Self {
+            state: RwLock::new((file, Mutex::new(used), RwLock::new(memtable))),
+            file_rotation_lock: Default::default(),
+            db_dir: db_dir.to_owned(),
+            table_files: table_files.clone(),
+            shard,
+            key_locks: std::array::from_fn(|_| Mutex::new(())),
+            listener: options.event_listener.clone(),
+            group_commit: GroupCommit::from_options(options),
+            batcher: WriteBatcher::from_options(options),
+            recycled: Default::default(),
+            max_recycled: options.recycled_log_files,
+            table_options: TableOptions::from(options),
+            quota: quota.clone(),
+            spill_values: options.spill_values,
+            clock: options.clock.clone(),
+            random: options.random.clone(),
+            last_write: Arc::new(AtomicU64::new(options.clock.now_millis())),
+            last_sequence: Default::default(),
+            frozen: Default::default(),
+            flush_lock: Default::default(),
+            max_immutable_memtables: options.max_immutable_memtables,
+            flush_signal: Default::default(),
+            queue_freed: Default::default(),
+            queue_lock: Default::default(),
+            flush_failed: Default::default(),
+            memtable_max_entries: options.memtable_max_entries,
+            memtable_max_bytes: options.memtable_max_bytes,
+            record_alignment: options
+                .log_record_alignment
+                .map(|block| block.max(MIN_RECORD_ALIGNMENT)),
+        })
+    }
+
+    /// Reopens a log file a rotation froze before a crash, see [`FrozenLog`], after the ones opened before it. The
+    /// flush thread, or else the next rotation (or flush), turns it into an SSTable
+    pub fn open_frozen(&mut self, log_file: &str, read_only: bool) -> Result<(), Error> {
+        let (file, used, memtable) = replay_log_file(
+            self.storage(),
+            &self.db_dir.join(log_file),
+            read_only,
+            self.spill_values,
+        )?;
+        self.quota.add_log(used - HEADER_BYTES);
+        self.quota.add_log_file(FILE_SIZE_BYTES);
+        self.frozen
+            .get_mut()
+            .expect("poisoned frozen logs")
+            .push_back(Arc::new(FrozenLog {
+                file,
+                used,
+                memtable,
+                reason: FlushReason::Recovered,
+            }));
+
+        Ok(())
+    }
+
+    /// Locks `key` (and the other keys sharing its stripe) against other read-modify-write operations.
+    ///
+    /// Plain writes don't take this lock.
+    pub fn lock_key(&self, key: &Key) -> MutexGuard<'_, ()> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let stripe = hasher.finish() as usize % KEY_LOCK_STRIPES;
+
+        self.key_locks[stripe].lock().expect("poisoned key lock")
+    }
+
+    /// Highest sequence number in the log, 0 if empty
+    pub fn max_sequence(&self) -> u64 {
+        self.fold_memtables(0, |sequence, memtable| {
+            sequence.max(memtable.max_sequence())
+        })
+    }
+
+    /// Number of entries in the log, overwritten ones included
+    pub fn entry_count(&self) -> usize {
+        self.fold_memtables(0, |count, memtable| count + memtable.len())
+    }
+
+    /// Memory held by the in-memory log
+    pub fn memtable_bytes(&self) -> u64 {
+        self.fold_memtables(0, |bytes, memtable| bytes + memtable.memory_bytes())
+    }
+
+    /// Folds the memtable and the ones of the frozen logs
+    fn fold_memtables<T>(&self, init: T, f: impl Fn(T, &Memtable) -> T) -> T {
+        let state_lock = self.state.read().expect("poisoned state lock");
+        let folded = f(init, &state_lock.2.read().expect("poisoned in_memory"));
+
+        self.frozen
+            .read()
+            .expect("poisoned frozen logs")
+            .iter()
+            .fold(folded, |folded, frozen| f(folded, &frozen.memtable))
+    }
+
+    /// Name of the log file currently receiving writes
+    pub fn file_name(&self) -> String {
+        let state_lock = self.state.read().expect("poisoned state lock");
+        log_file_name(&state_lock.0)
+    }
+
+    /// See [`AppendLog::last_write`]
+    pub fn last_write(&self) -> Arc<AtomicU64> {
+        self.last_write.clone()
+    }
+
+    /// The in-memory log, with the offset of every entry in the file
+    pub fn dump(&self) -> Result<LogDump, Error> {
+        let state_lock = self.state.read().expect("poisoned state lock");
+        let in_memory = state_lock.2.read().expect("poisoned in_memory");
+
+        Ok(LogDump {
+            file_name: log_file_name(&state_lock.0),
+            entries: in_memory
+                .entries(&state_lock.0)?
+                .iter()
+                .map(|(offset, entry)| (*offset, Change::from(entry)))
+                .collect(),
+        })
+    }
+
+    /// Point entries of the in-memory log in `start..end`
+    pub fn approximate_range(&self, start: Key, end: Key) -> RangeEstimate {
+        self.fold_memtables(RangeEstimate::default(), |mut estimate, memtable| {
+            estimate.add(memtable.approximate_range(start, end, self.table_files.order()));
+            estimate
         })
     }
 
-    /// This will search for `key` in the append log
-    pub fn find_key(&self, key: &Key) -> FindResult {
+    /// Forces all data of the log file (and of the frozen ones) to disk
+    pub fn sync(&self) -> Result<(), Error> {
+        let state_lock = self.state.read().expect("poisoned state lock");
+        state_lock.0.file.sync_all()?;
+        for frozen in &*self.frozen.read().expect("poisoned frozen logs") {
+            frozen.file.file.sync_all()?;
+        }
+
+        Ok(())
+    }
+
+    /// This will search for `key` in the append log, returning its newest entry (a range tombstone covering it
+    /// included)
+    pub fn find_key(&self, key: &Key) -> Result<Option<KVMemoryRepr>, Error> {
         let state_lock = self.state.read().expect("poisoned state lock");
+        let entry = state_lock.2.read().expect("poisoned in_memory").find(
+            &state_lock.0,
+            key,
+            self.table_files.order(),
+        )?;
+        if entry.is_some() {
+            return Ok(entry);
+        }
 
-        // Search from the end to get the most recent value for the key
-        for entry in state_lock
-            .2
+        // Newest first
+        for frozen in self
+            .frozen
             .read()
-            .expect("poisoned in_memory")
+            .expect("poisoned frozen logs")
             .iter()
             .rev()
         {
-            if entry.1.key() == key {
-                return match entry.1.value() {
-                    Some(v) => FindResult::Found(*v),
-                    None => FindResult::Tombstone,
-                };
+            let entry = frozen
+                .memtable
+                .find(&frozen.file, key, self.table_files.order())?;
+            if entry.is_some() {
+                return Ok(entry);
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Same as [`AppendLog::find_key`] for many keys, scanning the in-memory log only once
+    pub fn find_keys(&self, keys: &[Key], now: u64) -> Result<Vec<ReadOutcome>, Error> {
+        let state_lock = self.state.read().expect("poisoned state lock");
+        let mut results = state_lock.2.read().expect("poisoned in_memory").find_keys(
+            &state_lock.0,
+            keys,
+            now,
+            self.table_files.order(),
+        )?;
+
+        // Newest first
+        for frozen in self
+            .frozen
+            .read()
+            .expect("poisoned frozen logs")
+            .iter()
+            .rev()
+        {
+            let (missing, missing_keys): (Vec<_>, Vec<_>) = results
+                .iter()
+                .zip(keys)
+                .enumerate()
+                .filter(|(_, (result, _))| **result == ReadOutcome::NotFound)
+                .map(|(i, (_, key))| (i, *key))
+                .unzip();
+            if missing.is_empty() {
+                break;
+            }
+            let found = frozen.memtable.find_keys(
+                &frozen.file,
+                &missing_keys,
+                now,
+                self.table_files.order(),
+            )?;
+            for (i, result) in missing.into_iter().zip(found) {
+                results[i] = result;
             }
         }
 
-        FindResult::None
+        Ok(results)
+    }
+
+    /// Entries of the frozen logs, oldest first, then of the current one, each sorted by sequence. `state` is the
+    /// locked state of this log
+    fn memtable_entries(&self, state: &InnerState) -> Result<Vec<KVMemoryRepr>, Error> {
+        let mut entries = Vec::new();
+        for frozen in &*self.frozen.read().expect("poisoned frozen logs") {
+            entries.extend(frozen.entries()?);
+        }
+        let in_memory = state.2.read().expect("poisoned in_memory");
+        entries.extend(
+            in_memory
+                .entries(&state.0)?
+                .into_iter()
+                .map(|(_, entry)| entry),
+        );
+
+        Ok(entries)
+    }
+
+    /// This will write `data` in the append log, creating new files as needed.
+    ///
+    /// Point entries get the next sequence number along with their slot, so that the records of a log file follow the
+    /// sequence order. Range tombstones, written to every shard with the same number, must already carry theirs.
+    /// The write goes through these stages:
+    /// 1. a slot is reserved in the log file, rotating it when full
+    /// 2. the record is written to the slot, and synced first with [`Options::sync_writes`]
+    /// 3. the entry is published to the readers and to the subscribers of `changes`
+    /// 4. with [`Durability::Synced`] (and no `sync_writes`), the file is synced
+    ///
+    /// A failed sync, in stage 2 or 4, is reported as [`Error::NotDurable`], the entry staying readable as it would
+    /// be after a reopen.
+    ///
+    /// With [`Options::write_batching`], point entries go through these stages along with the ones of concurrent
+    /// writers, see [`AppendLog::write_batch`].
+    pub fn write_entry(
+        &self,
+        data: KVMemoryRepr,
+        sstables: &Mutex<TableList>,
+        manifest: &Manifest,
+        compaction_manager: &CompactorManager,
+        changes: Option<&ChangeHub>,
+        durability: Durability,
+    ) -> Result<(), Error> {
+        match &self.batcher {
+            Some(batcher) if !data.is_range_tombstone() => {
+                batcher.write(data, durability, |batch| {
+                    self.write_batch(batch, sstables, manifest, compaction_manager, changes)
+                })
+            }
+            _ => self.write_single(
+                data,
+                sstables,
+                manifest,
+                compaction_manager,
+                changes,
+                durability,
+            ),
+        }
     }
 
-    /// This will write a `key` in the append log, creating new files as needed
-    pub fn write_key(
+    /// Writes a single entry, see [`AppendLog::write_entry`]
+    fn write_single(
         &self,
-        key: Key,
-        value: Option<Value>,
-        sstables_dir: &Path,
-        sstables: &Mutex<Vec<Arc<SSTable>>>,
+        data: KVMemoryRepr,
+        sstables: &Mutex<TableList>,
+        manifest: &Manifest,
         compaction_manager: &CompactorManager,
+        changes: Option<&ChangeHub>,
+        durability: Durability,
     ) -> Result<(), Error> {
-        let data = KVMemoryRepr::new(key, value);
+        let span = span!("write", key = data.key(); serialized_len, rotated);
+        let staged = self.stage_entry(data, sstables, manifest, compaction_manager)?;
+        record!(span, serialized_len = staged.serialized_len);
+        record!(span, rotated = staged.rotated);
 
-        let serialized_data = serialization::serialize(&data)?;
-        let serialized_data_len = serialized_data.len() as u64;
+        self.publish_staged(
+            staged,
+            sstables,
+            manifest,
+            compaction_manager,
+            changes,
+            durability,
+        )
+    }
+
+    /// Stages 1 and 2 of [`AppendLog::write_entry`] but the sync: the record of `data` is in the log file, not yet
+    /// published. A failed write pads the slot, see [`AppendLog::pad_failed_slot`]
+    pub fn stage_entry(
+        &self,
+        data: KVMemoryRepr,
+        sstables: &Mutex<TableList>,
+        manifest: &Manifest,
+        compaction_manager: &CompactorManager,
+    ) -> Result<StagedWrite<'_>, Error> {
+        let mut data = data;
+        let numbered = data.is_range_tombstone();
+        if !numbered {
+            // The record size depends on the number, this one is close enough for the quota
+            data = data.with_sequence(self.last_sequence.load(Ordering::SeqCst) + 1);
+        }
+        let mut buffer = [0u8; serialization::MAX_RECORD_BYTES];
+        let estimated_len =
+            serialization::serialize_into(&data, &mut buffer, RecordFormat::CURRENT)? as u64;
+        self.quota
+            .try_add_log(estimated_len, data.value().is_none())?;
 
         // Clone the Arc since a slot on that file was acquired
-        let (slot, read_lock) = loop {
-            let log_slot = self.try_acquire_slot(serialized_data_len);
+        let mut rotated = false;
+        let (slot, serialized_len, padding, read_lock) = loop {
+            let log_slot = self
+                .try_acquire_slot(&mut data, numbered, &mut buffer)
+                .inspect_err(|_| self.quota.remove_log(estimated_len))?;
 
             match log_slot {
                 Some(slot) => break slot,
                 None => {
                     // Create new append file
                     {
-                        let rotation_lock_guard =
+                        let _rotation_lock_guard =
                             self.file_rotation_lock.lock().expect("poisoned lock");
 
                         // During wait for rotation lock another worker might have created a new file
-                        if let Some(slot) = self.try_acquire_slot(serialized_data_len) {
+                        let log_slot = self
+                            .try_acquire_slot(&mut data, numbered, &mut buffer)
+                            .inspect_err(|_| self.quota.remove_log(estimated_len))?;
+                        if let Some(slot) = log_slot {
                             break slot;
                         }
 
-                        let file = create_append_log_file(&self.db_dir)?;
+                        self.rotate(sstables, manifest, FlushReason::LogFull)
+                            .inspect_err(|_| self.quota.remove_log(estimated_len))?;
+                    };
+                    rotated = true;
+
+                    compaction_manager.signal_sstable_inserted();
+                }
+            }
+        };
+        if serialized_len as u64 + padding != estimated_len {
+            self.quota.remove_log(estimated_len);
+            self.quota.add_log(serialized_len as u64 + padding);
+        }
+        let serialized_data = &buffer[..serialized_len];
+
+        // Before the in-memory log, so that with `sync_writes` readers never see a write that isn't durable yet, unless
+        // syncing it fails
+        // The slot can't be given back, readers must skip it
+        let pad_slot = |_: &Error| {
+            self.pad_failed_slot(
+                &read_lock.0,
+                slot - padding,
+                serialized_len + padding as usize,
+            )
+        };
+        let pending = self.group_commit.as_ref().map(|group| group.begin());
+        self.write_padding(&read_lock.0, slot, padding)
+            .and_then(|()| {
+                functions::write_data_at_offset(&read_lock.0.file, serialized_data, slot)
+            })
+            .inspect_err(pad_slot)?;
 
-                        // Up until here, reads work (writes will wait for rotation lock).
-                        // It's important that after this point there's no ongoing writes on the file
-                        let mut append_log = self.state.write().expect("poisoned append_log");
+        Ok(StagedWrite {
+            data,
+            slot,
+            serialized_len,
+            padding,
+            rotated,
+            pending,
+            read_lock,
+        })
+    }
+
+    /// Stages 2 (the sync), 3 and 4 of [`AppendLog::write_entry`] for a write of [`AppendLog::stage_entry`]
+    pub fn publish_staged(
+        &self,
+        staged: StagedWrite<'_>,
+        sstables: &Mutex<TableList>,
+        manifest: &Manifest,
+        compaction_manager: &CompactorManager,
+        changes: Option<&ChangeHub>,
+        durability: Durability,
+    ) -> Result<(), Error> {
+        let StagedWrite {
+            data,
+            slot,
+            serialized_len,
+            pending,
+            read_lock,
+            ..
+        } = staged;
 
-                        let (old_log_file, ..) = mem::replace(
-                            &mut *append_log,
-                            (file, Default::default(), Default::default()),
-                        );
+        // A failed sync leaves the record in the file, it's published anyway and reported as not durable
+        let synced = match pending {
+            Some(pending) => pending.commit(|| read_lock.0.file.sync_data()),
+            None => Ok(()),
+        };
 
-                        let sstable =
-                            sstables::log_file_to_sstable(sstables_dir, &old_log_file.file)?;
-                        let sstable = Arc::new(sstable);
+        let mut in_memory_log_guard = read_lock.2.write().expect("poisoned in_memory_log lock");
 
-                        sstables
-                            .lock()
-                            .expect("poisoned sstables lock")
-                            .insert(0, sstable);
+        // Still under the lock, so that subscribers see the writes in the order of the log
+        if let Some(changes) = changes {
+            changes.publish(&data, self.table_files.order());
+        }
 
-                        drop(rotation_lock_guard);
-                        // It's important that append log lock is dropped after this point.
-                        // The in-memory logs are cleared and new reads must go through sstables, hence the write must happen.
-                        drop(append_log);
+        in_memory_log_guard.push(slot, data, serialized_len);
+        let memtable_full = self.memtable_full(&in_memory_log_guard);
+        drop(in_memory_log_guard);
+        self.last_write
+            .store(self.clock.now_millis(), Ordering::Relaxed);
 
-                        // Avoid making other threads wait on this
-                        cleanup::remove_file_logged(&old_log_file.path);
-                    };
+        let synced = synced.and_then(|()| {
+            if durability == Durability::Synced && self.group_commit.is_none() {
+                read_lock.0.file.sync_data()
+            } else {
+                Ok(())
+            }
+        });
+        drop(read_lock);
 
-                    compaction_manager.signal_sstable_inserted();
+        if memtable_full {
+            self.rotate_full_memtable(sstables, manifest, compaction_manager);
+        }
+
+        synced.map_err(Error::NotDurable)
+    }
+
+    /// Takes back a write of [`AppendLog::stage_entry`]: its record is covered with padding (and synced, it may have
+    /// been synced along with other writes) so that it's never replayed
+    pub fn abandon_staged(&self, staged: StagedWrite<'_>) {
+        let file = &staged.read_lock.0;
+        let len = staged.serialized_len + staged.padding as usize;
+        self.pad_failed_slot(file, staged.slot - staged.padding, len);
+        if let Err(e) = file.file.sync_data() {
+            log::error!(
+                "failed to sync the padding of log file {:?}: {:?}",
+                file.path,
+                e
+            );
+        }
+    }
+
+    /// Writes the point entries of `batch` as [`AppendLog::write_entry`] does, sharing the slot reservation, the file
+    /// write (and sync) and the memtable lock. Their numbers follow their order in the batch. Returns the result of
+    /// each entry.
+    ///
+    /// When the batch doesn't fit in the current log file, its entries are written one at a time instead.
+    fn write_batch(
+        &self,
+        batch: Batch,
+        sstables: &Mutex<TableList>,
+        manifest: &Manifest,
+        compaction_manager: &CompactorManager,
+        changes: Option<&ChangeHub>,
+    ) -> Vec<Result<(), Error>> {
+        record_histogram!("kv_write_batch_entries", batch.len() as f64);
+        if batch.len() == 1 {
+            let (data, durability) = batch.into_iter().next().expect("one entry");
+            return vec![self.write_single(
+                data,
+                sstables,
+                manifest,
+                compaction_manager,
+                changes,
+                durability,
+            )];
+        }
+
+        let mut results: Vec<Option<Result<(), Error>>> = batch.iter().map(|_| None).collect();
+        // The record sizes depend on the numbers, the next one is close enough for the quota
+        let sequence = self.last_sequence.load(Ordering::SeqCst) + 1;
+        let mut buffer = [0u8; serialization::MAX_RECORD_BYTES];
+        let mut estimated_len = 0;
+        let mut accepted = Vec::new();
+        for (i, (data, durability)) in batch.into_iter().enumerate() {
+            let data = data.with_sequence(sequence);
+            let admitted = serialization::serialize_into(&data, &mut buffer, RecordFormat::CURRENT)
+                .and_then(|len| {
+                    self.quota
+                        .try_add_log(len as u64, data.value().is_none())
+                        .map(|()| len as u64)
+                });
+            match admitted {
+                Ok(len) => {
+                    estimated_len += len;
+                    accepted.push((i, data, durability));
+                }
+                Err(e) => results[i] = Some(Err(e)),
+            }
+        }
+
+        let mut entries: Vec<_> = accepted.iter().map(|(_, data, _)| data.clone()).collect();
+        let mut records = Vec::new();
+        match self.try_acquire_slots(&mut entries, &mut records) {
+            Ok(Some((offset, slots, read_lock))) => {
+                if records.len() as u64 != estimated_len {
+                    self.quota.remove_log(estimated_len);
+                    self.quota.add_log(records.len() as u64);
+                }
+                let written =
+                    self.write_records(&read_lock, &records, offset, entries, &slots, changes);
+                let (memtable_full, committed) = match written {
+                    Ok(written) => written,
+                    Err(e) => {
+                        let e = Arc::new(e);
+                        for (i, _, _) in &accepted {
+                            results[*i] = Some(Err(Error::BatchFailed(e.clone())));
+                        }
+                        return results.into_iter().flatten().collect();
+                    }
+                };
+
+                let wants_sync = accepted.iter().any(|(_, _, d)| *d == Durability::Synced);
+                let synced = if wants_sync && self.group_commit.is_none() {
+                    read_lock.0.file.sync_data()
+                } else {
+                    Ok(())
+                };
+                drop(read_lock);
+
+                for (i, _, durability) in &accepted {
+                    results[*i] = Some(match (&committed, &synced) {
+                        (Err(e), _) => {
+                            Err(Error::NotDurable(io::Error::new(e.kind(), e.to_string())))
+                        }
+                        (_, Err(e)) if *durability == Durability::Synced => {
+                            Err(Error::NotDurable(io::Error::new(e.kind(), e.to_string())))
+                        }
+                        _ => Ok(()),
+                    });
+                }
+                if memtable_full {
+                    self.rotate_full_memtable(sstables, manifest, compaction_manager);
+                }
+            }
+            Ok(None) => {
+                self.quota.remove_log(estimated_len);
+                for (i, data, durability) in accepted {
+                    results[i] = Some(self.write_single(
+                        data,
+                        sstables,
+                        manifest,
+                        compaction_manager,
+                        changes,
+                        durability,
+                    ));
+                }
+            }
+            Err(e) => {
+                self.quota.remove_log(estimated_len);
+                let e = Arc::new(e);
+                for (i, _, _) in &accepted {
+                    results[*i] = Some(Err(Error::BatchFailed(e.clone())));
                 }
             }
+        }
+
+        results
+            .into_iter()
+            .map(|result| result.expect("every entry has a result"))
+            .collect()
+    }
+
+    /// Writes the serialized `records` of `entries` at `offset` of the log file of `state` (syncing them with
+    /// [`Options::sync_writes`]), then publishes them at their `slots`. Returns whether the memtable is full, and the
+    /// result of the sync: the records are published even if it failed
+    fn write_records(
+        &self,
+        state: &InnerState,
+        records: &[u8],
+        offset: u64,
+        entries: Vec<KVMemoryRepr>,
+        slots: &[(u64, usize)],
+        changes: Option<&ChangeHub>,
+    ) -> Result<(bool, io::Result<()>), Error> {
+        let file = &state.0;
+        // The slots can't be given back, readers must skip them
+        let pad_slots = |_: &Error| self.pad_failed_slot(file, offset, records.len());
+        let committed = if let Some(group_commit) = &self.group_commit {
+            let pending = group_commit.begin();
+            functions::write_data_at_offset(&file.file, records, offset).inspect_err(pad_slots)?;
+            pending.commit(|| file.file.sync_data())
+        } else {
+            functions::write_data_at_offset(&file.file, records, offset).inspect_err(pad_slots)?;
+            Ok(())
         };
 
-        functions::write_data_at_offset(&read_lock.0.file, &serialized_data, slot)?;
+        let mut memtable = state.2.write().expect("poisoned in_memory_log lock");
+        for (entry, (slot, size)) in entries.into_iter().zip(slots) {
+            if let Some(changes) = changes {
+                changes.publish(&entry, self.table_files.order());
+            }
+            memtable.push(*slot, entry, *size);
+        }
+        let memtable_full = self.memtable_full(&memtable);
+        drop(memtable);
+        self.last_write
+            .store(self.clock.now_millis(), Ordering::Relaxed);
 
-        let mut in_memory_log_guard = read_lock.2.write().expect("poisoned in_memory_log lock");
+        Ok((memtable_full, committed))
+    }
+
+    /// Whether `memtable` reached [`Options::memtable_max_entries`] or [`Options::memtable_max_bytes`]
+    fn memtable_full(&self, memtable: &Memtable) -> bool {
+        self.memtable_max_entries
+            .is_some_and(|max| memtable.len() >= max)
+            || self
+                .memtable_max_bytes
+                .is_some_and(|max| memtable.memory_bytes() >= max)
+    }
+
+    /// Rotates the log once a write filled its memtable, unless another writer did it first.
+    ///
+    /// The write is in the log already: a failure is only logged, the next write tries again
+    fn rotate_full_memtable(
+        &self,
+        sstables: &Mutex<TableList>,
+        manifest: &Manifest,
+        compaction_manager: &CompactorManager,
+    ) {
+        let _rotation_lock_guard = self.file_rotation_lock.lock().expect("poisoned lock");
+        let still_full = {
+            let state_lock = self.state.read().expect("poisoned state lock");
+            self.memtable_full(&state_lock.2.read().expect("poisoned in_memory_log lock"))
+        };
+        if !still_full {
+            return;
+        }
+
+        match self.rotate(sstables, manifest, FlushReason::MemtableLimit) {
+            Ok(()) => compaction_manager.signal_sstable_inserted(),
+            Err(e) => log::error!("failed to rotate the log of a full memtable: {e:?}"),
+        }
+    }
+
+    /// Turns the current log file into an SSTable, even if it's not full. The frozen logs, queued for the flush
+    /// thread or left by a failed rotation, go first.
+    ///
+    /// Does nothing if the log is empty, returns whether a flush happened.
+    pub fn flush(&self, sstables: &Mutex<TableList>, manifest: &Manifest) -> Result<bool, Error> {
+        let _rotation_lock_guard = self.file_rotation_lock.lock().expect("poisoned lock");
+
+        let flushed_frozen = self.flush_queue(sstables, manifest)?;
+        let is_empty = {
+            let state_lock = self.state.read().expect("poisoned state lock");
+            *state_lock.1.lock().expect("lock poisoned") == HEADER_BYTES
+        };
+
+        if is_empty {
+            return Ok(flushed_frozen);
+        }
+
+        self.rotate(sstables, manifest, FlushReason::Manual)?;
+        // Without waiting for the flush thread
+        self.flush_queue(sstables, manifest)?;
+
+        Ok(true)
+    }
+
+    /// Turns every frozen log into an SSTable, oldest first, returns whether there was one
+    fn flush_queue(&self, sstables: &Mutex<TableList>, manifest: &Manifest) -> Result<bool, Error> {
+        let mut flushed = false;
+        while self.flush_frozen(sstables, manifest)? {
+            flushed = true;
+        }
+
+        Ok(flushed)
+    }
+
+    /// Blocks until there's room for one more frozen log, see [`Options::max_immutable_memtables`]. Once the flush
+    /// thread failed, the oldest one is flushed here instead, failing with its error.
+    /// The caller must hold the rotation lock.
+    fn wait_for_queue_space(
+        &self,
+        sstables: &Mutex<TableList>,
+        manifest: &Manifest,
+    ) -> Result<(), Error> {
+        let mut queue_guard = self.queue_lock.lock().expect("poisoned queue lock");
+        while self.frozen.read().expect("poisoned frozen logs").len()
+            >= self.max_immutable_memtables
+        {
+            if self.flush_failed.load(Ordering::SeqCst) {
+                drop(queue_guard);
+                self.flush_frozen(sstables, manifest)?;
+                queue_guard = self.queue_lock.lock().expect("poisoned queue lock");
+                continue;
+            }
+
+            queue_guard = self
+                .queue_freed
+                .wait(queue_guard)
+                .expect("poisoned queue lock");
+        }
+
+        Ok(())
+    }
+
+    /// Called by the flush thread when the oldest frozen log failed to turn into an SSTable. Writers waiting for room
+    /// in the queue then try themselves, getting the error
+    pub fn flush_failed(&self, error: &Error) {
+        log::error!(
+            "failed to flush a frozen log of shard {}: {error:?}",
+            self.shard
+        );
+        if let Some(listener) = &self.listener {
+            listener.on_background_error(error);
+        }
+
+        self.flush_failed.store(true, Ordering::SeqCst);
+        let _queue_guard = self.queue_lock.lock().expect("poisoned queue lock");
+        self.queue_freed.notify_all();
+    }
+
+    /// Replaces the log file with a new one, then moves the old content into a new SSTable.
+    ///
+    /// The state lock is only held to swap the files: the old one stays readable as a [`FrozenLog`] while its
+    /// SSTable is built. Nothing changes when the swap fails, e.g. on [`Error::DiskFull`]: the current log keeps
+    /// serving reads and writes. When the SSTable can't be written, the frozen log stays until the next rotation
+    /// (or flush) which tries again first.
+    ///
+    /// With [`Options::max_immutable_memtables`], the frozen log is queued for the flush thread instead, once there's
+    /// room for it.
+    /// The caller must hold the rotation lock.
+    fn rotate(
+        &self,
+        sstables: &Mutex<TableList>,
+        manifest: &Manifest,
+        reason: FlushReason,
+    ) -> Result<(), Error> {
+        if self.flush_signal.get().is_some() {
+            self.wait_for_queue_space(sstables, manifest)?;
+        } else {
+            self.flush_queue(sstables, manifest)?;
+        }
+
+        let file = self.next_log_file()?;
+        let new_log_file = log_file_name(&file);
+
+        // Writes on the new file can only start once the manifest points to it. The old one is replayed on open
+        // until its table is registered
+        let updated = manifest.update(|data| {
+            let old_log_file = mem::replace(&mut data.log_files[self.shard], new_log_file);
+            data.push_frozen_log_file(self.shard, old_log_file);
+        });
+        if let Err(e) = updated {
+            self.retire_log_file(file, HEADER_BYTES);
+            return Err(e);
+        }
 
-        in_memory_log_guard.push((slot, data));
-        // Insertion sort since it's almost sorted
-        functions::insertion_sort_by_key(&mut in_memory_log_guard, |k| k.0);
+        // Up until here, reads and writes work.
+        // It's important that after this point there's no ongoing writes on the file
+        let mut append_log = self.state.write().expect("poisoned append_log");
+        let span = span!("rotation", shard = self.shard);
+        let (old_file, old_offset, memtable) = mem::replace(
+            &mut *append_log,
+            (
+                file,
+                Mutex::new(HEADER_BYTES),
+                RwLock::new(Memtable::new(self.spill_values)),
+            ),
+        );
+        let used = old_offset.into_inner().expect("lock poisoned");
+        let used = used + self.pad_to_end(&old_file, used);
+        self.frozen
+            .write()
+            .expect("poisoned frozen logs")
+            .push_back(Arc::new(FrozenLog {
+                file: old_file,
+                used,
+                memtable: memtable.into_inner().expect("poisoned in_memory"),
+                reason,
+            }));
+        drop(append_log);
+        drop(span);
+
+        match self.flush_signal.get() {
+            Some(flush_signal) => flush_signal.notify(),
+            None => {
+                self.flush_frozen(sstables, manifest)?;
+            }
+        }
 
         Ok(())
     }
 
-    /// Returns, if possible, the read lock to the state and the reserved slot
-    fn try_acquire_slot(&self, size: u64) -> Option<(u64, RwLockReadGuard<'_, InnerState>)> {
+    /// Turns the oldest frozen log into an SSTable and registers it, returns whether there was one
+    pub fn flush_frozen(
+        &self,
+        sstables: &Mutex<TableList>,
+        manifest: &Manifest,
+    ) -> Result<bool, Error> {
+        let _flush_lock_guard = self.flush_lock.lock().expect("poisoned flush lock");
+        let Some(frozen_log) = self
+            .frozen
+            .read()
+            .expect("poisoned frozen logs")
+            .front()
+            .cloned()
+        else {
+            return Ok(false);
+        };
+
+        let started = Instant::now();
+        let mut info = FlushInfo {
+            reason: frozen_log.reason,
+            log_bytes: frozen_log.used - HEADER_BYTES,
+            table_id: None,
+            table_bytes: 0,
+            duration: Default::default(),
+        };
+        if let Some(listener) = &self.listener {
+            listener.on_flush_begin(&info);
+        }
+
+        // The log file is only read back after a crash, or for the values of a spilled memtable
+        let sstable = frozen_log.entries().and_then(|entries| {
+            sstables::memtable_to_sstable(&self.table_files, entries, self.table_options)
+        })?;
+        let sstable = Arc::new(sstable);
+        info.table_id = Some(sstable.id());
+        info.table_bytes = sstable.file_size();
+
+        {
+            let mut sstables_guard = sstables.lock().expect("poisoned sstables lock");
+
+            let updated = manifest.update(|data| {
+                data.pop_frozen_log_file(self.shard);
+                data.sstables = std::iter::once(sstable.id())
+                    .chain(sstables_guard.iter().map(|t| t.id()))
+                    .collect();
+            });
+            if let Err(e) = updated {
+                drop(sstables_guard);
+                let table_path = sstable.file_path().to_owned();
+                drop(sstable);
+                cleanup::remove_file_logged(self.storage(), &table_path);
+                return Err(e);
+            }
+
+            sstables_guard.push_newest(sstable);
+            // Still under the sstables lock, so that the entries are seen either in the frozen log or in the table
+            // by the readers taking both
+            let popped = self
+                .frozen
+                .write()
+                .expect("poisoned frozen logs")
+                .pop_front()
+                .expect("the flush lock is held");
+            debug_assert!(Arc::ptr_eq(&popped, &frozen_log));
+        }
+        self.flush_failed.store(false, Ordering::SeqCst);
+        {
+            let _queue_guard = self.queue_lock.lock().expect("poisoned queue lock");
+            self.queue_freed.notify_all();
+        }
+
+        self.quota.add_table(info.table_bytes);
+        self.quota.remove_log(frozen_log.used - HEADER_BYTES);
+
+        // Readers only look at the queue under its lock
+        let frozen_log = Arc::into_inner(frozen_log).expect("frozen log out of the queue");
+        self.retire_log_file(frozen_log.file, frozen_log.used);
+
+        info.duration = started.elapsed();
+        record_histogram!("kv_flush_duration_seconds", info.duration.as_secs_f64());
+        if let Some(listener) = &self.listener {
+            listener.on_flush_complete(&info);
+        }
+
+        Ok(true)
+    }
+
+    /// A recycled log file (with a new header) if there's one, a new one otherwise
+    fn next_log_file(&self) -> Result<FileWithPath, Error> {
+        let recycled = self.recycled.lock().expect("poisoned recycled logs").pop();
+
+        match recycled {
+            Some(mut file) => {
+                FileHeader::write_new(FileKind::Log, &file.file)?;
+                file.record_format = RecordFormat::CURRENT;
+                increment_counter!("kv_log_files_recycled_total", 1);
+                Ok(file)
+            }
+            None => create_append_log_file(self.storage(), &self.db_dir, &*self.random)
+                .inspect(|_| self.quota.add_log_file(FILE_SIZE_BYTES)),
+        }
+    }
+
+    /// Keeps a log file whose content is now in an SSTable for reuse, deleting it when the pool is full.
+    ///
+    /// The `used` bytes, header included, are zeroed (and synced) first: replaying the file after it's reused must never find entries
+    /// of a previous life past the new ones.
+    fn retire_log_file(&self, file: FileWithPath, used: u64) {
+        if self.recycled.lock().expect("poisoned recycled logs").len() >= self.max_recycled {
+            self.remove_log_file(&file);
+            return;
+        }
+
+        if let Err(e) = functions::zero_file_prefix(&file.file, used) {
+            log::error!("failed to recycle log file {:?}: {:?}", file.path, e);
+            self.remove_log_file(&file);
+            return;
+        }
+
+        let mut recycled = self.recycled.lock().expect("poisoned recycled logs");
+        if recycled.len() < self.max_recycled {
+            recycled.push(file);
+        } else {
+            drop(recycled);
+            self.remove_log_file(&file);
+        }
+    }
+
+    fn remove_log_file(&self, file: &FileWithPath) {
+        cleanup::remove_file_logged(self.storage(), &file.path);
+        self.quota.remove_log_file(FILE_SIZE_BYTES);
+    }
+
+    /// Covers the `len` bytes at `slot`, whose record failed to be written, with padding so that the records after it
+    /// are still read. Without it (the padding fails too, or the file has framed records) replaying the file fails
+    fn pad_failed_slot(&self, file: &FileWithPath, slot: u64, len: usize) {
+        let Some(padding) = serialization::padding(len as u64, file.record_format) else {
+            log::error!("hole left in log file {:?} at {slot}", file.path);
+            return;
+        };
+
+        if let Err(e) = functions::write_data_at_offset(&file.file, &padding, slot) {
+            log::error!("failed to pad log file {:?} at {slot}: {:?}", file.path, e);
+        }
+    }
+
+    /// Padding to put before a record of `len` bytes at `offset` of `file` so that it doesn't cross a block boundary,
+    /// see [`Options::align_log_records`]. 0 when the record fits in what's left of the block, or without alignment.
+    fn alignment_padding(&self, file: &FileWithPath, offset: u64, len: usize) -> u64 {
+        // Framed records have no padding record
+        let Some(block) = self
+            .record_alignment
+            .filter(|_| file.record_format != RecordFormat::Framed)
+        else {
+            return 0;
+        };
+
+        let remaining = block - offset % block;
+        let len = len as u64;
+        // A padding record takes at least 2 bytes: a record never leaves a single one before the boundary
+        if len == remaining || len + 1 < remaining {
+            0
+        } else if remaining > 1 {
+            remaining
+        } else {
+            // Only in files written without alignment
+            remaining + block
+        }
+    }
+
+    /// Writes the `padding` bytes before `slot`, see [`AppendLog::alignment_padding`]
+    fn write_padding(&self, file: &FileWithPath, slot: u64, padding: u64) -> Result<(), Error> {
+        if padding == 0 {
+            return Ok(());
+        }
+        let header = serialization::padding(padding, file.record_format)
+            .expect("alignment padding holds a padding record");
+
+        functions::write_data_at_offset(&file.file, &header, slot - padding)
+    }
+
+    /// Covers the rest of `file`, from `used` on, with padding so that readers know its records are over. Returns the
+    /// size of the padding record, what's now used of the file beyond `used`.
+    ///
+    /// Nothing is written when the padding doesn't fit or fails to be written: the zeros left end the records as well
+    fn pad_to_end(&self, file: &FileWithPath, used: u64) -> u64 {
+        let Some(padding) = serialization::padding(FILE_SIZE_BYTES - used, file.record_format)
+        else {
+            return 0;
+        };
+
+        match functions::write_data_at_offset(&file.file, &padding, used) {
+            Ok(()) => {
+                self.quota.add_log(padding.len() as u64);
+                padding.len() as u64
+            }
+            Err(e) => {
+                log::warn!("failed to pad log file {:?}: {:?}", file.path, e);
+                0
+            }
+        }
+    }
+
+    /// Where the log files live, shared with the tables
+    fn storage(&self) -> &dyn Storage {
+        &**self.table_files.storage()
+    }
+
+    /// Reserves the slot of `data` in the current file, serialized into `buffer`, and returns its offset and length
+    /// along with the padding to write before it. `None` when the file is full.
+    ///
+    /// Unless `numbered`, `data` gets the next sequence number, once it's sure to fit
+    fn try_acquire_slot(
+        &self,
+        data: &mut KVMemoryRepr,
+        numbered: bool,
+        buffer: &mut [u8],
+    ) -> Result<Option<Slot<'_>>, Error> {
         let state_lock = self.state.read().expect("poisoned append_log_lock");
-        AppendLog::calculate_log_slot(size, &state_lock.1).map(|location| (location, state_lock))
+        let mut offset_guard = state_lock.1.lock().expect("lock poisoned");
+        let current_write_offset = *offset_guard;
+
+        // Numbered under the offset lock so that the numbers follow the offsets, and only when the record fits: a
+        // number drawn for a full file would end up before newer ones in the next file.
+        // The record size depends on the number, another shard taking it means serializing again
+        let size = loop {
+            let sequence = self.last_sequence.load(Ordering::SeqCst) + 1;
+            if !numbered {
+                *data = data.clone().with_sequence(sequence);
+            }
+            let size = serialization::serialize_into(data, buffer, state_lock.0.record_format)?;
+            let padding = self.alignment_padding(&state_lock.0, current_write_offset, size);
+            if padding + size as u64 > FILE_SIZE_BYTES - current_write_offset {
+                return Ok(None);
+            }
+
+            let taken = numbered
+                || self
+                    .last_sequence
+                    .compare_exchange(sequence - 1, sequence, Ordering::SeqCst, Ordering::SeqCst)
+                    .is_ok();
+            if taken {
+                break (size, padding);
+            }
+        };
+        let (size, padding) = size;
+        *offset_guard += padding + size as u64;
+        drop(offset_guard);
+
+        Ok(Some((
+            current_write_offset + padding,
+            size,
+            padding,
+            state_lock,
+        )))
     }
 
-    /// Checks if the current file can fit a new entry and, if so, updates the current write pointer
-    fn calculate_log_slot(requested_size: u64, offset: &Mutex<u64>) -> Option<u64> {
-        let mut offset_guard = offset.lock().expect("lock poisoned");
+    /// Same as [`AppendLog::try_acquire_slot`] for consecutive slots of `entries`, numbered in order and serialized
+    /// back to back into `records` along with their padding. Returns where `records` go and the offset and size of
+    /// each record
+    fn try_acquire_slots(
+        &self,
+        entries: &mut [KVMemoryRepr],
+        records: &mut Vec<u8>,
+    ) -> Result<Option<Slots<'_>>, Error> {
+        let state_lock = self.state.read().expect("poisoned append_log_lock");
+        let mut offset_guard = state_lock.1.lock().expect("lock poisoned");
         let current_write_offset = *offset_guard;
 
-        let remaining_space = FILE_SIZE_BYTES - current_write_offset;
+        let mut buffer = [0u8; serialization::MAX_RECORD_BYTES];
+        let slots = loop {
+            let first = self.last_sequence.load(Ordering::SeqCst) + 1;
+            records.clear();
+            let mut slots = Vec::with_capacity(entries.len());
+            for (sequence, entry) in (first..).zip(entries.iter_mut()) {
+                *entry = entry.clone().with_sequence(sequence);
+                let size =
+                    serialization::serialize_into(entry, &mut buffer, state_lock.0.record_format)?;
+                let offset = current_write_offset + records.len() as u64;
+                let padding = self.alignment_padding(&state_lock.0, offset, size);
+                if padding > 0 {
+                    let header = serialization::padding(padding, state_lock.0.record_format)
+                        .expect("alignment padding holds a padding record");
+                    records.extend_from_slice(&header);
+                    // The skipped bytes are left as they are, zeros
+                    records.resize((offset + padding - current_write_offset) as usize, 0);
+                }
+                slots.push((offset + padding, size));
+                records.extend_from_slice(&buffer[..size]);
+            }
+            if records.len() as u64 > FILE_SIZE_BYTES - current_write_offset {
+                return Ok(None);
+            }
 
-        if requested_size > remaining_space {
-            None
-        } else {
-            *offset_guard += requested_size;
-            Some(current_write_offset)
+            let last = first + entries.len() as u64 - 1;
+            if self
+                .last_sequence
+                .compare_exchange(first - 1, last, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
+                break slots;
+            }
+        };
+        *offset_guard += records.len() as u64;
+        drop(offset_guard);
+
+        Ok(Some((current_write_offset, slots, state_lock)))
+    }
+}
+
+impl Drop for AppendLog {
+    fn drop(&mut self) {
+        // Not referenced by the manifest, they would only be found as orphans on the next open
+        let recycled = mem::take(self.recycled.get_mut().expect("poisoned recycled logs"));
+        for file in recycled {
+            self.remove_log_file(&file);
         }
     }
 }
 
-fn create_append_log_file(base_dir: &Path) -> Result<FileWithPath, Error> {
-    let random_suffix = rand::random::<u64>();
-    let log_name = format!("log_{}", random_suffix);
+/// Named after a number drawn from `random`
+pub fn create_append_log_file(
+    storage: &dyn Storage,
+    base_dir: &Path,
+    random: &dyn RandomSource,
+) -> Result<FileWithPath, Error> {
+    let random_suffix = random.next_u64();
+    let log_name = format!("{LOG_FILE_PREFIX}{random_suffix}");
     let log_path = base_dir.join(log_name);
 
-    let file = functions::create_file(&log_path, FILE_SIZE_BYTES)?;
+    let file = storage.create(&log_path, FILE_SIZE_BYTES)?;
+    if let Err(e) = FileHeader::write_new(FileKind::Log, &file) {
+        cleanup::remove_file_logged(storage, &log_path);
+        return Err(e);
+    }
 
     Ok(FileWithPath {
         file,
         path: log_path,
+        record_format: RecordFormat::CURRENT,
     })
 }
+
+/// Opens the log file at `path` and reads its entries back, returns it with its written bytes (header included) and
+/// its memtable
+fn replay_log_file(
+    storage: &dyn Storage,
+    path: &Path,
+    read_only: bool,
+    spill_values: bool,
+) -> Result<(FileWithPath, u64, Memtable), Error> {
+    let file = storage.open(path, !read_only)?;
+
+    let content = functions::read_file(&file, FILE_SIZE_BYTES)?;
+    let (records, record_format) = file_header::strip_header(FileKind::Log, &content)?;
+    let (mut entries, end) =
+        serialization::deserialize_entries_with_offsets(records, "log_file", record_format)?;
+    for (offset, _) in &mut entries {
+        *offset += HEADER_BYTES;
+    }
+    entries.sort_by_key(|(_, entry)| entry.sequence());
+    let memtable = Memtable::from_entries(entries, spill_values, record_format)?;
+
+    Ok((
+        FileWithPath {
+            file,
+            path: path.to_owned(),
+            record_format,
+        },
+        HEADER_BYTES + end,
+        memtable,
+    ))
+}
+
+/// Entries of a log file with their offset, in file order, without modifying it.
+///
+/// Another process may be writing to the file, so reading stops at the first record that can't be decoded.
+pub fn read_live_log(
+    storage: &dyn Storage,
+    path: &Path,
+) -> Result<Vec<(u64, KVMemoryRepr)>, Error> {
+    let file = storage.open(path, false)?;
+    let content = functions::read_file(&file, file.size()?.min(FILE_SIZE_BYTES))?;
+    let (records, record_format) = file_header::strip_header(FileKind::Log, &content)?;
+
+    Ok(serialization::deserialize_prefix(records, record_format)
+        .into_iter()
+        .map(|(offset, entry)| (HEADER_BYTES + offset, entry))
+        .collect())
+}
+
+/// Everything that can be decoded from a (possibly damaged) log file, and whether anything was skipped
+pub fn salvage_log_file(content: &[u8]) -> (Vec<KVMemoryRepr>, bool) {
+    let header = FileHeader::decode(FileKind::Log, content);
+    // Written by this version as far as we can tell when the header is damaged
+    let record_format = header
+        .as_ref()
+        .map_or(RecordFormat::CURRENT, FileHeader::record_format);
+    let records = content.get(HEADER_BYTES as usize..).unwrap_or_default();
+    let (entries, skipped) = serialization::salvage_entries(records, record_format);
+
+    (entries, header.is_err() || skipped > 0)
+}
+
+pub fn log_file_name(file: &FileWithPath) -> String {
+    file.path
+        .file_name()
+        .expect("log files always have a name")
+        .to_string_lossy()
+        .into_owned()
+}
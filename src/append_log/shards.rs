@@ -0,0 +1,403 @@
+use super::{
+    AppendLog,
+    flusher::{FlushRegistration, Flusher},
+};
+use crate::{
+    Key,
+    changes::ChangeHub,
+    clock::Clock,
+    comparator::KeyOrder,
+    debug::LogDump,
+    errors::Error,
+    functions::ReadOutcome,
+    instrumentation::increment_counter,
+    manifest::{Manifest, ManifestData},
+    options::{Durability, Options},
+    quota::DiskQuota,
+    serialization::{self, KVMemoryRepr},
+    snapshot::Snapshot,
+    sstables::{SSTable, TableFiles, TableList, compactor::CompactorManager},
+    stats::RangeEstimate,
+};
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    sync::{
+        Arc, Mutex, MutexGuard, RwLock,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+/// Append logs receiving the writes in parallel, every key belongs to a single shard.
+///
+/// Each shard has its own file, offset and in-memory log and rotates on its own into the shared SSTables.
+/// Range tombstones cover keys of every shard, so they're written to all of them with the same sequence number, and
+/// only published once they're in every log file.
+pub struct ShardedAppendLog {
+    shards: Vec<Arc<AppendLog>>,
+    /// Sequence number of the latest write, shared with the shards which draw the numbers of point writes
+    last_sequence: Arc<AtomicU64>,
+    /// See [`ShardedAppendLog::start_flusher`]
+    flusher: Mutex<Option<FlushRegistration>>,
+    /// Gives every write its time, set with [`Options::write_timestamps`]
+    write_clock: Option<Arc<dyn Clock>>,
+    /// Shared by point writes, held exclusively by a range tombstone from its numbering until every shard has it, so
+    /// that no newer point write reaches a shard (and its tables) before the tombstone does
+    range_lock: RwLock<()>,
+}
+
+impl ShardedAppendLog {
+    pub fn new(
+        db_dir: &Path,
+        table_files: &Arc<TableFiles>,
+        options: &Options,
+        quota: &Arc<DiskQuota>,
+    ) -> Result<Self, Error> {
+        let shards = (0..options.write_shards.max(1))
+            .map(|shard| AppendLog::new(db_dir, table_files, shard, options, quota))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self::with_sequence(shards, 0, options))
+    }
+
+    /// Reopens the log files of every shard listed in `manifest`, in shard order, with the logs they froze.
+    ///
+    /// `last_sequence` is the highest sequence number stored outside of the logs.
+    pub fn open(
+        db_dir: &Path,
+        table_files: &Arc<TableFiles>,
+        manifest: &ManifestData,
+        last_sequence: u64,
+        read_only: bool,
+        options: &Options,
+        quota: &Arc<DiskQuota>,
+    ) -> Result<Self, Error> {
+        let shards: Vec<_> = manifest
+            .log_files
+            .iter()
+            .enumerate()
+            .map(|(shard, log_file)| {
+                let mut log = AppendLog::open(
+                    db_dir,
+                    table_files,
+                    shard,
+                    log_file,
+                    read_only,
+                    options,
+                    quota,
+                )?;
+                for frozen in manifest.frozen_log_files(shard) {
+                    log.open_frozen(frozen, read_only)?;
+                }
+
+                Ok(log)
+            })
+            .collect::<Result<_, Error>>()?;
+
+        let last_sequence = shards
+            .iter()
+            .map(|shard| shard.max_sequence())
+            .fold(last_sequence, u64::max);
+
+        Ok(Self::with_sequence(shards, last_sequence, options))
+    }
+
+    /// Shares a counter starting after `last_sequence` between `shards`
+    fn with_sequence(shards: Vec<AppendLog>, last_sequence: u64, options: &Options) -> Self {
+        let last_sequence = Arc::new(AtomicU64::new(last_sequence));
+        let shards = shards
+            .into_iter()
+            .map(|mut shard| {
+                shard.last_sequence = last_sequence.clone();
+                Arc::new(shard)
+            })
+            .collect();
+
+        Self {
+            shards,
+            last_sequence,
+            flusher: Default::default(),
+            write_clock: options.write_timestamps.then(|| options.clock.clone()),
+            range_lock: RwLock::new(()),
+        }
+    }
+
+    /// Has the thread of `flusher` flush the memtables queued by the rotations, which are flushed by the rotations
+    /// themselves until then. It goes on until [`ShardedAppendLog::stop_flusher`]
+    pub fn start_flusher(
+        &self,
+        flusher: &Flusher,
+        sstables: &Arc<Mutex<TableList>>,
+        manifest: &Arc<Manifest>,
+        compaction_manager: &Arc<CompactorManager>,
+    ) {
+        for shard in &self.shards {
+            let _ = shard.flush_signal.set(flusher.signal().clone());
+        }
+
+        *self.flusher.lock().expect("poisoned flusher lock") = Some(flusher.register(
+            self.shards.clone(),
+            sstables.clone(),
+            manifest.clone(),
+            compaction_manager.clone(),
+        ));
+    }
+
+    /// Waits for the flush in progress, if any, leaving the other queued memtables for [`ShardedAppendLog::flush`]
+    pub fn stop_flusher(&self) {
+        // Dropping it takes the store off the thread
+        self.flusher.lock().expect("poisoned flusher lock").take();
+    }
+
+    /// Shard owning `key`
+    fn shard(&self, key: &Key) -> &AppendLog {
+        // Fixed mixing rather than `DefaultHasher`: logs are replayed into the same shard after a restart
+        let hash = key.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 32;
+        &self.shards[(hash % self.shards.len() as u64) as usize]
+    }
+
+    /// See [`AppendLog::lock_key`]
+    pub fn lock_key(&self, key: &Key) -> MutexGuard<'_, ()> {
+        self.shard(key).lock_key(key)
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    pub fn last_sequence(&self) -> u64 {
+        self.last_sequence.load(Ordering::SeqCst)
+    }
+
+    /// Takes the next sequence number for entries written straight to tables
+    pub fn take_sequence(&self) -> u64 {
+        self.last_sequence.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Number of entries in the logs, overwritten ones included
+    pub fn entry_count(&self) -> usize {
+        self.shards.iter().map(|shard| shard.entry_count()).sum()
+    }
+
+    /// See [`AppendLog::memtable_bytes`]
+    pub fn memtable_bytes(&self) -> u64 {
+        self.shards.iter().map(|shard| shard.memtable_bytes()).sum()
+    }
+
+    /// Names of the log files currently receiving writes, in shard order
+    pub fn file_names(&self) -> Vec<String> {
+        self.shards.iter().map(|shard| shard.file_name()).collect()
+    }
+
+    /// Clock times of the latest write of every shard, see [`AppendLog::last_write`]
+    pub fn last_writes(&self) -> Vec<Arc<AtomicU64>> {
+        self.shards.iter().map(|shard| shard.last_write()).collect()
+    }
+
+    /// See [`AppendLog::approximate_range`]
+    pub fn approximate_range(&self, start: Key, end: Key) -> RangeEstimate {
+        let mut estimate = RangeEstimate::default();
+        for shard in &self.shards {
+            estimate.add(shard.approximate_range(start, end));
+        }
+
+        estimate
+    }
+
+    /// See [`AppendLog::dump`]
+    pub fn dump(&self) -> Result<Vec<LogDump>, Error> {
+        self.shards.iter().map(|shard| shard.dump()).collect()
+    }
+
+    pub fn sync(&self) -> Result<(), Error> {
+        for shard in &self.shards {
+            shard.sync()?;
+        }
+
+        Ok(())
+    }
+
+    /// See [`AppendLog::find_key`]
+    pub fn find_key(&self, key: &Key) -> Result<Option<KVMemoryRepr>, Error> {
+        self.shard(key).find_key(key)
+    }
+
+    /// See [`AppendLog::find_keys`]
+    pub fn find_keys(&self, keys: &[Key], now: u64) -> Result<Vec<ReadOutcome>, Error> {
+        let mut results: Vec<_> = keys.iter().map(|_| ReadOutcome::NotFound).collect();
+
+        // Indexes (into `keys`) of the keys of every shard
+        let mut by_shard: Vec<Vec<usize>> = vec![Vec::new(); self.shards.len()];
+        for (i, key) in keys.iter().enumerate() {
+            let shard = self.shard(key).shard;
+            by_shard[shard].push(i);
+        }
+
+        for (shard, indexes) in self.shards.iter().zip(by_shard) {
+            if indexes.is_empty() {
+                continue;
+            }
+
+            let shard_keys: Vec<_> = indexes.iter().map(|i| keys[*i]).collect();
+            for (i, result) in indexes.into_iter().zip(shard.find_keys(&shard_keys, now)?) {
+                results[i] = result;
+            }
+        }
+
+        Ok(results)
+    }
+
+    /// Captures the in-memory logs together with the current SSTables.
+    ///
+    /// Everything is taken under the state locks of all shards and the sstables lock, so no rotation can move
+    /// entries meanwhile. Expiration is evaluated at `now` for the whole life of the snapshot.
+    pub fn snapshot(
+        &self,
+        sstables: &Mutex<TableList>,
+        now: u64,
+        order: &KeyOrder,
+    ) -> Result<Snapshot, Error> {
+        let state_locks: Vec<_> = self
+            .shards
+            .iter()
+            .map(|shard| shard.state.read().expect("poisoned state lock"))
+            .collect();
+        // Rotations register the tables of their frozen logs under it
+        let sstables = sstables.lock().expect("poisoned sstables lock");
+
+        let mut memtable = BTreeMap::new();
+        let mut ranges = Vec::new();
+        for (shard, state_lock) in self.shards.iter().zip(&state_locks) {
+            // Entries are sorted by sequence, later ones overwrite older ones
+            for entry in shard.memtable_entries(state_lock)? {
+                if entry.is_range_tombstone() {
+                    ranges.push(entry);
+                } else {
+                    memtable.insert(*entry.key(), entry);
+                }
+            }
+        }
+        ranges.sort_by_key(|range| range.sequence());
+        serialization::dedup_range_copies(&mut ranges);
+
+        Ok(Snapshot::new(
+            memtable,
+            ranges,
+            sstables.clone(),
+            self.last_sequence(),
+            now,
+            order.clone(),
+        ))
+    }
+
+    /// Entries of the logs with a sequence number above `sequence`, sorted by sequence.
+    ///
+    /// Also returns the SSTables at that time, newer first.
+    pub fn entries_since(
+        &self,
+        sequence: u64,
+        sstables: &Mutex<TableList>,
+    ) -> Result<(Vec<KVMemoryRepr>, Vec<Arc<SSTable>>), Error> {
+        let state_locks: Vec<_> = self
+            .shards
+            .iter()
+            .map(|shard| shard.state.read().expect("poisoned state lock"))
+            .collect();
+        // See `snapshot`, an entry must not be both in a frozen log and in the tables
+        let sstables = sstables.lock().expect("poisoned sstables lock");
+
+        let mut entries = Vec::new();
+        for (shard, state_lock) in self.shards.iter().zip(&state_locks) {
+            entries.extend(
+                shard
+                    .memtable_entries(state_lock)?
+                    .into_iter()
+                    .filter(|entry| entry.sequence() > sequence),
+            );
+        }
+        entries.sort_by_key(|entry| entry.sequence());
+        serialization::dedup_range_copies(&mut entries);
+
+        Ok((entries, sstables.clone()))
+    }
+
+    /// Writes `entry` to the shard owning its key, which gives it the next sequence number. Range tombstones are
+    /// numbered here and written to all shards, point writes wait meanwhile, see [`AppendLog::write_entry`]. A
+    /// tombstone that fails to reach a shard is taken back from all of them. With [`Options::write_timestamps`]
+    /// the entry gets the current time
+    pub fn write_entry(
+        &self,
+        entry: KVMemoryRepr,
+        sstables: &Mutex<TableList>,
+        manifest: &Manifest,
+        compaction_manager: &CompactorManager,
+        changes: &ChangeHub,
+        durability: Durability,
+    ) -> Result<(), Error> {
+        increment_counter!("kv_writes_total", 1);
+
+        let entry = match &self.write_clock {
+            Some(clock) => entry.with_written_at(Some(clock.now_millis())),
+            None => entry,
+        };
+        if !entry.is_range_tombstone() {
+            let _range_lock = self.range_lock.read().expect("poisoned range lock");
+            let shard = self.shard(entry.key());
+            return shard.write_entry(
+                entry,
+                sstables,
+                manifest,
+                compaction_manager,
+                Some(changes),
+                durability,
+            );
+        }
+
+        let _range_lock = self.range_lock.write().expect("poisoned range lock");
+        let sequence = self.last_sequence.fetch_add(1, Ordering::SeqCst) + 1;
+        let data = entry.with_sequence(sequence);
+
+        // In every log file before any shard publishes it, a failed write takes back the copies already written
+        let mut staged = Vec::with_capacity(self.shards.len());
+        for shard in &self.shards {
+            match shard.stage_entry(data.clone(), sstables, manifest, compaction_manager) {
+                Ok(write) => staged.push(write),
+                Err(e) => {
+                    for (shard, write) in self.shards.iter().zip(staged) {
+                        shard.abandon_staged(write);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        let mut written = Ok(());
+        for (i, (shard, write)) in self.shards.iter().zip(staged).enumerate() {
+            // Subscribers get a single copy
+            let changes = (i == 0).then_some(changes);
+            // Only a failed sync, the copy is readable already and the others need publishing too
+            if let Err(e) = shard.publish_staged(
+                write,
+                sstables,
+                manifest,
+                compaction_manager,
+                changes,
+                durability,
+            ) {
+                written = Err(e);
+            }
+        }
+
+        written
+    }
+
+    /// Turns the log file of every shard into an SSTable, returns whether any flush happened
+    pub fn flush(&self, sstables: &Mutex<TableList>, manifest: &Manifest) -> Result<bool, Error> {
+        let mut flushed = false;
+        for shard in &self.shards {
+            flushed |= shard.flush(sstables, manifest)?;
+        }
+
+        Ok(flushed)
+    }
+}
@@ -0,0 +1,134 @@
+use crate::{Error, KVStorage, Key, Value};
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Default of [`AsyncKVStorage::with_max_blocking`]
+const DEFAULT_MAX_BLOCKING: u32 = 64;
+
+/// Async front of a [`KVStorage`] for tokio, calls run on the blocking pool so that file I/O doesn't stall the runtime.
+///
+/// At most `max_blocking` calls run at once, the others wait without holding a thread of the pool.
+pub struct AsyncKVStorage {
+    inner: KVStorage,
+    permits: Arc<Semaphore>,
+    max_blocking: u32,
+}
+
+impl AsyncKVStorage {
+    pub fn new(storage: KVStorage) -> Self {
+        Self::with_max_blocking(storage, DEFAULT_MAX_BLOCKING)
+    }
+
+    pub fn with_max_blocking(storage: KVStorage, max_blocking: u32) -> Self {
+        let max_blocking = max_blocking.max(1);
+
+        Self {
+            inner: storage,
+            permits: Arc::new(Semaphore::new(max_blocking as usize)),
+            max_blocking,
+        }
+    }
+
+    /// See [`KVStorage::read`]
+    pub async fn read(&self, key: Key) -> Result<Option<Value>, Error> {
+        self.run(move |storage| storage.read(&key)).await
+    }
+
+    /// See [`KVStorage::write`]
+    pub async fn write(&self, key: Key, value: Option<Value>) -> Result<(), Error> {
+        self.run(move |storage| storage.write(key, value)).await
+    }
+
+    /// Same as writing `None`
+    pub async fn delete(&self, key: Key) -> Result<(), Error> {
+        self.write(key, None).await
+    }
+
+    /// See [`KVStorage::flush`]
+    pub async fn flush(&self) -> Result<(), Error> {
+        self.run(KVStorage::flush).await
+    }
+
+    /// Runs `f` on the blocking pool, for the methods without an async version
+    pub async fn run<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&KVStorage) -> T + Send + 'static,
+    ) -> T {
+        let permit = self.acquire(1).await;
+        let storage = self.inner.clone();
+
+        let task = tokio::task::spawn_blocking(move || {
+            // Released once the call is done, even if the caller stopped waiting for it
+            let _permit = permit;
+            let result = f(&storage);
+            // Before the permit, so that close finds the only handle left
+            drop(storage);
+            result
+        });
+
+        match task.await {
+            Ok(result) => result,
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        }
+    }
+
+    /// Waits for the calls still running (including the ones whose futures were dropped), then closes the store as
+    /// [`KVStorage::close`] does, compaction included
+    pub async fn close(self) -> Result<(), Error> {
+        let _all_permits = self.acquire(self.max_blocking).await;
+        let storage = self.inner;
+
+        match tokio::task::spawn_blocking(move || storage.close()).await {
+            Ok(result) => result,
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        }
+    }
+
+    async fn acquire(&self, permits: u32) -> OwnedSemaphorePermit {
+        self.permits
+            .clone()
+            .acquire_many_owned(permits)
+            .await
+            .expect("the semaphore is never closed")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Options;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_reads_during_rotation() {
+        let options = Options::new().write_shards(2);
+        let kv = Arc::new(AsyncKVStorage::with_max_blocking(
+            KVStorage::new_in_memory_with_options(options).unwrap(),
+            4,
+        ));
+        for key in 0..2000 {
+            kv.write(key, Some(key)).await.unwrap();
+        }
+
+        let readers: Vec<_> = (0..8)
+            .map(|_| {
+                let kv = kv.clone();
+                tokio::spawn(async move {
+                    for key in 0..2000 {
+                        assert_eq!(kv.read(key).await.unwrap(), Some(key));
+                    }
+                })
+            })
+            .collect();
+
+        kv.flush().await.unwrap();
+        for reader in readers {
+            reader.await.unwrap();
+        }
+
+        kv.delete(1).await.unwrap();
+        assert_eq!(kv.read(1).await.unwrap(), None);
+        let dump = kv.run(|storage| storage.dump(false)).await.unwrap();
+        assert!(!dump.tables.is_empty());
+        Arc::into_inner(kv).unwrap().close().await.unwrap();
+    }
+}
@@ -0,0 +1,156 @@
+use crate::{Key, Value, errors::Error, iter::KvIter};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+
+/// First bytes of an export stream
+const EXPORT_MAGIC: [u8; 4] = *b"KVXP";
+/// Version of the export stream, the byte after the magic
+const EXPORT_VERSION: u8 = 1;
+/// Frame holding a key and its value
+const FRAME_ENTRY: u8 = 1;
+/// Last frame, holding the number of entries of the stream
+const FRAME_END: u8 = 0;
+
+/// What [`crate::KVStorage::import`] does with the keys already in the store
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportMode {
+    /// The imported values overwrite the ones in the store
+    Replace,
+    /// Keys holding a value in the store keep it, only the others are imported
+    Preserve,
+}
+
+/// Returned by [`crate::KVStorage::export`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ExportSummary {
+    pub entries: u64,
+    /// Size of the stream, header included
+    pub bytes: u64,
+}
+
+/// Returned by [`crate::KVStorage::import`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ImportSummary {
+    /// Entries read from the stream
+    pub entries: u64,
+    /// Entries written to the store
+    pub imported: u64,
+    /// Entries left out by [`ImportMode::Preserve`]
+    pub skipped: u64,
+}
+
+/// Writes `entries` as an export stream: the magic and version, a frame per entry, then the end frame. The end frame
+/// is left out if iterating fails, so that the stream isn't taken for a complete one.
+pub fn write_export(mut entries: KvIter, writer: impl Write) -> Result<ExportSummary, Error> {
+    let mut writer = BufWriter::new(writer);
+    writer.write_all(&EXPORT_MAGIC)?;
+    writer.write_all(&[EXPORT_VERSION])?;
+    let mut summary = ExportSummary {
+        entries: 0,
+        bytes: EXPORT_MAGIC.len() as u64 + 1,
+    };
+
+    for (key, value) in entries.by_ref() {
+        writer.write_all(&[FRAME_ENTRY])?;
+        writer.write_all(&key.to_le_bytes())?;
+        writer.write_all(&value.to_le_bytes())?;
+        summary.entries += 1;
+        summary.bytes += 17;
+    }
+    if let Some(e) = entries.into_error() {
+        return Err(e);
+    }
+
+    writer.write_all(&[FRAME_END])?;
+    writer.write_all(&summary.entries.to_le_bytes())?;
+    writer.flush()?;
+    summary.bytes += 9;
+
+    Ok(summary)
+}
+
+/// Reads the entries of an export stream, checking its header first. A stream cut short, or whose end frame doesn't
+/// match the entries read, is [`Error::InvalidExport`].
+pub struct ExportReader<R: Read> {
+    reader: BufReader<R>,
+    entries: u64,
+    done: bool,
+}
+
+impl<R: Read> ExportReader<R> {
+    pub fn new(reader: R) -> Result<Self, Error> {
+        let mut reader = BufReader::new(reader);
+        let mut header = [0u8; EXPORT_MAGIC.len() + 1];
+        read_frame(&mut reader, &mut header)?;
+        if header[..EXPORT_MAGIC.len()] != EXPORT_MAGIC {
+            return Err(Error::InvalidExport);
+        }
+        let version = header[EXPORT_MAGIC.len()];
+        if version != EXPORT_VERSION {
+            return Err(Error::UnsupportedVersion {
+                found: version,
+                supported: EXPORT_VERSION,
+            });
+        }
+
+        Ok(Self {
+            reader,
+            entries: 0,
+            done: false,
+        })
+    }
+
+    /// Number of entries read so far
+    pub fn entries(&self) -> u64 {
+        self.entries
+    }
+
+    fn read_entry(&mut self) -> Result<Option<(Key, Value)>, Error> {
+        let mut tag = [0u8; 1];
+        read_frame(&mut self.reader, &mut tag)?;
+        let mut payload = [0u8; 16];
+        match tag[0] {
+            FRAME_ENTRY => {
+                read_frame(&mut self.reader, &mut payload)?;
+                self.entries += 1;
+                let (key, value) = payload.split_at(8);
+
+                Ok(Some((
+                    Key::from_le_bytes(key.try_into().expect("8 bytes")),
+                    Value::from_le_bytes(value.try_into().expect("8 bytes")),
+                )))
+            }
+            FRAME_END => {
+                read_frame(&mut self.reader, &mut payload[..8])?;
+                let count = u64::from_le_bytes(payload[..8].try_into().expect("8 bytes"));
+                if count != self.entries {
+                    return Err(Error::InvalidExport);
+                }
+
+                Ok(None)
+            }
+            _ => Err(Error::InvalidExport),
+        }
+    }
+}
+
+impl<R: Read> Iterator for ExportReader<R> {
+    type Item = Result<(Key, Value), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let entry = self.read_entry();
+        self.done = !matches!(entry, Ok(Some(_)));
+        entry.transpose()
+    }
+}
+
+/// Fills `buffer`, a stream ending before is [`Error::InvalidExport`]
+fn read_frame(reader: &mut impl Read, buffer: &mut [u8]) -> Result<(), Error> {
+    reader.read_exact(buffer).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => Error::InvalidExport,
+        _ => Error::from(e),
+    })
+}
@@ -0,0 +1,167 @@
+//! Offline inspection of a database directory, which another process may have open.
+//!
+//! `kvdump <location> list-tables | dump-table <id> | dump-log | verify`
+
+use key_value_store::{DbDump, Error, Inspector};
+use std::{env, process::ExitCode};
+
+const USAGE: &str = "usage: kvdump <location> list-tables | dump-table <id> | dump-log | verify";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    match run(&args) {
+        Ok((output, healthy)) => {
+            print!("{output}");
+            if healthy {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            }
+        }
+        Err(message) => {
+            eprintln!("{message}");
+            ExitCode::from(2)
+        }
+    }
+}
+
+/// Returns the output of the command and whether the database is healthy, which only `verify` checks
+fn run(args: &[String]) -> Result<(String, bool), String> {
+    let [location, command, rest @ ..] = args else {
+        return Err(USAGE.to_owned());
+    };
+    let inspector = Inspector::open(location).map_err(describe)?;
+
+    let output = match (command.as_str(), rest) {
+        ("list-tables", []) => inspector
+            .tables()
+            .map_err(describe)?
+            .iter()
+            .map(|table| format!("{table}\n"))
+            .collect(),
+        ("dump-table", [id]) => {
+            let id = id.parse().map_err(|_| format!("invalid table id {id}"))?;
+            let dump = DbDump {
+                tables: vec![inspector.table(id).map_err(describe)?],
+                logs: Vec::new(),
+            };
+            dump.to_string()
+        }
+        ("dump-log", []) => {
+            let dump = DbDump {
+                tables: Vec::new(),
+                logs: inspector.logs().map_err(describe)?,
+            };
+            dump.to_string()
+        }
+        ("verify", []) => {
+            let report = inspector.verify();
+            let mut output = String::new();
+            for table in &report.tables {
+                output += &format!(
+                    "table {}: {} entries, {} anomalies\n",
+                    table.id,
+                    table.entry_count,
+                    table.anomalies.len()
+                );
+                for anomaly in &table.anomalies {
+                    output += &format!("  {anomaly:?}\n");
+                }
+            }
+            return Ok((output, report.is_ok()));
+        }
+        _ => return Err(USAGE.to_owned()),
+    };
+
+    Ok((output, true))
+}
+
+fn describe(error: Error) -> String {
+    format!("error: {error:?}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use key_value_store::{KVStorage, Options};
+    use std::fs;
+
+    fn kvdump(location: &str, command: &[&str]) -> Result<(String, bool), String> {
+        let args: Vec<_> = [location]
+            .iter()
+            .chain(command)
+            .map(|arg| arg.to_string())
+            .collect();
+        run(&args)
+    }
+
+    #[test]
+    fn test_golden_output() {
+        let location = format!("./test-dbs/{}", rand::random::<u64>());
+        fs::create_dir_all(&location).unwrap();
+        let options = Options::new().write_shards(1);
+
+        let kv = KVStorage::new_with_options(&location, options.clone()).unwrap();
+        for key in 1..=3 {
+            kv.write(key, Some(key * 10)).unwrap();
+        }
+        kv.delete_range(10, 20).unwrap();
+        kv.close().unwrap();
+
+        // Still open while inspected
+        let kv = KVStorage::open_with_options(&location, options).unwrap();
+        kv.write(2, None).unwrap();
+        kv.write(4, Some(40)).unwrap();
+        let inspector = Inspector::open(&location).unwrap();
+        let id = inspector.table_ids()[0];
+        let log = &inspector.logs().unwrap()[0].file_name;
+        // The size of the bloom filter section is up to the bloom filter crate
+        let size = fs::metadata(inspector.table(id).unwrap().path)
+            .unwrap()
+            .len();
+
+        assert_eq!(
+            kvdump(&location, &["list-tables"]).unwrap(),
+            (
+                format!("table {id}: {size} bytes, 3 entries, keys 1..=19\n"),
+                true
+            )
+        );
+        assert_eq!(
+            kvdump(&location, &["dump-table", &id.to_string()]).unwrap(),
+            (
+                format!(
+                    "table {id}: {size} bytes, 3 entries, keys 1..=19
+  block 0 at 0, 62 bytes, first key 1
+  #1 1 = 10
+  #2 2 = 20
+  #3 3 = 30
+  #4 10..20 deleted
+"
+                ),
+                true
+            )
+        );
+        assert_eq!(
+            kvdump(&location, &["dump-log"]).unwrap(),
+            (
+                format!(
+                    "log 0 {log}: 2 entries
+  16: #5 2 deleted
+  26: #6 4 = 40
+"
+                ),
+                true
+            )
+        );
+        assert_eq!(
+            kvdump(&location, &["verify"]).unwrap(),
+            (format!("table {id}: 3 entries, 0 anomalies\n"), true)
+        );
+
+        assert_eq!(kvdump(&location, &["dump-table"]), Err(USAGE.to_owned()));
+        assert!(kvdump(&location, &["dump-table", "1"]).is_err());
+        drop(kv);
+    }
+}
@@ -0,0 +1,159 @@
+use crate::{Key, Value, comparator::KeyOrder};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+};
+
+/// Number of invalidation counters shared by all keys
+const VERSION_STRIPES: usize = 64;
+
+/// LRU cache of the values read from the SSTables, tombstones and missing keys included
+pub struct ReadCache {
+    capacity: usize,
+    inner: Mutex<CacheInner>,
+}
+
+struct CacheInner {
+    entries: HashMap<Key, CachedEntry>,
+    /// Keys ordered by last use, the first one is evicted first
+    by_use: BTreeMap<u64, Key>,
+    tick: u64,
+    /// Bumped on every invalidation, see [`ReadCache::ticket`]
+    versions: [u64; VERSION_STRIPES],
+}
+
+struct CachedEntry {
+    value: Option<Value>,
+    expires_at: Option<u64>,
+    last_use: u64,
+}
+
+/// Taken before a lookup, lets [`ReadCache::insert`] detect writes that happened meanwhile
+pub struct CacheTicket(u64);
+
+impl ReadCache {
+    /// A `capacity` of 0 disables the cache
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::new(CacheInner {
+                entries: HashMap::new(),
+                by_use: BTreeMap::new(),
+                tick: 0,
+                versions: [0; VERSION_STRIPES],
+            }),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Must be called before looking `key` up anywhere, so that its result isn't cached if it raced with a write
+    pub fn ticket(&self, key: &Key) -> CacheTicket {
+        if !self.is_enabled() {
+            return CacheTicket(0);
+        }
+
+        let inner = self.inner.lock().expect("poisoned cache lock");
+        CacheTicket(inner.versions[stripe(key)])
+    }
+
+    /// Returns the cached value, `Some(None)` for cached tombstones
+    pub fn get(&self, key: &Key, now: u64) -> Option<Option<Value>> {
+        if !self.is_enabled() {
+            return None;
+        }
+
+        let mut inner = self.inner.lock().expect("poisoned cache lock");
+        inner.tick += 1;
+        let tick = inner.tick;
+
+        let entry = inner.entries.get_mut(key)?;
+        let old_use = std::mem::replace(&mut entry.last_use, tick);
+        let value = match entry.expires_at {
+            Some(expires_at) if expires_at <= now => None,
+            _ => entry.value,
+        };
+
+        inner.by_use.remove(&old_use);
+        inner.by_use.insert(tick, *key);
+
+        Some(value)
+    }
+
+    pub fn insert(
+        &self,
+        key: Key,
+        value: Option<Value>,
+        expires_at: Option<u64>,
+        ticket: CacheTicket,
+    ) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let mut inner = self.inner.lock().expect("poisoned cache lock");
+        if inner.versions[stripe(&key)] != ticket.0 {
+            return;
+        }
+
+        inner.tick += 1;
+        let tick = inner.tick;
+
+        let entry = CachedEntry {
+            value,
+            expires_at,
+            last_use: tick,
+        };
+        if let Some(old) = inner.entries.insert(key, entry) {
+            inner.by_use.remove(&old.last_use);
+        }
+        inner.by_use.insert(tick, key);
+
+        while inner.entries.len() > self.capacity {
+            let (_, evicted) = inner.by_use.pop_first().expect("entries are tracked");
+            inner.entries.remove(&evicted);
+        }
+    }
+
+    /// Drops the cached value of `key`, to be called after every write
+    pub fn invalidate(&self, key: &Key) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let mut inner = self.inner.lock().expect("poisoned cache lock");
+        inner.versions[stripe(key)] += 1;
+
+        if let Some(old) = inner.entries.remove(key) {
+            inner.by_use.remove(&old.last_use);
+        }
+    }
+
+    /// Drops the cached values of the keys in `start..end`
+    pub fn invalidate_range(&self, start: Key, end: Key, order: &KeyOrder) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let mut inner = self.inner.lock().expect("poisoned cache lock");
+        // The range can span any number of stripes
+        for version in inner.versions.iter_mut() {
+            *version += 1;
+        }
+
+        let inner = &mut *inner;
+        inner.entries.retain(|key, entry| {
+            let keep = !order.in_range(&start, &end, key);
+            if !keep {
+                inner.by_use.remove(&entry.last_use);
+            }
+            keep
+        });
+    }
+}
+
+fn stripe(key: &Key) -> usize {
+    (*key % VERSION_STRIPES as u64) as usize
+}
@@ -0,0 +1,234 @@
+use crate::{
+    Key, Value,
+    comparator::KeyOrder,
+    options::OverflowPolicy,
+    serialization::KVMemoryRepr,
+    watch::{WatchHandle, Watches},
+};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    time::Duration,
+};
+
+/// A committed write, as delivered to [`ChangeReceiver`]s
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub sequence: u64,
+    pub key: Key,
+    /// `None` for deletions
+    pub value: Option<Value>,
+    /// Milliseconds since the UNIX epoch, see [`crate::KVStorage::write_with_ttl`]
+    pub expires_at: Option<u64>,
+    /// Set when every key in `key..range_end` was deleted, see [`crate::KVStorage::delete_range`]
+    pub range_end: Option<Key>,
+    /// Milliseconds since the UNIX epoch, see [`crate::Options::write_timestamps`]
+    pub written_at: Option<u64>,
+}
+
+impl From<&KVMemoryRepr> for Change {
+    fn from(entry: &KVMemoryRepr) -> Self {
+        Change {
+            sequence: entry.sequence(),
+            key: *entry.key(),
+            value: *entry.value(),
+            expires_at: entry.expires_at(),
+            range_end: entry.range_end(),
+            written_at: entry.written_at(),
+        }
+    }
+}
+
+/// Fans the committed writes out to the subscribers and the watches
+pub struct ChangeHub {
+    capacity: usize,
+    overflow: OverflowPolicy,
+    subscribers: Mutex<Vec<Arc<ChangeQueue>>>,
+    watches: Arc<Watches>,
+}
+
+struct ChangeQueue {
+    state: Mutex<QueueState>,
+    /// Notified on every push, pop and close
+    changed: Condvar,
+}
+
+struct QueueState {
+    changes: VecDeque<Change>,
+    /// Changes discarded by [`OverflowPolicy::DropOldest`]
+    dropped: u64,
+    /// Either side is gone
+    closed: bool,
+}
+
+impl ChangeQueue {
+    fn lock(&self) -> MutexGuard<'_, QueueState> {
+        self.state.lock().expect("poisoned change queue lock")
+    }
+
+    fn close(&self) {
+        self.lock().closed = true;
+        self.changed.notify_all();
+    }
+}
+
+impl ChangeHub {
+    pub fn new(capacity: usize, overflow: OverflowPolicy) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            overflow,
+            subscribers: Default::default(),
+            watches: Default::default(),
+        }
+    }
+
+    pub fn subscribe(&self) -> ChangeReceiver {
+        let queue = Arc::new(ChangeQueue {
+            state: Mutex::new(QueueState {
+                changes: VecDeque::new(),
+                dropped: 0,
+                closed: false,
+            }),
+            changed: Condvar::new(),
+        });
+
+        let mut subscribers = self.subscribers.lock().expect("poisoned subscribers lock");
+        subscribers.push(queue.clone());
+
+        ChangeReceiver { queue }
+    }
+
+    pub fn watch(&self, key: Key) -> WatchHandle {
+        self.watches.watch(key)
+    }
+
+    #[cfg(test)]
+    pub fn watches(&self) -> &Watches {
+        &self.watches
+    }
+
+    /// Delivers `entry` to every subscriber and watch, in the order of the calls
+    pub fn publish(&self, entry: &KVMemoryRepr, order: &KeyOrder) {
+        self.watches.notify(entry, order);
+
+        let mut subscribers = self.subscribers.lock().expect("poisoned subscribers lock");
+        if subscribers.is_empty() {
+            return;
+        }
+
+        let change = Change::from(entry);
+        subscribers.retain(|queue| {
+            let mut state = queue.lock();
+
+            while !state.closed && state.changes.len() >= self.capacity {
+                match self.overflow {
+                    OverflowPolicy::DropOldest => {
+                        state.changes.pop_front();
+                        state.dropped += 1;
+                    }
+                    OverflowPolicy::Block => {
+                        state = queue
+                            .changed
+                            .wait(state)
+                            .expect("poisoned change queue lock");
+                    }
+                }
+            }
+
+            if state.closed {
+                return false;
+            }
+
+            state.changes.push_back(change.clone());
+            queue.changed.notify_all();
+            true
+        });
+    }
+}
+
+impl Drop for ChangeHub {
+    fn drop(&mut self) {
+        self.watches.close();
+
+        let subscribers = self.subscribers.lock().expect("poisoned subscribers lock");
+        for queue in subscribers.iter() {
+            queue.close();
+        }
+    }
+}
+
+/// Receives the writes committed after [`crate::KVStorage::subscribe`], in commit order.
+///
+/// Iterating blocks until the next change, the iteration ends once the store is dropped.
+pub struct ChangeReceiver {
+    queue: Arc<ChangeQueue>,
+}
+
+impl ChangeReceiver {
+    /// Waits for the next change, `None` once the store is dropped and every change was received
+    pub fn recv(&self) -> Option<Change> {
+        self.recv_inner(None)
+    }
+
+    /// Like [`ChangeReceiver::recv`], also returning `None` after `timeout`
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<Change> {
+        self.recv_inner(Some(timeout))
+    }
+
+    pub fn try_recv(&self) -> Option<Change> {
+        self.recv_inner(Some(Duration::ZERO))
+    }
+
+    /// Number of changes lost to [`OverflowPolicy::DropOldest`], the subscriber is then out of sync
+    pub fn dropped(&self) -> u64 {
+        self.queue.lock().dropped
+    }
+
+    fn recv_inner(&self, timeout: Option<Duration>) -> Option<Change> {
+        let mut state = self.queue.lock();
+
+        loop {
+            if let Some(change) = state.changes.pop_front() {
+                self.queue.changed.notify_all();
+                return Some(change);
+            }
+            if state.closed {
+                return None;
+            }
+
+            state = match timeout {
+                None => self
+                    .queue
+                    .changed
+                    .wait(state)
+                    .expect("poisoned change queue lock"),
+                Some(timeout) => {
+                    let (state, result) = self
+                        .queue
+                        .changed
+                        .wait_timeout(state, timeout)
+                        .expect("poisoned change queue lock");
+                    if result.timed_out() && state.changes.is_empty() {
+                        return None;
+                    }
+                    state
+                }
+            };
+        }
+    }
+}
+
+impl Iterator for ChangeReceiver {
+    type Item = Change;
+
+    fn next(&mut self) -> Option<Change> {
+        self.recv()
+    }
+}
+
+impl Drop for ChangeReceiver {
+    fn drop(&mut self) {
+        // Unblocks a writer waiting for room, the hub then forgets this queue
+        self.queue.close();
+    }
+}
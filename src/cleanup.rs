@@ -1,21 +1,41 @@
+use crate::{
+    append_log::LOG_FILE_PREFIX,
+    clock::Clock,
+    errors::Error,
+    file_header::{self, FileKind},
+    files::PositionedFile,
+    identity::{IDENTITY_NAME, IDENTITY_TMP_NAME},
+    instrumentation::span,
+    manifest::{MANIFEST_NAME, MANIFEST_TMP_NAME, ManifestData, read_manifest},
+    repair::LOST_DIR,
+    sstables::TMP_EXTENSION,
+    storage::{FileInfo, Storage},
+};
 use std::{
-    fs::remove_file,
     path::{Path, PathBuf},
-    sync::Arc,
-    thread::{sleep, spawn},
-    time::Duration,
+    sync::{
+        Arc, Condvar, Mutex,
+        mpsc::{Receiver, RecvTimeoutError, Sender, TryRecvError, channel},
+    },
+    thread::{JoinHandle, spawn},
+    time::{Duration, Instant, SystemTime},
 };
 
-// 10ms to 10 seconds
-const MAX_RETRIES: u32 = 10;
-const FIRST_DELAY_INTERVAL_MS: u32 = 10;
+const REAPER_RETRY_INTERVAL: Duration = Duration::from_millis(20);
+/// How long a stopping reaper keeps waiting for files still in use by default, in real time whatever the clock. See
+/// [`Reaper::shutdown`]
+const REAPER_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+/// Files modified more recently than this are never considered orphans
+pub const ORPHAN_GRACE_PERIOD: Duration = Duration::from_secs(10);
 
 pub trait CleanableFile {
     fn path(&self) -> PathBuf;
+    /// Bytes freed by the deletion
+    fn size(&self) -> u64;
 }
 
-pub fn remove_file_logged(path: &Path) {
-    match remove_file(path) {
+pub fn remove_file_logged(storage: &dyn Storage, path: &Path) {
+    match storage.remove(path) {
         Ok(_) => {}
         Err(e) => {
             log::error!("failed to remove file {:?}: {:?}", path, e);
@@ -23,25 +43,426 @@ pub fn remove_file_logged(path: &Path) {
     }
 }
 
-/// Removes a file after it's not longer used. Uses exponential backoff.
+type QueuedFile = Arc<dyn CleanableFile + Send + Sync>;
+/// A file along with the files of the reaper that queued it
+type Queued = (QueuedFile, Arc<PendingFiles>);
+
+/// Files queued by one [`Reaper`] and not removed yet
+#[derive(Default)]
+struct PendingFiles {
+    /// Number of files and their size
+    state: Mutex<(usize, u64)>,
+    removed: Condvar,
+}
+
+impl PendingFiles {
+    fn add(&self, size: u64) {
+        let mut state = self.state.lock().expect("poisoned reaper pending files");
+        state.0 += 1;
+        state.1 += size;
+    }
+
+    fn remove(&self, size: u64) {
+        let mut state = self.state.lock().expect("poisoned reaper pending files");
+        state.0 -= 1;
+        state.1 -= size;
+        self.removed.notify_all();
+    }
+
+    fn bytes(&self) -> u64 {
+        self.state.lock().expect("poisoned reaper pending files").1
+    }
+
+    /// Returns the number of files still there at `deadline`
+    fn wait_removed(&self, deadline: Instant) -> usize {
+        let mut state = self.state.lock().expect("poisoned reaper pending files");
+        while state.0 > 0 {
+            let Some(left) = deadline.checked_duration_since(Instant::now()) else {
+                break;
+            };
+            state = self
+                .removed
+                .wait_timeout(state, left)
+                .expect("poisoned reaper pending files")
+                .0;
+        }
+        state.0
+    }
+}
+
+/// Background thread removing files once they're not longer used.
+///
+/// The namespaces of a store queue their files on the thread of the store, see [`Reaper::for_namespace`].
+/// This relies on the fact that all other copies of a queued `Arc` are dropped after being used.
+pub struct Reaper {
+    /// Shared with the namespaces, the shutdown of the store disconnects it for all of them
+    sender: Arc<Mutex<Option<Sender<Queued>>>>,
+    /// Returns the number of files left on disk
+    worker: Mutex<Option<JoinHandle<usize>>>,
+    /// Whether this started the worker, false for the reaper of a namespace
+    owns_worker: bool,
+    /// Until when the worker waits for the files still in use once the channel is disconnected, set by
+    /// [`Reaper::shutdown`]
+    deadline: Arc<Mutex<Option<Instant>>>,
+    /// The files queued by this reaper
+    pending: Arc<PendingFiles>,
+}
+
+impl Reaper {
+    /// Files are removed from `storage`, the ones still in use are checked again after sleeping on `clock`
+    pub fn new(storage: Arc<dyn Storage>, clock: Arc<dyn Clock>) -> Self {
+        let (sender, receiver) = channel();
+        let deadline = Arc::new(Mutex::new(None));
+
+        let worker = {
+            let deadline = deadline.clone();
+            spawn(move || reaper_loop(&*storage, &*clock, receiver, &deadline))
+        };
+
+        Self {
+            sender: Arc::new(Mutex::new(Some(sender))),
+            worker: Mutex::new(Some(worker)),
+            owns_worker: true,
+            deadline,
+            pending: Arc::default(),
+        }
+    }
+
+    /// A reaper removing its files on the thread of this one, with its own [`Reaper::pending_bytes`]. It must be
+    /// shut down before this one.
+    pub fn for_namespace(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            worker: Mutex::new(None),
+            owns_worker: false,
+            deadline: self.deadline.clone(),
+            pending: Arc::default(),
+        }
+    }
+
+    /// Size of the files queued for deletion and still on disk
+    pub fn pending_bytes(&self) -> u64 {
+        self.pending.bytes()
+    }
+
+    /// Queues `file` for deletion, which happens once all other copies of the `Arc` are dropped
+    pub fn delete(&self, file: QueuedFile) {
+        let sender = self.sender.lock().expect("poisoned reaper sender");
+
+        self.pending.add(file.size());
+        match sender
+            .as_ref()
+            .map(|sender| sender.send((file, self.pending.clone())))
+        {
+            Some(Ok(_)) => {}
+            _ => log::error!("reaper is stopped, file left on disk"),
+        }
+    }
+
+    /// Whether the worker thread is still there, false once a shutdown joined it
+    #[cfg(test)]
+    pub fn is_running(&self) -> bool {
+        self.worker
+            .lock()
+            .expect("poisoned reaper worker")
+            .as_ref()
+            .is_some_and(|worker| !worker.is_finished())
+    }
+
+    /// [`Reaper::shutdown`] waiting up to [`REAPER_SHUTDOWN_TIMEOUT`]
+    pub fn stop(&self) {
+        self.shutdown(Instant::now() + REAPER_SHUTDOWN_TIMEOUT);
+    }
+
+    /// Stops accepting files, removes the queued ones as they stop being used until `deadline`, then joins the
+    /// worker. Returns the number of files still in use at the deadline, which are left on disk for the orphan
+    /// cleanup of the next open. Later calls return 0.
+    ///
+    /// The reaper of a namespace only waits for its own files, the thread keeps going for the store.
+    pub fn shutdown(&self, deadline: Instant) -> usize {
+        if !self.owns_worker {
+            return self.pending.wait_removed(deadline);
+        }
+
+        *self.deadline.lock().expect("poisoned reaper deadline") = Some(deadline);
+        // Disconnecting the channel tells the worker to finish up
+        self.sender.lock().expect("poisoned reaper sender").take();
+
+        let Some(worker) = self.worker.lock().expect("poisoned reaper worker").take() else {
+            return 0;
+        };
+        worker.join().unwrap_or_else(|_| {
+            log::error!("reaper thread panicked");
+            0
+        })
+    }
+}
+
+/// Returns the number of files left on disk at shutdown
+fn reaper_loop(
+    storage: &dyn Storage,
+    clock: &dyn Clock,
+    receiver: Receiver<Queued>,
+    deadline: &Mutex<Option<Instant>>,
+) -> usize {
+    let mut pending: Vec<Queued> = Vec::new();
+    let mut shutdown_deadline = None;
+
+    loop {
+        let received = if shutdown_deadline.is_some() {
+            clock.sleep(REAPER_RETRY_INTERVAL);
+            Ok(None)
+        } else if pending.is_empty() {
+            // Nothing to retry, no need to wake up
+            receiver
+                .recv()
+                .map(Some)
+                .map_err(|_| RecvTimeoutError::Disconnected)
+        } else {
+            // Files queued meanwhile wait for the end of the sleep
+            match receiver.try_recv() {
+                Ok(file) => Ok(Some(file)),
+                Err(TryRecvError::Empty) => {
+                    clock.sleep(REAPER_RETRY_INTERVAL);
+                    Err(RecvTimeoutError::Timeout)
+                }
+                Err(TryRecvError::Disconnected) => Err(RecvTimeoutError::Disconnected),
+            }
+        };
+
+        let retry = match received {
+            Ok(Some(file)) => {
+                pending.push(file);
+                false
+            }
+            Ok(None) | Err(RecvTimeoutError::Timeout) => true,
+            Err(RecvTimeoutError::Disconnected) => {
+                shutdown_deadline.get_or_insert_with(|| {
+                    deadline
+                        .lock()
+                        .expect("poisoned reaper deadline")
+                        .unwrap_or_else(|| Instant::now() + REAPER_SHUTDOWN_TIMEOUT)
+                });
+                false
+            }
+        };
+
+        // The reaper holds the only copy of these, it's safe to remove them
+        let (unused, in_use): (Vec<_>, Vec<_>) = pending
+            .drain(..)
+            .partition(|(file, _)| Arc::strong_count(file) == 1);
+        pending = in_use;
+        let _span = retry.then(|| {
+            span!(
+                "reaper_retry",
+                removable = unused.len(),
+                still_in_use = pending.len()
+            )
+        });
+
+        for (file, owner) in unused {
+            let path = file.path();
+            let size = file.size();
+            // Closes (and unmaps) the file first, some platforms can't delete open files
+            drop(file);
+            remove_file_logged(storage, &path);
+            owner.remove(size);
+            log::trace!("File {path:?} cleaned");
+        }
+
+        if let Some(deadline) = shutdown_deadline {
+            if pending.is_empty() {
+                return 0;
+            }
+            if Instant::now() > deadline {
+                for (file, _) in &pending {
+                    log::error!(
+                        "file {:?} still in use at shutdown, left on disk",
+                        file.path()
+                    );
+                }
+                return pending.len();
+            }
+        }
+    }
+}
+
+/// Deletes log files and SSTables which are not referenced by `live`.
 ///
-/// This function relies on the fact that all other copies of the `Arc` are dropped after being used.
-pub fn background_file_delete<T: CleanableFile + Sync + Send + 'static>(mut file: Arc<T>) {
-    let path = file.path();
-    spawn(move || {
-        for retry in 0..MAX_RETRIES {
-            let delay = FIRST_DELAY_INTERVAL_MS * 2_u32.pow(retry);
-            sleep(Duration::from_millis(delay as u64));
-
-            file = match Arc::try_unwrap(file) {
+/// These are left behind by crashes or by the [`Reaper`] stopping while files were in use.
+/// Returns the number of files and bytes reclaimed.
+pub fn remove_orphan_files(
+    storage: &dyn Storage,
+    db_dir: &Path,
+    sstables_dir: &Path,
+    cold_dir: Option<&Path>,
+    live: &ManifestData,
+    grace_period: Duration,
+) -> Result<(u64, u64), Error> {
+    let is_orphan_log = |name: &str| {
+        name.starts_with(LOG_FILE_PREFIX)
+            && !live.log_files.iter().any(|live| live == name)
+            && !live
+                .frozen_log_files
+                .iter()
+                .flatten()
+                .any(|live| live == name)
+    };
+    let is_orphan_sstable = |name: &str| {
+        name.parse::<u64>()
+            .is_ok_and(|id| !live.sstables.contains(&id))
+    };
+
+    let mut files = 0;
+    let mut bytes = 0;
+
+    let dirs = [
+        (Some(db_dir), &is_orphan_log as &dyn Fn(&str) -> bool),
+        (Some(sstables_dir), &is_orphan_sstable),
+        (cold_dir, &is_orphan_sstable),
+    ];
+    for (dir, is_orphan) in dirs
+        .into_iter()
+        .filter_map(|(dir, is_orphan)| Some((dir?, is_orphan)))
+    {
+        for file in storage.list(dir)? {
+            let name = file.path.file_name().unwrap_or_default();
+            if !is_orphan(&name.to_string_lossy()) {
+                continue;
+            }
+
+            let age = SystemTime::now()
+                .duration_since(file.modified)
+                .unwrap_or_default();
+            if age < grace_period {
+                continue;
+            }
+
+            let path = file.path;
+            match storage.remove(&path) {
                 Ok(_) => {
-                    remove_file_logged(&path);
-                    log::trace!("File {path:?} cleaned at retry {retry}");
-                    return;
+                    log::info!("removed orphan file {path:?}");
+                    files += 1;
+                    bytes += file.len;
                 }
-                Err(arc) => arc,
-            };
+                Err(e) => log::error!("failed to remove orphan file {:?}: {:?}", path, e),
+            }
         }
-        log::error!("failed to remove log file {:?}: max retires reached", path,);
-    });
+    }
+
+    Ok((files, bytes))
+}
+
+/// Removes the files of the database in `db_dir` (namespaces included), then its directories.
+///
+/// Nothing is removed unless every file is one the database could have written: manifests, log files and tables
+/// (recognized by their header). Files quarantined by a repair are removed without checks.
+pub fn destroy_database(storage: &dyn Storage, db_dir: &Path) -> Result<(), Error> {
+    let mut files = Vec::new();
+    let mut dirs = Vec::new();
+    collect_database(storage, db_dir, &mut files, &mut dirs)?;
+
+    for file in &files {
+        storage.remove(file)?;
+    }
+    for dir in &dirs {
+        storage.remove_dir(dir)?;
+    }
+
+    Ok(())
+}
+
+/// Lists the files and directories of the database in `db_dir`, nested directories first. Fails with
+/// [`Error::NotADatabase`] on a file the database didn't write.
+fn collect_database(
+    storage: &dyn Storage,
+    db_dir: &Path,
+    files: &mut Vec<PathBuf>,
+    dirs: &mut Vec<PathBuf>,
+) -> Result<(), Error> {
+    let sstables_dir = db_dir.join("sstables");
+    if !storage.is_dir(db_dir) || !storage.is_dir(&sstables_dir) {
+        return Err(Error::NotADatabase);
+    }
+
+    let db_files = storage.list(db_dir)?;
+    let table_files = storage.list(&sstables_dir)?;
+    let all_ours = db_files.iter().all(is_database_file)
+        && table_files.iter().all(|file| is_table_file(storage, file));
+    if !all_ours {
+        return Err(Error::NotADatabase);
+    }
+
+    // An unreadable manifest leaves the namespaces and cold tables behind, `db_dir` then can't be removed
+    let manifest = read_manifest(storage, db_dir).unwrap_or_default();
+    for name in &manifest.namespaces {
+        collect_database(storage, &db_dir.join(name), files, dirs)?;
+    }
+
+    // The cold storage directory itself isn't the database's
+    if let Some(cold_dir) = manifest.cold_storage_dir.as_deref().map(Path::new)
+        && storage.is_dir(cold_dir)
+    {
+        let cold_tables = manifest.cold_sstables.iter().map(u64::to_string);
+        files.extend(
+            storage
+                .list(cold_dir)?
+                .into_iter()
+                .filter(|file| cold_tables.clone().any(|id| id == file_name(file)))
+                .map(|file| file.path),
+        );
+    }
+
+    let lost_dir = db_dir.join(LOST_DIR);
+    if storage.is_dir(&lost_dir) {
+        files.extend(storage.list(&lost_dir)?.into_iter().map(|file| file.path));
+        dirs.push(lost_dir);
+    }
+    files.extend(
+        db_files
+            .into_iter()
+            .chain(table_files)
+            .map(|file| file.path),
+    );
+    dirs.extend([sstables_dir, db_dir.to_owned()]);
+
+    Ok(())
+}
+
+fn file_name(file: &FileInfo) -> String {
+    file.path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// A file written in `db/` by the database, table files excluded
+pub fn is_database_file(file: &FileInfo) -> bool {
+    let name = file_name(file);
+
+    name == MANIFEST_NAME
+        || name == MANIFEST_TMP_NAME
+        || name == IDENTITY_NAME
+        || name == IDENTITY_TMP_NAME
+        || name
+            .strip_prefix(LOG_FILE_PREFIX)
+            .is_some_and(|suffix| suffix.parse::<u64>().is_ok())
+}
+
+/// Complete tables must start with a table header, partially written ones only need the right name
+fn is_table_file(storage: &dyn Storage, file: &FileInfo) -> bool {
+    let name = file_name(file);
+    if let Some(id) = name.strip_suffix(&format!(".{TMP_EXTENSION}")) {
+        return id.parse::<u64>().is_ok();
+    }
+    if name.parse::<u64>().is_err() {
+        return false;
+    }
+
+    let mut magic = [0u8; 4];
+    storage
+        .open(&file.path, false)
+        .and_then(|handle| Ok(handle.read_exact_at(&mut magic, 0)?))
+        .is_ok_and(|_| file_header::has_magic(FileKind::Table, &magic))
 }
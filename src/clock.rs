@@ -0,0 +1,24 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Source of the current time, used for TTLs and by the background threads waiting before a retry
+pub trait Clock: Send + Sync {
+    /// Milliseconds since the UNIX epoch
+    fn now_millis(&self) -> u64;
+
+    /// Blocks the calling thread for `duration`, a manual clock can return once it's moved past it instead
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+/// The system wall clock
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("system time before UNIX epoch")
+            .as_millis() as u64
+    }
+}
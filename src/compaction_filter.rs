@@ -0,0 +1,16 @@
+use crate::{Key, Value};
+
+/// What to do with an entry during compaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterDecision {
+    Keep,
+    /// Deletes the entry, as if a tombstone had been written
+    Remove,
+    /// Keeps the entry with a different value
+    Replace(Value),
+}
+
+/// Called by the compactor on the newest entry of every merged key, tombstones (`None`) included
+pub trait CompactionFilter: Send + Sync {
+    fn filter(&self, key: &Key, value: &Option<Value>) -> FilterDecision;
+}
@@ -0,0 +1,147 @@
+use crate::Key;
+use std::{cmp::Ordering, ops::Bound, sync::Arc};
+
+/// Order of the keys in the tables, in iterations and in the ranges of [`crate::KVStorage::delete_range`].
+///
+/// Recorded by name in the manifest: a database can only be opened with the comparator it was created with, see
+/// [`crate::Error::ComparatorMismatch`].
+pub trait KeyComparator: Send + Sync {
+    /// Identifies the order, two comparators with the same name must order keys the same way
+    fn name(&self) -> &str;
+
+    fn cmp(&self, a: &Key, b: &Key) -> Ordering;
+
+    /// The key right before `key`, if there is one and it's cheap to tell. Only narrows the key range of the tables
+    /// holding range tombstones, which otherwise reach their (excluded) end
+    fn predecessor(&self, key: &Key) -> Option<Key> {
+        let _ = key;
+        None
+    }
+}
+
+/// Smallest key first, the default
+pub struct Ascending;
+
+impl KeyComparator for Ascending {
+    fn name(&self) -> &str {
+        "ascending"
+    }
+
+    fn cmp(&self, a: &Key, b: &Key) -> Ordering {
+        a.cmp(b)
+    }
+
+    fn predecessor(&self, key: &Key) -> Option<Key> {
+        key.checked_sub(1)
+    }
+}
+
+/// Largest key first
+pub struct Descending;
+
+impl KeyComparator for Descending {
+    fn name(&self) -> &str {
+        "descending"
+    }
+
+    fn cmp(&self, a: &Key, b: &Key) -> Ordering {
+        b.cmp(a)
+    }
+
+    fn predecessor(&self, key: &Key) -> Option<Key> {
+        key.checked_add(1)
+    }
+}
+
+/// The built-in comparator called `name`
+pub(crate) fn builtin(name: &str) -> Option<Arc<dyn KeyComparator>> {
+    match name {
+        "ascending" => Some(Arc::new(Ascending)),
+        "descending" => Some(Arc::new(Descending)),
+        _ => None,
+    }
+}
+
+/// Turns the keys of a test into the ones it writes, see [`test_orders`]
+#[cfg(test)]
+pub(crate) type KeyMapping = fn(Key) -> Key;
+
+/// The built-in comparators, each with a mapping of the keys that it orders as [`Ascending`] orders the unmapped
+/// ones. Order-sensitive tests write mapped keys, so that they run unchanged under both
+#[cfg(test)]
+pub(crate) fn test_orders() -> [(Arc<dyn KeyComparator>, KeyMapping); 2] {
+    [
+        (Arc::new(Ascending), |key| key),
+        (Arc::new(Descending), |key| Key::MAX - key),
+    ]
+}
+
+/// Shared handle on the comparator of a store, with the comparisons the store makes
+#[derive(Clone)]
+pub(crate) struct KeyOrder(Arc<dyn KeyComparator>);
+
+impl Default for KeyOrder {
+    fn default() -> Self {
+        Self(Arc::new(Ascending))
+    }
+}
+
+impl KeyOrder {
+    pub fn new(comparator: Arc<dyn KeyComparator>) -> Self {
+        Self(comparator)
+    }
+
+    pub fn cmp(&self, a: &Key, b: &Key) -> Ordering {
+        self.0.cmp(a, b)
+    }
+
+    pub fn lt(&self, a: &Key, b: &Key) -> bool {
+        self.cmp(a, b) == Ordering::Less
+    }
+
+    pub fn min(&self, a: Key, b: Key) -> Key {
+        if self.lt(&b, &a) { b } else { a }
+    }
+
+    pub fn max(&self, a: Key, b: Key) -> Key {
+        if self.lt(&a, &b) { b } else { a }
+    }
+
+    /// Whether `key` is in `start..end`
+    pub fn in_range(&self, start: &Key, end: &Key, key: &Key) -> bool {
+        !self.lt(key, start) && self.lt(key, end)
+    }
+
+    /// Whether `key` is in `first..=last`
+    pub fn in_bounds(&self, first: &Key, last: &Key, key: &Key) -> bool {
+        !self.lt(key, first) && !self.lt(last, key)
+    }
+
+    /// Whether `key` is past the lower bound `lower`
+    pub fn above(&self, lower: Bound<Key>, key: &Key) -> bool {
+        match lower {
+            Bound::Unbounded => true,
+            Bound::Included(bound) => !self.lt(key, &bound),
+            Bound::Excluded(bound) => self.lt(&bound, key),
+        }
+    }
+
+    /// Whether `key` is before the upper bound `upper`
+    pub fn below(&self, upper: Bound<Key>, key: &Key) -> bool {
+        match upper {
+            Bound::Unbounded => true,
+            Bound::Included(bound) => !self.lt(&bound, key),
+            Bound::Excluded(bound) => self.lt(key, &bound),
+        }
+    }
+
+    /// First and last key that `start..end` can hold, `None` if it's empty. The last one is `end` when the
+    /// comparator can't tell the key before it
+    pub fn bounds(&self, start: Key, end: Key) -> Option<(Key, Key)> {
+        if !self.lt(&start, &end) {
+            return None;
+        }
+
+        Some((start, self.0.predecessor(&end).unwrap_or(end)))
+    }
+}
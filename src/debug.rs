@@ -0,0 +1,124 @@
+use crate::changes::Change;
+use std::{fmt, path::PathBuf};
+
+/// The SSTables and the append log as stored, see [`crate::KVStorage::dump`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DbDump {
+    /// Newest first, the order reads visit them in
+    pub tables: Vec<TableDump>,
+    /// In shard order
+    pub logs: Vec<LogDump>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableDump {
+    pub id: u64,
+    pub path: PathBuf,
+    pub file_size: u64,
+    /// Point entries, tombstones included
+    pub entry_count: u64,
+    pub tombstone_count: u64,
+    /// Entries of the merged tables that compaction left out of this one
+    pub discarded_entries: u64,
+    /// Smallest and largest key, range tombstones included. `None` for empty tables
+    pub key_range: Option<(u64, u64)>,
+    pub blocks: Vec<BlockDump>,
+    /// Every entry, range tombstones last. Only decoded when asked for
+    pub entries: Option<Vec<Change>>,
+}
+
+/// An entry of the block index of a table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockDump {
+    pub first_key: u64,
+    /// From the end of the file header
+    pub offset: u64,
+    /// Stored (compressed) size
+    pub len: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogDump {
+    pub file_name: String,
+    /// Entries with their offset in the file, in sequence order
+    pub entries: Vec<(u64, Change)>,
+}
+
+impl fmt::Display for DbDump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for table in &self.tables {
+            writeln!(f, "{table}")?;
+            for (i, block) in table.blocks.iter().enumerate() {
+                writeln!(
+                    f,
+                    "  block {i} at {}, {} bytes, first key {}",
+                    block.offset, block.len, block.first_key
+                )?;
+            }
+            for entry in table.entries.iter().flatten() {
+                writeln!(f, "  {}", DisplayChange(entry))?;
+            }
+        }
+
+        for (shard, log) in self.logs.iter().enumerate() {
+            writeln!(
+                f,
+                "log {shard} {}: {} entries",
+                log.file_name,
+                log.entries.len()
+            )?;
+            for (offset, entry) in &log.entries {
+                writeln!(f, "  {offset}: {}", DisplayChange(entry))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The summary line of the table, without blocks and entries
+impl fmt::Display for TableDump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "table {}: {} bytes, {} entries",
+            self.id, self.file_size, self.entry_count
+        )?;
+        if self.tombstone_count > 0 {
+            write!(f, " ({} tombstones)", self.tombstone_count)?;
+        }
+        if self.discarded_entries > 0 {
+            write!(f, ", {} discarded by compaction", self.discarded_entries)?;
+        }
+        if let Some((min, max)) = self.key_range {
+            write!(f, ", keys {min}..={max}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// `#sequence key = value`, `#sequence key deleted` or `#sequence start..end deleted`, then the expiration and write
+/// time when there are
+struct DisplayChange<'a>(&'a Change);
+
+impl fmt::Display for DisplayChange<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let change = self.0;
+        write!(f, "#{} {}", change.sequence, change.key)?;
+
+        match (change.range_end, change.value) {
+            (Some(end), _) => write!(f, "..{end} deleted")?,
+            (None, Some(value)) => write!(f, " = {value}")?,
+            (None, None) => write!(f, " deleted")?,
+        }
+        if let Some(expires_at) = change.expires_at {
+            write!(f, " (expires at {expires_at})")?;
+        }
+        if let Some(written_at) = change.written_at {
+            write!(f, " (written at {written_at})")?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,60 @@
+#[cfg(any(test, feature = "testing"))]
+use crate::clock::{Clock, SystemClock};
+#[cfg(any(test, feature = "testing"))]
+use rand::{RngCore, SeedableRng, rngs::StdRng};
+#[cfg(any(test, feature = "testing"))]
+use std::sync::{Arc, Mutex};
+
+/// Source of the random numbers of the store: log file names and table ids
+pub trait RandomSource: Send + Sync {
+    fn next_u64(&self) -> u64;
+}
+
+/// The thread-local generator of `rand`, used unless replaced with [`crate::KVStorage::new_with_env`]
+pub struct ThreadRandom;
+
+impl RandomSource for ThreadRandom {
+    fn next_u64(&self) -> u64 {
+        rand::random()
+    }
+}
+
+/// Same numbers in the same order for the same seed, so that file names and table ids are reproducible
+#[cfg(any(test, feature = "testing"))]
+pub struct SeededRandom(Mutex<StdRng>);
+
+#[cfg(any(test, feature = "testing"))]
+impl SeededRandom {
+    pub fn new(seed: u64) -> Self {
+        SeededRandom(Mutex::new(StdRng::seed_from_u64(seed)))
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl RandomSource for SeededRandom {
+    fn next_u64(&self) -> u64 {
+        self.0.lock().expect("poisoned random source").next_u64()
+    }
+}
+
+/// Time and randomness of a store, see [`crate::KVStorage::new_with_env`].
+///
+/// The clock replaces [`crate::Options::clock`], it also paces the retries of the background threads through
+/// [`Clock::sleep`]
+#[cfg(any(test, feature = "testing"))]
+#[derive(Clone)]
+pub struct Env {
+    pub clock: Arc<dyn Clock>,
+    pub random: Arc<dyn RandomSource>,
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl Env {
+    /// The system clock with numbers drawn from `seed`
+    pub fn seeded(seed: u64) -> Self {
+        Env {
+            clock: Arc::new(SystemClock),
+            random: Arc::new(SeededRandom::new(seed)),
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use std::io;
+use std::{io, path::PathBuf, sync::Arc};
 
 use crate::serialization::SerializationError;
 
@@ -9,6 +9,80 @@ pub enum Error {
     Serialization(SerializationError),
     IO(io::Error),
     TooBig,
+    /// A numeric operation would go below zero or above `u64::MAX`
+    Overflow,
+    /// The database was opened with [`crate::KVStorage::open_read_only`]
+    ReadOnly,
+    /// A log or table file was written with a format version this build can't read
+    UnsupportedVersion {
+        found: u8,
+        supported: u8,
+    },
+    /// The `db/` directory holds files the database didn't write, or an identity file of something else. Nothing was
+    /// removed by [`crate::KVStorage::destroy`], which also fails with it when there's no database at all
+    NotADatabase,
+    /// The database was created by a newer version, with a directory layout this build can't open
+    LayoutTooNew {
+        found: u32,
+        supported: u32,
+    },
+    /// The `db/` directory holds database files but neither its identity file nor its manifest, it may be damaged.
+    /// See [`crate::KVStorage::repair`]
+    MissingIdentity,
+    /// [`crate::CreateMode::CreateNew`] found a database already there
+    DatabaseExists,
+    /// Namespace names are made of lowercase ASCII letters, digits, `-` and `_`, see
+    /// [`crate::KVStorage::create_namespace`]
+    InvalidNamespaceName,
+    NamespaceExists,
+    NamespaceNotFound,
+    /// [`crate::KVStorage::drop_namespace`] was called while a [`crate::Namespace`] handle was still alive
+    NamespaceInUse,
+    /// [`crate::KVStorage::close`] or [`crate::KVStorage::close_and_destroy`] was called while other clones of the
+    /// handle were alive
+    StoreInUse,
+    /// [`crate::KVStorage::bulk_load`] or [`crate::sstable::SstWriter::add`] got a key not above the previous one, at
+    /// `position` in the input
+    UnsortedBulkLoad {
+        position: u64,
+    },
+    /// The file system ran out of space. Rotations and merges hitting it are rolled back, the store stays usable
+    DiskFull,
+    /// The write would take the store over [`crate::Options::max_db_size_bytes`]
+    QuotaExceeded,
+    /// The database has tables in a cold storage directory other than [`crate::Options::cold_storage_dir`]
+    ColdStorageDirChanged,
+    /// A [`crate::Durability::Synced`] write was made but syncing it failed: it's readable, yet may be lost in a
+    /// crash. See [`crate::KVStorage::is_healthy`]
+    NotDurable(io::Error),
+    /// A merge thread panicked, with the panic message. Its input tables were kept
+    MergePanicked(String),
+    /// The writer combining a batch of writes panicked, with the panic message, see
+    /// [`crate::Options::write_batching`]. The other writes of the batch may or may not have been made
+    WritePanicked(String),
+    /// A batch of combined writes failed as a whole, every write of it gets this same error. See
+    /// [`crate::Options::write_batching`]
+    BatchFailed(Arc<Error>),
+    /// A table couldn't be written: its directory is gone and can't be created again, or the file system is
+    /// read-only. After a few failures in a row, writes fail with it until the directory is usable again
+    StorageUnavailable,
+    /// A key doesn't hold the component read from it with [`crate::keys::KeyParser`]
+    KeyDecode,
+    /// The database was created with another [`crate::KeyComparator`] than the one of [`crate::Options::key_comparator`]
+    ComparatorMismatch {
+        stored: String,
+        configured: String,
+    },
+    /// The input of [`crate::KVStorage::import`] isn't an export stream, or was cut short
+    InvalidExport,
+    /// [`crate::KVStorage::space_report`] stopped because its cancel flag was set
+    Cancelled,
+    /// The file of table `id` is gone from `path`, deleted from outside of the store. See
+    /// [`crate::Options::skip_missing_tables`]
+    TableMissing {
+        id: u64,
+        path: PathBuf,
+    },
 }
 
 impl From<SerializationError> for Error {
@@ -19,6 +93,9 @@ impl From<SerializationError> for Error {
 
 impl From<io::Error> for Error {
     fn from(error: io::Error) -> Self {
-        Error::IO(error)
+        match error.kind() {
+            io::ErrorKind::StorageFull => Error::DiskFull,
+            _ => Error::IO(error),
+        }
     }
 }
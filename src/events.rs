@@ -0,0 +1,65 @@
+use crate::Error;
+use std::time::Duration;
+
+/// Why an append log was turned into an SSTable
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushReason {
+    /// No room left in the log file
+    LogFull,
+    /// The memtable reached [`crate::Options::memtable_max_entries`] or [`crate::Options::memtable_max_bytes`]
+    MemtableLimit,
+    /// [`crate::KVStorage::flush`]
+    Manual,
+    /// Left by a failed flush before the store was opened again
+    Recovered,
+}
+
+/// A full (or flushed) append log being turned into an SSTable
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlushInfo {
+    pub reason: FlushReason,
+    /// Bytes written to the append log
+    pub log_bytes: u64,
+    /// Set on completion
+    pub table_id: Option<u64>,
+    /// Set on completion
+    pub table_bytes: u64,
+    /// Set on completion
+    pub duration: Duration,
+}
+
+/// Consecutive SSTables being merged into one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactionInfo {
+    /// Newer first
+    pub input_tables: Vec<u64>,
+    pub input_bytes: u64,
+    /// Set on completion, stays `None` when nothing was left to write
+    pub output_table: Option<u64>,
+    /// Set on completion
+    pub output_bytes: u64,
+    /// Tombstones (expired entries included) removed for good, set on completion
+    pub dropped_tombstones: u64,
+    /// Set on completion
+    pub duration: Duration,
+}
+
+/// Notified of the background work of the store, registered with [`crate::Options::event_listener`].
+///
+/// Flush callbacks run on the writing thread while the log rotates, compaction callbacks on the compaction threads.
+/// They must return quickly and never call back into the store synchronously, which can deadlock.
+pub trait EventListener: Send + Sync {
+    fn on_flush_begin(&self, _info: &FlushInfo) {}
+
+    fn on_flush_complete(&self, _info: &FlushInfo) {}
+
+    fn on_compaction_begin(&self, _info: &CompactionInfo) {}
+
+    fn on_compaction_complete(&self, _info: &CompactionInfo) {}
+
+    /// The merge failed or panicked, its input tables stay in place and are merged again by a later compaction
+    fn on_compaction_failed(&self, _info: &CompactionInfo, _error: &Error) {}
+
+    /// Flushes and merges keep failing, writes are refused with `error` meanwhile: [`Error::StorageUnavailable`]
+    fn on_background_error(&self, _error: &Error) {}
+}
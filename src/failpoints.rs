@@ -0,0 +1,129 @@
+use std::{collections::HashMap, io, sync::Mutex, thread, time::Duration};
+
+/// [`crate::storage::MemStorage::create`]
+pub const CREATE_FILE: &str = "create_file";
+/// [`crate::functions::write_data_at_offset`], the log records
+pub const WRITE_DATA_AT_OFFSET: &str = "write_data_at_offset";
+/// [`crate::functions::write_file`], the SSTables and manifests
+pub const WRITE_FILE: &str = "write_file";
+/// [`crate::functions::read_file`] and [`crate::functions::read_data_at_offset`]
+pub const READ_FILE: &str = "read_file";
+/// [`crate::storage::Handle::sync_data`]
+pub const SYNC_DATA: &str = "sync_data";
+
+/// What a triggered failpoint does
+#[derive(Debug, Clone, Copy)]
+pub enum FailAction {
+    Error(io::ErrorKind),
+    Panic,
+    /// Sleeps, then lets the call through
+    Delay(Duration),
+}
+
+struct Failpoint {
+    /// Calls still let through
+    skip: u64,
+    action: FailAction,
+    /// Cleared once triggered
+    once: bool,
+}
+
+/// Named places of the I/O path of a [`crate::storage::MemStorage`] that tests can make fail
+#[derive(Default)]
+pub struct Failpoints {
+    points: Mutex<HashMap<&'static str, Failpoint>>,
+}
+
+impl Failpoints {
+    /// Lets `skip` calls of the failpoint `name` through, then triggers `action` on every call until cleared
+    pub fn set(&self, name: &'static str, skip: u64, action: FailAction) {
+        self.points.lock().expect("poisoned failpoints").insert(
+            name,
+            Failpoint {
+                skip,
+                action,
+                once: false,
+            },
+        );
+    }
+
+    /// Same as [`Failpoints::set`], triggering a single time
+    pub fn set_once(&self, name: &'static str, skip: u64, action: FailAction) {
+        self.points.lock().expect("poisoned failpoints").insert(
+            name,
+            Failpoint {
+                skip,
+                action,
+                once: true,
+            },
+        );
+    }
+
+    pub fn clear(&self, name: &str) {
+        self.points
+            .lock()
+            .expect("poisoned failpoints")
+            .remove(name);
+    }
+
+    /// Called at the failpoint `name`
+    pub fn hit(&self, name: &str) -> io::Result<()> {
+        let mut points = self.points.lock().expect("poisoned failpoints");
+        let Some(point) = points.get_mut(name) else {
+            return Ok(());
+        };
+        if point.skip > 0 {
+            point.skip -= 1;
+            return Ok(());
+        }
+
+        let action = point.action;
+        if point.once {
+            points.remove(name);
+        }
+        match action {
+            FailAction::Error(kind) => Err(io::Error::new(kind, format!("failpoint {name}"))),
+            FailAction::Panic => {
+                // Not poisoning the lock, the panic may be caught
+                drop(points);
+                panic!("failpoint {name}")
+            }
+            FailAction::Delay(duration) => {
+                // Other failpoints aren't held up meanwhile
+                drop(points);
+                thread::sleep(duration);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_skip_then_fail() {
+        let failpoints = Failpoints::default();
+        failpoints.set(WRITE_FILE, 2, FailAction::Error(io::ErrorKind::Other));
+
+        assert!(failpoints.hit(READ_FILE).is_ok());
+        assert!(failpoints.hit(WRITE_FILE).is_ok());
+        assert!(failpoints.hit(WRITE_FILE).is_ok());
+        assert!(failpoints.hit(WRITE_FILE).is_err());
+        assert!(failpoints.hit(WRITE_FILE).is_err());
+
+        failpoints.clear(WRITE_FILE);
+        assert!(failpoints.hit(WRITE_FILE).is_ok());
+    }
+
+    #[test]
+    #[should_panic(expected = "failpoint create_file")]
+    fn test_panic() {
+        let failpoints = Failpoints::default();
+        failpoints.set(CREATE_FILE, 1, FailAction::Panic);
+
+        let _ = failpoints.hit(CREATE_FILE);
+        let _ = failpoints.hit(CREATE_FILE);
+    }
+}
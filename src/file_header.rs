@@ -0,0 +1,163 @@
+use crate::{
+    clock::{Clock, SystemClock},
+    errors::Error,
+    files::PositionedFile,
+    serialization::{RecordFormat, SerializationError},
+    sstables,
+    storage::Handle,
+};
+
+/// Size of the header at the start of every log and table file, offsets in the files account for it
+pub const HEADER_BYTES: u64 = 16;
+/// Stored in the header of log files, bumped on incompatible changes of their layout
+pub const LOG_FORMAT_VERSION: u8 = 2;
+/// Oldest version of the log files still read, with [`RecordFormat::Framed`] records
+const LOG_OLDEST_VERSION: u8 = 1;
+/// First version of the log files with [`RecordFormat::Fixed`] records
+const LOG_FIXED_RECORDS_VERSION: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    Log,
+    Table,
+}
+
+impl FileKind {
+    fn magic(self) -> [u8; 4] {
+        match self {
+            FileKind::Log => *b"KVLG",
+            FileKind::Table => *b"KVST",
+        }
+    }
+
+    /// Version of the files written now
+    pub fn version(self) -> u8 {
+        match self {
+            FileKind::Log => LOG_FORMAT_VERSION,
+            FileKind::Table => sstables::TABLE_FORMAT_VERSION,
+        }
+    }
+
+    /// Older files are refused
+    fn oldest_version(self) -> u8 {
+        match self {
+            FileKind::Log => LOG_OLDEST_VERSION,
+            FileKind::Table => sstables::TABLE_OLDEST_VERSION,
+        }
+    }
+
+    /// How the records of the files of `version` are laid out
+    pub fn record_format(self, version: u8) -> RecordFormat {
+        let fixed_from = match self {
+            FileKind::Log => LOG_FIXED_RECORDS_VERSION,
+            FileKind::Table => sstables::TABLE_FIXED_RECORDS_VERSION,
+        };
+
+        if version < fixed_from {
+            RecordFormat::Framed
+        } else {
+            RecordFormat::Fixed
+        }
+    }
+
+    /// Stored in the header of the files written now, see [`FileHeader::min_reader_version`]
+    fn min_reader_version(self) -> u8 {
+        match self {
+            FileKind::Log => 0,
+            FileKind::Table => sstables::TABLE_MIN_READER_VERSION,
+        }
+    }
+}
+
+/// First bytes of log and table files:
+/// `[magic (4)][version (1)][flags (1)][min reader version (1)][reserved (1)][created at (8)]`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileHeader {
+    pub kind: FileKind,
+    /// Of the writer
+    pub version: u8,
+    /// Reserved for optional features, always 0 for now
+    pub flags: u8,
+    /// Oldest version able to read the file, files written by newer versions are read while it's not above the
+    /// current one. 0 when the file can only be read by its own version, as logs and tables written before it existed
+    pub min_reader_version: u8,
+    /// Milliseconds since the UNIX epoch
+    pub created_at: u64,
+}
+
+impl FileHeader {
+    /// Header of a file of `kind` created now, with the current version
+    pub fn new(kind: FileKind) -> Self {
+        Self {
+            kind,
+            version: kind.version(),
+            flags: 0,
+            min_reader_version: kind.min_reader_version(),
+            created_at: SystemClock.now_millis(),
+        }
+    }
+
+    pub fn encode(&self) -> [u8; HEADER_BYTES as usize] {
+        let mut bytes = [0u8; HEADER_BYTES as usize];
+        bytes[0..4].copy_from_slice(&self.kind.magic());
+        bytes[4] = self.version;
+        bytes[5] = self.flags;
+        bytes[6] = self.min_reader_version;
+        bytes[8..16].copy_from_slice(&self.created_at.to_le_bytes());
+
+        bytes
+    }
+
+    /// Checks that `bytes` starts with the header of a supported file of `kind`
+    pub fn decode(kind: FileKind, bytes: &[u8]) -> Result<Self, Error> {
+        let header = bytes
+            .get(..HEADER_BYTES as usize)
+            .filter(|header| header[0..4] == kind.magic())
+            .ok_or(SerializationError::InvalidFileHeader)?;
+
+        let version = header[4];
+        let min_reader_version = header[6];
+        let readable_from = if min_reader_version == 0 {
+            version
+        } else {
+            min_reader_version
+        };
+        if version < kind.oldest_version() || readable_from > kind.version() {
+            return Err(Error::UnsupportedVersion {
+                found: version,
+                supported: kind.version(),
+            });
+        }
+
+        Ok(Self {
+            kind,
+            version,
+            flags: header[5],
+            min_reader_version,
+            created_at: u64::from_le_bytes(header[8..16].try_into().expect("8 bytes")),
+        })
+    }
+
+    pub fn record_format(&self) -> RecordFormat {
+        self.kind.record_format(self.version)
+    }
+
+    /// Writes a new header of `kind` at the start of `file`
+    pub fn write_new(kind: FileKind, file: &Handle) -> Result<(), Error> {
+        file.write_all_at(&Self::new(kind).encode(), 0)?;
+
+        Ok(())
+    }
+}
+
+/// Whether `bytes` starts with the magic bytes of `kind`, whatever the version
+pub fn has_magic(kind: FileKind, bytes: &[u8]) -> bool {
+    bytes.starts_with(&kind.magic())
+}
+
+/// The part of `content` after its header, once the header is validated, with the format of its records
+pub fn strip_header(kind: FileKind, content: &[u8]) -> Result<(&[u8], RecordFormat), Error> {
+    let header = FileHeader::decode(kind, content)?;
+
+    Ok((&content[HEADER_BYTES as usize..], header.record_format()))
+}
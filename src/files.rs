@@ -1,14 +1,104 @@
-use std::{fs::File, path::PathBuf};
+use std::{fs::File, io, path::PathBuf};
 
-use crate::cleanup::CleanableFile;
+use crate::{cleanup::CleanableFile, serialization::RecordFormat, storage::Handle};
 
 pub struct FileWithPath {
-    pub file: File,
+    pub file: Handle,
     pub path: PathBuf,
+    /// Of the records written to the file, from the version in its header
+    pub record_format: RecordFormat,
 }
 
 impl CleanableFile for FileWithPath {
     fn path(&self) -> PathBuf {
         self.path.clone()
     }
+
+    fn size(&self) -> u64 {
+        self.file.size().unwrap_or(0)
+    }
+}
+
+/// Reads and writes at an offset, so that a file can be shared by threads without a cursor.
+///
+/// `pread`/`pwrite` on Unix. On Windows the cursor moves, nothing in the crate relies on it. Elsewhere the cursor is
+/// moved to the offset first, under a lock shared by every file of the process.
+pub trait PositionedFile {
+    fn read_exact_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<()>;
+    fn write_all_at(&self, buffer: &[u8], offset: u64) -> io::Result<()>;
+}
+
+#[cfg(unix)]
+impl PositionedFile for File {
+    fn read_exact_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<()> {
+        std::os::unix::fs::FileExt::read_exact_at(self, buffer, offset)
+    }
+
+    fn write_all_at(&self, buffer: &[u8], offset: u64) -> io::Result<()> {
+        std::os::unix::fs::FileExt::write_all_at(self, buffer, offset)
+    }
+}
+
+#[cfg(windows)]
+impl PositionedFile for File {
+    fn read_exact_at(&self, mut buffer: &mut [u8], mut offset: u64) -> io::Result<()> {
+        use std::os::windows::fs::FileExt;
+
+        while !buffer.is_empty() {
+            match self.seek_read(buffer, offset) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(read) => {
+                    buffer = &mut buffer[read..];
+                    offset += read as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_all_at(&self, mut buffer: &[u8], mut offset: u64) -> io::Result<()> {
+        use std::os::windows::fs::FileExt;
+
+        while !buffer.is_empty() {
+            match self.seek_write(buffer, offset) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(written) => {
+                    buffer = &buffer[written..];
+                    offset += written as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Serializes the seeks and the reads or writes after them, see [`PositionedFile`]
+#[cfg(not(any(unix, windows)))]
+static SEEK_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+#[cfg(not(any(unix, windows)))]
+impl PositionedFile for File {
+    fn read_exact_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<()> {
+        use std::io::{Read, Seek, SeekFrom};
+
+        let _seek_guard = SEEK_LOCK.lock().expect("poisoned seek lock");
+        let mut file = self;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(buffer)
+    }
+
+    fn write_all_at(&self, buffer: &[u8], offset: u64) -> io::Result<()> {
+        use std::io::{Seek, SeekFrom, Write};
+
+        let _seek_guard = SEEK_LOCK.lock().expect("poisoned seek lock");
+        let mut file = self;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(buffer)
+    }
 }
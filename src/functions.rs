@@ -1,47 +1,76 @@
 use super::Value;
-use crate::errors::Error;
-use std::{
-    fs::{File, OpenOptions},
-    os::unix::fs::FileExt,
-    path::Path,
-};
-
-pub enum FindResult {
+#[cfg(test)]
+use crate::failpoints;
+use crate::{errors::Error, files::PositionedFile, storage::Handle};
+
+/// Telling deleted keys from missing ones, see [`crate::KVStorage::read_detailed`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadOutcome {
     Found(Value),
-    Tombstone,
-    None,
+    /// The newest entry of the key is a deletion, or expired
+    Deleted,
+    /// No entry of the key is stored. Also the case of keys deleted long ago, once compaction dropped the tombstone
+    NotFound,
+}
+
+/// Answer of [`crate::KVStorage::read_detailed`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadDetails {
+    pub outcome: ReadOutcome,
+    /// Milliseconds since the UNIX epoch when the entry answering the read was written, also for deletions. Only
+    /// stored with [`crate::Options::write_timestamps`], `None` for missing keys
+    pub written_at: Option<u64>,
+}
+
+impl ReadOutcome {
+    /// The value, `None` for deleted and missing keys alike
+    pub fn value(self) -> Option<Value> {
+        match self {
+            ReadOutcome::Found(value) => Some(value),
+            ReadOutcome::Deleted | ReadOutcome::NotFound => None,
+        }
+    }
 }
 
-pub fn create_file(path: &Path, file_size_bytes: u64) -> Result<File, Error> {
-    let file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(path)?;
-    file.set_len(file_size_bytes)?;
+pub fn write_data_at_offset(file: &Handle, data: &[u8], offset: u64) -> Result<(), Error> {
+    #[cfg(test)]
+    file.hit_failpoint(failpoints::WRITE_DATA_AT_OFFSET)?;
+    file.write_all_at(data, offset)?;
 
-    Ok(file)
+    Ok(())
 }
 
-pub fn write_data_at_offset(file: &File, data: &[u8], offset: u64) -> Result<(), Error> {
-    file.write_at(data, offset)?;
+/// Overwrites the first `len` bytes of `file` with zeros and syncs them
+pub fn zero_file_prefix(file: &Handle, len: u64) -> Result<(), Error> {
+    file.write_all_at(&vec![0u8; len as usize], 0)?;
+    file.sync_data()?;
 
     Ok(())
 }
 
-pub fn read_file(file: &File, file_size: u64) -> Result<Vec<u8>, Error> {
+pub fn read_file(file: &Handle, file_size: u64) -> Result<Vec<u8>, Error> {
     let mut buffer = vec![0u8; file_size as usize];
-    file.read_exact_at(&mut buffer, 0)?;
+    read_data_at_offset(file, &mut buffer, 0)?;
 
     Ok(buffer)
 }
 
-pub fn write_file(file: &File, buffer: &[u8], max_size: u64) -> Result<(), Error> {
+/// Fills `buffer` with the bytes of `file` starting at `offset`
+pub fn read_data_at_offset(file: &Handle, buffer: &mut [u8], offset: u64) -> Result<(), Error> {
+    #[cfg(test)]
+    file.hit_failpoint(failpoints::READ_FILE)?;
+    file.read_exact_at(buffer, offset)?;
+
+    Ok(())
+}
+
+pub fn write_file(file: &Handle, buffer: &[u8], max_size: u64) -> Result<(), Error> {
     if buffer.len() > max_size as usize {
         return Err(Error::FileDirectoryCreation);
     }
 
+    #[cfg(test)]
+    file.hit_failpoint(failpoints::WRITE_FILE)?;
     file.write_all_at(buffer, 0)?;
 
     Ok(())
@@ -0,0 +1,166 @@
+use crate::{
+    Error,
+    clock::Clock,
+    events::{CompactionInfo, EventListener, FlushInfo},
+    options::Options,
+};
+use std::{
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+/// Time of the flushes and merges that didn't happen yet
+const NEVER: u64 = u64::MAX;
+
+/// Summary of the store state for monitoring, returned by [`crate::KVStorage::health`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Health {
+    /// Error of the last failed merge, or of the table writes that made the store refuse writes. Cleared once a
+    /// flush or a merge completes
+    pub background_error: Option<String>,
+    /// Writes are refused with [`Error::StorageUnavailable`] until tables can be written again
+    pub writes_refused: bool,
+    /// A [`crate::Durability::Synced`] write failed to sync, later writes may not survive a crash
+    pub sync_failed: bool,
+    /// Tables the compaction policy would merge and that aren't merged yet
+    pub compaction_backlog: u64,
+    /// See [`crate::Stats::reclaimable_entries`]
+    pub reclaimable_entries: u64,
+    /// See [`crate::Stats::disk_bytes_used`]
+    pub disk_bytes_used: u64,
+    /// [`Options::max_db_size_bytes`], if set
+    pub disk_quota_bytes: Option<u64>,
+    /// According to [`Options::clock`], `None` before the first flush since the store was opened
+    pub since_last_flush: Option<Duration>,
+    /// According to [`Options::clock`], `None` before the first merge since the store was opened
+    pub since_last_compaction: Option<Duration>,
+}
+
+impl Health {
+    /// No background error, writes accepted and synced, and disk usage below the quota
+    pub fn is_healthy(&self) -> bool {
+        self.background_error.is_none()
+            && !self.writes_refused
+            && !self.sync_failed
+            && self
+                .disk_quota_bytes
+                .is_none_or(|quota| self.disk_bytes_used < quota)
+    }
+}
+
+/// Stands in for the [`Options::event_listener`] of the background work, remembering when flushes and merges last
+/// completed and the last error before passing the events on
+pub struct HealthTracker {
+    listener: Option<Arc<dyn EventListener>>,
+    clock: Arc<dyn Clock>,
+    /// In milliseconds, [`NEVER`] until the first one
+    last_flush: AtomicU64,
+    last_compaction: AtomicU64,
+    background_error: Mutex<Option<String>>,
+}
+
+impl HealthTracker {
+    /// Returns `options` with the tracker as their listener, along with the tracker
+    pub fn install(mut options: Options) -> (Options, Arc<Self>) {
+        let tracker = Arc::new(Self {
+            listener: options.event_listener.take(),
+            clock: options.clock.clone(),
+            last_flush: AtomicU64::new(NEVER),
+            last_compaction: AtomicU64::new(NEVER),
+            background_error: Default::default(),
+        });
+        options.event_listener = Some(tracker.clone());
+
+        (options, tracker)
+    }
+
+    /// `options` with the listener given before [`HealthTracker::install`] back, for the stores tracked apart
+    pub fn untracked(&self, options: Options) -> Options {
+        Options {
+            event_listener: self.listener.clone(),
+            ..options
+        }
+    }
+
+    pub fn background_error(&self) -> Option<String> {
+        self.background_error
+            .lock()
+            .expect("poisoned background error")
+            .clone()
+    }
+
+    pub fn since_last_flush(&self) -> Option<Duration> {
+        self.since(&self.last_flush)
+    }
+
+    pub fn since_last_compaction(&self) -> Option<Duration> {
+        self.since(&self.last_compaction)
+    }
+
+    fn since(&self, millis: &AtomicU64) -> Option<Duration> {
+        let millis = millis.load(Ordering::Relaxed);
+        (millis != NEVER)
+            .then(|| Duration::from_millis(self.clock.now_millis().saturating_sub(millis)))
+    }
+
+    fn completed(&self, millis: &AtomicU64) {
+        millis.store(self.clock.now_millis(), Ordering::Relaxed);
+        *self
+            .background_error
+            .lock()
+            .expect("poisoned background error") = None;
+    }
+
+    fn failed(&self, error: &Error) {
+        *self
+            .background_error
+            .lock()
+            .expect("poisoned background error") = Some(format!("{error:?}"));
+    }
+}
+
+impl EventListener for HealthTracker {
+    fn on_flush_begin(&self, info: &FlushInfo) {
+        if let Some(listener) = &self.listener {
+            listener.on_flush_begin(info);
+        }
+    }
+
+    fn on_flush_complete(&self, info: &FlushInfo) {
+        self.completed(&self.last_flush);
+        if let Some(listener) = &self.listener {
+            listener.on_flush_complete(info);
+        }
+    }
+
+    fn on_compaction_begin(&self, info: &CompactionInfo) {
+        if let Some(listener) = &self.listener {
+            listener.on_compaction_begin(info);
+        }
+    }
+
+    fn on_compaction_complete(&self, info: &CompactionInfo) {
+        self.completed(&self.last_compaction);
+        if let Some(listener) = &self.listener {
+            listener.on_compaction_complete(info);
+        }
+    }
+
+    fn on_compaction_failed(&self, info: &CompactionInfo, error: &Error) {
+        self.failed(error);
+        if let Some(listener) = &self.listener {
+            listener.on_compaction_failed(info, error);
+        }
+    }
+
+    fn on_background_error(&self, error: &Error) {
+        self.failed(error);
+        if let Some(listener) = &self.listener {
+            listener.on_background_error(error);
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use crate::Key;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// Bits of the hash picking the register, the standard error is about `1.04 / sqrt(2^PRECISION)`, 1.6%
+const PRECISION: u32 = 12;
+const REGISTERS: usize = 1 << PRECISION;
+
+/// HyperLogLog sketch counting the distinct keys written to the store, see [`crate::Stats::estimated_distinct_keys`].
+///
+/// Keys are added without a lock, and adding one again changes nothing: the sketch persisted in the manifest is
+/// completed on open with every key replayed from the logs, whether or not it was counted already.
+pub struct HyperLogLog {
+    /// Longest run of leading zeros (plus one) seen among the hashes of each register
+    registers: Box<[AtomicU8]>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        Self {
+            registers: (0..REGISTERS).map(|_| AtomicU8::new(0)).collect(),
+        }
+    }
+}
+
+impl HyperLogLog {
+    pub fn add(&self, key: &Key) {
+        let hash = mix(*key);
+        let register = (hash >> (64 - PRECISION)) as usize;
+        // The bit set past the others bounds the run when the remaining bits are all zeros
+        let rank = ((hash << PRECISION) | (1 << (PRECISION - 1))).leading_zeros() as u8 + 1;
+
+        self.registers[register].fetch_max(rank, Ordering::Relaxed);
+    }
+
+    /// Estimated number of distinct keys added
+    pub fn estimate(&self) -> u64 {
+        let registers = REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / registers);
+
+        let mut sum = 0.0;
+        let mut zeros = 0;
+        for register in &self.registers {
+            let rank = register.load(Ordering::Relaxed);
+            sum += 2f64.powi(-(rank as i32));
+            if rank == 0 {
+                zeros += 1;
+            }
+        }
+        let estimate = alpha * registers * registers / sum;
+
+        // Few keys: counting the empty registers is more accurate
+        if estimate <= 2.5 * registers && zeros > 0 {
+            return (registers * (registers / zeros as f64).ln()).round() as u64;
+        }
+
+        estimate.round() as u64
+    }
+
+    /// The registers, read back by [`HyperLogLog::from_bytes`]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.registers
+            .iter()
+            .map(|register| register.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    /// `None` unless `bytes` come from [`HyperLogLog::to_bytes`]
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        (bytes.len() == REGISTERS).then(|| Self {
+            registers: bytes.iter().map(|rank| AtomicU8::new(*rank)).collect(),
+        })
+    }
+}
+
+/// Spreads the bits of `key` over the whole hash. Fixed rather than `DefaultHasher`: the sketch is persisted
+fn mix(key: Key) -> u64 {
+    let mut hash = key.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+
+    hash ^ (hash >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(estimate: u64, expected: u64) {
+        let error = (estimate as f64 - expected as f64).abs() / expected as f64;
+        assert!(error < 0.05, "estimated {estimate} for {expected}");
+    }
+
+    #[test]
+    fn test_estimate() {
+        let sketch = HyperLogLog::default();
+        assert_eq!(sketch.estimate(), 0);
+
+        for key in 0..100 {
+            sketch.add(&key);
+        }
+        assert_close(sketch.estimate(), 100);
+
+        // Added again, nothing changes
+        for key in 0..100 {
+            sketch.add(&key);
+        }
+        assert_close(sketch.estimate(), 100);
+
+        for key in 0..1_000_000u64 {
+            sketch.add(&key.wrapping_mul(7919));
+        }
+        assert_close(sketch.estimate(), 1_000_000);
+    }
+
+    #[test]
+    fn test_bytes() {
+        let sketch = HyperLogLog::default();
+        for key in 0..60_000 {
+            sketch.add(&key);
+        }
+
+        let loaded = HyperLogLog::from_bytes(&sketch.to_bytes()).unwrap();
+        assert_eq!(loaded.estimate(), sketch.estimate());
+        for key in 40_000..100_000 {
+            loaded.add(&key);
+        }
+        assert_close(loaded.estimate(), 100_000);
+
+        assert!(HyperLogLog::from_bytes(&[0; 16]).is_none());
+    }
+}
@@ -0,0 +1,90 @@
+use crate::cleanup;
+use crate::errors::Error;
+use crate::files::PositionedFile;
+use crate::functions;
+use crate::manifest::MANIFEST_NAME;
+use crate::storage::Storage;
+use std::path::Path;
+
+pub const IDENTITY_NAME: &str = "IDENTITY";
+pub const IDENTITY_TMP_NAME: &str = "IDENTITY.tmp";
+/// First word of the identity file, telling the `db/` directories of this store from others
+const MAGIC: &str = "key-value-store";
+/// Bumped when the directory layout changes in a way older versions can't open
+pub const LAYOUT_VERSION: u32 = 1;
+
+/// What a `db/` directory holds, see [`inspect`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DbState {
+    /// No directory, or an empty one
+    Absent,
+    /// A database, `identified` unless it was created before the identity files
+    Store { identified: bool },
+}
+
+/// Looks at `db_dir` before opening or creating a database in it.
+///
+/// Fails with [`Error::NotADatabase`] on a directory holding anything else, [`Error::LayoutTooNew`] on the
+/// database of a newer version and [`Error::MissingIdentity`] on database files without an identity nor a manifest.
+pub fn inspect(storage: &dyn Storage, db_dir: &Path) -> Result<DbState, Error> {
+    if !storage.is_dir(db_dir) {
+        return Ok(DbState::Absent);
+    }
+
+    let files = storage.list(db_dir)?;
+    let has_file = |name: &str| {
+        files
+            .iter()
+            .any(|file| file.path.file_name().is_some_and(|found| found == name))
+    };
+    if has_file(IDENTITY_NAME) {
+        check(storage, db_dir)?;
+        return Ok(DbState::Store { identified: true });
+    }
+
+    let has_sstables = storage.is_dir(&db_dir.join("sstables"));
+    if files.is_empty() && !has_sstables {
+        return Ok(DbState::Absent);
+    }
+    if has_file(MANIFEST_NAME) {
+        return Ok(DbState::Store { identified: false });
+    }
+    if has_sstables || files.iter().any(cleanup::is_database_file) {
+        return Err(Error::MissingIdentity);
+    }
+
+    Err(Error::NotADatabase)
+}
+
+/// Writes the identity file of a new database, or of one created before them
+pub fn write(storage: &dyn Storage, db_dir: &Path) -> Result<(), Error> {
+    let tmp_path = db_dir.join(IDENTITY_TMP_NAME);
+
+    let file = storage.create(&tmp_path, 0)?;
+    file.write_all_at(format!("{MAGIC} {LAYOUT_VERSION}\n").as_bytes(), 0)?;
+    file.sync_all()?;
+
+    storage.rename(&tmp_path, &db_dir.join(IDENTITY_NAME))?;
+    storage.sync_dir(db_dir)?;
+
+    Ok(())
+}
+
+fn check(storage: &dyn Storage, db_dir: &Path) -> Result<(), Error> {
+    let file = storage.open(&db_dir.join(IDENTITY_NAME), false)?;
+    let content = functions::read_file(&file, file.size()?)?;
+
+    let version = std::str::from_utf8(&content)
+        .ok()
+        .and_then(|content| content.trim_end().strip_prefix(MAGIC)?.strip_prefix(' '))
+        .and_then(|version| version.parse::<u32>().ok())
+        .ok_or(Error::NotADatabase)?;
+    if version > LAYOUT_VERSION {
+        return Err(Error::LayoutTooNew {
+            found: version,
+            supported: LAYOUT_VERSION,
+        });
+    }
+
+    Ok(())
+}
@@ -0,0 +1,125 @@
+use crate::{
+    append_log,
+    changes::Change,
+    comparator::{self, Ascending, KeyComparator, KeyOrder},
+    debug::{LogDump, TableDump},
+    errors::Error,
+    manifest::{Manifest, ManifestData},
+    sstables::{SSTable, TableFiles},
+    storage::{DiskStorage, Storage},
+    verify::{Anomaly, TableReport, VerifyReport},
+};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// Open files kept by an inspector, tables are opened one at a time
+const INSPECTOR_OPEN_TABLES: usize = 4;
+
+/// Reads a database directory without opening the store, e.g. while another process has it open.
+///
+/// Files are only ever opened read-only. The tables and logs are the ones listed in the manifest when the inspector
+/// was created: a compaction or rotation by the owner of the store can remove them later.
+///
+/// Only databases ordered by a built-in [`KeyComparator`] can be inspected, the others fail with
+/// [`Error::ComparatorMismatch`].
+pub struct Inspector {
+    db_dir: PathBuf,
+    manifest: ManifestData,
+    table_files: Arc<TableFiles>,
+}
+
+impl Inspector {
+    pub fn open(location: &str) -> Result<Self, Error> {
+        let storage: Arc<dyn Storage> = Arc::new(DiskStorage);
+        let db_dir = Path::new(location).join("db");
+        if !storage.is_dir(&db_dir) {
+            return Err(Error::InvalidDbLocation);
+        }
+
+        let manifest = Manifest::load(&storage, &db_dir)?.data();
+        let comparator = match &manifest.comparator {
+            None => Arc::new(Ascending),
+            Some(name) => comparator::builtin(name).ok_or_else(|| Error::ComparatorMismatch {
+                stored: name.clone(),
+                configured: Ascending.name().to_owned(),
+            })?,
+        };
+        let table_files = Arc::new(
+            TableFiles::new(storage, db_dir.join("sstables"), INSPECTOR_OPEN_TABLES)
+                .with_cold_dir(
+                    manifest.cold_storage_dir.as_ref().map(PathBuf::from),
+                    &manifest.cold_sstables,
+                )
+                .with_order(KeyOrder::new(comparator)),
+        );
+
+        Ok(Self {
+            db_dir,
+            manifest,
+            table_files,
+        })
+    }
+
+    /// Ids of the tables, newest first
+    pub fn table_ids(&self) -> &[u64] {
+        &self.manifest.sstables
+    }
+
+    /// Every table without its entries, newest first
+    pub fn tables(&self) -> Result<Vec<TableDump>, Error> {
+        self.manifest
+            .sstables
+            .iter()
+            .map(|id| SSTable::open(&self.table_files, *id)?.dump(false))
+            .collect()
+    }
+
+    /// A table with all its entries, which doesn't need to be listed in the manifest
+    pub fn table(&self, id: u64) -> Result<TableDump, Error> {
+        SSTable::open(&self.table_files, id)?.dump(true)
+    }
+
+    /// The log of every write shard, in shard order
+    pub fn logs(&self) -> Result<Vec<LogDump>, Error> {
+        let storage = self.table_files.storage();
+
+        self.manifest
+            .log_files
+            .iter()
+            .map(|file_name| {
+                let mut entries =
+                    append_log::read_live_log(&**storage, &self.db_dir.join(file_name))?;
+                entries.sort_by_key(|(_, entry)| entry.sequence());
+
+                Ok(LogDump {
+                    file_name: file_name.clone(),
+                    entries: entries
+                        .iter()
+                        .map(|(offset, entry)| (*offset, Change::from(entry)))
+                        .collect(),
+                })
+            })
+            .collect()
+    }
+
+    /// Same as [`crate::KVStorage::verify`], tables that can't be opened are reported as unreadable
+    pub fn verify(&self) -> VerifyReport {
+        let tables = self
+            .manifest
+            .sstables
+            .iter()
+            .map(|id| match SSTable::open(&self.table_files, *id) {
+                Ok(sstable) => sstable.verify(),
+                Err(e) => TableReport {
+                    id: *id,
+                    entry_count: 0,
+                    anomalies: vec![Anomaly::Unreadable(format!("{e:?}"))],
+                },
+            })
+            .collect();
+
+        VerifyReport { tables }
+    }
+}
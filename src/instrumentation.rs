@@ -0,0 +1,71 @@
+//! Metrics emitted through the `metrics` facade when the `metrics` feature is enabled, and spans emitted through
+//! `tracing` when the `tracing` feature is.
+//!
+//! Without the `metrics` feature the metric macros expand to nothing and their arguments are never evaluated.
+//! Without the `tracing` feature spans are logged with `log::trace!` instead.
+
+/// Adds `$value` to the counter `$name`
+macro_rules! increment_counter {
+    ($name:literal, $value:expr) => {
+        #[cfg(feature = "metrics")]
+        ::metrics::counter!($name).increment($value);
+        #[cfg(not(feature = "metrics"))]
+        let _ = || $value;
+    };
+}
+
+/// Records `$value` (an `f64`) in the histogram `$name`
+macro_rules! record_histogram {
+    ($name:literal, $value:expr) => {
+        #[cfg(feature = "metrics")]
+        ::metrics::histogram!($name).record($value);
+        #[cfg(not(feature = "metrics"))]
+        let _ = || $value;
+    };
+}
+
+/// Enters the span `$name`, returning its guard. Fields are recorded with their `Debug` representation, the ones after
+/// `;` are left empty to be set later with [`record!`]
+macro_rules! span {
+    ($name:literal $(, $field:ident = $value:expr)* $(; $($empty:ident),+)?) => {{
+        #[cfg(feature = "tracing")]
+        let span = ::tracing::trace_span!(
+            $name
+            $(, $field = ?$value)*
+            $($(, $empty = ::tracing::field::Empty)+)?
+        )
+        .entered();
+        #[cfg(not(feature = "tracing"))]
+        let span = {
+            ::log::trace!(concat!($name $(, " ", stringify!($field), "={:?}")*) $(, $value)*);
+            $crate::instrumentation::NoSpan($name)
+        };
+        span
+    }};
+}
+
+/// Sets the empty field `$field` of a span entered with [`span!`]
+macro_rules! record {
+    ($span:expr, $field:ident = $value:expr) => {
+        #[cfg(feature = "tracing")]
+        $span.record(stringify!($field), ::tracing::field::debug(&$value));
+        #[cfg(not(feature = "tracing"))]
+        ::log::trace!(concat!("{} ", stringify!($field), "={:?}"), $span.0, $value);
+    };
+}
+
+/// What [`span!`] returns without the `tracing` feature, the name of the span. Logs its end when dropped
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct NoSpan(pub(crate) &'static str);
+
+#[cfg(not(feature = "tracing"))]
+impl Drop for NoSpan {
+    fn drop(&mut self) {
+        log::trace!("{} done", self.0);
+    }
+}
+
+pub(crate) use increment_counter;
+pub(crate) use record;
+pub(crate) use record_histogram;
+pub(crate) use span;
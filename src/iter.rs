@@ -0,0 +1,310 @@
+use crate::{
+    Key, Value,
+    comparator::KeyOrder,
+    errors::Error,
+    functions::ReadOutcome,
+    serialization::{self, KVMemoryRepr},
+    sstables::SSTable,
+};
+use std::{collections::BTreeMap, ops::Bound, sync::Arc};
+
+/// Ordered cursor over the live entries of a [`crate::Snapshot`], in the order of [`crate::Options::key_comparator`].
+///
+/// The cursor sits between two keys: [`KvIter::next`] returns the entry after it and [`KvIter::prev`] the one before,
+/// moving past the returned entry. Tombstones and expired entries are skipped.
+///
+/// Reading a table can fail, in which case the iteration stops and the error is kept, see [`KvIter::error`].
+pub struct KvIter {
+    /// Sorted by key
+    memtable: Vec<KVMemoryRepr>,
+    /// Newer at the beginning
+    tables: Vec<TableCursor>,
+    /// Range tombstones of the memtable and of all the tables
+    ranges: Vec<KVMemoryRepr>,
+    now: u64,
+    position: Position,
+    order: KeyOrder,
+    error: Option<Error>,
+}
+
+/// Where the cursor of a [`KvIter`] sits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Position {
+    /// Before the first possible key
+    Start,
+    Before(Key),
+    After(Key),
+    /// After the last possible key
+    End,
+}
+
+impl KvIter {
+    pub(crate) fn new(
+        memtable: BTreeMap<Key, KVMemoryRepr>,
+        mut ranges: Vec<KVMemoryRepr>,
+        sstables: Vec<Arc<SSTable>>,
+        now: u64,
+        order: KeyOrder,
+    ) -> Self {
+        for table in &sstables {
+            ranges.extend_from_slice(table.range_tombstones());
+        }
+        let mut memtable: Vec<_> = memtable.into_values().collect();
+        memtable.sort_by(|a, b| order.cmp(a.key(), b.key()));
+
+        Self {
+            memtable,
+            tables: sstables.into_iter().map(TableCursor::new).collect(),
+            ranges,
+            now,
+            position: Position::Start,
+            order,
+            error: None,
+        }
+    }
+
+    /// Moves the cursor right before `key`, the next call to [`KvIter::next`] returns the first live key `>= key`
+    pub fn seek(&mut self, key: Key) {
+        self.position = Position::Before(key);
+    }
+
+    /// Moves the cursor right before the first key within `start`
+    pub(crate) fn seek_bound(&mut self, start: Bound<Key>) {
+        self.position = match start {
+            Bound::Included(key) => Position::Before(key),
+            Bound::Excluded(key) => Position::After(key),
+            Bound::Unbounded => Position::Start,
+        };
+    }
+
+    /// Moves the cursor after the last key, for reverse traversal with [`KvIter::prev`]
+    pub fn seek_to_end(&mut self) {
+        self.position = Position::End;
+    }
+
+    /// Returns the first live entry before the cursor
+    pub fn prev(&mut self) -> Option<(Key, Value)> {
+        loop {
+            let upper = match self.position {
+                Position::Start => return None,
+                Position::Before(key) => Bound::Excluded(key),
+                Position::After(key) => Bound::Included(key),
+                Position::End => Bound::Unbounded,
+            };
+            if self.error.is_some() {
+                return None;
+            }
+
+            let Some(entry) = self.keep_error(|iter| iter.find_backward(upper))? else {
+                self.position = Position::Start;
+                return None;
+            };
+
+            self.position = Position::Before(*entry.key());
+            if let Some(value) = self.live_value(&entry) {
+                return Some((*entry.key(), value));
+            }
+        }
+    }
+
+    /// The error that stopped the iteration, if any
+    pub fn error(&self) -> Option<&Error> {
+        self.error.as_ref()
+    }
+
+    pub fn into_error(self) -> Option<Error> {
+        self.error
+    }
+
+    /// Value of `entry`, unless it's deleted, expired or covered by a newer range tombstone
+    fn live_value(&self, entry: &KVMemoryRepr) -> Option<Value> {
+        let range = serialization::newest_covering(&self.ranges, entry.key(), &self.order);
+        if range.is_some_and(|range| range.sequence() > entry.sequence()) {
+            return None;
+        }
+
+        match entry.read_outcome(self.now) {
+            ReadOutcome::Found(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn keep_error<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T, Error>) -> Option<T> {
+        match f(self) {
+            Ok(result) => Some(result),
+            Err(e) => {
+                self.error = Some(e);
+                None
+            }
+        }
+    }
+
+    /// Newest entry with the smallest key above `lower`
+    fn find_forward(&mut self, lower: Bound<Key>) -> Result<Option<KVMemoryRepr>, Error> {
+        let order = &self.order;
+        let first = self
+            .memtable
+            .partition_point(|e| !order.above(lower, e.key()));
+        let mut candidates: Vec<KVMemoryRepr> =
+            self.memtable.get(first).cloned().into_iter().collect();
+        for table in &mut self.tables {
+            candidates.extend(table.first_from(lower, order)?);
+        }
+
+        let Some(key) = candidates
+            .iter()
+            .map(|e| *e.key())
+            .reduce(|a, b| order.min(a, b))
+        else {
+            return Ok(None);
+        };
+
+        Ok(newest_with_key(candidates, key))
+    }
+
+    /// Newest entry with the largest key below `upper`
+    fn find_backward(&mut self, upper: Bound<Key>) -> Result<Option<KVMemoryRepr>, Error> {
+        let order = &self.order;
+        let end = self
+            .memtable
+            .partition_point(|e| order.below(upper, e.key()));
+        let mut candidates: Vec<KVMemoryRepr> = end
+            .checked_sub(1)
+            .map(|last| self.memtable[last].clone())
+            .into_iter()
+            .collect();
+        for table in &mut self.tables {
+            candidates.extend(table.last_before(upper, order)?);
+        }
+
+        let Some(key) = candidates
+            .iter()
+            .map(|e| *e.key())
+            .reduce(|a, b| order.max(a, b))
+        else {
+            return Ok(None);
+        };
+
+        Ok(newest_with_key(candidates, key))
+    }
+}
+
+impl Iterator for KvIter {
+    type Item = (Key, Value);
+
+    /// Returns the first live entry after the cursor
+    fn next(&mut self) -> Option<(Key, Value)> {
+        loop {
+            let lower = match self.position {
+                Position::Start => Bound::Unbounded,
+                Position::Before(key) => Bound::Included(key),
+                Position::After(key) => Bound::Excluded(key),
+                Position::End => return None,
+            };
+            if self.error.is_some() {
+                return None;
+            }
+
+            let Some(entry) = self.keep_error(|iter| iter.find_forward(lower))? else {
+                self.position = Position::End;
+                return None;
+            };
+
+            self.position = Position::After(*entry.key());
+            if let Some(value) = self.live_value(&entry) {
+                return Some((*entry.key(), value));
+            }
+        }
+    }
+}
+
+fn newest_with_key(candidates: Vec<KVMemoryRepr>, key: Key) -> Option<KVMemoryRepr> {
+    candidates
+        .into_iter()
+        .filter(|e| *e.key() == key)
+        .max_by_key(|e| e.sequence())
+}
+
+/// Position inside a table, only the block (range between two index entries) in use is kept decoded
+struct TableCursor {
+    table: Arc<SSTable>,
+    block: Option<(usize, Vec<KVMemoryRepr>)>,
+}
+
+impl TableCursor {
+    fn new(table: Arc<SSTable>) -> Self {
+        Self { table, block: None }
+    }
+
+    fn load(&mut self, block: usize) -> Result<&[KVMemoryRepr], Error> {
+        if self
+            .block
+            .as_ref()
+            .is_none_or(|(loaded, _)| *loaded != block)
+        {
+            self.block = Some((block, self.table.read_block(block)?));
+        }
+
+        Ok(&self.block.as_ref().expect("just loaded").1)
+    }
+
+    fn first_from(
+        &mut self,
+        lower: Bound<Key>,
+        order: &KeyOrder,
+    ) -> Result<Option<KVMemoryRepr>, Error> {
+        let Some((_, max)) = self.table.key_range() else {
+            return Ok(None);
+        };
+        if !order.above(lower, &max) {
+            return Ok(None);
+        }
+
+        let mut block = match lower {
+            Bound::Included(key) | Bound::Excluded(key) => self.table.block_of(&key),
+            Bound::Unbounded => 0,
+        };
+        loop {
+            let entries = self.load(block)?;
+            let i = entries.partition_point(|e| !order.above(lower, e.key()));
+            if let Some(entry) = entries.get(i) {
+                return Ok(Some(entry.clone()));
+            }
+
+            block += 1;
+            if block >= self.table.block_count() {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn last_before(
+        &mut self,
+        upper: Bound<Key>,
+        order: &KeyOrder,
+    ) -> Result<Option<KVMemoryRepr>, Error> {
+        let Some((min, _)) = self.table.key_range() else {
+            return Ok(None);
+        };
+        if !order.below(upper, &min) {
+            return Ok(None);
+        }
+
+        let mut block = match upper {
+            Bound::Included(key) | Bound::Excluded(key) => self.table.block_of(&key),
+            Bound::Unbounded => self.table.block_count() - 1,
+        };
+        loop {
+            let entries = self.load(block)?;
+            let i = entries.partition_point(|e| order.below(upper, e.key()));
+            if i > 0 {
+                return Ok(Some(entries[i - 1].clone()));
+            }
+
+            if block == 0 {
+                return Ok(None);
+            }
+            block -= 1;
+        }
+    }
+}
@@ -0,0 +1,266 @@
+//! Order-preserving encoding of composite keys, for byte keys.
+//!
+//! Keys built with [`KeyBuilder`] compare (as bytes) in the order of their components, compared one after the other:
+//! integers are big-endian (signed ones with their sign bit flipped), strings and byte strings have their zeros
+//! escaped and end with a terminator sorting below any content. [`KeyParser`] reads the components back.
+//!
+//! Every key starting with some components falls in `prefix..prefix_successor(prefix)`, the bounds of a scan:
+//!
+//! ```
+//! use key_value_store::keys::{KeyBuilder, KeyParser, prefix_successor};
+//!
+//! let key = |tenant: u32, at: u64, id: &str| KeyBuilder::new().u32(tenant).u64(at).str(id).build();
+//! assert!(key(1, 100, "b") < key(1, 200, "a"));
+//! assert!(key(1, 100, "a") < key(1, 100, "a\0"));
+//!
+//! // Everything of tenant 1
+//! let start = KeyBuilder::new().u32(1).build();
+//! let end = prefix_successor(&start).unwrap();
+//! assert!((start.clone()..end.clone()).contains(&key(1, u64::MAX, "z")));
+//! assert!(!(start..end).contains(&key(2, 0, "")));
+//!
+//! let encoded = key(7, 42, "order-9");
+//! let mut parser = KeyParser::new(&encoded);
+//! assert_eq!(parser.u32().unwrap(), 7);
+//! assert_eq!(parser.u64().unwrap(), 42);
+//! assert_eq!(parser.string().unwrap(), "order-9");
+//! parser.finish().unwrap();
+//! ```
+
+use crate::errors::Error;
+
+/// Escapes a zero byte of a string, sorting above any zero ending it
+const ESCAPED_ZERO: [u8; 2] = [0x00, 0xFF];
+/// Ends strings, below any content so that a string sorts before the longer ones it starts
+const TERMINATOR: [u8; 2] = [0x00, 0x01];
+
+/// Appends the components of a key, see the [module](self) documentation
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyBuilder {
+    bytes: Vec<u8>,
+}
+
+impl KeyBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn u16(mut self, value: u16) -> Self {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub fn u32(mut self, value: u32) -> Self {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub fn u64(mut self, value: u64) -> Self {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    /// Negative values first
+    pub fn i64(self, value: i64) -> Self {
+        self.u64(value as u64 ^ (1 << 63))
+    }
+
+    pub fn str(self, value: &str) -> Self {
+        self.bytes(value.as_bytes())
+    }
+
+    /// Zeros included, shorter byte strings sort before the longer ones they start
+    pub fn bytes(mut self, value: &[u8]) -> Self {
+        for &byte in value {
+            match byte {
+                0 => self.bytes.extend_from_slice(&ESCAPED_ZERO),
+                byte => self.bytes.push(byte),
+            }
+        }
+        self.bytes.extend_from_slice(&TERMINATOR);
+        self
+    }
+
+    pub fn build(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+/// Reads back the components of a key built with [`KeyBuilder`], in the same order.
+///
+/// Fails with [`Error::KeyDecode`] when the key ends before a component, or a string isn't terminated
+#[derive(Debug, Clone)]
+pub struct KeyParser<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> KeyParser<'a> {
+    pub fn new(key: &'a [u8]) -> Self {
+        KeyParser { bytes: key }
+    }
+
+    pub fn u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_be_bytes(self.take()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_be_bytes(self.take()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_be_bytes(self.take()?))
+    }
+
+    pub fn i64(&mut self) -> Result<i64, Error> {
+        Ok((self.u64()? ^ (1 << 63)) as i64)
+    }
+
+    /// Also fails when the string isn't UTF-8
+    pub fn string(&mut self) -> Result<String, Error> {
+        String::from_utf8(self.bytes()?).map_err(|_| Error::KeyDecode)
+    }
+
+    pub fn bytes(&mut self) -> Result<Vec<u8>, Error> {
+        let mut value = Vec::new();
+        let mut rest = self.bytes;
+        loop {
+            match rest {
+                [0x00, 0x01, after @ ..] => {
+                    self.bytes = after;
+                    return Ok(value);
+                }
+                [0x00, 0xFF, after @ ..] => {
+                    value.push(0);
+                    rest = after;
+                }
+                [0x00, ..] | [] => return Err(Error::KeyDecode),
+                [byte, after @ ..] => {
+                    value.push(*byte);
+                    rest = after;
+                }
+            }
+        }
+    }
+
+    /// What's left after the components read
+    pub fn remaining(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    /// Fails unless every component was read
+    pub fn finish(self) -> Result<(), Error> {
+        match self.bytes {
+            [] => Ok(()),
+            _ => Err(Error::KeyDecode),
+        }
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let (component, rest) = self.bytes.split_first_chunk().ok_or(Error::KeyDecode)?;
+        self.bytes = rest;
+
+        Ok(*component)
+    }
+}
+
+/// The smallest key above every key starting with `prefix`, the exclusive end of a prefix scan.
+///
+/// `None` when there's none (`prefix` is empty or only made of `0xFF`): the scan goes to the end
+pub fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let last = prefix.iter().rposition(|&byte| byte != 0xFF)?;
+    let mut successor = prefix[..=last].to_vec();
+    successor[last] += 1;
+
+    Some(successor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// A composite key as a user would compare it
+    #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+    enum Component {
+        U64(u64),
+        I64(i64),
+        Bytes(Vec<u8>),
+    }
+
+    fn encode(components: &[Component]) -> Vec<u8> {
+        components
+            .iter()
+            .fold(KeyBuilder::new(), |builder, component| match component {
+                Component::U64(value) => builder.u64(*value),
+                Component::I64(value) => builder.i64(*value),
+                Component::Bytes(value) => builder.bytes(value),
+            })
+            .build()
+    }
+
+    /// Same component kinds in the same order for both keys, zeros are common in the strings
+    fn key_pairs() -> impl Strategy<Value = (Vec<Component>, Vec<Component>)> {
+        let bytes = || prop::collection::vec(prop_oneof![Just(0u8), Just(0xFF), any::<u8>()], 0..8);
+        prop::collection::vec(0..3u8, 1..4).prop_flat_map(move |kinds| {
+            let key = kinds
+                .iter()
+                .map(|kind| match kind {
+                    0 => any::<u64>().prop_map(Component::U64).boxed(),
+                    1 => any::<i64>().prop_map(Component::I64).boxed(),
+                    _ => bytes().prop_map(Component::Bytes).boxed(),
+                })
+                .collect::<Vec<_>>();
+            (key.clone(), key)
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn test_order_preserved((a, b) in key_pairs()) {
+            prop_assert_eq!(encode(&a).cmp(&encode(&b)), a.cmp(&b));
+        }
+
+        #[test]
+        fn test_round_trip((key, _) in key_pairs()) {
+            let encoded = encode(&key);
+            let mut parser = KeyParser::new(&encoded);
+            for component in &key {
+                let parsed = match component {
+                    Component::U64(_) => Component::U64(parser.u64().unwrap()),
+                    Component::I64(_) => Component::I64(parser.i64().unwrap()),
+                    Component::Bytes(_) => Component::Bytes(parser.bytes().unwrap()),
+                };
+                prop_assert_eq!(&parsed, component);
+            }
+            prop_assert!(parser.finish().is_ok());
+        }
+
+        #[test]
+        fn test_prefix_successor(
+            prefix in prop::collection::vec(any::<u8>(), 0..6),
+            suffix in prop::collection::vec(any::<u8>(), 0..6),
+        ) {
+            let key = [prefix.clone(), suffix].concat();
+            match prefix_successor(&prefix) {
+                Some(end) => prop_assert!(prefix <= key && key < end),
+                None => prop_assert!(prefix.iter().all(|&byte| byte == 0xFF)),
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid_keys() {
+        let key = KeyBuilder::new().u32(1).str("a\0b").build();
+
+        let mut parser = KeyParser::new(&key[..3]);
+        assert!(matches!(parser.u32(), Err(Error::KeyDecode)));
+
+        // Cut before the terminator
+        let mut parser = KeyParser::new(&key[..key.len() - 1]);
+        parser.u32().unwrap();
+        assert!(matches!(parser.string(), Err(Error::KeyDecode)));
+
+        let parser = KeyParser::new(&key);
+        assert!(matches!(parser.finish(), Err(Error::KeyDecode)));
+    }
+}
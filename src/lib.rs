@@ -1,30 +1,169 @@
 mod append_log;
+#[cfg(feature = "tokio")]
+mod async_storage;
+mod backup;
+mod cache;
+mod changes;
 mod cleanup;
+mod clock;
+mod compaction_filter;
+mod comparator;
+mod debug;
+mod env;
 mod errors;
+mod events;
+#[cfg(test)]
+mod failpoints;
+mod file_header;
 mod files;
 mod functions;
+mod health;
+mod hll;
+mod identity;
+mod inspect;
+mod instrumentation;
+mod iter;
+pub mod keys;
+mod manifest;
+mod namespace;
+mod negative_cache;
+mod options;
+mod quota;
+mod rate_limit;
+mod repair;
 mod serialization;
+mod snapshot;
+mod space;
+pub mod sstable;
 mod sstables;
+mod stats;
+mod storage;
+#[cfg(all(feature = "uring", target_os = "linux"))]
+mod uring;
+mod verify;
+mod watch;
+mod workers;
 
-use crate::append_log::AppendLog;
-use crate::errors::Error;
-use crate::functions::FindResult;
-use crate::sstables::SSTable;
+use crate::append_log::ShardedAppendLog;
+use crate::backup::ExportReader;
+use crate::cache::ReadCache;
+use crate::changes::ChangeHub;
+use crate::comparator::KeyOrder;
+use crate::health::HealthTracker;
+use crate::identity::DbState;
+use crate::instrumentation::{increment_counter, record_histogram};
+use crate::manifest::{Manifest, ManifestData};
+use crate::negative_cache::NegativeCache;
+use crate::quota::DiskQuota;
+use crate::serialization::{KVMemoryRepr, RecordFormat};
+use crate::sstables::policy::{self, TableStats};
+use crate::sstables::{KeyLookup, SSTable, TableFiles, TableList, TableOptions, TableView};
+use crate::stats::StatsCounters;
+use crate::storage::{DiskStorage, MemStorage, Storage};
+use crate::workers::Workers;
 use sstables::compactor::CompactorManager;
-use std::fs::{self};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::ops::Bound;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[cfg(feature = "tokio")]
+pub use async_storage::AsyncKVStorage;
+pub use backup::{ExportSummary, ImportMode, ImportSummary};
+pub use changes::{Change, ChangeReceiver};
+pub use clock::{Clock, SystemClock};
+pub use compaction_filter::{CompactionFilter, FilterDecision};
+pub use comparator::{Ascending, Descending, KeyComparator};
+pub use debug::{BlockDump, DbDump, LogDump, TableDump};
+#[cfg(feature = "testing")]
+pub use env::{Env, RandomSource, SeededRandom};
+pub use errors::Error;
+pub use events::{CompactionInfo, EventListener, FlushInfo, FlushReason};
+pub use functions::{ReadDetails, ReadOutcome};
+pub use health::Health;
+pub use inspect::Inspector;
+pub use iter::KvIter;
+pub use namespace::Namespace;
+pub use options::{
+    BloomRecovery, BloomSize, ColdStoragePolicy, CompactionPolicy, Compression, CreateMode,
+    Durability, IncrementOptions, IoBackend, Options, OverflowPolicy, WriteOptions,
+};
+pub use repair::{RepairReport, SalvagedFile};
+pub use snapshot::{Page, Snapshot};
+pub use space::{LargeValue, SpaceReport};
+pub use sstables::policy::PlannedCompaction;
+pub use stats::{BloomStats, DiskUsage, RangeEstimate, ReadTrace, Stats, VALUE_SIZE_BUCKETS};
+pub use verify::{Anomaly, TableReport, VerifyReport};
+pub use watch::WatchHandle;
 
+/// Size of every log file, 16 pages of 16 KiB: whole blocks for the usual sizes of [`Options::align_log_records`]
 const FILE_SIZE_BYTES: u64 = 1024 * 16 * 16;
 
+/// Handle of a database, cheap to clone: every clone shares the same store, so it can be handed to other threads
+/// instead of wrapping it in an [`Arc`].
+///
+/// Every method takes `&self` and can be called from any number of threads at once. The background threads stop and
+/// the files are closed when the last handle is dropped, or when [`KVStorage::close`] is called on it.
+///
+/// ```
+/// # use key_value_store::KVStorage;
+/// let kv = KVStorage::new_in_memory().unwrap();
+/// let writer = {
+///     let kv = kv.clone();
+///     std::thread::spawn(move || kv.write(1, Some(10)).unwrap())
+/// };
+/// writer.join().unwrap();
+/// assert_eq!(kv.read(&1).unwrap(), Some(10));
+/// ```
+#[derive(Clone)]
 pub struct KVStorage {
+    inner: Arc<Inner>,
+}
+
+// Handles are shared across threads
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<KVStorage>();
+};
+
+/// The store shared by the clones of a [`KVStorage`]
+struct Inner {
     // Key lock
     /// File and the current write offset
-    append_log: AppendLog,
-    /// Sorted list (newer at the beginning) of SSTables
-    sstables: Arc<Mutex<Vec<Arc<SSTable>>>>,
-    sstables_dir: PathBuf,
-    compaction_manager: CompactorManager,
+    append_log: ShardedAppendLog,
+    /// Sorted list (newer at the beginning) of SSTables, locked by the changes to it
+    sstables: Arc<Mutex<TableList>>,
+    /// Latest list of `sstables`, loaded by reads without waiting for the changes in progress
+    table_view: TableView,
+    /// Open files of the SSTables
+    table_files: Arc<TableFiles>,
+    /// `db/` under the location, holding every file of the database
+    db_dir: PathBuf,
+    manifest: Arc<Manifest>,
+    compaction_manager: Arc<CompactorManager>,
+    /// Background threads, shared with the namespaces
+    workers: Workers,
+    stats: Arc<StatsCounters>,
+    /// See [`Options::max_db_size_bytes`]
+    quota: Arc<DiskQuota>,
+    /// Values read from the SSTables, see [`Options::cache_capacity`]
+    cache: ReadCache,
+    /// See [`Options::negative_cache_slots`]
+    negative_cache: NegativeCache,
+    /// Subscribers of the committed writes, see [`KVStorage::subscribe`]
+    changes: ChangeHub,
+    options: Options,
+    /// Set by [`KVStorage::open_read_only`]
+    read_only: bool,
+    /// Stores of the namespaces, see [`KVStorage::create_namespace`]
+    namespaces: Mutex<HashMap<String, KVStorage>>,
+    /// See [`KVStorage::is_healthy`]
+    sync_failed: AtomicBool,
+    /// Listens to the background work for [`KVStorage::health`], in place of [`Options::event_listener`]
+    health: Arc<HealthTracker>,
 }
 
 type Key = u64;
@@ -33,86 +172,5531 @@ type Value = u64;
 impl KVStorage {
     /// Creates a new KV database
     pub fn new(location: &str) -> Result<Self, Error> {
+        Self::new_with_options(location, Options::default())
+    }
+
+    pub fn new_with_options(location: &str, options: Options) -> Result<Self, Error> {
+        Self::open_with_mode(location, options, CreateMode::CreateNew)
+    }
+
+    /// Creates or opens the database in `location` depending on `mode`. The `db/` directory there must be empty or
+    /// hold a database, see [`Error::NotADatabase`], [`Error::LayoutTooNew`] and [`Error::MissingIdentity`]
+    pub fn open_with_mode(
+        location: &str,
+        options: Options,
+        mode: CreateMode,
+    ) -> Result<Self, Error> {
         let path = Path::new(location);
         if !path.is_dir() {
             return Err(Error::InvalidDbLocation);
         }
-
+        let storage = storage::disk_storage(options.io_backend);
         let db_dir = path.join("db");
-        fs::create_dir(&db_dir).map_err(|_| Error::FileDirectoryCreation)?;
+
+        match (identity::inspect(&*storage, &db_dir)?, mode) {
+            (DbState::Absent, CreateMode::OpenExisting) => Err(Error::InvalidDbLocation),
+            (DbState::Absent, _) => Self::create(storage, &db_dir, options),
+            (DbState::Store { .. }, CreateMode::CreateNew) => Err(Error::DatabaseExists),
+            (DbState::Store { .. }, _) => {
+                let storage = Self::open_dir(storage, db_dir, options, false)?;
+                storage.signal_pending_merges();
+                Ok(storage)
+            }
+        }
+    }
+
+    /// Creates a KV database drawing its file names and table ids from `env.random`, with `env.clock` replacing
+    /// [`Options::clock`]. Meant for reproducible tests, behind the `testing` feature
+    #[cfg(any(test, feature = "testing"))]
+    pub fn new_with_env(location: &str, options: Options, env: env::Env) -> Result<Self, Error> {
+        let env::Env { clock, random } = env;
+
+        Self::new_with_options(
+            location,
+            Options {
+                random,
+                ..options.clock(clock)
+            },
+        )
+    }
+
+    /// Creates a KV database that never touches the disk, everything is lost once it's dropped
+    pub fn new_in_memory() -> Result<Self, Error> {
+        Self::new_in_memory_with_options(Options::default())
+    }
+
+    pub fn new_in_memory_with_options(options: Options) -> Result<Self, Error> {
+        Self::create(Arc::new(MemStorage::new()), Path::new("db"), options)
+    }
+
+    /// `db_dir` must not exist or be empty, see [`identity::inspect`]
+    fn create(storage: Arc<dyn Storage>, db_dir: &Path, options: Options) -> Result<Self, Error> {
+        Self::create_store(storage, db_dir, options, None)
+    }
+
+    /// Namespaces run on the `workers` of their store, a database starts its own with `None`
+    fn create_store(
+        storage: Arc<dyn Storage>,
+        db_dir: &Path,
+        options: Options,
+        workers: Option<Workers>,
+    ) -> Result<Self, Error> {
+        let (options, health) = HealthTracker::install(options);
+        let db_dir = db_dir.to_owned();
+        if !storage.is_dir(&db_dir) {
+            storage
+                .create_dir(&db_dir)
+                .map_err(|_| Error::FileDirectoryCreation)?;
+        }
         let sstables_dir = db_dir.join("sstables");
-        fs::create_dir(&sstables_dir).map_err(|_| Error::FileDirectoryCreation)?;
+        storage
+            .create_dir(&sstables_dir)
+            .map_err(|_| Error::FileDirectoryCreation)?;
+
+        if let Some(cold_dir) = &options.cold_storage_dir {
+            storage
+                .create_dir_all(cold_dir)
+                .map_err(|_| Error::FileDirectoryCreation)?;
+        }
 
         let sstables: Arc<Mutex<_>> = Default::default();
+        let table_files = Arc::new(
+            TableFiles::new(storage.clone(), sstables_dir, options.max_open_tables)
+                .with_cold_dir(options.cold_storage_dir.clone(), &[])
+                .with_listener(options.event_listener.clone())
+                .with_random(options.random.clone())
+                .with_order(KeyOrder::new(options.key_comparator.clone()))
+                .with_bloom_recovery(options.bloom_recovery),
+        );
 
-        let append_log = AppendLog::new(&db_dir)?;
+        let quota = Arc::new(DiskQuota::new(&options));
+        let append_log = ShardedAppendLog::new(&db_dir, &table_files, &options, &quota)?;
+        let manifest = Arc::new(Manifest::create(
+            &storage,
+            &db_dir,
+            ManifestData {
+                log_files: append_log.file_names(),
+                comparator: Some(options.key_comparator.name().to_owned()),
+                ..Default::default()
+            },
+        )?);
+        // Last, a database without it is opened as one created before the identity files
+        identity::write(&*storage, &db_dir)?;
 
+        let workers = workers.unwrap_or_else(|| Workers::new(storage, &options));
+        let stats: Arc<StatsCounters> = Default::default();
+        let compaction_manager = Arc::new(
+            CompactorManager::new(
+                table_files.clone(),
+                sstables.clone(),
+                manifest.clone(),
+                options.clone(),
+                stats.clone(),
+                quota.clone(),
+                &workers,
+            )
+            .with_ticker(append_log.last_writes()),
+        );
+        if let Some(flusher) = &workers.flusher {
+            append_log.start_flusher(flusher, &sstables, &manifest, &compaction_manager);
+        }
+
+        let table_view = sstables.lock().expect("sstables lock poisoned").view();
         Ok(Self {
-            append_log,
-            sstables: sstables.clone(),
-            sstables_dir: sstables_dir.clone(),
-            compaction_manager: CompactorManager::new(sstables_dir, sstables),
+            inner: Arc::new(Inner {
+                append_log,
+                sstables,
+                table_view,
+                table_files,
+                db_dir,
+                manifest,
+                compaction_manager,
+                workers,
+                stats,
+                quota,
+                cache: ReadCache::new(options.cache_capacity),
+                negative_cache: NegativeCache::new(options.negative_cache_slots),
+                changes: ChangeHub::new(options.subscriber_capacity, options.subscriber_overflow),
+                options,
+                read_only: false,
+                namespaces: Default::default(),
+                sync_failed: Default::default(),
+                health,
+            }),
         })
     }
 
-    pub fn write(&self, key: Key, value: Option<Value>) -> Result<(), Error> {
-        self.append_log.write_key(
-            key,
-            value,
-            &self.sstables_dir,
-            &self.sstables,
-            &self.compaction_manager,
+    /// Opens a KV database previously created with [`KVStorage::new`]
+    pub fn open(location: &str) -> Result<Self, Error> {
+        Self::open_with_options(location, Options::default())
+    }
+
+    pub fn open_with_options(location: &str, options: Options) -> Result<Self, Error> {
+        Self::open_with_mode(location, options, CreateMode::OpenExisting)
+    }
+
+    /// Catches up on merges that were pending when the database was closed
+    fn signal_pending_merges(&self) {
+        self.inner.compaction_manager.signal_sstable_inserted();
+        for namespace in self
+            .inner
+            .namespaces
+            .lock()
+            .expect("namespaces lock poisoned")
+            .values()
+        {
+            namespace.inner.compaction_manager.signal_sstable_inserted();
+        }
+    }
+
+    /// Opens a database without ever modifying its directory, e.g. one owned by another process.
+    ///
+    /// The append log is only replayed in memory, writes fail with [`Error::ReadOnly`] and no compaction runs.
+    pub fn open_read_only(location: &str) -> Result<Self, Error> {
+        Self::open_read_only_with_options(location, Options::default())
+    }
+
+    pub fn open_read_only_with_options(location: &str, options: Options) -> Result<Self, Error> {
+        let storage = storage::disk_storage(options.io_backend);
+
+        Self::open_dir(storage, Path::new(location).join("db"), options, true)
+    }
+
+    fn open_dir(
+        storage: Arc<dyn Storage>,
+        db_dir: PathBuf,
+        options: Options,
+        read_only: bool,
+    ) -> Result<Self, Error> {
+        Self::open_store(storage, db_dir, options, read_only, None)
+    }
+
+    /// See [`KVStorage::create_store`] for `workers`
+    fn open_store(
+        storage: Arc<dyn Storage>,
+        db_dir: PathBuf,
+        options: Options,
+        read_only: bool,
+        workers: Option<Workers>,
+    ) -> Result<Self, Error> {
+        match identity::inspect(&*storage, &db_dir)? {
+            DbState::Absent => return Err(Error::InvalidDbLocation),
+            DbState::Store { identified: false } if !read_only => {
+                identity::write(&*storage, &db_dir)?;
+            }
+            DbState::Store { .. } => {}
+        }
+        let (options, health) = HealthTracker::install(options);
+        let sstables_dir = db_dir.join("sstables");
+
+        let manifest = Arc::new(Manifest::load(&storage, &db_dir)?);
+        let manifest_data = manifest.data();
+        check_comparator(&options, &manifest_data)?;
+        let cold_dir = cold_storage_dir(&options, &manifest_data)?;
+
+        let stats: Arc<StatsCounters> = Default::default();
+        if !read_only {
+            sstables::remove_tmp_files(&*storage, &sstables_dir)?;
+            if let Some(cold_dir) = &cold_dir {
+                storage
+                    .create_dir_all(cold_dir)
+                    .map_err(|_| Error::FileDirectoryCreation)?;
+                sstables::remove_tmp_files(&*storage, cold_dir)?;
+            }
+
+            let (orphan_files, orphan_bytes) = cleanup::remove_orphan_files(
+                &*storage,
+                &db_dir,
+                &sstables_dir,
+                cold_dir.as_deref(),
+                &manifest_data,
+                cleanup::ORPHAN_GRACE_PERIOD,
+            )?;
+            stats
+                .orphan_files_removed
+                .fetch_add(orphan_files, Ordering::Relaxed);
+            stats
+                .orphan_bytes_removed
+                .fetch_add(orphan_bytes, Ordering::Relaxed);
+        }
+
+        let table_files = Arc::new(
+            TableFiles::new(storage.clone(), sstables_dir, options.max_open_tables)
+                .with_cold_dir(cold_dir, &manifest_data.cold_sstables)
+                .with_listener(options.event_listener.clone())
+                .with_random(options.random.clone())
+                .with_order(KeyOrder::new(options.key_comparator.clone()))
+                .with_bloom_recovery(options.bloom_recovery),
+        );
+        let sstables = manifest_data
+            .sstables
+            .iter()
+            .map(|id| SSTable::open(&table_files, *id).map(Arc::new))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let last_table_sequence = sstables.iter().map(|t| t.max_sequence()).max();
+        let quota = Arc::new(DiskQuota::new(&options));
+        quota.add_table(sstables.iter().map(|t| t.file_size()).sum());
+        let sstables = Arc::new(Mutex::new(TableList::new(sstables)));
+
+        let append_log = ShardedAppendLog::open(
+            &db_dir,
+            &table_files,
+            &manifest_data,
+            last_table_sequence.unwrap_or(0),
+            read_only,
+            &options,
+            &quota,
+        )?;
+        sketch_keys(&manifest, &manifest_data, &append_log, &sstables)?;
+
+        let workers = workers.unwrap_or_else(|| Workers::new(storage.clone(), &options));
+        let namespaces = manifest_data
+            .namespaces
+            .iter()
+            .map(|name| {
+                let dir = db_dir.join(name);
+                let options = health.untracked(options.for_namespace(name));
+                let store = Self::open_store(
+                    storage.clone(),
+                    dir,
+                    options,
+                    read_only,
+                    Some(workers.for_namespace()),
+                )?;
+
+                Ok((name.clone(), store))
+            })
+            .collect::<Result<_, Error>>()?;
+
+        let mut compaction_manager = CompactorManager::new(
+            table_files.clone(),
+            sstables.clone(),
+            manifest.clone(),
+            options.clone(),
+            stats.clone(),
+            quota.clone(),
+            &workers,
+        );
+        if !read_only {
+            compaction_manager = compaction_manager.with_ticker(append_log.last_writes());
+        }
+        let compaction_manager = Arc::new(compaction_manager);
+        if let Some(flusher) = workers.flusher.as_ref().filter(|_| !read_only) {
+            append_log.start_flusher(flusher, &sstables, &manifest, &compaction_manager);
+        }
+
+        let table_view = sstables.lock().expect("sstables lock poisoned").view();
+        Ok(Self {
+            inner: Arc::new(Inner {
+                append_log,
+                sstables,
+                table_view,
+                table_files,
+                db_dir,
+                manifest,
+                compaction_manager,
+                workers,
+                stats,
+                quota,
+                cache: ReadCache::new(options.cache_capacity),
+                negative_cache: NegativeCache::new(options.negative_cache_slots),
+                changes: ChangeHub::new(options.subscriber_capacity, options.subscriber_overflow),
+                options,
+                read_only,
+                namespaces: Mutex::new(namespaces),
+                sync_failed: Default::default(),
+                health,
+            }),
+        })
+    }
+
+    /// Closes the database: stops the compactor, flushes the append log into an SSTable and syncs everything to disk.
+    ///
+    /// Fails with [`Error::StoreInUse`], closing nothing, while other clones of the handle are alive.
+    pub fn close(self) -> Result<(), Error> {
+        let inner = self.into_inner()?;
+
+        let namespaces =
+            std::mem::take(&mut *inner.namespaces.lock().expect("namespaces lock poisoned"));
+        for store in namespaces.into_values() {
+            store.close()?;
+        }
+
+        inner.compaction_manager.stop();
+        inner.append_log.stop_flusher();
+
+        if inner.read_only {
+            inner.workers.stop();
+            return Ok(());
+        }
+
+        inner.append_log.flush(&inner.sstables, &inner.manifest)?;
+        inner.append_log.sync()?;
+
+        let sstables = inner.sstables.lock().expect("sstables lock poisoned");
+        for sstable in sstables.iter() {
+            sstable.sync()?;
+        }
+        inner.manifest.update(|data| {
+            data.sstables = sstables.iter().map(|t| t.id()).collect();
+        })?;
+        drop(sstables);
+
+        inner.workers.stop();
+
+        Ok(())
+    }
+
+    /// The store, as long as this is its only handle
+    fn into_inner(self) -> Result<Inner, Error> {
+        Arc::into_inner(self.inner).ok_or(Error::StoreInUse)
+    }
+
+    /// Deletes the database at `location`, which must not be open.
+    ///
+    /// Fails with [`Error::NotADatabase`], removing nothing, unless every file under `location/db` is one the database
+    /// writes. `location` itself is kept.
+    pub fn destroy(location: &str) -> Result<(), Error> {
+        cleanup::destroy_database(&DiskStorage, &Path::new(location).join("db"))
+    }
+
+    /// Rebuilds a database that can't be opened anymore out of what its files still hold. It must not be open.
+    ///
+    /// Every record that can be decoded from the log and table files listed in the manifest goes into a single new
+    /// table, the newest entry of each key winning. Files with unreadable parts are moved to `db/lost/`, the others
+    /// are deleted. Without a readable manifest every file is salvaged, which can bring back entries deleted by
+    /// compactions.
+    ///
+    /// Namespaces are kept as they are, and aren't listed anymore when the manifest was unreadable.
+    pub fn repair(location: &str) -> Result<RepairReport, Error> {
+        Self::repair_with_options(location, Options::default())
+    }
+
+    /// Same as [`KVStorage::repair`] for a database created with another [`Options::key_comparator`], the only
+    /// option it uses
+    pub fn repair_with_options(location: &str, options: Options) -> Result<RepairReport, Error> {
+        let storage: Arc<dyn Storage> = Arc::new(DiskStorage);
+
+        repair::repair_database(
+            &storage,
+            &Path::new(location).join("db"),
+            &options.key_comparator,
         )
     }
 
-    pub fn read(&self, key: &Key) -> Result<Option<Value>, Error> {
-        let append_log_result = self.append_log.find_key(key);
+    /// Stops the background threads, so that nothing writes files anymore, then deletes the database as
+    /// [`KVStorage::destroy`] does. Fails with [`Error::StoreInUse`] while other clones of the handle are alive
+    pub fn close_and_destroy(self) -> Result<(), Error> {
+        self.check_writable()?;
+        let inner = self.into_inner()?;
 
-        match append_log_result {
-            FindResult::Found(value) => return Ok(Some(value)),
-            FindResult::Tombstone => return Ok(None),
-            FindResult::None => {}
+        // Before the store, they run on its threads
+        for namespace in inner
+            .namespaces
+            .lock()
+            .expect("namespaces lock poisoned")
+            .values()
+        {
+            namespace.inner.compaction_manager.stop();
+            namespace.inner.append_log.stop_flusher();
+            namespace.inner.workers.stop();
         }
+        inner.compaction_manager.stop();
+        inner.append_log.stop_flusher();
+        inner.workers.stop();
+
+        let storage = inner.table_files.storage().clone();
+        let db_dir = inner.db_dir.clone();
+        // Closes every file, including the recycled logs
+        drop(inner);
+
+        cleanup::destroy_database(&*storage, &db_dir)
+    }
+
+    /// Writes a copy of the database in `dest`, which can then be opened with [`KVStorage::open`].
+    ///
+    /// The append log is flushed first, SSTables are hard-linked when possible. Writes can continue meanwhile.
+    pub fn checkpoint(&self, dest: &Path) -> Result<(), Error> {
+        self.check_writable()?;
+
+        self.inner
+            .table_files
+            .storage()
+            .create_dir_all(dest)
+            .map_err(|_| Error::FileDirectoryCreation)?;
+
+        self.checkpoint_dir(&dest.join("db"))
+    }
 
-        // Clone the current state (not the sstables themselves)
-        // Since their content is effectively immutable this operation is safe (the only possible change is compaction/merge)
-        let current_sstables_state = &self
+    /// Copies the files of the store (and of its namespaces) into `dest_db_dir`, which must not exist
+    fn checkpoint_dir(&self, dest_db_dir: &Path) -> Result<(), Error> {
+        let storage = self.inner.table_files.storage();
+        let dest_sstables_dir = dest_db_dir.join("sstables");
+        storage
+            .create_dir(dest_db_dir)
+            .map_err(|_| Error::FileDirectoryCreation)?;
+        storage
+            .create_dir(&dest_sstables_dir)
+            .map_err(|_| Error::FileDirectoryCreation)?;
+
+        self.inner
+            .append_log
+            .flush(&self.inner.sstables, &self.inner.manifest)?;
+
+        // Holding the tables keeps their files around even if compaction replaces them
+        let sstables = self
+            .inner
             .sstables
             .lock()
             .expect("sstables lock poisoned")
             .clone();
 
-        // Start scanning SSTables in order
-        for sstable in current_sstables_state {
-            let res = sstable.find(key)?;
+        for sstable in &sstables {
+            sstable.sync()?;
+            storage.link_or_copy(
+                sstable.file_path(),
+                &dest_sstables_dir.join(sstable.id().to_string()),
+            )?;
+        }
+        storage.sync_dir(&dest_sstables_dir)?;
 
-            match res {
-                FindResult::Found(value) => return Ok(Some(value)),
-                FindResult::Tombstone => return Ok(None),
-                FindResult::None => {}
+        let mut log_files = Vec::new();
+        for _ in 0..self.inner.append_log.shard_count() {
+            let log_file = append_log::create_append_log_file(
+                &**storage,
+                dest_db_dir,
+                &*self.inner.options.random,
+            )?;
+            log_file.file.sync_all()?;
+            log_files.push(append_log::log_file_name(&log_file));
+        }
+
+        let namespaces = self
+            .inner
+            .namespaces
+            .lock()
+            .expect("namespaces lock poisoned")
+            .clone();
+        for (name, namespace) in &namespaces {
+            namespace.checkpoint_dir(&dest_db_dir.join(name))?;
+        }
+
+        Manifest::create(
+            storage,
+            dest_db_dir,
+            ManifestData {
+                log_files,
+                sstables: sstables.iter().map(|t| t.id()).collect(),
+                namespaces: namespaces.into_keys().collect(),
+                comparator: Some(self.inner.options.key_comparator.name().to_owned()),
+                distinct_keys: self.inner.manifest.distinct_keys().to_bytes(),
+                ..Default::default()
+            },
+        )?;
+        identity::write(&**storage, dest_db_dir)?;
+
+        Ok(())
+    }
+
+    /// Creates a keyspace in `db/<name>/`, with its own append log, SSTables and compaction. Its flushes, merges and
+    /// deletions run on the threads of the store.
+    ///
+    /// Namespaces are opened along with the store and closed with it. `name` is made of lowercase ASCII letters,
+    /// digits, `-` and `_` and can't be the name of a file or directory of the store (e.g. `sstables`).
+    pub fn create_namespace(&self, name: &str) -> Result<Namespace<'_>, Error> {
+        self.check_writable()?;
+        namespace::check_name(name)?;
+
+        let mut namespaces = self
+            .inner
+            .namespaces
+            .lock()
+            .expect("namespaces lock poisoned");
+        if namespaces.contains_key(name) {
+            return Err(Error::NamespaceExists);
+        }
+
+        let storage = self.inner.table_files.storage();
+        let dir = self.inner.db_dir.join(name);
+        if storage.is_dir(&dir) {
+            // Left over by a crash before the manifest listed it
+            cleanup::destroy_database(&**storage, &dir)?;
+        }
+        let store = Self::create_store(
+            storage.clone(),
+            &dir,
+            self.inner
+                .health
+                .untracked(self.inner.options.for_namespace(name)),
+            Some(self.inner.workers.for_namespace()),
+        )?;
+        self.inner
+            .manifest
+            .update(|data| data.namespaces.push(name.to_owned()))?;
+        namespaces.insert(name.to_owned(), store.clone());
+
+        Ok(Namespace::new(name, store))
+    }
+
+    /// The namespace called `name`, if it was created
+    pub fn namespace(&self, name: &str) -> Option<Namespace<'_>> {
+        let namespaces = self
+            .inner
+            .namespaces
+            .lock()
+            .expect("namespaces lock poisoned");
+
+        namespaces
+            .get(name)
+            .map(|store| Namespace::new(name, store.clone()))
+    }
+
+    /// Names of the namespaces, sorted
+    pub fn namespace_names(&self) -> Vec<String> {
+        let namespaces = self
+            .inner
+            .namespaces
+            .lock()
+            .expect("namespaces lock poisoned");
+        let mut names: Vec<_> = namespaces.keys().cloned().collect();
+        names.sort();
+
+        names
+    }
+
+    /// Deletes the namespace and all its files. Fails with [`Error::NamespaceInUse`] while a [`Namespace`] handle of
+    /// it is alive.
+    pub fn drop_namespace(&self, name: &str) -> Result<(), Error> {
+        self.check_writable()?;
+
+        let mut namespaces = self
+            .inner
+            .namespaces
+            .lock()
+            .expect("namespaces lock poisoned");
+        let store = namespaces.remove(name).ok_or(Error::NamespaceNotFound)?;
+        // Handles of the namespace are only made under the lock
+        if Arc::strong_count(&store.inner) > 1 {
+            namespaces.insert(name.to_owned(), store);
+            return Err(Error::NamespaceInUse);
+        }
+
+        if let Err(e) = self
+            .inner
+            .manifest
+            .update(|data| data.namespaces.retain(|n| n != name))
+        {
+            namespaces.insert(name.to_owned(), store);
+            return Err(e);
+        }
+        drop(namespaces);
+
+        store.close_and_destroy()
+    }
+
+    /// Decodes every SSTable, reporting corrupted blocks and records, unsorted keys and bloom filters missing keys.
+    ///
+    /// The append log isn't checked. Writes and compactions continue meanwhile, on the tables present at the start.
+    pub fn verify(&self) -> Result<VerifyReport, Error> {
+        let sstables = self
+            .inner
+            .sstables
+            .lock()
+            .expect("sstables lock poisoned")
+            .clone();
+
+        Ok(VerifyReport {
+            tables: sstables.iter().map(|sstable| sstable.verify()).collect(),
+        })
+    }
+
+    /// Moves the content of the append log into SSTables
+    pub fn flush(&self) -> Result<(), Error> {
+        self.check_writable()?;
+
+        if self
+            .inner
+            .append_log
+            .flush(&self.inner.sstables, &self.inner.manifest)?
+        {
+            self.inner.compaction_manager.signal_sstable_inserted();
+        }
+
+        Ok(())
+    }
+
+    /// Flushes as [`KVStorage::flush`] does, then waits for the compaction that the new table started, so that the
+    /// list of tables is settled when it returns
+    pub fn flush_and_wait(&self) -> Result<(), Error> {
+        self.flush()?;
+        self.inner.compaction_manager.wait_idle(None);
+
+        Ok(())
+    }
+
+    /// Blocks until no compaction runs and none is left to do according to [`Options::compaction_policy`], starting
+    /// the ones left. Merges that don't fit in the quota headroom aren't waited for.
+    ///
+    /// Returns false if `timeout` expired first, e.g. because merges keep failing or the store is closing.
+    pub fn wait_for_pending_compactions(&self, timeout: Duration) -> Result<bool, Error> {
+        self.check_writable()?;
+
+        Ok(self.inner.compaction_manager.wait_for_pending(timeout))
+    }
+
+    /// Describes the SSTables and the append log as stored, for debugging. See the `Display` impl of [`DbDump`].
+    ///
+    /// `with_entries` decodes every entry of every table, which reads them whole. The tables and the logs aren't
+    /// captured atomically, a rotation in between can show entries twice or not at all.
+    pub fn dump(&self, with_entries: bool) -> Result<DbDump, Error> {
+        let sstables = self
+            .inner
+            .sstables
+            .lock()
+            .expect("sstables lock poisoned")
+            .clone();
+
+        Ok(DbDump {
+            tables: sstables
+                .iter()
+                .map(|sstable| sstable.dump(with_entries))
+                .collect::<Result<_, _>>()?,
+            logs: self.inner.append_log.dump()?,
+        })
+    }
+
+    /// Sequence number of the latest write, every write gets the next one
+    pub fn last_sequence(&self) -> u64 {
+        self.inner.append_log.last_sequence()
+    }
+
+    pub fn stats(&self) -> Stats {
+        let sstables = self.inner.sstables.lock().expect("poisoned sstables lock");
+        let (estimated_distinct_keys, reclaimable_entries) = self.reclaimable_entries(&sstables);
+        Stats {
+            open_table_files: self.inner.table_files.open_count() as u64,
+            disk_bytes_used: self.inner.quota.used(),
+            memtable_bytes: self.inner.append_log.memtable_bytes(),
+            bloom_filter_bytes: sstables
+                .iter()
+                .map(|table| table.bloom_filter_bytes())
+                .sum(),
+            estimated_distinct_keys,
+            reclaimable_entries,
+            bloom_filters: sstables.iter().map(|table| table.bloom_stats()).collect(),
+            ..self.inner.stats.snapshot()
+        }
+    }
+
+    /// Estimated distinct keys, and the entries of `sstables` and of the logs beyond them
+    fn reclaimable_entries(&self, sstables: &[Arc<SSTable>]) -> (u64, u64) {
+        let distinct_keys = self.inner.manifest.distinct_keys().estimate();
+        let entries = sstables.iter().map(|t| t.entry_count()).sum::<u64>()
+            + self.inner.append_log.entry_count() as u64;
+
+        (distinct_keys, entries.saturating_sub(distinct_keys))
+    }
+
+    /// Disk space used by the store, from sizes tracked as files are created and deleted. Namespaces aren't included.
+    ///
+    /// Flushes and merges running meanwhile can be counted partly, see [`KVStorage::refresh_from_disk`].
+    pub fn disk_usage(&self) -> DiskUsage {
+        let tables: Vec<TableStats> = self
+            .inner
+            .sstables
+            .lock()
+            .expect("poisoned sstables lock")
+            .iter()
+            .map(|table| TableStats::from(&**table))
+            .collect();
+
+        DiskUsage {
+            table_bytes: self.inner.quota.table_bytes(),
+            log_allocated_bytes: self.inner.quota.log_allocated(),
+            log_used_bytes: self.inner.quota.log_bytes(),
+            pending_deletion_bytes: self.inner.workers.reaper.pending_bytes(),
+            dead_bytes: policy::dead_bytes(&tables),
+        }
+    }
+
+    /// Distribution of the value sizes stored in the tables, with the `top_n` largest values and their keys.
+    ///
+    /// Scans the tables of the moment a block at a time, overwritten values and tombstones included; the append log
+    /// isn't scanned, see [`Stats::value_sizes`] for the writes. Setting `cancel` stops the scan with
+    /// [`Error::Cancelled`].
+    pub fn space_report(&self, top_n: usize, cancel: &AtomicBool) -> Result<SpaceReport, Error> {
+        space::scan_tables(&self.inner.table_view.load(), top_n, cancel)
+    }
+
+    /// Replaces the tracked sizes of the live SSTables and of the log files with the ones on disk, then returns
+    /// [`KVStorage::disk_usage`].
+    ///
+    /// Lists the directories holding them, log files left behind by a crash are counted until removed.
+    pub fn refresh_from_disk(&self) -> Result<DiskUsage, Error> {
+        let storage = self.inner.table_files.storage();
+
+        let log_allocated = storage
+            .list(&self.inner.db_dir)?
+            .into_iter()
+            .filter(|file| {
+                file.path.file_name().is_some_and(|name| {
+                    name.to_string_lossy()
+                        .starts_with(append_log::LOG_FILE_PREFIX)
+                })
+            })
+            .map(|file| file.len)
+            .sum();
+
+        // Held so that no merge replaces tables meanwhile
+        let sstables = self.inner.sstables.lock().expect("poisoned sstables lock");
+        let mut sizes = HashMap::new();
+        for dir in sstables
+            .iter()
+            .filter_map(|table| table.file_path().parent())
+        {
+            if !sizes.contains_key(dir) {
+                let files = storage.list(dir)?;
+                sizes.insert(dir.to_owned(), files);
             }
         }
+        let table_bytes = sstables
+            .iter()
+            .filter_map(|table| {
+                let files = sizes.get(table.file_path().parent()?)?;
+                files.iter().find(|file| file.path == table.file_path())
+            })
+            .map(|file| file.len)
+            .sum();
+        self.inner.quota.reconcile(table_bytes, log_allocated);
+        drop(sstables);
 
-        Ok(None)
+        Ok(self.disk_usage())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    pub fn write(&self, key: Key, value: Option<Value>) -> Result<(), Error> {
+        self.write_with(key, value, &WriteOptions::default())
+    }
 
-    #[test]
-    fn test_everything() {
-        let location = format!("./test-dbs/{}", rand::random::<u64>());
-        fs::create_dir_all(&location).unwrap();
+    /// Writes `value` (deletes `key` with `None`), returning as set by `options`.
+    ///
+    /// The write is readable as soon as its record is in the log file, before any sync. When a
+    /// [`Durability::Synced`] write fails with [`Error::NotDurable`], the store is no longer
+    /// [healthy](KVStorage::is_healthy).
+    pub fn write_with(
+        &self,
+        key: Key,
+        value: Option<Value>,
+        options: &WriteOptions,
+    ) -> Result<(), Error> {
+        self.check_writable()?;
 
-        let kv = KVStorage::new(&location).unwrap();
-        kv.write(1, Some(10)).unwrap();
-        assert_eq!(kv.read(&1).unwrap(), Some(10));
-        kv.write(1, Some(20)).unwrap();
-        assert_eq!(kv.read(&1).unwrap(), Some(20));
-        kv.write(2, None).unwrap();
-        assert_eq!(kv.read(&2).unwrap(), None);
-        assert_eq!(kv.read(&99).unwrap(), None);
+        self.write_point(KVMemoryRepr::new(key, value, 0), options)
+    }
+
+    /// Writes the point `entry` to the append log, shared by [`KVStorage::write_with`] and
+    /// [`KVStorage::write_with_ttl`]
+    fn write_point(&self, entry: KVMemoryRepr, options: &WriteOptions) -> Result<(), Error> {
+        let key = *entry.key();
+        let value_size = entry.value_size();
+        let written = self.inner.append_log.write_entry(
+            entry,
+            &self.inner.sstables,
+            &self.inner.manifest,
+            &self.inner.compaction_manager,
+            &self.inner.changes,
+            options.durability,
+        );
+        // After the write, so that a concurrent read can't cache the previous value
+        self.inner.cache.invalidate(&key);
+        self.inner.negative_cache.invalidate(&key);
+        if matches!(written, Ok(()) | Err(Error::NotDurable(_))) {
+            self.inner.stats.record_value_size(value_size);
+            self.inner.manifest.distinct_keys().add(&key);
+        }
+        self.record_sync_failure(&written);
+
+        written
+    }
+
+    /// Marks the store as not [healthy](KVStorage::is_healthy) when `written` failed with [`Error::NotDurable`]
+    fn record_sync_failure(&self, written: &Result<(), Error>) {
+        if let Err(Error::NotDurable(e)) = written {
+            log::error!("failed to sync the append log: {e:?}");
+            self.inner.sync_failed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// False once a [`Durability::Synced`] write failed to sync, writes since then may not survive a crash. Also false
+    /// while writes are refused with [`Error::StorageUnavailable`]
+    pub fn is_healthy(&self) -> bool {
+        !self.inner.sync_failed.load(Ordering::Relaxed) && !self.inner.table_files.is_unavailable()
+    }
+
+    /// The merges the compaction policy would run on the current tables, the most worthwhile first. Nothing is merged,
+    /// see [`Options::compaction_policy`]
+    pub fn compaction_plan(&self) -> Vec<PlannedCompaction> {
+        self.inner.compaction_manager.plan()
+    }
+
+    /// Summary of the store state for monitoring, see [`Health::is_healthy`]. Namespaces aren't included
+    pub fn health(&self) -> Health {
+        let sstables = self
+            .inner
+            .sstables
+            .lock()
+            .expect("sstables lock poisoned")
+            .clone();
+        Health {
+            background_error: self.inner.health.background_error(),
+            writes_refused: self.inner.table_files.is_unavailable(),
+            sync_failed: self.inner.sync_failed.load(Ordering::Relaxed),
+            compaction_backlog: self.inner.compaction_manager.backlog(),
+            reclaimable_entries: self.reclaimable_entries(&sstables).1,
+            disk_bytes_used: self.inner.quota.used(),
+            disk_quota_bytes: self.inner.options.max_db_size_bytes,
+            since_last_flush: self.inner.health.since_last_flush(),
+            since_last_compaction: self.inner.health.since_last_compaction(),
+        }
+    }
+
+    /// Writes `value`, which reads as deleted once `ttl` has passed (according to [`Options::clock`]). Returns as
+    /// set by `options`, see [`KVStorage::write_with`]
+    pub fn write_with_ttl(
+        &self,
+        key: Key,
+        value: Value,
+        ttl: Duration,
+        options: &WriteOptions,
+    ) -> Result<(), Error> {
+        self.check_writable()?;
+
+        let expires_at = self
+            .inner
+            .options
+            .clock
+            .now_millis()
+            .saturating_add(ttl.as_millis() as u64);
+
+        let entry = KVMemoryRepr::new(key, Some(value), 0).with_expiration(Some(expires_at));
+        self.write_point(entry, options)
+    }
+
+    /// Loads `entries`, strictly sorted by [`Options::key_comparator`], straight into new SSTables of about
+    /// [`Options::bulk_load_table_size`] bytes, without going through the append log.
+    ///
+    /// The tables go below the existing ones: writes made before or after the load win over loaded entries, and so do
+    /// deletions as long as compaction kept their tombstone. Meant for filling a new store. Nothing is loaded if the
+    /// keys aren't sorted, see [`Error::UnsortedBulkLoad`].
+    pub fn bulk_load(
+        &self,
+        entries: impl Iterator<Item = (Key, Option<Value>)>,
+    ) -> Result<(), Error> {
+        self.check_writable()?;
+
+        let mut tables = Vec::new();
+        // Older than any write, the tables go below the existing ones
+        let written = self.write_bulk_tables(entries, 0, &mut tables);
+        self.register_bulk_tables(tables, written, false)
+    }
+
+    /// Writes the live entries of a snapshot to `writer` as a single stream, sorted by key, that
+    /// [`KVStorage::import`] reads back into this store or another one with the same [`Options::key_comparator`].
+    /// Expirations aren't kept.
+    ///
+    /// The stream is buffered internally. It ends with a frame counting the entries, which isn't written if reading
+    /// the snapshot fails.
+    pub fn export(&self, writer: impl Write) -> Result<ExportSummary, Error> {
+        let snapshot = self.snapshot()?;
+
+        backup::write_export(snapshot.iter(), writer)
+    }
+
+    /// Loads a stream written by [`KVStorage::export`] straight into new SSTables, as [`KVStorage::bulk_load`] does,
+    /// except that the imported entries are newer than the ones already in the store, which `mode` decides about.
+    ///
+    /// The tables are only added to the store once the whole stream was read: nothing is imported if it's cut short
+    /// or reading it fails. Writes made while importing may end up older than the imported entries.
+    pub fn import(&self, reader: impl Read, mode: ImportMode) -> Result<ImportSummary, Error> {
+        self.check_writable()?;
+
+        let mut stream = ExportReader::new(reader)?;
+        // The log would shadow the tables, whatever their sequence
+        self.flush()?;
+        let existing = match mode {
+            ImportMode::Replace => None,
+            ImportMode::Preserve => Some(self.snapshot()?),
+        };
+
+        let mut summary = ImportSummary::default();
+        let mut failure = None;
+        let entries = std::iter::from_fn(|| {
+            loop {
+                let (key, value) = match stream.next()? {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        failure = Some(e);
+                        return None;
+                    }
+                };
+                match existing.as_ref().map(|snapshot| snapshot.read(&key)) {
+                    Some(Ok(Some(_))) => summary.skipped += 1,
+                    Some(Err(e)) => {
+                        failure = Some(e);
+                        return None;
+                    }
+                    Some(Ok(None)) | None => {
+                        summary.imported += 1;
+                        return Some((key, Some(value)));
+                    }
+                }
+            }
+        });
+
+        let mut tables = Vec::new();
+        let sequence = self.inner.append_log.take_sequence();
+        let written = self.write_bulk_tables(entries, sequence, &mut tables);
+        let written = match failure {
+            Some(e) => Err(e),
+            None => written,
+        };
+        summary.entries = stream.entries();
+        self.register_bulk_tables(tables, written, true)?;
+
+        Ok(summary)
+    }
+
+    /// Adds the tables staged by [`KVStorage::write_bulk_tables`] to the store with a single manifest update, newer
+    /// than the existing ones if `newest`. They are deleted instead if writing them, or the update, failed.
+    fn register_bulk_tables(
+        &self,
+        tables: Vec<Arc<SSTable>>,
+        written: Result<Option<(Key, Key)>, Error>,
+        newest: bool,
+    ) -> Result<(), Error> {
+        let key_range = match written {
+            Ok(Some(key_range)) => key_range,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                for table in tables {
+                    self.inner.workers.reaper.delete(table);
+                }
+                return Err(e);
+            }
+        };
+
+        let mut sstables = self.inner.sstables.lock().expect("sstables lock poisoned");
+        let new_state: Vec<_> = if newest {
+            tables.iter().chain(sstables.iter()).cloned().collect()
+        } else {
+            sstables.iter().chain(&tables).cloned().collect()
+        };
+        if let Err(e) = self.inner.manifest.update(|data| {
+            data.sstables = new_state.iter().map(|t| t.id()).collect();
+        }) {
+            drop(sstables);
+            for table in tables {
+                self.inner.workers.reaper.delete(table);
+            }
+            return Err(e);
+        }
+        sstables.set(new_state);
+        drop(sstables);
+        self.inner
+            .quota
+            .add_table(tables.iter().map(|t| t.file_size()).sum());
+
+        let (first, last) = key_range;
+        self.inner
+            .cache
+            .invalidate_range(first, last, self.inner.table_files.order());
+        self.inner.cache.invalidate(&last);
+        // The loaded keys were added without going through the writes
+        self.inner.negative_cache.clear();
+        self.inner.compaction_manager.signal_sstable_inserted();
+
+        Ok(())
+    }
+
+    /// Writes the tables of [`KVStorage::bulk_load`] into `tables`, with entries numbered `sequence`, returning the
+    /// smallest and largest key loaded
+    fn write_bulk_tables(
+        &self,
+        entries: impl Iterator<Item = (Key, Option<Value>)>,
+        sequence: u64,
+        tables: &mut Vec<Arc<SSTable>>,
+    ) -> Result<Option<(Key, Key)>, Error> {
+        let table_options = TableOptions::from(&self.inner.options);
+        let mut key_range: Option<(Key, Key)> = None;
+        let mut chunk = Vec::new();
+        let mut chunk_bytes = 0;
+
+        for (position, (key, value)) in entries.enumerate() {
+            if let Some((_, last)) = &mut key_range {
+                if !self.inner.table_files.order().lt(last, &key) {
+                    return Err(Error::UnsortedBulkLoad {
+                        position: position as u64,
+                    });
+                }
+                *last = key;
+            } else {
+                key_range = Some((key, key));
+            }
+
+            let entry = KVMemoryRepr::new(key, value, sequence);
+            chunk_bytes += serialization::serialize(&entry, RecordFormat::CURRENT)?.len() as u64;
+            chunk.push(entry);
+            self.inner.manifest.distinct_keys().add(&key);
+
+            if chunk_bytes >= self.inner.options.bulk_load_table_size {
+                let table = sstables::write_table(&self.inner.table_files, &chunk, table_options)?;
+                tables.push(Arc::new(table));
+                self.inner
+                    .quota
+                    .check_tables(tables.iter().map(|t| t.file_size()).sum())?;
+                chunk.clear();
+                chunk_bytes = 0;
+            }
+        }
+
+        if !chunk.is_empty() {
+            let table = sstables::write_table(&self.inner.table_files, &chunk, table_options)?;
+            tables.push(Arc::new(table));
+            self.inner
+                .quota
+                .check_tables(tables.iter().map(|t| t.file_size()).sum())?;
+        }
+
+        Ok(key_range)
+    }
+
+    /// Deletes every key in `start..end`, in the order of [`Options::key_comparator`], with a single range tombstone
+    pub fn delete_range(&self, start: Key, end: Key) -> Result<(), Error> {
+        self.check_writable()?;
+
+        let order = self.inner.table_files.order();
+        if !order.lt(&start, &end) {
+            return Ok(());
+        }
+
+        let written = self.inner.append_log.write_entry(
+            KVMemoryRepr::range_tombstone(start, end, 0),
+            &self.inner.sstables,
+            &self.inner.manifest,
+            &self.inner.compaction_manager,
+            &self.inner.changes,
+            Durability::Buffered,
+        );
+        if matches!(written, Ok(()) | Err(Error::NotDurable(_))) {
+            self.inner.cache.invalidate_range(start, end, order);
+        }
+        self.record_sync_failure(&written);
+
+        written
+    }
+
+    fn check_writable(&self) -> Result<(), Error> {
+        if self.inner.read_only {
+            return Err(Error::ReadOnly);
+        }
+
+        self.inner.table_files.check_available()
+    }
+
+    /// Writes `new` only if the current value of `key` is `expected`, otherwise returns the current value.
+    ///
+    /// Atomic with respect to other conditional writes on the same key.
+    pub fn compare_and_swap(
+        &self,
+        key: Key,
+        expected: Option<Value>,
+        new: Option<Value>,
+    ) -> Result<Result<(), Option<Value>>, Error> {
+        let _key_guard = self.inner.append_log.lock_key(&key);
+
+        let current = self.read(&key)?;
+        if current != expected {
+            return Ok(Err(current));
+        }
+
+        self.write(key, new)?;
+
+        Ok(Ok(()))
+    }
+
+    /// Replaces the value of `key` with the result of `f` (`None` deletes the key), returning the new value.
+    ///
+    /// Conditional writes ([`KVStorage::update`], [`KVStorage::compare_and_swap`]) on the same key are serialized,
+    /// while plain [`KVStorage::write`] calls don't wait for them: a concurrent plain write may land between the read and the write of `f`'s result,
+    /// and would then be overwritten.
+    pub fn update(
+        &self,
+        key: Key,
+        f: impl FnOnce(Option<Value>) -> Option<Value>,
+    ) -> Result<Option<Value>, Error> {
+        let _key_guard = self.inner.append_log.lock_key(&key);
+
+        let new = f(self.read(&key)?);
+        self.write(key, new)?;
+
+        Ok(new)
+    }
+
+    /// Adds `delta` to the value of `key`, returning the new value. Missing keys count as `0`.
+    pub fn increment(&self, key: Key, delta: u64) -> Result<u64, Error> {
+        self.increment_with(key, delta, &IncrementOptions::default())
+    }
+
+    pub fn increment_with(
+        &self,
+        key: Key,
+        delta: u64,
+        options: &IncrementOptions,
+    ) -> Result<u64, Error> {
+        self.apply_delta(key, options, |v| {
+            v.checked_add(delta)
+                .or(options.saturating.then_some(u64::MAX))
+        })
+    }
+
+    /// Subtracts `delta` from the value of `key`, returning the new value. Missing keys count as `0`.
+    pub fn decrement(&self, key: Key, delta: u64) -> Result<u64, Error> {
+        self.decrement_with(key, delta, &IncrementOptions::default())
+    }
+
+    pub fn decrement_with(
+        &self,
+        key: Key,
+        delta: u64,
+        options: &IncrementOptions,
+    ) -> Result<u64, Error> {
+        self.apply_delta(key, options, |v| {
+            v.checked_sub(delta).or(options.saturating.then_some(0))
+        })
+    }
+
+    /// Same locking as [`KVStorage::update`], `op` returns `None` on overflow
+    fn apply_delta(
+        &self,
+        key: Key,
+        options: &IncrementOptions,
+        op: impl FnOnce(u64) -> Option<u64>,
+    ) -> Result<u64, Error> {
+        let _key_guard = self.inner.append_log.lock_key(&key);
+
+        let current = self.read(&key)?.unwrap_or(options.default);
+        let new = op(current).ok_or(Error::Overflow)?;
+        self.write(key, Some(new))?;
+
+        Ok(new)
+    }
+
+    pub fn read(&self, key: &Key) -> Result<Option<Value>, Error> {
+        self.read_with_trace(key).map(|(value, _)| value)
+    }
+
+    /// Same as [`KVStorage::read`], also describing how deep the read went. See [`ReadTrace`]
+    pub fn read_with_trace(&self, key: &Key) -> Result<(Option<Value>, ReadTrace), Error> {
+        let mut trace = ReadTrace::default();
+        let read = self.read_traced(key, &mut trace, false)?;
+        self.inner.stats.record_read(&trace);
+
+        Ok((read.outcome.value(), trace))
+    }
+
+    /// Same as [`KVStorage::read`], telling a deleted key from one that was never written, and when the answer was
+    /// written with [`Options::write_timestamps`].
+    ///
+    /// Compaction drops the tombstones merged into the oldest table, keys deleted long ago are then
+    /// [`ReadOutcome::NotFound`] too.
+    pub fn read_detailed(&self, key: &Key) -> Result<ReadDetails, Error> {
+        let mut trace = ReadTrace::default();
+        let read = self.read_traced(key, &mut trace, true)?;
+        self.inner.stats.record_read(&trace);
+
+        Ok(read)
+    }
+
+    /// With `detailed` unset, deleted keys may be reported as [`ReadOutcome::NotFound`], and answers from the cache
+    /// have no write time
+    fn read_traced(
+        &self,
+        key: &Key,
+        trace: &mut ReadTrace,
+        detailed: bool,
+    ) -> Result<ReadDetails, Error> {
+        increment_counter!("kv_reads_total", 1);
+
+        let now = self.inner.options.clock.now_millis();
+        let cache_ticket = self.inner.cache.ticket(key);
+        let negative_ticket = self.inner.negative_cache.ticket(key);
+        let answer = |outcome| ReadDetails {
+            outcome,
+            written_at: None,
+        };
+
+        if let Some(entry) = self.inner.append_log.find_key(key)? {
+            trace.memtable_hit = true;
+            return Ok(ReadDetails {
+                outcome: entry.read_outcome(now),
+                written_at: entry.written_at(),
+            });
+        }
+
+        // The cache keeps no write times
+        let write_times = detailed && self.inner.options.write_timestamps;
+        if self.inner.cache.is_enabled() && !write_times {
+            // The cache keeps no difference between deleted and missing keys
+            match self.inner.cache.get(key, now) {
+                Some(Some(value)) => {
+                    self.inner.stats.cache_hits.fetch_add(1, Ordering::Relaxed);
+                    trace.cache_hit = true;
+                    return Ok(answer(ReadOutcome::Found(value)));
+                }
+                Some(None) if !detailed => {
+                    self.inner.stats.cache_hits.fetch_add(1, Ordering::Relaxed);
+                    trace.cache_hit = true;
+                    return Ok(answer(ReadOutcome::NotFound));
+                }
+                _ => {
+                    self.inner
+                        .stats
+                        .cache_misses
+                        .fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+
+        // A range deleting the key since it was cached doesn't invalidate it, only detailed reads tell the difference
+        if !detailed && self.inner.negative_cache.contains(key) {
+            self.inner
+                .stats
+                .negative_cache_hits
+                .fetch_add(1, Ordering::Relaxed);
+            return Ok(answer(ReadOutcome::NotFound));
+        }
+
+        // The current list is shared, not copied. Tables are immutable: a merge publishes a new list, the tables of
+        // this one stay readable until it's dropped
+        let current_sstables_state = self.inner.table_view.load();
+
+        // The tables in key range are probed all at once, then visited in order like sequential lookups so that
+        // newer tables win
+        let mut probes = self.inner.workers.probe_pool.as_ref().and_then(|pool| {
+            let tables: Vec<_> = current_sstables_state
+                .iter()
+                .filter(|sstable| sstable.in_key_range(key))
+                .cloned()
+                .collect();
+            if tables.iter().filter(|t| t.may_contain(key)).count() < 2 {
+                return None;
+            }
+
+            let probes = pool.probe(*key, &tables);
+            for (_, probe_trace) in probes.iter().flatten() {
+                trace.add_probe(probe_trace);
+            }
+            Some(probes.into_iter())
+        });
+
+        // Start scanning SSTables in order
+        let mut lookup = KeyLookup::default();
+        // Sequence and table of the newest entry found, the one answering. Not a list, table reads don't allocate
+        let mut newest: Option<(u64, u64)> = None;
+        for sstable in current_sstables_state.iter() {
+            if !sstable.in_key_range(key) {
+                self.inner
+                    .stats
+                    .sstables_skipped_by_key_range
+                    .fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+
+            let probe = probes.as_mut().and_then(Iterator::next);
+            if lookup.shadows(sstable) {
+                continue;
+            }
+
+            trace.sstables_probed += 1;
+            let entry = match probe {
+                Some(probe) => probe.map(|(entry, _)| entry),
+                None => sstable.find_entry_traced(key, trace),
+            };
+            let entry = match entry {
+                Err(Error::TableMissing { id, .. }) if self.inner.options.skip_missing_tables => {
+                    self.drop_missing_table(id);
+                    continue;
+                }
+                entry => entry?,
+            };
+            if let Some(entry) = &entry {
+                debug_assert!(
+                    (sstable.min_sequence()..=sstable.max_sequence()).contains(&entry.sequence()),
+                    "entry outside the sequences of table {}",
+                    sstable.id()
+                );
+                if newest.is_none_or(|(sequence, _)| sequence < entry.sequence()) {
+                    newest = Some((entry.sequence(), sstable.id()));
+                }
+            }
+            if lookup.visit(entry) {
+                break;
+            }
+        }
+        record_histogram!("kv_read_sstables_probed", trace.sstables_probed as f64);
+
+        let Some(entry) = lookup.finish() else {
+            self.inner.cache.insert(*key, None, None, cache_ticket);
+            self.inner.negative_cache.insert(key, negative_ticket);
+            return Ok(answer(ReadOutcome::NotFound));
+        };
+        trace.table = newest
+            .filter(|(sequence, _)| *sequence == entry.sequence())
+            .map(|(_, id)| id);
+
+        self.inner
+            .cache
+            .insert(*key, *entry.value(), entry.expires_at(), cache_ticket);
+
+        Ok(ReadDetails {
+            outcome: entry.read_outcome(now),
+            written_at: entry.written_at(),
+        })
+    }
+
+    /// Reads many keys at once, returning the values in the same order as `keys`.
+    ///
+    /// Cheaper than repeated [`KVStorage::read`] calls since every SSTable is visited only once.
+    pub fn multi_get(&self, keys: &[Key]) -> Result<Vec<Option<Value>>, Error> {
+        increment_counter!("kv_reads_total", keys.len() as u64);
+
+        let mut results = vec![None; keys.len()];
+        // Indexes (into `keys`) of the keys without an answer yet
+        let mut pending = Vec::new();
+        let now = self.inner.options.clock.now_millis();
+
+        for (i, res) in self
+            .inner
+            .append_log
+            .find_keys(keys, now)?
+            .into_iter()
+            .enumerate()
+        {
+            match res {
+                ReadOutcome::Found(value) => results[i] = Some(value),
+                ReadOutcome::Deleted => {}
+                ReadOutcome::NotFound => pending.push((i, KeyLookup::default())),
+            }
+        }
+
+        let current_sstables_state = self.inner.table_view.load();
+
+        for sstable in current_sstables_state.iter() {
+            if pending.is_empty() {
+                break;
+            }
+
+            let pending_keys: Vec<_> = pending.iter().map(|(i, _)| keys[*i]).collect();
+            let found = match sstable.find_entries(&pending_keys) {
+                Err(Error::TableMissing { id, .. }) if self.inner.options.skip_missing_tables => {
+                    self.drop_missing_table(id);
+                    continue;
+                }
+                found => found?,
+            };
+
+            let mut still_pending = Vec::new();
+            for ((i, mut lookup), entry) in pending.into_iter().zip(found) {
+                if lookup.visit(entry) {
+                    results[i] = lookup_value(lookup, now);
+                } else {
+                    still_pending.push((i, lookup));
+                }
+            }
+            pending = still_pending;
+        }
+
+        for (i, lookup) in pending {
+            results[i] = lookup_value(lookup, now);
+        }
+
+        Ok(results)
+    }
+
+    /// Removes table `id`, whose file is gone, from the live tables so that reads go on with the older ones, see
+    /// [`Options::skip_missing_tables`]
+    fn drop_missing_table(&self, id: u64) {
+        let mut sstables = self.inner.sstables.lock().expect("sstables lock poisoned");
+        // Dropped already by a concurrent read
+        let Some(missing) = sstables.iter().find(|t| t.id() == id).cloned() else {
+            return;
+        };
+        let new_state: Vec<_> = sstables.iter().filter(|t| t.id() != id).cloned().collect();
+        if let Err(e) = self.inner.manifest.update(|data| {
+            data.sstables = new_state.iter().map(|t| t.id()).collect();
+            data.cold_sstables.retain(|cold| *cold != id);
+        }) {
+            log::error!("failed to drop missing table {id} from the manifest: {e:?}");
+            return;
+        }
+        sstables.set(new_state);
+        drop(sstables);
+        self.inner.table_files.remove_cold(&[id]);
+        self.inner.quota.remove_table(missing.file_size());
+
+        log::error!(
+            "dropped table {id} whose file is gone, reads may now return older values of its keys"
+        );
+    }
+
+    /// Cheap estimate of the number of live keys, computed from counters without any I/O.
+    ///
+    /// Tombstones are subtracted, but keys overwritten across the log and different tables are counted more than once,
+    /// so the estimate is usually too high until compaction catches up. Use [`KVStorage::count`] for the exact value.
+    pub fn approximate_len(&self) -> u64 {
+        let sstables = self.inner.sstables.lock().expect("sstables lock poisoned");
+        let in_tables: u64 = sstables
+            .iter()
+            .map(|t| t.entry_count() - t.tombstone_count())
+            .sum();
+
+        in_tables + self.inner.append_log.entry_count() as u64
+    }
+
+    /// Rough number of entries and bytes stored for the keys in `start..end`, out of the in-memory table indexes
+    /// and the append log, without any I/O.
+    ///
+    /// Overwritten values and tombstones not compacted away yet are counted. For keys spread evenly, the estimate is
+    /// within a factor of 2 of the entries stored in ranges covering a few blocks; ranges within a single block are
+    /// rougher.
+    pub fn approximate_size(&self, start: Key, end: Key) -> RangeEstimate {
+        let mut estimate = self.inner.append_log.approximate_range(start, end);
+
+        for sstable in self.inner.table_view.load().iter() {
+            estimate.add(sstable.approximate_range(start, end));
+        }
+
+        estimate
+    }
+
+    /// Exact number of live keys. Scans the whole database, see [`KVStorage::approximate_len`] for a cheap estimate
+    pub fn count(&self) -> Result<u64, Error> {
+        let mut iter = self.iter()?;
+        let count = iter.by_ref().count() as u64;
+
+        match iter.into_error() {
+            Some(e) => Err(e),
+            None => Ok(count),
+        }
+    }
+
+    /// Whether there are no live keys, stops at the first one found
+    pub fn is_empty(&self) -> Result<bool, Error> {
+        let mut iter = self.iter()?;
+        let empty = iter.next().is_none();
+
+        match iter.into_error() {
+            Some(e) => Err(e),
+            None => Ok(empty),
+        }
+    }
+
+    /// Iterates over the database as of now, see [`KvIter`]
+    pub fn iter(&self) -> Result<KvIter, Error> {
+        Ok(self.snapshot()?.iter())
+    }
+
+    /// A page of the live entries from `start`, and the key the next one starts from, see [`Snapshot::scan_page`].
+    ///
+    /// Every page reads the database as of its call: writes and compactions between pages are seen by the next ones.
+    /// Page through a [`KVStorage::snapshot`] for a consistent view
+    pub fn scan_page(&self, start: Bound<Key>, limit: usize) -> Result<Page, Error> {
+        self.snapshot()?.scan_page(start, limit)
+    }
+
+    /// Returns a consistent view of the database as of now, see [`Snapshot`]
+    pub fn snapshot(&self) -> Result<Snapshot, Error> {
+        self.inner.append_log.snapshot(
+            &self.inner.sstables,
+            self.inner.options.clock.now_millis(),
+            self.inner.table_files.order(),
+        )
+    }
+
+    /// Receives every write committed from now on, see [`ChangeReceiver`].
+    ///
+    /// The buffer of the receiver is bounded, see [`Options::subscriber_capacity`].
+    pub fn subscribe(&self) -> ChangeReceiver {
+        self.inner.changes.subscribe()
+    }
+
+    /// Follows the value of `key`, see [`WatchHandle`]. Watching a missing key is fine, the first write notifies it.
+    ///
+    /// Waiters are woken once a write is durable (as configured) and before it can be read.
+    pub fn watch(&self, key: Key) -> Result<WatchHandle, Error> {
+        let handle = self.inner.changes.watch(key);
+        // Registered first, so that a write in between is either read here or notified
+        handle.init(self.read(&key)?);
+
+        Ok(handle)
+    }
+
+    /// Stored writes with a sequence number above `sequence`, in sequence order, to catch up before following
+    /// [`KVStorage::subscribe`] (subscribe first, then skip the changes already returned here).
+    ///
+    /// Compaction only keeps the latest write of every key: overwritten values are missing,
+    /// and so are deletions once merged into the oldest table.
+    pub fn changes_since(&self, sequence: u64) -> Result<Vec<Change>, Error> {
+        let (mut entries, sstables) = self
+            .inner
+            .append_log
+            .entries_since(sequence, &self.inner.sstables)?;
+
+        for table in sstables.iter().filter(|t| t.max_sequence() > sequence) {
+            let table_entries = table.entries()?;
+            entries.extend(
+                table_entries
+                    .into_iter()
+                    .filter(|e| e.sequence() > sequence),
+            );
+        }
+        entries.sort_by_key(|e| e.sequence());
+        serialization::dedup_range_copies(&mut entries);
+
+        Ok(entries.iter().map(Change::from).collect())
+    }
+}
+
+/// Value found by `lookup`, as seen at `now`
+fn lookup_value(lookup: KeyLookup, now: u64) -> Option<Value> {
+    match lookup.finish()?.read_outcome(now) {
+        ReadOutcome::Found(value) => Some(value),
+        _ => None,
+    }
+}
+
+/// Directory of the cold tables: the one in `options`, or the one the manifest recorded when there's none
+fn cold_storage_dir(options: &Options, manifest: &ManifestData) -> Result<Option<PathBuf>, Error> {
+    let recorded = manifest.cold_storage_dir.as_ref().map(PathBuf::from);
+
+    match (&options.cold_storage_dir, recorded) {
+        (Some(dir), Some(recorded)) if *dir != recorded && !manifest.cold_sstables.is_empty() => {
+            Err(Error::ColdStorageDirChanged)
+        }
+        (Some(dir), _) => Ok(Some(dir.clone())),
+        (None, recorded) => Ok(recorded),
+    }
+}
+
+/// Completes the sketch of the distinct keys loaded with `manifest` with the keys of the logs, written since it was
+/// saved. Databases created before the sketch get one out of every table
+fn sketch_keys(
+    manifest: &Manifest,
+    manifest_data: &ManifestData,
+    append_log: &ShardedAppendLog,
+    sstables: &Mutex<TableList>,
+) -> Result<(), Error> {
+    let distinct_keys = manifest.distinct_keys();
+    let (entries, tables) = append_log.entries_since(0, sstables)?;
+
+    if manifest_data.distinct_keys.is_empty() {
+        for table in &tables {
+            for block in 0..table.block_count() {
+                for entry in table.read_block(block)? {
+                    if !entry.is_range_tombstone() {
+                        distinct_keys.add(entry.key());
+                    }
+                }
+            }
+        }
+    }
+    for entry in entries.iter().filter(|entry| !entry.is_range_tombstone()) {
+        distinct_keys.add(entry.key());
+    }
+
+    Ok(())
+}
+
+/// Fails unless the database was created with the comparator of `options`
+fn check_comparator(options: &Options, manifest: &ManifestData) -> Result<(), Error> {
+    let configured = options.key_comparator.name();
+    let stored = manifest.comparator.as_deref().unwrap_or(Ascending.name());
+    if stored != configured {
+        return Err(Error::ComparatorMismatch {
+            stored: stored.to_owned(),
+            configured: configured.to_owned(),
+        });
+    }
+
+    Ok(())
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        // Before the store, they run on its threads
+        drop(std::mem::take(
+            self.namespaces.get_mut().expect("namespaces lock poisoned"),
+        ));
+        self.compaction_manager.stop();
+        self.append_log.stop_flusher();
+        self.workers.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use append_log::LOG_FILE_PREFIX;
+    use failpoints::FailAction;
+    use manifest::MANIFEST_NAME;
+    use std::collections::BTreeMap;
+    use std::fs;
+
+    const COMPACTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// A directory under `./test-dbs`, removed with everything in it when dropped
+    struct TestDir(String);
+
+    impl TestDir {
+        fn path(&self) -> &Path {
+            Path::new(&self.0)
+        }
+    }
+
+    impl std::ops::Deref for TestDir {
+        type Target = str;
+
+        fn deref(&self) -> &str {
+            &self.0
+        }
+    }
+
+    impl AsRef<std::ffi::OsStr> for TestDir {
+        fn as_ref(&self) -> &std::ffi::OsStr {
+            self.0.as_ref()
+        }
+    }
+
+    impl AsRef<Path> for TestDir {
+        fn as_ref(&self) -> &Path {
+            self.path()
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn test_location() -> TestDir {
+        let location = format!("./test-dbs/{}", rand::random::<u64>());
+        fs::create_dir_all(&location).unwrap();
+        TestDir(location)
+    }
+
+    #[test]
+    fn test_everything() {
+        let kv = KVStorage::new_in_memory().unwrap();
+        kv.write(1, Some(10)).unwrap();
+        assert_eq!(kv.read(&1).unwrap(), Some(10));
+        kv.write(1, Some(20)).unwrap();
+        assert_eq!(kv.read(&1).unwrap(), Some(20));
+        kv.write(2, None).unwrap();
+        assert_eq!(kv.read(&2).unwrap(), None);
+        assert_eq!(kv.read(&99).unwrap(), None);
+    }
+
+    #[test]
+    fn test_close_and_reopen() {
+        let location = test_location();
+
+        let kv = KVStorage::new(&location).unwrap();
+        // Enough writes to rotate the log a few times
+        for key in 0..30000 {
+            kv.write(key, Some(key * 2)).unwrap();
+        }
+        kv.write(7, None).unwrap();
+        kv.close().unwrap();
+
+        let kv = KVStorage::open(&location).unwrap();
+        assert_eq!(kv.read(&7).unwrap(), None);
+        for key in (0..30000).filter(|k| *k != 7) {
+            assert_eq!(kv.read(&key).unwrap(), Some(key * 2));
+        }
+
+        // Unflushed writes are replayed from the log on open
+        kv.write(1, Some(1)).unwrap();
+        drop(kv);
+        let kv = KVStorage::open(&location).unwrap();
+        assert_eq!(kv.read(&1).unwrap(), Some(1));
+    }
+
+    #[cfg(all(feature = "uring", target_os = "linux"))]
+    #[test]
+    fn test_uring_backend() {
+        let location = test_location();
+        let options = Options::new().io_backend(IoBackend::Uring).write_shards(2);
+
+        let kv = KVStorage::new_with_options(&location, options.clone()).unwrap();
+        for key in 0..30000 {
+            kv.write(key, Some(key * 2)).unwrap();
+        }
+        kv.write(7, None).unwrap();
+        kv.close().unwrap();
+
+        let kv = KVStorage::open_with_options(&location, options).unwrap();
+        for key in (0..30000).filter(|k| *k != 7) {
+            assert_eq!(kv.read(&key).unwrap(), Some(key * 2));
+        }
+        assert_eq!(
+            kv.multi_get(&[7, 8, 40000]).unwrap(),
+            [None, Some(16), None]
+        );
+        assert!(kv.verify().is_ok());
+    }
+
+    #[test]
+    fn test_drop_stops_compactor() {
+        let kv = KVStorage::new_in_memory().unwrap();
+        let activity = kv.inner.compaction_manager.activity.clone();
+        for key in 0..60000 {
+            kv.write(key, Some(key)).unwrap();
+        }
+        drop(kv);
+
+        // Joined, no compaction thread is left holding on to the activity
+        assert!(!activity.is_running());
+        assert_eq!(Arc::strong_count(&activity), 1);
+    }
+
+    #[test]
+    fn test_cache_never_stale() {
+        const THREADS: u64 = 4;
+
+        let location = test_location();
+        let options = Options::new().cache_capacity(16);
+        let kv = KVStorage::new_with_options(&location, options).unwrap();
+
+        // Every thread owns 10 keys, so it must always read back its own latest write.
+        // A reader keeps filling the cache with all of them meanwhile
+        let stop = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let reader = {
+            let kv = kv.clone();
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    for key in 0..THREADS * 10 {
+                        kv.read(&key).unwrap();
+                    }
+                }
+            })
+        };
+
+        let writers: Vec<_> = (0..THREADS)
+            .map(|thread| {
+                let kv = kv.clone();
+                std::thread::spawn(move || {
+                    for i in 0..5000 {
+                        let key = thread * 10 + i % 10;
+                        let value = (i % 7 != 0).then_some(i);
+                        kv.write(key, value).unwrap();
+                        assert_eq!(kv.read(&key).unwrap(), value);
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        stop.store(true, Ordering::Relaxed);
+        reader.join().unwrap();
+
+        // Once out of the log, repeated reads are served by the cache
+        kv.inner
+            .append_log
+            .flush(&kv.inner.sstables, &kv.inner.manifest)
+            .unwrap();
+        let value = kv.read(&1).unwrap();
+        let before = kv.stats();
+        assert_eq!(kv.read(&1).unwrap(), value);
+        assert!(kv.stats().cache_hits > before.cache_hits);
+        assert!(kv.stats().cache_hit_rate() > 0.0);
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let location = test_location();
+        let checkpoint_location = Path::new(&location).join("checkpoint");
+
+        let kv = KVStorage::new(&location).unwrap();
+        for key in 0..20000 {
+            kv.write(key, Some(key)).unwrap();
+        }
+        kv.write(3, None).unwrap();
+        kv.checkpoint(&checkpoint_location).unwrap();
+
+        for key in 0..30000 {
+            kv.write(key, Some(key + 1)).unwrap();
+        }
+        assert!(kv.checkpoint(&checkpoint_location).is_err());
+
+        let checkpoint = KVStorage::open(checkpoint_location.to_str().unwrap()).unwrap();
+        let report = checkpoint.verify().unwrap();
+        assert!(report.is_ok());
+        assert!(report.entry_count() >= 20000);
+        assert_eq!(checkpoint.read(&3).unwrap(), None);
+        for key in (0..20000).filter(|k| *k != 3) {
+            assert_eq!(checkpoint.read(&key).unwrap(), Some(key));
+        }
+        assert_eq!(checkpoint.read(&25000).unwrap(), None);
+        assert_eq!(kv.read(&25000).unwrap(), Some(25001));
+    }
+
+    #[test]
+    fn test_dump() {
+        let location = test_location();
+        let options = Options::new()
+            .write_shards(1)
+            .clock(Arc::new(ManualClock::default()));
+
+        let kv = KVStorage::new_with_options(&location, options.clone()).unwrap();
+        for key in 1..=3 {
+            kv.write(key, Some(key * 10)).unwrap();
+        }
+        kv.delete_range(10, 20).unwrap();
+        kv.close().unwrap();
+
+        let kv = KVStorage::open_with_options(&location, options).unwrap();
+        kv.write(2, None).unwrap();
+        kv.write_with_ttl(4, 40, Duration::from_secs(1), &WriteOptions::default())
+            .unwrap();
+
+        let dump = kv.dump(true).unwrap();
+        // The size of the bloom filter section is up to the bloom filter crate
+        let file_size = fs::metadata(&dump.tables[0].path).unwrap().len();
+        let expected = format!(
+            "table {}: {file_size} bytes, 3 entries, keys 1..=19
+  block 0 at 0, 62 bytes, first key 1
+  #1 1 = 10
+  #2 2 = 20
+  #3 3 = 30
+  #4 10..20 deleted
+log 0 {}: 2 entries
+  16: #5 2 deleted
+  26: #6 4 = 40 (expires at 1000)
+",
+            dump.tables[0].id, dump.logs[0].file_name
+        );
+        assert_eq!(dump.to_string(), expected);
+        assert!(kv.dump(false).unwrap().tables[0].entries.is_none());
+    }
+
+    #[test]
+    fn test_discarded_entries() {
+        let location = test_location();
+        let options =
+            Options::new()
+                .write_shards(1)
+                .compaction_policy(CompactionPolicy::SizeTiered {
+                    ratio: 2.0,
+                    min_merge: 2,
+                });
+
+        let kv = KVStorage::new_with_options(&location, options.clone()).unwrap();
+        for value in 0..2 {
+            for key in 0..10 {
+                kv.write(key, Some(value)).unwrap();
+            }
+            kv.inner
+                .append_log
+                .flush(&kv.inner.sstables, &kv.inner.manifest)
+                .unwrap();
+        }
+        assert!(kv.wait_for_pending_compactions(COMPACTION_TIMEOUT).unwrap());
+        kv.close().unwrap();
+
+        // Persisted in the footer of the merged table
+        let kv = KVStorage::open_with_options(&location, options).unwrap();
+        let table = &kv.dump(false).unwrap().tables[0];
+        assert_eq!((table.entry_count, table.discarded_entries), (10, 10));
+        assert!(table.to_string().contains("10 discarded by compaction"));
+    }
+
+    #[test]
+    fn test_create_modes() {
+        let open = |location: &str, mode| {
+            KVStorage::open_with_mode(location, Options::new(), mode).map(|kv| kv.close().unwrap())
+        };
+        let empty = || test_location();
+        let valid = || {
+            let location = test_location();
+            KVStorage::new(&location).unwrap().close().unwrap();
+            location
+        };
+        let foreign = || {
+            let location = test_location();
+            fs::create_dir(Path::new(&location).join("db")).unwrap();
+            fs::write(Path::new(&location).join("db/notes.txt"), b"unrelated").unwrap();
+            location
+        };
+
+        assert!(open(&empty(), CreateMode::CreateNew).is_ok());
+        assert!(matches!(
+            open(&valid(), CreateMode::CreateNew),
+            Err(Error::DatabaseExists)
+        ));
+        assert!(matches!(
+            open(&empty(), CreateMode::OpenExisting),
+            Err(Error::InvalidDbLocation)
+        ));
+        assert!(open(&valid(), CreateMode::OpenExisting).is_ok());
+        assert!(open(&empty(), CreateMode::OpenOrCreate).is_ok());
+        assert!(open(&valid(), CreateMode::OpenOrCreate).is_ok());
+        for mode in [
+            CreateMode::CreateNew,
+            CreateMode::OpenExisting,
+            CreateMode::OpenOrCreate,
+        ] {
+            assert!(matches!(open(&foreign(), mode), Err(Error::NotADatabase)));
+        }
+
+        // An empty `db/` directory is used as it is
+        let location = empty();
+        fs::create_dir(Path::new(&location).join("db")).unwrap();
+        assert!(open(&location, CreateMode::CreateNew).is_ok());
+        assert!(open(&location, CreateMode::OpenExisting).is_ok());
+    }
+
+    #[test]
+    fn test_identity_file() {
+        let location = test_location();
+        let kv = KVStorage::new(&location).unwrap();
+        kv.write(1, Some(1)).unwrap();
+        kv.close().unwrap();
+        let db_dir = Path::new(&location).join("db");
+        let identity = db_dir.join(identity::IDENTITY_NAME);
+
+        fs::write(&identity, b"key-value-store 99\n").unwrap();
+        assert!(matches!(
+            KVStorage::open(&location),
+            Err(Error::LayoutTooNew {
+                found: 99,
+                supported: identity::LAYOUT_VERSION
+            })
+        ));
+        fs::write(&identity, b"other-store 1\n").unwrap();
+        assert!(matches!(
+            KVStorage::open(&location),
+            Err(Error::NotADatabase)
+        ));
+
+        // Databases created before the identity files get one when opened
+        fs::remove_file(&identity).unwrap();
+        let kv = KVStorage::open_read_only(&location).unwrap();
+        assert_eq!(kv.read(&1).unwrap(), Some(1));
+        drop(kv);
+        assert!(!identity.exists());
+        let kv = KVStorage::open(&location).unwrap();
+        assert_eq!(kv.read(&1).unwrap(), Some(1));
+        kv.close().unwrap();
+        assert!(identity.exists());
+
+        fs::remove_file(&identity).unwrap();
+        fs::remove_file(db_dir.join(MANIFEST_NAME)).unwrap();
+        assert!(matches!(
+            KVStorage::open(&location),
+            Err(Error::MissingIdentity)
+        ));
+        assert!(matches!(
+            KVStorage::new(&location),
+            Err(Error::MissingIdentity)
+        ));
+    }
+
+    #[test]
+    fn test_destroy() {
+        let location = test_location();
+        let db_dir = Path::new(&location).join("db");
+
+        let kv = KVStorage::new(&location).unwrap();
+        for key in 0..20000 {
+            kv.write(key, Some(key)).unwrap();
+        }
+        kv.close().unwrap();
+        KVStorage::destroy(&location).unwrap();
+        assert!(!db_dir.exists());
+        assert!(Path::new(&location).exists());
+        assert!(KVStorage::open(&location).is_err());
+
+        let kv = KVStorage::new(&location).unwrap();
+        kv.write(1, Some(1)).unwrap();
+        kv.close_and_destroy().unwrap();
+        assert!(!db_dir.exists());
+    }
+
+    #[test]
+    fn test_namespaces() {
+        let location = test_location();
+        let db_dir = Path::new(&location).join("db");
+
+        let kv = KVStorage::new(&location).unwrap();
+        let users = kv.create_namespace("users").unwrap();
+        let sessions = kv.create_namespace("sessions").unwrap();
+        kv.write(1, Some(1)).unwrap();
+        users.write(1, Some(10)).unwrap();
+        sessions.write(1, Some(100)).unwrap();
+        sessions.delete(1).unwrap();
+        for key in 2..20000 {
+            users.write(key, Some(key)).unwrap();
+        }
+        assert_eq!(kv.read(&1).unwrap(), Some(1));
+        assert_eq!(users.read(&1).unwrap(), Some(10));
+        assert_eq!(sessions.read(&1).unwrap(), None);
+        assert_eq!(kv.read(&2).unwrap(), None);
+
+        assert!(matches!(
+            kv.create_namespace("users"),
+            Err(Error::NamespaceExists)
+        ));
+        for name in ["", "sstables", "lost", "log_1", "Users", "a/b"] {
+            assert!(matches!(
+                kv.create_namespace(name),
+                Err(Error::InvalidNamespaceName)
+            ));
+        }
+        drop((users, sessions));
+        kv.close().unwrap();
+
+        let kv = KVStorage::open(&location).unwrap();
+        assert_eq!(kv.namespace_names(), ["sessions", "users"]);
+        let users = kv.namespace("users").unwrap();
+        assert_eq!(users.read(&1).unwrap(), Some(10));
+        assert_eq!(users.read(&19999).unwrap(), Some(19999));
+        assert!(kv.namespace("other").is_none());
+
+        assert!(matches!(
+            kv.drop_namespace("users"),
+            Err(Error::NamespaceInUse)
+        ));
+        drop(users);
+        kv.drop_namespace("users").unwrap();
+        assert!(!db_dir.join("users").exists());
+        assert!(matches!(
+            kv.drop_namespace("users"),
+            Err(Error::NamespaceNotFound)
+        ));
+        assert_eq!(kv.namespace_names(), ["sessions"]);
+        kv.close().unwrap();
+
+        let kv = KVStorage::open(&location).unwrap();
+        assert_eq!(kv.namespace_names(), ["sessions"]);
+        kv.close().unwrap();
+        KVStorage::destroy(&location).unwrap();
+        assert!(!db_dir.exists());
+    }
+
+    #[test]
+    fn test_namespaces_share_workers() {
+        let storage = Arc::new(MemStorage::new());
+        let options = Options::new()
+            .max_immutable_memtables(1)
+            .parallel_probe_threads(2)
+            .compaction_interval(Duration::from_millis(10))
+            .compaction_policy(CompactionPolicy::SizeTiered {
+                ratio: 2.0,
+                min_merge: 2,
+            });
+        let namespace_store =
+            |kv: &KVStorage, name: &str| kv.inner.namespaces.lock().unwrap()[name].clone();
+        let assert_shared = |kv: &KVStorage, namespace: &KVStorage| {
+            let (store, namespace) = (&kv.inner.workers, &namespace.inner.workers);
+            assert!(Arc::ptr_eq(&store.compaction, &namespace.compaction));
+            assert!(Arc::ptr_eq(
+                store.flusher.as_ref().unwrap(),
+                namespace.flusher.as_ref().unwrap()
+            ));
+            assert!(Arc::ptr_eq(
+                store.probe_pool.as_ref().unwrap(),
+                namespace.probe_pool.as_ref().unwrap()
+            ));
+            assert!(!Arc::ptr_eq(&store.reaper, &namespace.reaper));
+        };
+
+        let kv = KVStorage::create(storage.clone(), Path::new("db"), options.clone()).unwrap();
+        for name in ["users", "sessions"] {
+            kv.create_namespace(name).unwrap();
+        }
+        let users = namespace_store(&kv, "users");
+        assert_shared(&kv, &users);
+
+        // Merges of the namespace run on the thread of the store, which removes the replaced tables
+        for key in 0..20 {
+            users.write(key, Some(key)).unwrap();
+            if key % 10 == 9 {
+                users.flush().unwrap();
+            }
+        }
+        assert!(
+            users
+                .wait_for_pending_compactions(COMPACTION_TIMEOUT)
+                .unwrap()
+        );
+        assert_eq!(users.inner.sstables.lock().unwrap().len(), 1);
+        users.inner.workers.reaper.stop();
+        assert_eq!(users.disk_usage().pending_deletion_bytes, 0);
+        assert_eq!(
+            storage.list(Path::new("db/users/sstables")).unwrap().len(),
+            1
+        );
+        drop(users);
+
+        // The threads keep going for the store once a namespace is gone
+        kv.drop_namespace("users").unwrap();
+        for key in 0..20 {
+            kv.write(key, Some(key)).unwrap();
+            if key % 10 == 9 {
+                kv.flush().unwrap();
+            }
+        }
+        assert!(kv.wait_for_pending_compactions(COMPACTION_TIMEOUT).unwrap());
+        assert_eq!(kv.inner.sstables.lock().unwrap().len(), 1);
+        kv.close().unwrap();
+
+        let kv = KVStorage::open_dir(storage, PathBuf::from("db"), options, false).unwrap();
+        let sessions = namespace_store(&kv, "sessions");
+        assert_shared(&kv, &sessions);
+        sessions.write(1, Some(1)).unwrap();
+        drop(sessions);
+        let reaper = kv.inner.workers.reaper.clone();
+        drop(kv);
+        assert!(!reaper.is_running());
+    }
+
+    #[test]
+    fn test_bulk_load() {
+        let location = test_location();
+        let options = Options::new().bulk_load_table_size(64 * 1024);
+
+        let kv = KVStorage::new_with_options(&location, options.clone()).unwrap();
+        kv.write(5, Some(1)).unwrap();
+        kv.write(7, None).unwrap();
+        kv.bulk_load((0..100000).map(|key| (key * 2, Some(key))))
+            .unwrap();
+        assert!(kv.inner.sstables.lock().unwrap().len() > 1);
+
+        // Earlier writes aren't shadowed, later ones win
+        assert_eq!(kv.read(&5).unwrap(), Some(1));
+        assert_eq!(kv.read(&7).unwrap(), None);
+        kv.write(10, Some(1)).unwrap();
+        assert_eq!(kv.read(&10).unwrap(), Some(1));
+        for _ in 0..1000 {
+            let key = rand::random::<u64>() % 100000;
+            let expected = if key == 5 { 1 } else { key };
+            assert_eq!(kv.read(&(key * 2)).unwrap(), Some(expected));
+            if key != 2 {
+                assert_eq!(kv.read(&(key * 2 + 1)).unwrap(), None);
+            }
+        }
+
+        let tables = kv.inner.sstables.lock().unwrap().len();
+        let unsorted = [(1, Some(1)), (3, Some(3)), (3, Some(4)), (2, Some(2))];
+        assert!(matches!(
+            kv.bulk_load(unsorted.into_iter()),
+            Err(Error::UnsortedBulkLoad { position: 2 })
+        ));
+        assert_eq!(kv.inner.sstables.lock().unwrap().len(), tables);
+        assert_eq!(kv.read(&1).unwrap(), None);
+        kv.close().unwrap();
+
+        let kv = KVStorage::open_with_options(&location, options).unwrap();
+        assert_eq!(kv.read(&10).unwrap(), Some(1));
+        assert_eq!(kv.read(&199998).unwrap(), Some(99999));
+    }
+
+    #[test]
+    fn test_export_import() {
+        let source = KVStorage::new_in_memory().unwrap();
+        for key in 0..1000 {
+            source.write(key, Some(key * 10)).unwrap();
+        }
+        source.flush().unwrap();
+        source.write(3, None).unwrap();
+        source.delete_range(100, 200).unwrap();
+        source.write(150, Some(1)).unwrap();
+
+        let mut stream = Vec::new();
+        let exported = source.export(&mut stream).unwrap();
+        assert_eq!(exported.entries, 900);
+        assert_eq!(exported.bytes, stream.len() as u64);
+
+        let location = test_location();
+        let options = Options::new().bulk_load_table_size(4 * 1024);
+        let kv = KVStorage::new_with_options(&location, options.clone()).unwrap();
+        kv.write(0, Some(7)).unwrap();
+        kv.write(3, Some(7)).unwrap();
+        kv.write(5000, Some(7)).unwrap();
+        let summary = kv.import(stream.as_slice(), ImportMode::Replace).unwrap();
+        assert_eq!(
+            summary,
+            ImportSummary {
+                entries: 900,
+                imported: 900,
+                skipped: 0,
+            }
+        );
+        assert!(kv.inner.sstables.lock().unwrap().len() > 1);
+        kv.close().unwrap();
+
+        // The imported entries stay newer than the ones they replaced
+        let kv = KVStorage::open_with_options(&location, options).unwrap();
+        assert_eq!(kv.iter().unwrap().collect::<Vec<_>>(), {
+            let mut expected = source.iter().unwrap().collect::<Vec<_>>();
+            expected.insert(3, (3, 7));
+            expected.push((5000, 7));
+            expected
+        });
+        kv.write(0, Some(8)).unwrap();
+        assert_eq!(kv.read(&0).unwrap(), Some(8));
+
+        let kv = KVStorage::new_in_memory().unwrap();
+        kv.write(1, Some(7)).unwrap();
+        kv.write(2, None).unwrap();
+        let summary = kv.import(stream.as_slice(), ImportMode::Preserve).unwrap();
+        assert_eq!((summary.imported, summary.skipped), (899, 1));
+        assert_eq!(kv.read(&1).unwrap(), Some(7));
+        assert_eq!(kv.read(&2).unwrap(), Some(20));
+    }
+
+    #[test]
+    fn test_interrupted_import() {
+        /// Fails once `left` bytes were read
+        struct FailingReader<'a> {
+            data: &'a [u8],
+            left: usize,
+        }
+
+        impl Read for FailingReader<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                if self.left == 0 {
+                    return Err(std::io::Error::other("connection reset"));
+                }
+                let len = buf.len().min(self.left);
+                let read = self.data.read(&mut buf[..len])?;
+                self.left -= read;
+                Ok(read)
+            }
+        }
+
+        let source = KVStorage::new_in_memory().unwrap();
+        source
+            .bulk_load((0..10000).map(|key| (key, Some(key))))
+            .unwrap();
+        let mut stream = Vec::new();
+        source.export(&mut stream).unwrap();
+
+        let kv =
+            KVStorage::new_in_memory_with_options(Options::new().bulk_load_table_size(4 * 1024))
+                .unwrap();
+        kv.write(1, Some(1)).unwrap();
+        kv.flush().unwrap();
+        let tables = kv.dump(false).unwrap().tables;
+
+        let reader = FailingReader {
+            data: &stream,
+            left: stream.len() / 2,
+        };
+        assert!(matches!(
+            kv.import(reader, ImportMode::Replace),
+            Err(Error::IO(_))
+        ));
+        let truncated = &stream[..stream.len() - 1];
+        assert!(matches!(
+            kv.import(truncated, ImportMode::Replace),
+            Err(Error::InvalidExport)
+        ));
+        assert!(matches!(
+            kv.import(&b"not an export"[..], ImportMode::Replace),
+            Err(Error::InvalidExport)
+        ));
+
+        // The tables written before the failure were never registered
+        assert_eq!(kv.dump(false).unwrap().tables, tables);
+        assert_eq!(kv.read(&1).unwrap(), Some(1));
+        assert_eq!(kv.read(&2).unwrap(), None);
+    }
+
+    #[test]
+    fn test_repair() {
+        let location = test_location();
+        let db_dir = Path::new(&location).join("db");
+
+        let kv = KVStorage::new(&location).unwrap();
+        for key in 0..20000 {
+            kv.write(key, Some(key)).unwrap();
+        }
+        kv.write(5, None).unwrap();
+        kv.close().unwrap();
+
+        // Garbage in the middle of the largest table
+        let table = fs::read_dir(db_dir.join("sstables"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .max_by_key(|path| fs::metadata(path).unwrap().len())
+            .unwrap();
+        let mut content = fs::read(&table).unwrap();
+        let middle = content.len() / 2;
+        content[middle..middle + 40].fill(0xab);
+        fs::write(&table, &content).unwrap();
+
+        let report = KVStorage::repair(&location).unwrap();
+        assert!(report.missing.is_empty());
+        let lost = db_dir.join("lost").join(table.file_name().unwrap());
+        assert_eq!(report.quarantined().collect::<Vec<_>>(), [lost.as_path()]);
+        assert!(lost.exists());
+
+        let kv = KVStorage::open(&location).unwrap();
+        assert!(kv.verify().unwrap().is_ok());
+        assert_eq!(kv.read(&0).unwrap(), Some(0));
+        assert_eq!(kv.read(&5).unwrap(), None);
+        assert_eq!(kv.read(&19999).unwrap(), Some(19999));
+        let found = (0..20000)
+            .filter(|key| kv.read(key).unwrap().is_some())
+            .count();
+        assert!(found > 19990, "{found} keys left");
+        kv.close().unwrap();
+
+        KVStorage::destroy(&location).unwrap();
+        assert!(!db_dir.exists());
+    }
+
+    #[test]
+    fn test_destroy_foreign_file() {
+        let location = test_location();
+        let db_dir = Path::new(&location).join("db");
+
+        KVStorage::new(&location).unwrap().close().unwrap();
+        fs::write(db_dir.join("notes.txt"), b"not ours").unwrap();
+
+        assert!(matches!(
+            KVStorage::destroy(&location),
+            Err(Error::NotADatabase)
+        ));
+        assert!(db_dir.join(MANIFEST_NAME).exists());
+        assert!(
+            KVStorage::destroy(&Path::new(&location).join("missing").to_string_lossy()).is_err()
+        );
+    }
+
+    #[test]
+    fn test_open_read_only() {
+        fn list_files(dir: &Path) -> Vec<PathBuf> {
+            let mut files: Vec<_> = fs::read_dir(dir)
+                .unwrap()
+                .flat_map(|entry| {
+                    let path = entry.unwrap().path();
+                    if path.is_dir() {
+                        list_files(&path)
+                    } else {
+                        vec![path]
+                    }
+                })
+                .collect();
+            files.sort();
+            files
+        }
+
+        let location = test_location();
+
+        let kv = KVStorage::new(&location).unwrap();
+        for key in 0..20000 {
+            kv.write(key, Some(key)).unwrap();
+        }
+        // Left in the log
+        kv.write(1, None).unwrap();
+        drop(kv);
+
+        let files_before = list_files(Path::new(&location));
+        let kv = KVStorage::open_read_only(&location).unwrap();
+        assert_eq!(kv.read(&1).unwrap(), None);
+        for key in 2..20000 {
+            assert_eq!(kv.read(&key).unwrap(), Some(key));
+        }
+        assert!(matches!(kv.write(1, Some(1)), Err(Error::ReadOnly)));
+        assert!(matches!(kv.increment(1, 1), Err(Error::ReadOnly)));
+        kv.close().unwrap();
+        assert_eq!(list_files(Path::new(&location)), files_before);
+    }
+
+    #[test]
+    fn test_open_removes_partial_sstables() {
+        let location = test_location();
+
+        let kv = KVStorage::new(&location).unwrap();
+        kv.write(1, Some(10)).unwrap();
+        kv.close().unwrap();
+
+        // Simulates a crash in the middle of writing a table
+        let tmp_path = Path::new(&location).join("db/sstables/1234.tmp");
+        fs::write(&tmp_path, [1, 2, 3]).unwrap();
+
+        let kv = KVStorage::open(&location).unwrap();
+        assert!(!tmp_path.exists());
+        assert_eq!(kv.read(&1).unwrap(), Some(10));
+    }
+
+    #[test]
+    fn test_open_removes_orphan_files() {
+        let location = test_location();
+
+        let kv = KVStorage::new(&location).unwrap();
+        for key in 0..30000 {
+            kv.write(key, Some(key)).unwrap();
+        }
+        kv.close().unwrap();
+
+        let db_dir = Path::new(&location).join("db");
+        let old = std::time::SystemTime::now() - 2 * cleanup::ORPHAN_GRACE_PERIOD;
+        let orphans = [db_dir.join("log_1"), db_dir.join("sstables/1")];
+        for orphan in &orphans {
+            fs::write(orphan, [0; 10]).unwrap();
+            fs::File::options()
+                .write(true)
+                .open(orphan)
+                .unwrap()
+                .set_modified(old)
+                .unwrap();
+        }
+        // Too recent to be touched
+        let recent = db_dir.join("sstables/2");
+        fs::write(&recent, [0; 10]).unwrap();
+
+        let kv = KVStorage::open(&location).unwrap();
+        assert!(orphans.iter().all(|orphan| !orphan.exists()));
+        assert!(recent.exists());
+        assert_eq!(kv.stats().orphan_files_removed, 2);
+        assert_eq!(kv.stats().orphan_bytes_removed, 20);
+        for key in 0..30000 {
+            assert_eq!(kv.read(&key).unwrap(), Some(key));
+        }
+    }
+
+    #[test]
+    fn test_read_with_trace() {
+        let kv = KVStorage::new_in_memory().unwrap();
+        // Three tables with the same key range, key 50 only in the oldest one
+        for keys in [[0, 50, 100], [0, 60, 100], [0, 70, 100]] {
+            for key in keys {
+                kv.write(key, Some(key)).unwrap();
+            }
+            kv.flush().unwrap();
+        }
+        let oldest = kv.inner.sstables.lock().unwrap()[2].id();
+
+        let (value, trace) = kv.read_with_trace(&50).unwrap();
+        assert_eq!(value, Some(50));
+        assert!(!trace.memtable_hit && !trace.cache_hit);
+        assert_eq!(trace.sstables_probed, 3);
+        assert!(trace.bloom_maybes >= 1);
+        assert!(trace.bytes_read > 0);
+        assert_eq!(trace.table, Some(oldest));
+
+        let (_, missing) = kv.read_with_trace(&55).unwrap();
+        assert_eq!(missing.sstables_probed, 3);
+        assert_eq!(missing.table, None);
+
+        kv.write(50, None).unwrap();
+        let (value, trace) = kv.read_with_trace(&50).unwrap();
+        assert_eq!(value, None);
+        assert_eq!(
+            trace,
+            ReadTrace {
+                memtable_hit: true,
+                ..Default::default()
+            }
+        );
+
+        let stats = kv.stats();
+        assert_eq!(stats.sstables_probed, 6);
+        assert!(stats.table_bytes_read >= trace.bytes_read);
+    }
+
+    #[test]
+    fn test_range_tombstone_skips_older_tables() {
+        let kv = KVStorage::new_in_memory().unwrap();
+        for key in 0..100 {
+            kv.write(key, Some(key)).unwrap();
+        }
+        kv.flush().unwrap();
+        kv.delete_range(10, 20).unwrap();
+        kv.flush().unwrap();
+
+        // Everything in the older table was written before the range
+        let (value, trace) = kv.read_with_trace(&15).unwrap();
+        assert_eq!(value, None);
+        assert_eq!(trace.sstables_probed, 1);
+
+        let (value, trace) = kv.read_with_trace(&25).unwrap();
+        assert_eq!(value, Some(25));
+        assert_eq!(trace.sstables_probed, 1);
+    }
+
+    #[test]
+    fn test_bloom_stats() {
+        let kv = KVStorage::new_in_memory().unwrap();
+        for key in 0..100 {
+            kv.write(key * 2, Some(key)).unwrap();
+        }
+        kv.flush().unwrap();
+
+        for key in 0..200 {
+            kv.read(&key).unwrap();
+        }
+        let [bloom] = kv.stats().bloom_filters[..] else {
+            panic!("expected a single table");
+        };
+        assert_eq!(bloom.checks, 199);
+        assert_eq!(bloom.negatives + bloom.false_positives, 99);
+        assert!(bloom.false_positive_rate() < 0.1);
+    }
+
+    #[test]
+    fn test_bloom_recovery() {
+        let location = test_location();
+        let kv = KVStorage::new(&location).unwrap();
+        for key in 0..100 {
+            kv.write(key * 2, Some(key)).unwrap();
+        }
+        kv.flush().unwrap();
+        kv.delete_range(10, 20).unwrap();
+        kv.write(7, Some(7)).unwrap();
+        kv.flush().unwrap();
+        kv.close().unwrap();
+
+        let mut reads = Vec::new();
+        for recovery in [
+            BloomRecovery::FromFooter,
+            BloomRecovery::Rebuild,
+            BloomRecovery::Skip,
+        ] {
+            let options = Options::new().bloom_recovery(recovery);
+            let kv = KVStorage::open_with_options(&location, options).unwrap();
+            reads.push(
+                (0..210)
+                    .map(|key| kv.read(&key).unwrap())
+                    .collect::<Vec<_>>(),
+            );
+
+            let stats = kv.stats();
+            assert_eq!(
+                stats.bloom_filter_bytes > 0,
+                recovery != BloomRecovery::Skip
+            );
+            if recovery == BloomRecovery::Skip {
+                assert!(stats.bloom_filters.iter().all(|bloom| bloom.checks == 0));
+            }
+            kv.close().unwrap();
+        }
+        assert_eq!(reads[0], reads[1]);
+        assert_eq!(reads[0], reads[2]);
+        assert_eq!(reads[0][7], Some(7));
+        assert_eq!(reads[0][12], None);
+        assert_eq!(reads[0][22], Some(11));
+    }
+
+    #[test]
+    fn test_idle_compaction() {
+        let options = Options::new()
+            .write_shards(1)
+            .idle_compaction_after(Duration::from_millis(20));
+        let kv = KVStorage::new_in_memory_with_options(options).unwrap();
+
+        // Three tables, below the 4 the default policy merges
+        for table in 0..3 {
+            for key in 0..10 {
+                kv.write(key, (table != 1).then_some(table * 10 + key))
+                    .unwrap();
+            }
+            kv.inner
+                .append_log
+                .flush(&kv.inner.sstables, &kv.inner.manifest)
+                .unwrap();
+        }
+        assert_eq!(kv.inner.sstables.lock().unwrap().len(), 3);
+
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while kv.inner.sstables.lock().unwrap().len() > 1 && std::time::Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(kv.inner.sstables.lock().unwrap().len(), 1);
+        for key in 0..10 {
+            assert_eq!(kv.read(&key).unwrap(), Some(20 + key));
+        }
+
+        // The ticker doesn't hold up closing
+        let options = Options::new().compaction_interval(Duration::from_secs(3600));
+        let kv = KVStorage::new_in_memory_with_options(options).unwrap();
+        let start = std::time::Instant::now();
+        kv.close().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_compaction_without_output() {
+        let options = Options::new().compaction_policy(CompactionPolicy::SizeTiered {
+            ratio: 2.0,
+            min_merge: 2,
+        });
+        let kv = KVStorage::new_in_memory_with_options(options).unwrap();
+
+        // Merging the two tables leaves nothing, tombstones are dropped along with the oldest table
+        for key in 0..10 {
+            kv.write(key, Some(key)).unwrap();
+        }
+        kv.inner
+            .append_log
+            .flush(&kv.inner.sstables, &kv.inner.manifest)
+            .unwrap();
+        for key in 0..10 {
+            kv.write(key, None).unwrap();
+        }
+        kv.inner
+            .append_log
+            .flush(&kv.inner.sstables, &kv.inner.manifest)
+            .unwrap();
+
+        assert!(kv.wait_for_pending_compactions(COMPACTION_TIMEOUT).unwrap());
+
+        assert!(kv.inner.sstables.lock().unwrap().is_empty());
+        assert!(kv.inner.manifest.data().sstables.is_empty());
+        assert_eq!(kv.read(&3).unwrap(), None);
+        kv.write(3, Some(30)).unwrap();
+        assert_eq!(kv.read(&3).unwrap(), Some(30));
+    }
+
+    #[test]
+    fn test_close_removes_merged_tables() {
+        let location = test_location();
+        let options =
+            Options::new()
+                .write_shards(1)
+                .compaction_policy(CompactionPolicy::SizeTiered {
+                    ratio: 2.0,
+                    min_merge: 2,
+                });
+        let kv = KVStorage::new_with_options(&location, options).unwrap();
+        for round in 0..8 {
+            for key in 0..100 {
+                kv.write(key, Some(key + round)).unwrap();
+            }
+            kv.flush().unwrap();
+        }
+        assert!(kv.wait_for_pending_compactions(COMPACTION_TIMEOUT).unwrap());
+
+        let reaper = kv.inner.workers.reaper.clone();
+        let live: std::collections::BTreeSet<_> = kv
+            .inner
+            .manifest
+            .data()
+            .sstables
+            .iter()
+            .map(|id| id.to_string())
+            .collect();
+        kv.close().unwrap();
+
+        // The merged tables were removed before close returned, nothing is left to race the directory removal
+        assert!(!reaper.is_running());
+        let files: std::collections::BTreeSet<_> =
+            fs::read_dir(Path::new(&location).join("db/sstables"))
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect();
+        assert_eq!(files, live);
+        fs::remove_dir_all(&location).unwrap();
+    }
+
+    #[test]
+    fn test_cold_storage() {
+        let location = test_location();
+        let cold_dir = test_location();
+        let options = Options::new()
+            .compaction_policy(CompactionPolicy::SizeTiered {
+                ratio: 2.0,
+                min_merge: 2,
+            })
+            .cold_storage_dir(cold_dir.path());
+        let kv = KVStorage::new_with_options(&location, options).unwrap();
+        let sstables_dir = Path::new(&location).join("db/sstables");
+
+        for round in 0..2 {
+            for key in 0..100 {
+                kv.write(key, Some(key + round)).unwrap();
+            }
+            kv.inner
+                .append_log
+                .flush(&kv.inner.sstables, &kv.inner.manifest)
+                .unwrap();
+        }
+        assert!(kv.wait_for_pending_compactions(COMPACTION_TIMEOUT).unwrap());
+
+        // The merge reached the oldest table, its output is cold
+        let [merged] = kv.inner.manifest.data().sstables[..] else {
+            panic!("expected a single table");
+        };
+        assert_eq!(kv.inner.manifest.data().cold_sstables, [merged]);
+        assert!(cold_dir.path().join(merged.to_string()).exists());
+        assert!(!sstables_dir.join(merged.to_string()).exists());
+
+        // Flushes stay in the SSTables directory
+        kv.write(1000, Some(1)).unwrap();
+        kv.inner
+            .append_log
+            .flush(&kv.inner.sstables, &kv.inner.manifest)
+            .unwrap();
+        let newest = kv.inner.manifest.data().sstables[0];
+        assert!(sstables_dir.join(newest.to_string()).exists());
+        assert_eq!(kv.read(&50).unwrap(), Some(51));
+        kv.close().unwrap();
+
+        // The manifest knows where the cold tables are
+        let kv = KVStorage::open(&location).unwrap();
+        assert_eq!(kv.read(&50).unwrap(), Some(51));
+        assert_eq!(kv.read(&1000).unwrap(), Some(1));
+        kv.close().unwrap();
+
+        let moved_dir = test_location();
+        let moved = Options::new().cold_storage_dir(moved_dir.path());
+        assert!(matches!(
+            KVStorage::open_with_options(&location, moved),
+            Err(Error::ColdStorageDirChanged)
+        ));
+
+        KVStorage::destroy(&location).unwrap();
+        assert!(!cold_dir.path().join(merged.to_string()).exists());
+    }
+
+    #[test]
+    fn test_quota() {
+        let options = Options::new()
+            .write_shards(1)
+            .compaction_policy(CompactionPolicy::SizeTiered {
+                ratio: 2.0,
+                min_merge: 2,
+            })
+            .max_db_size_bytes(8 * 1024)
+            .quota_headroom_bytes(64 * 1024);
+        let kv = KVStorage::new_in_memory_with_options(options).unwrap();
+
+        let mut written = 0;
+        let error = loop {
+            match kv.write(written, Some(written)) {
+                Ok(()) => written += 1,
+                Err(e) => break e,
+            }
+        };
+        assert!(matches!(error, Error::QuotaExceeded));
+        assert!(written > 100);
+        assert!(kv.stats().disk_bytes_used <= 8 * 1024);
+        kv.flush().unwrap();
+        assert!(matches!(kv.write(0, Some(1)), Err(Error::QuotaExceeded)));
+
+        // Refused writes don't count as distinct keys
+        for key in written..written + 1000 {
+            assert!(matches!(
+                kv.write(key, Some(key)),
+                Err(Error::QuotaExceeded)
+            ));
+        }
+        let distinct = kv.stats().estimated_distinct_keys;
+        assert!(distinct.abs_diff(written) < written / 10, "{distinct}");
+
+        // Deletions can use the headroom, merging both tables then leaves nothing
+        for key in 0..written {
+            kv.write(key, None).unwrap();
+        }
+        kv.flush_and_wait().unwrap();
+        assert!(kv.inner.sstables.lock().unwrap().is_empty());
+        assert_eq!(kv.stats().disk_bytes_used, 0);
+
+        kv.write(0, Some(1)).unwrap();
+        assert_eq!(kv.read(&0).unwrap(), Some(1));
+    }
+
+    #[test]
+    fn test_disk_full() {
+        let storage = Arc::new(MemStorage::new());
+        let options =
+            Options::new()
+                .write_shards(1)
+                .compaction_policy(CompactionPolicy::SizeTiered {
+                    ratio: 2.0,
+                    min_merge: 2,
+                });
+        let kv = KVStorage::create(storage.clone(), Path::new("db"), options).unwrap();
+        assert!(matches!(
+            Error::from(std::io::Error::from(std::io::ErrorKind::StorageFull)),
+            Error::DiskFull
+        ));
+
+        for key in 0..10 {
+            kv.write(key, Some(key)).unwrap();
+        }
+        kv.inner
+            .append_log
+            .flush(&kv.inner.sstables, &kv.inner.manifest)
+            .unwrap();
+        for key in 10..20 {
+            kv.write(key, Some(key)).unwrap();
+        }
+        let manifest = kv.inner.manifest.data();
+
+        // The failed rotation leaves the log in place, it keeps taking writes
+        storage.failpoints().set(
+            failpoints::CREATE_FILE,
+            0,
+            FailAction::Error(std::io::ErrorKind::StorageFull),
+        );
+        assert!(matches!(kv.flush(), Err(Error::DiskFull)));
+        kv.write(20, Some(20)).unwrap();
+        assert_eq!(kv.read(&15).unwrap(), Some(15));
+        assert_eq!(kv.inner.manifest.data().log_files, manifest.log_files);
+        assert_eq!(kv.inner.manifest.data().sstables, manifest.sstables);
+
+        assert_eq!(storage.list(Path::new("db/sstables")).unwrap().len(), 1);
+
+        // The merge fails too, then compaction waits before trying again
+        storage.failpoints().clear(failpoints::CREATE_FILE);
+        kv.inner
+            .append_log
+            .flush(&kv.inner.sstables, &kv.inner.manifest)
+            .unwrap();
+        storage.failpoints().set(
+            failpoints::CREATE_FILE,
+            0,
+            FailAction::Error(std::io::ErrorKind::StorageFull),
+        );
+        kv.inner.compaction_manager.signal_sstable_inserted();
+        assert!(
+            kv.inner
+                .compaction_manager
+                .wait_idle(Some(COMPACTION_TIMEOUT))
+        );
+        assert_eq!(kv.inner.sstables.lock().unwrap().len(), 2);
+        storage.failpoints().clear(failpoints::CREATE_FILE);
+        kv.inner.compaction_manager.signal_sstable_inserted();
+        assert!(!kv.inner.compaction_manager.activity.is_running());
+
+        // Retried once the pause is over
+        assert!(kv.wait_for_pending_compactions(COMPACTION_TIMEOUT).unwrap());
+        assert_eq!(kv.inner.sstables.lock().unwrap().len(), 1);
+        for key in 0..21 {
+            assert_eq!(kv.read(&key).unwrap(), Some(key));
+        }
+    }
+
+    #[test]
+    fn test_rotation_keeps_last_write() {
+        let kv = KVStorage::new_in_memory_with_options(Options::new().write_shards(1)).unwrap();
+
+        // Keys are overwritten all along, the rotations cut through the overwrites
+        let mut expected = HashMap::new();
+        for i in 0..60000 {
+            let key = i % 97;
+            let value = (i % 5 != 0).then_some(i);
+            kv.write(key, value).unwrap();
+            expected.insert(key, value);
+        }
+        assert!(!kv.inner.sstables.lock().unwrap().is_empty());
+        for (key, value) in &expected {
+            assert_eq!(kv.read(key).unwrap(), *value);
+        }
+
+        kv.flush().unwrap();
+        for (key, value) in &expected {
+            assert_eq!(kv.read(key).unwrap(), *value);
+        }
+    }
+
+    #[test]
+    fn test_rotation_write_failure() {
+        let storage = Arc::new(MemStorage::new());
+        let options = Options::new().write_shards(1);
+        let kv = KVStorage::create(storage.clone(), Path::new("db"), options.clone()).unwrap();
+
+        // The log fills up, the write needing the rotation fails along with the table
+        storage.failpoints().set(
+            failpoints::WRITE_FILE,
+            0,
+            FailAction::Error(std::io::ErrorKind::Other),
+        );
+        let mut written = 0;
+        let error = loop {
+            match kv.write(written, Some(written)) {
+                Ok(()) => written += 1,
+                Err(e) => break e,
+            }
+        };
+        assert!(matches!(error, Error::IO(_)));
+        assert!(kv.inner.sstables.lock().unwrap().is_empty());
+        for key in 0..written {
+            assert_eq!(kv.read(&key).unwrap(), Some(key));
+        }
+
+        // The new log took over, the old one stays readable until the next rotation registers its table first. It's
+        // replayed on open meanwhile
+        kv.write(written, Some(written)).unwrap();
+        assert!(!kv.inner.manifest.data().frozen_log_files(0).is_empty());
+        drop(kv);
+        let kv = KVStorage::open_dir(storage.clone(), PathBuf::from("db"), options, false).unwrap();
+        for key in 0..=written {
+            assert_eq!(kv.read(&key).unwrap(), Some(key));
+        }
+
+        storage.failpoints().clear(failpoints::WRITE_FILE);
+        kv.flush().unwrap();
+        assert_eq!(kv.inner.sstables.lock().unwrap().len(), 2);
+        for key in 0..=written {
+            assert_eq!(kv.read(&key).unwrap(), Some(key));
+        }
+    }
+
+    #[test]
+    fn test_failed_log_write_is_padded() {
+        let storage = Arc::new(MemStorage::new());
+        let options = Options::new().write_shards(1);
+        let kv = KVStorage::create(storage.clone(), Path::new("db"), options.clone()).unwrap();
+        for key in 0..10 {
+            kv.write(key, Some(key)).unwrap();
+        }
+
+        storage.failpoints().set_once(
+            failpoints::WRITE_DATA_AT_OFFSET,
+            0,
+            FailAction::Error(std::io::ErrorKind::Other),
+        );
+        assert!(matches!(kv.write(10, Some(10)), Err(Error::IO(_))));
+
+        // The records after the hole are replayed, then flushed
+        for key in 11..20 {
+            kv.write(key, Some(key)).unwrap();
+        }
+        let check = |kv: &KVStorage| {
+            for key in (0..20).filter(|key| *key != 10) {
+                assert_eq!(kv.read(&key).unwrap(), Some(key));
+            }
+            assert_eq!(kv.read(&10).unwrap(), None);
+        };
+        check(&kv);
+        drop(kv);
+
+        let kv = KVStorage::open_dir(storage.clone(), PathBuf::from("db"), options, false).unwrap();
+        check(&kv);
+        kv.flush().unwrap();
+        assert_eq!(kv.inner.sstables.lock().unwrap().len(), 1);
+        check(&kv);
+        assert!(kv.verify().unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_reads_and_writes_during_slow_flush() {
+        let storage = Arc::new(MemStorage::new());
+        let options = Options::new().write_shards(1);
+        let kv = KVStorage::create(storage.clone(), Path::new("db"), options).unwrap();
+        for key in 0..100 {
+            kv.write(key, Some(key)).unwrap();
+        }
+        let log_files = kv.inner.append_log.file_names();
+
+        // Writing the table takes a while
+        storage.failpoints().set(
+            failpoints::WRITE_FILE,
+            0,
+            FailAction::Delay(Duration::from_millis(500)),
+        );
+        let flush = {
+            let kv = kv.clone();
+            std::thread::spawn(move || kv.flush())
+        };
+        while kv.inner.append_log.file_names() == log_files {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        // The log was swapped, its entries are still read from the frozen log
+        let mut key = 100;
+        while !flush.is_finished() {
+            for old_key in (0..100).step_by(7) {
+                assert_eq!(kv.read(&old_key).unwrap(), Some(old_key));
+            }
+            kv.write(key, Some(key)).unwrap();
+            assert_eq!(kv.read(&key).unwrap(), Some(key));
+            key += 1;
+        }
+        flush.join().unwrap().unwrap();
+        assert!(key > 100);
+        assert_eq!(kv.inner.sstables.lock().unwrap().len(), 1);
+        assert!(kv.inner.manifest.data().frozen_log_files(0).is_empty());
+        for key in 0..key {
+            assert_eq!(kv.read(&key).unwrap(), Some(key));
+        }
+    }
+
+    #[test]
+    fn test_compaction_create_failure() {
+        let storage = Arc::new(MemStorage::new());
+        let options =
+            Options::new()
+                .write_shards(1)
+                .compaction_policy(CompactionPolicy::SizeTiered {
+                    ratio: 2.0,
+                    min_merge: 2,
+                });
+        let kv = KVStorage::create(storage.clone(), Path::new("db"), options).unwrap();
+        for key in 0..20 {
+            kv.write(key, Some(key)).unwrap();
+            if key == 9 {
+                kv.inner
+                    .append_log
+                    .flush(&kv.inner.sstables, &kv.inner.manifest)
+                    .unwrap();
+            }
+        }
+        kv.inner
+            .append_log
+            .flush(&kv.inner.sstables, &kv.inner.manifest)
+            .unwrap();
+
+        // The merge output can't be created, the inputs stay in place
+        storage.failpoints().set(
+            failpoints::CREATE_FILE,
+            0,
+            FailAction::Error(std::io::ErrorKind::PermissionDenied),
+        );
+        kv.inner.compaction_manager.signal_sstable_inserted();
+        assert!(
+            kv.inner
+                .compaction_manager
+                .wait_idle(Some(COMPACTION_TIMEOUT))
+        );
+        assert_eq!(kv.inner.sstables.lock().unwrap().len(), 2);
+        assert_eq!(kv.inner.manifest.data().sstables.len(), 2);
+        kv.write(20, Some(20)).unwrap();
+        for key in 0..21 {
+            assert_eq!(kv.read(&key).unwrap(), Some(key));
+        }
+
+        storage.failpoints().clear(failpoints::CREATE_FILE);
+        assert!(kv.wait_for_pending_compactions(COMPACTION_TIMEOUT).unwrap());
+        assert_eq!(kv.inner.sstables.lock().unwrap().len(), 1);
+        for key in 0..21 {
+            assert_eq!(kv.read(&key).unwrap(), Some(key));
+        }
+    }
+
+    #[test]
+    fn test_compaction_manifest_failure() {
+        let storage = Arc::new(MemStorage::new());
+        let options =
+            Options::new()
+                .write_shards(1)
+                .compaction_policy(CompactionPolicy::SizeTiered {
+                    ratio: 2.0,
+                    min_merge: 2,
+                });
+        let kv = KVStorage::create(storage.clone(), Path::new("db"), options).unwrap();
+        for key in 0..20 {
+            kv.write(key, Some(key)).unwrap();
+            if key == 9 {
+                kv.inner
+                    .append_log
+                    .flush(&kv.inner.sstables, &kv.inner.manifest)
+                    .unwrap();
+            }
+        }
+        kv.inner
+            .append_log
+            .flush(&kv.inner.sstables, &kv.inner.manifest)
+            .unwrap();
+        let tables = kv.inner.manifest.data().sstables;
+
+        // The merge output is written, the manifest listing it isn't
+        storage.failpoints().set_once(
+            failpoints::CREATE_FILE,
+            1,
+            FailAction::Error(std::io::ErrorKind::PermissionDenied),
+        );
+        kv.inner.compaction_manager.signal_sstable_inserted();
+        assert!(
+            kv.inner
+                .compaction_manager
+                .wait_idle(Some(COMPACTION_TIMEOUT))
+        );
+        assert_eq!(kv.stats().compaction_failures, 1);
+        assert_eq!(kv.inner.manifest.data().sstables, tables);
+        let listed: Vec<_> = kv
+            .inner
+            .sstables
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|t| t.id())
+            .collect();
+        assert_eq!(listed, tables);
+        for key in 0..20 {
+            assert_eq!(kv.read(&key).unwrap(), Some(key));
+        }
+
+        // The output was reaped, only the inputs are left
+        kv.close().unwrap();
+        let mut files: Vec<u64> = storage
+            .list(Path::new("db/sstables"))
+            .unwrap()
+            .iter()
+            .map(|file| {
+                file.path
+                    .file_name()
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+                    .parse()
+                    .unwrap()
+            })
+            .collect();
+        files.sort();
+        let mut tables = tables;
+        tables.sort();
+        assert_eq!(files, tables);
+    }
+
+    #[test]
+    fn test_health() {
+        let storage = Arc::new(MemStorage::new());
+        let clock = Arc::new(ManualClock::default());
+        let options = Options::new()
+            .write_shards(1)
+            .clock(clock.clone())
+            .max_db_size_bytes(1 << 30)
+            .compaction_policy(CompactionPolicy::SizeTiered {
+                ratio: 2.0,
+                min_merge: 2,
+            });
+        let kv = KVStorage::create(storage.clone(), Path::new("db"), options).unwrap();
+        let health = kv.health();
+        assert!(health.is_healthy());
+        assert_eq!(health.since_last_flush, None);
+        assert_eq!(health.disk_quota_bytes, Some(1 << 30));
+
+        for key in 0..2 {
+            kv.write(key, Some(key)).unwrap();
+            kv.inner
+                .append_log
+                .flush(&kv.inner.sstables, &kv.inner.manifest)
+                .unwrap();
+        }
+        // The merge of the two tables can't create its output
+        storage.failpoints().set(
+            failpoints::CREATE_FILE,
+            0,
+            FailAction::Error(std::io::ErrorKind::PermissionDenied),
+        );
+        clock.advance(Duration::from_secs(5));
+        kv.inner.compaction_manager.signal_sstable_inserted();
+        assert!(
+            kv.inner
+                .compaction_manager
+                .wait_idle(Some(COMPACTION_TIMEOUT))
+        );
+
+        let health = kv.health();
+        assert!(!health.is_healthy());
+        assert!(health.background_error.is_some());
+        assert!(!health.writes_refused);
+        assert_eq!(health.compaction_backlog, 2);
+        assert_eq!(health.since_last_flush, Some(Duration::from_secs(5)));
+        assert_eq!(health.since_last_compaction, None);
+
+        storage.failpoints().clear(failpoints::CREATE_FILE);
+        assert!(kv.wait_for_pending_compactions(COMPACTION_TIMEOUT).unwrap());
+        let health = kv.health();
+        assert!(health.is_healthy());
+        assert_eq!(health.compaction_backlog, 0);
+        assert_eq!(health.since_last_compaction, Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_merge_panic() {
+        let storage = Arc::new(MemStorage::new());
+        let options = Options::new()
+            .write_shards(1)
+            .max_compaction_threads(2)
+            .compaction_policy(CompactionPolicy::SizeTiered {
+                ratio: 2.0,
+                min_merge: 2,
+            });
+        let kv = KVStorage::create(storage.clone(), Path::new("db"), options).unwrap();
+
+        // Two big tables, then two small ones: two groups merged at once
+        for (start, count) in [(0, 2000), (2000, 2000), (4000, 100), (4100, 100)] {
+            for key in start..start + count {
+                kv.write(key, Some(key)).unwrap();
+            }
+            kv.inner
+                .append_log
+                .flush(&kv.inner.sstables, &kv.inner.manifest)
+                .unwrap();
+        }
+
+        // The second merge to write its output panics, the first one still lands
+        storage
+            .failpoints()
+            .set_once(failpoints::WRITE_FILE, 1, FailAction::Panic);
+        kv.inner.compaction_manager.signal_sstable_inserted();
+        assert!(
+            kv.inner
+                .compaction_manager
+                .wait_idle(Some(COMPACTION_TIMEOUT))
+        );
+        assert_eq!(kv.inner.sstables.lock().unwrap().len(), 3);
+        assert_eq!(kv.inner.manifest.data().sstables.len(), 3);
+        assert_eq!(kv.stats().compaction_failures, 1);
+        // Nothing left by the panicked merge, once the merged inputs are deleted
+        while kv.disk_usage().pending_deletion_bytes > 0 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(storage.list(Path::new("db/sstables")).unwrap().len(), 3);
+        for key in 0..4200 {
+            assert_eq!(kv.read(&key).unwrap(), Some(key));
+        }
+
+        assert!(kv.wait_for_pending_compactions(COMPACTION_TIMEOUT).unwrap());
+        assert_eq!(kv.inner.sstables.lock().unwrap().len(), 2);
+        for key in 0..4200 {
+            assert_eq!(kv.read(&key).unwrap(), Some(key));
+        }
+    }
+
+    #[test]
+    fn test_table_read_failure() {
+        let storage = Arc::new(MemStorage::new());
+        let kv = KVStorage::create(storage.clone(), Path::new("db"), Options::new()).unwrap();
+        for key in 0..10 {
+            kv.write(key, Some(key)).unwrap();
+        }
+        kv.flush().unwrap();
+        kv.write(10, Some(10)).unwrap();
+
+        // Only the reads reaching the table fail
+        storage.failpoints().set(
+            failpoints::READ_FILE,
+            0,
+            FailAction::Error(std::io::ErrorKind::UnexpectedEof),
+        );
+        assert!(matches!(kv.read(&3), Err(Error::IO(_))));
+        assert_eq!(kv.read(&10).unwrap(), Some(10));
+        kv.write(11, Some(11)).unwrap();
+
+        storage.failpoints().clear(failpoints::READ_FILE);
+        for key in 0..12 {
+            assert_eq!(kv.read(&key).unwrap(), Some(key));
+        }
+    }
+
+    #[test]
+    fn test_multi_get() {
+        let location = test_location();
+
+        let kv = KVStorage::new(&location).unwrap();
+        for key in 0..30000 {
+            kv.write(key, Some(key + 1)).unwrap();
+        }
+        // Shadow some flushed values, both in the log and in newer tables
+        kv.write(5, None).unwrap();
+        kv.write(6, Some(60)).unwrap();
+
+        let keys = [6, 99999, 5, 20000, 0, 29999, 20000];
+        let expected: Vec<_> = keys.iter().map(|k| kv.read(k).unwrap()).collect();
+
+        assert_eq!(kv.multi_get(&keys).unwrap(), expected);
+        assert_eq!(
+            expected,
+            [
+                Some(60),
+                None,
+                None,
+                Some(20001),
+                Some(1),
+                Some(30000),
+                Some(20001)
+            ]
+        );
+        assert_eq!(kv.multi_get(&[]).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_snapshot_reads() {
+        let location = test_location();
+
+        let kv = KVStorage::new(&location).unwrap();
+        for key in 0..20000 {
+            kv.write(key, Some(key)).unwrap();
+        }
+
+        let snapshot = kv.snapshot().unwrap();
+
+        // Overwrite everything, rotating and compacting several times
+        for key in 0..40000 {
+            kv.write(key, Some(key + 1)).unwrap();
+        }
+        kv.write(1, None).unwrap();
+
+        assert_eq!(kv.read(&1).unwrap(), None);
+        assert_eq!(kv.read(&15000).unwrap(), Some(15001));
+        assert_eq!(snapshot.read(&1).unwrap(), Some(1));
+        assert_eq!(snapshot.read(&15000).unwrap(), Some(15000));
+        assert_eq!(snapshot.read(&19999).unwrap(), Some(19999));
+        assert_eq!(snapshot.read(&30000).unwrap(), None);
+    }
+
+    #[test]
+    fn test_snapshot_ignores_later_tables() {
+        let kv = KVStorage::new_in_memory().unwrap();
+        kv.write(1, Some(1)).unwrap();
+        kv.flush().unwrap();
+        let sequence = kv.last_sequence();
+
+        kv.write(1, Some(2)).unwrap();
+        kv.write(2, Some(2)).unwrap();
+        kv.flush().unwrap();
+
+        // Tables written after the snapshot sequence are left out even when handed to it
+        let tables = kv.inner.table_view.load().to_vec();
+        assert_eq!(tables.len(), 2);
+        assert_eq!(tables[0].min_sequence(), sequence + 1);
+        let order = kv.inner.table_files.order().clone();
+        let snapshot = Snapshot::new(
+            BTreeMap::new(),
+            Vec::new(),
+            tables.clone(),
+            sequence,
+            0,
+            order.clone(),
+        );
+        assert_eq!(snapshot.read(&1).unwrap(), Some(1));
+        assert_eq!(snapshot.read(&2).unwrap(), None);
+        assert_eq!(snapshot.iter().collect::<Vec<_>>(), vec![(1, 1)]);
+
+        let latest = Snapshot::new(BTreeMap::new(), Vec::new(), tables, sequence + 2, 0, order);
+        assert_eq!(latest.read(&1).unwrap(), Some(2));
+        assert_eq!(kv.snapshot().unwrap().sequence(), sequence + 2);
+    }
+
+    #[test]
+    fn test_iter_seek_and_reverse() {
+        let location = test_location();
+        let kv = KVStorage::new(&location).unwrap();
+
+        // Only even keys, so odd seeks land between index entries
+        let mut expected = std::collections::BTreeMap::new();
+        for key in (0..40000).step_by(2) {
+            kv.write(key, Some(key)).unwrap();
+            expected.insert(key, key);
+        }
+        for key in (100..200).step_by(2) {
+            kv.write(key, None).unwrap();
+            expected.remove(&key);
+        }
+        kv.write(39998, Some(1)).unwrap();
+        expected.insert(39998, 1);
+
+        let mut iter = kv.iter().unwrap();
+        assert!(iter.by_ref().eq(expected.clone()));
+        assert!(iter.error().is_none());
+
+        // Walking backwards from the end
+        iter.seek_to_end();
+        let reversed: Vec<_> = std::iter::from_fn(|| iter.prev()).collect();
+        assert!(reversed.into_iter().eq(expected.clone().into_iter().rev()));
+
+        // Seeks at the table boundaries, between keys and on tombstones
+        let boundaries: Vec<_> = kv
+            .inner
+            .sstables
+            .lock()
+            .unwrap()
+            .iter()
+            .flat_map(|t| {
+                let (min, max) = t.key_range().unwrap();
+                [min.saturating_sub(1), min, max, max + 1]
+            })
+            .collect();
+        let mut iter = kv.iter().unwrap();
+        for seek in boundaries
+            .into_iter()
+            .chain([0, 1, 99, 100, 150, 39997, 39999, u64::MAX])
+        {
+            iter.seek(seek);
+            assert_eq!(
+                iter.next(),
+                expected.range(seek..).next().map(|(k, v)| (*k, *v)),
+                "seek to {seek}"
+            );
+
+            iter.seek(seek);
+            assert_eq!(
+                iter.prev(),
+                expected.range(..seek).next_back().map(|(k, v)| (*k, *v)),
+                "seek to {seek}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_key_comparators() {
+        let comparators: [Arc<dyn KeyComparator>; 2] = [Arc::new(Ascending), Arc::new(Descending)];
+        for comparator in comparators {
+            let location = test_location();
+            let options = Options::new()
+                .key_comparator(comparator.clone())
+                .compaction_policy(CompactionPolicy::SizeTiered {
+                    ratio: 2.0,
+                    min_merge: 2,
+                });
+            let sorted = |mut keys: Vec<Key>| {
+                keys.sort_by(|a, b| comparator.cmp(a, b));
+                keys
+            };
+
+            let kv = KVStorage::new_with_options(&location, options.clone()).unwrap();
+            // Two rounds over many tables so that merges happen
+            for round in 0..2 {
+                for key in 0..15000 {
+                    kv.write(key, Some(key + round)).unwrap();
+                }
+            }
+            let [start, end] = sorted(vec![1000, 14000])[..] else {
+                unreachable!()
+            };
+            kv.delete_range(start, end).unwrap();
+            assert!(kv.wait_for_pending_compactions(COMPACTION_TIMEOUT).unwrap());
+
+            let live = sorted(
+                (0..15000)
+                    .filter(|key| {
+                        comparator.cmp(key, &start).is_lt() || comparator.cmp(key, &end).is_ge()
+                    })
+                    .collect(),
+            );
+            let check = |kv: &KVStorage| {
+                assert_eq!(kv.read(&start).unwrap(), None);
+                assert_eq!(kv.read(&end).unwrap(), Some(end + 1));
+                assert_eq!(kv.read(&7000).unwrap(), None);
+
+                let mut iter = kv.iter().unwrap();
+                assert!(iter.by_ref().map(|(key, _)| key).eq(live.iter().copied()));
+                iter.seek_to_end();
+                let reversed: Vec<_> = std::iter::from_fn(|| iter.prev())
+                    .map(|(key, _)| key)
+                    .collect();
+                assert!(reversed.into_iter().eq(live.iter().rev().copied()));
+
+                // Seeks land on the first key at or after the sought one, in the order of the comparator
+                iter.seek(7000);
+                assert_eq!(iter.next().map(|(key, _)| key), Some(end));
+            };
+
+            check(&kv);
+            kv.close().unwrap();
+            let kv = KVStorage::open_with_options(&location, options.clone()).unwrap();
+            check(&kv);
+
+            // Checkpoints record the comparator too
+            let checkpoint_location = Path::new(&location).join("checkpoint");
+            kv.checkpoint(&checkpoint_location).unwrap();
+            let checkpoint =
+                KVStorage::open_with_options(checkpoint_location.to_str().unwrap(), options)
+                    .unwrap();
+            check(&checkpoint);
+
+            // Bulk loads follow the order too
+            let keys = sorted((20000..20010).collect());
+            kv.bulk_load(keys.iter().map(|key| (*key, Some(*key))))
+                .unwrap();
+            assert_eq!(kv.read(&20005).unwrap(), Some(20005));
+            assert!(matches!(
+                kv.bulk_load(keys.iter().rev().map(|key| (*key, Some(*key)))),
+                Err(Error::UnsortedBulkLoad { position: 1 })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_comparator_mismatch() {
+        let location = test_location();
+        let kv = KVStorage::new_with_options(
+            &location,
+            Options::new().key_comparator(Arc::new(Descending)),
+        )
+        .unwrap();
+        kv.write(1, Some(1)).unwrap();
+        kv.close().unwrap();
+
+        assert!(matches!(
+            KVStorage::open(&location),
+            Err(Error::ComparatorMismatch { stored, configured })
+                if stored == "descending" && configured == "ascending"
+        ));
+        let kv = KVStorage::open_with_options(
+            &location,
+            Options::new().key_comparator(Arc::new(Descending)),
+        )
+        .unwrap();
+        assert_eq!(kv.read(&1).unwrap(), Some(1));
+    }
+
+    #[test]
+    fn test_count_and_approximate_len() {
+        let location = test_location();
+        let kv = KVStorage::new(&location).unwrap();
+        assert!(kv.is_empty().unwrap());
+        assert_eq!(kv.count().unwrap(), 0);
+
+        // Two rounds of overwrites across many tables, then deletes in the log
+        for round in 0..2 {
+            for key in 0..15000 {
+                kv.write(key, Some(key + round)).unwrap();
+            }
+        }
+        for key in 0..100 {
+            kv.write(key, None).unwrap();
+        }
+
+        assert!(!kv.is_empty().unwrap());
+        assert_eq!(kv.count().unwrap(), 14900);
+        let approximate = kv.approximate_len();
+        assert!((7450..=45000).contains(&approximate), "{approximate}");
+    }
+
+    #[test]
+    fn test_approximate_size() {
+        let kv = KVStorage::new_in_memory().unwrap();
+        for i in 0..40000 {
+            kv.write(1000 + i * 10, Some(i)).unwrap();
+        }
+        kv.flush().unwrap();
+
+        for (start, end) in [
+            (0, u64::MAX),
+            (100000, 200000),
+            (1000, 1500),
+            (350000, 401000),
+        ] {
+            let exact = (0..40000u64)
+                .filter(|i| (start..end).contains(&(1000 + i * 10)))
+                .count() as u64;
+            let estimate = kv.approximate_size(start, end);
+            assert!(
+                (exact / 2..=exact * 2).contains(&estimate.entries),
+                "{start}..{end}: {estimate:?}, {exact} entries"
+            );
+            assert!(estimate.bytes > 0);
+        }
+
+        assert_eq!(kv.approximate_size(5000, 5000), RangeEstimate::default());
+        assert_eq!(kv.approximate_size(0, 1000), RangeEstimate::default());
+        assert_eq!(
+            kv.approximate_size(500000, 600000),
+            RangeEstimate::default()
+        );
+
+        // The append log is counted exactly
+        for key in 500000..500005 {
+            kv.write(key, None).unwrap();
+        }
+        assert_eq!(kv.approximate_size(500000, 600000).entries, 5);
+    }
+
+    #[test]
+    fn test_space_report() {
+        // No merges, the overwritten value stays
+        let kv = KVStorage::new_in_memory_with_options(Options::new().compaction_policy(
+            CompactionPolicy::SizeTiered {
+                ratio: 2.0,
+                min_merge: usize::MAX,
+            },
+        ))
+        .unwrap();
+        for key in 0..1000 {
+            kv.write(key, Some(key)).unwrap();
+        }
+        for key in 0..100 {
+            kv.write(key, None).unwrap();
+        }
+        kv.flush().unwrap();
+        kv.write(500, Some(1)).unwrap();
+        kv.flush().unwrap();
+
+        let mut value_sizes = [0; VALUE_SIZE_BUCKETS];
+        value_sizes[0] = 100;
+        value_sizes[4] = 1001;
+        assert_eq!(kv.stats().value_sizes, value_sizes);
+
+        let report = kv.space_report(3, &AtomicBool::new(false)).unwrap();
+        assert_eq!(report.entries, 1001);
+        value_sizes[4] = 901;
+        assert_eq!(report.value_sizes, value_sizes);
+        // All values take 8 bytes, the smallest keys come first
+        assert_eq!(
+            report
+                .largest
+                .iter()
+                .map(|value| (value.key, value.size))
+                .collect::<Vec<_>>(),
+            [(100, 8), (101, 8), (102, 8)]
+        );
+
+        assert!(matches!(
+            kv.space_report(3, &AtomicBool::new(true)),
+            Err(Error::Cancelled)
+        ));
+    }
+
+    #[test]
+    fn test_delete_range() {
+        let location = test_location();
+        let kv = KVStorage::new(&location).unwrap();
+
+        for key in 0..15000 {
+            kv.write(key, Some(key)).unwrap();
+        }
+        // Covers keys both in the SSTables and in the append log
+        kv.delete_range(100, 14900).unwrap();
+        kv.write(500, Some(1)).unwrap();
+
+        let check = |kv: &KVStorage| {
+            assert_eq!(kv.read(&99).unwrap(), Some(99));
+            assert_eq!(kv.read(&100).unwrap(), None);
+            assert_eq!(kv.read(&14899).unwrap(), None);
+            assert_eq!(kv.read(&14900).unwrap(), Some(14900));
+            assert_eq!(kv.read(&500).unwrap(), Some(1));
+            assert_eq!(
+                kv.multi_get(&[99, 100, 500]).unwrap(),
+                [Some(99), None, Some(1)]
+            );
+            assert_eq!(kv.count().unwrap(), 201);
+        };
+
+        check(&kv);
+        kv.inner
+            .append_log
+            .flush(&kv.inner.sstables, &kv.inner.manifest)
+            .unwrap();
+        check(&kv);
+
+        kv.close().unwrap();
+        let kv = KVStorage::open(&location).unwrap();
+        check(&kv);
+    }
+
+    #[test]
+    fn test_range_tombstone_never_shadows_newer_writes() {
+        const KEYS: u64 = 16;
+
+        let storage = Arc::new(MemStorage::new());
+        let options = Options::new().write_shards(4);
+        let kv = KVStorage::create(storage.clone(), Path::new("db"), options).unwrap();
+        // Slow log writes leave room for a flush between the copies of a tombstone
+        storage.failpoints().set(
+            failpoints::WRITE_DATA_AT_OFFSET,
+            0,
+            FailAction::Delay(Duration::from_millis(1)),
+        );
+
+        for round in 0..40 {
+            let writer = {
+                let kv = kv.clone();
+                std::thread::spawn(move || {
+                    for key in 0..KEYS {
+                        kv.write(key, Some(round)).unwrap();
+                    }
+                })
+            };
+            let deleter = {
+                let kv = kv.clone();
+                std::thread::spawn(move || {
+                    std::thread::sleep(Duration::from_millis(2));
+                    kv.delete_range(0, KEYS).unwrap();
+                })
+            };
+            // Moves point writes to the tables, at times while the tombstone goes from shard to shard
+            std::thread::sleep(Duration::from_micros(500 * (round % 10)));
+            kv.flush().unwrap();
+            writer.join().unwrap();
+            deleter.join().unwrap();
+
+            // Whatever wins, the answer doesn't change once both are in the tables
+            let before: Vec<_> = (0..KEYS).map(|key| kv.read(&key).unwrap()).collect();
+            kv.flush().unwrap();
+            let after: Vec<_> = (0..KEYS).map(|key| kv.read(&key).unwrap()).collect();
+            assert_eq!(before, after, "round {round}");
+        }
+    }
+
+    #[test]
+    fn test_failed_range_tombstone_deletes_nothing() {
+        const KEYS: u64 = 64;
+
+        let storage = Arc::new(MemStorage::new());
+        let options = Options::new().write_shards(4);
+        let kv = KVStorage::create(storage.clone(), Path::new("db"), options.clone()).unwrap();
+        for key in 0..KEYS {
+            kv.write(key, Some(key)).unwrap();
+        }
+
+        // The copy of the first shard is written, the one of the second fails
+        storage.failpoints().set_once(
+            failpoints::WRITE_DATA_AT_OFFSET,
+            1,
+            FailAction::Error(std::io::ErrorKind::Other),
+        );
+        assert!(kv.delete_range(0, KEYS).is_err());
+        for key in 0..KEYS {
+            assert_eq!(kv.read(&key).unwrap(), Some(key));
+        }
+        drop(kv);
+
+        let kv = KVStorage::open_dir(storage.clone(), PathBuf::from("db"), options, false).unwrap();
+        for key in 0..KEYS {
+            assert_eq!(kv.read(&key).unwrap(), Some(key));
+        }
+        // The log files still take writes after the padded records
+        kv.delete_range(0, KEYS / 2).unwrap();
+        assert_eq!(kv.read(&0).unwrap(), None);
+        assert_eq!(kv.read(&(KEYS / 2)).unwrap(), Some(KEYS / 2));
+    }
+
+    #[test]
+    fn test_write_shards() {
+        const THREADS: u64 = 4;
+
+        let location = test_location();
+        let options = Options::new().write_shards(3);
+        let kv = KVStorage::new_with_options(&location, options).unwrap();
+
+        let writers: Vec<_> = (0..THREADS)
+            .map(|thread| {
+                let kv = kv.clone();
+                std::thread::spawn(move || {
+                    for i in 0..10000 {
+                        kv.write(i * THREADS + thread, Some(i)).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(kv.last_sequence(), 40000);
+
+        // Every shard flushes its copy of the range tombstone, the rewritten keys must still win over it
+        kv.delete_range(1000, 2000).unwrap();
+        for key in (1000..2000).step_by(2) {
+            kv.write(key, Some(1)).unwrap();
+        }
+        kv.inner
+            .append_log
+            .flush(&kv.inner.sstables, &kv.inner.manifest)
+            .unwrap();
+
+        let check = |kv: &KVStorage| {
+            for key in (0..40000).step_by(7) {
+                let expected = match key {
+                    1000..2000 if key % 2 == 0 => Some(1),
+                    1000..2000 => None,
+                    _ => Some(key / THREADS),
+                };
+                assert_eq!(kv.read(&key).unwrap(), expected, "key {key}");
+                assert_eq!(kv.multi_get(&[key]).unwrap(), [expected]);
+            }
+            assert_eq!(kv.count().unwrap(), 39500);
+        };
+
+        check(&kv);
+        kv.close().unwrap();
+        let kv = KVStorage::open(&location).unwrap();
+        assert_eq!(kv.inner.append_log.shard_count(), 3);
+        check(&kv);
+    }
+
+    #[test]
+    fn test_recycled_log_files() {
+        let location = test_location();
+        let options = Options::new().write_shards(1).recycled_log_files(2);
+        let kv = KVStorage::new_with_options(&location, options).unwrap();
+
+        let log_files = || {
+            fs::read_dir(Path::new(&location).join("db"))
+                .unwrap()
+                .filter(|e| {
+                    let name = e.as_ref().unwrap().file_name();
+                    name.to_string_lossy().starts_with(LOG_FILE_PREFIX)
+                })
+                .count()
+        };
+
+        // Every round fills some log files, the last one is flushed on its own
+        const ROUNDS: u64 = 20;
+        for round in 0..ROUNDS {
+            for key in 0..3000 {
+                kv.write(key, Some(round)).unwrap();
+            }
+            kv.inner
+                .append_log
+                .flush(&kv.inner.sstables, &kv.inner.manifest)
+                .unwrap();
+
+            // The one in use and the recycled ones
+            assert!(log_files() <= 3);
+        }
+
+        // Only a few entries in a reused file, followed by zeroes rather than older rounds
+        kv.write(0, None).unwrap();
+        drop(kv);
+
+        let kv = KVStorage::open(&location).unwrap();
+        assert_eq!(kv.read(&0).unwrap(), None);
+        for key in 1..3000 {
+            assert_eq!(kv.read(&key).unwrap(), Some(ROUNDS - 1));
+        }
+        assert_eq!(log_files(), 1);
+    }
+
+    #[test]
+    fn test_unsupported_log_version() {
+        let location = test_location();
+        let kv = KVStorage::new_with_options(&location, Options::new().write_shards(1)).unwrap();
+        kv.write(1, Some(1)).unwrap();
+        drop(kv);
+
+        let log_path = fs::read_dir(Path::new(&location).join("db"))
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .find(|path| {
+                path.file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .starts_with(LOG_FILE_PREFIX)
+            })
+            .unwrap();
+        let mut content = fs::read(&log_path).unwrap();
+        // Records start right after the header
+        assert_ne!(content[file_header::HEADER_BYTES as usize], 0);
+        content[4] += 1;
+        fs::write(&log_path, &content).unwrap();
+
+        assert!(matches!(
+            KVStorage::open(&location),
+            Err(Error::UnsupportedVersion { found, supported })
+                if found == file_header::LOG_FORMAT_VERSION + 1
+                    && supported == file_header::LOG_FORMAT_VERSION
+        ));
+    }
+
+    #[test]
+    fn test_read_log_with_framed_records() {
+        let location = test_location();
+        let options = || Options::new().write_shards(1).spill_values(true);
+        let kv = KVStorage::new_with_options(&location, options()).unwrap();
+        drop(kv);
+
+        let log_path = fs::read_dir(Path::new(&location).join("db"))
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .find(|path| {
+                path.file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .starts_with(LOG_FILE_PREFIX)
+            })
+            .unwrap();
+
+        // As written by the last version with framed records
+        let mut header = file_header::FileHeader::new(file_header::FileKind::Log);
+        header.version = file_header::LOG_FORMAT_VERSION - 1;
+        header.min_reader_version = 0;
+        let mut content = fs::read(&log_path).unwrap();
+        let mut records = header.encode().to_vec();
+        for key in 0..100 {
+            let entry = KVMemoryRepr::new(key, Some(key * 2), key + 1);
+            records.extend(serialization::serialize(&entry, RecordFormat::Framed).unwrap());
+        }
+        content[..records.len()].copy_from_slice(&records);
+        fs::write(&log_path, &content).unwrap();
+
+        // New writes go on in the format of the file
+        let kv = KVStorage::open_with_options(&location, options()).unwrap();
+        for key in 0..100 {
+            assert_eq!(kv.read(&key).unwrap(), Some(key * 2));
+        }
+        kv.write(100, Some(200)).unwrap();
+        kv.write(0, None).unwrap();
+        drop(kv);
+
+        let kv = KVStorage::open_with_options(&location, options()).unwrap();
+        assert_eq!(kv.read(&0).unwrap(), None);
+        assert_eq!(kv.read(&100).unwrap(), Some(200));
+
+        // Into a table with fixed records, the next log file too
+        kv.flush().unwrap();
+        kv.write(101, Some(202)).unwrap();
+        drop(kv);
+        let kv = KVStorage::open_with_options(&location, options()).unwrap();
+        for key in 1..=101 {
+            assert_eq!(kv.read(&key).unwrap(), Some(key * 2));
+        }
+        assert_eq!(kv.read(&0).unwrap(), None);
+    }
+
+    #[test]
+    fn test_more_tables_than_open_files() {
+        let location = test_location();
+        let options = Options::new()
+            .write_shards(1)
+            .max_open_tables(2)
+            .compaction_policy(CompactionPolicy::SizeTiered {
+                ratio: 2.0,
+                min_merge: 100,
+            });
+        let kv = KVStorage::new_with_options(&location, options.clone()).unwrap();
+
+        for table in 0..10 {
+            for key in table * 100..(table + 1) * 100 {
+                kv.write(key, Some(key * 2)).unwrap();
+            }
+            kv.inner
+                .append_log
+                .flush(&kv.inner.sstables, &kv.inner.manifest)
+                .unwrap();
+        }
+        assert_eq!(kv.inner.sstables.lock().unwrap().len(), 10);
+
+        for key in 0..1000 {
+            assert_eq!(kv.read(&key).unwrap(), Some(key * 2));
+        }
+        assert!(kv.stats().open_table_files <= 2);
+        drop(kv);
+
+        let kv = KVStorage::open_with_options(&location, options).unwrap();
+        let keys: Vec<_> = (0..1000).rev().collect();
+        let values = kv.multi_get(&keys).unwrap();
+        assert!(keys.iter().zip(values).all(|(k, v)| v == Some(k * 2)));
+        assert!(kv.stats().open_table_files <= 2);
+    }
+
+    #[test]
+    fn test_sync_writes() {
+        let location = test_location();
+        let options = Options::new()
+            .sync_writes(true)
+            .group_commit_delay(Duration::from_millis(1));
+        let kv = KVStorage::new_with_options(&location, options).unwrap();
+
+        let writers: Vec<_> = (0..4)
+            .map(|thread| {
+                let kv = kv.clone();
+                std::thread::spawn(move || {
+                    for i in 0..500 {
+                        kv.write(i * 4 + thread, Some(i)).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        drop(kv);
+
+        // Nothing was flushed, everything is replayed from the synced logs
+        let kv = KVStorage::open(&location).unwrap();
+        for key in 0..2000 {
+            assert_eq!(kv.read(&key).unwrap(), Some(key / 4));
+        }
+    }
+
+    #[test]
+    fn test_write_durability() {
+        let storage = Arc::new(MemStorage::new());
+        let kv = KVStorage::create(storage.clone(), Path::new("db"), Options::new()).unwrap();
+        let synced = WriteOptions {
+            durability: Durability::Synced,
+        };
+
+        // Read-your-writes in both modes
+        kv.write(1, Some(1)).unwrap();
+        assert_eq!(kv.read(&1).unwrap(), Some(1));
+        kv.write_with(2, Some(2), &synced).unwrap();
+        assert_eq!(kv.read(&2).unwrap(), Some(2));
+
+        // Buffered writes don't sync
+        storage.failpoints().set(
+            failpoints::SYNC_DATA,
+            0,
+            FailAction::Error(std::io::ErrorKind::Other),
+        );
+        kv.write(3, Some(3)).unwrap();
+        let ttl = Duration::from_secs(60);
+        kv.write_with_ttl(5, 5, ttl, &WriteOptions::default())
+            .unwrap();
+        assert!(kv.is_healthy());
+
+        // The failed sync isn't reported as success, the write is readable anyway
+        let error = kv.write_with(4, Some(4), &synced).unwrap_err();
+        assert!(matches!(error, Error::NotDurable(_)));
+        assert_eq!(kv.read(&4).unwrap(), Some(4));
+        assert!(!kv.is_healthy());
+
+        // Same for writes with a TTL
+        let kv = KVStorage::create(storage.clone(), Path::new("db2"), Options::new()).unwrap();
+        let error = kv.write_with_ttl(6, 6, ttl, &synced).unwrap_err();
+        assert!(matches!(error, Error::NotDurable(_)));
+        assert_eq!(kv.read(&6).unwrap(), Some(6));
+        assert!(!kv.is_healthy());
+    }
+
+    #[test]
+    fn test_failed_group_commit_is_not_durable() {
+        let storage = Arc::new(MemStorage::new());
+        let options = Options::new().sync_writes(true);
+        let kv = KVStorage::create(storage.clone(), Path::new("db"), options.clone()).unwrap();
+        kv.write(1, Some(1)).unwrap();
+
+        storage.failpoints().set(
+            failpoints::SYNC_DATA,
+            0,
+            FailAction::Error(std::io::ErrorKind::Other),
+        );
+        let error = kv.write(2, Some(2)).unwrap_err();
+        storage.failpoints().clear(failpoints::SYNC_DATA);
+        assert!(matches!(error, Error::NotDurable(_)));
+        assert!(kv.health().sync_failed);
+        assert!(!kv.is_healthy());
+
+        // In the log file, so readable now as it is after a reopen
+        assert_eq!(kv.read(&2).unwrap(), Some(2));
+        kv.write(3, Some(3)).unwrap();
+        drop(kv);
+
+        let kv = KVStorage::open_dir(storage.clone(), PathBuf::from("db"), options, false).unwrap();
+        for key in 1..=3 {
+            assert_eq!(kv.read(&key).unwrap(), Some(key));
+        }
+        assert!(kv.is_healthy());
+    }
+
+    #[test]
+    fn test_watch() {
+        const UPDATES: u64 = 200;
+
+        let kv = KVStorage::new_in_memory().unwrap();
+        let watch = kv.watch(1).unwrap();
+        assert_eq!(watch.latest(), None);
+        assert_eq!(watch.wait_for_change(Duration::ZERO), None);
+
+        let (acks, acked) = std::sync::mpsc::channel();
+        let writer = {
+            let kv = kv.clone();
+            std::thread::spawn(move || {
+                for value in 1..=UPDATES {
+                    kv.write(1, Some(value)).unwrap();
+                    kv.write(2, Some(value)).unwrap();
+                    // Every other update waits for the watcher, the others may be coalesced
+                    if value.is_multiple_of(2) {
+                        acked.recv().unwrap();
+                    }
+                }
+                kv.delete_range(0, 10).unwrap();
+            })
+        };
+
+        let mut last = 0;
+        while last < UPDATES {
+            let value = watch
+                .wait_for_change(Duration::from_secs(5))
+                .unwrap()
+                .unwrap();
+            assert!(value > last);
+            last = value;
+            if value.is_multiple_of(2) {
+                acks.send(()).unwrap();
+            }
+        }
+        writer.join().unwrap();
+        assert_eq!(watch.wait_for_change(Duration::from_secs(5)), Some(None));
+        assert_eq!(watch.latest(), None);
+
+        kv.write(3, Some(3)).unwrap();
+        let other = kv.watch(3).unwrap();
+        assert_eq!(other.latest(), Some(3));
+        kv.write(3, None).unwrap();
+        assert_eq!(other.wait_for_change(Duration::from_secs(5)), Some(None));
+
+        assert_eq!(kv.inner.changes.watches().watched_keys(), 2);
+        drop((watch, other));
+        assert_eq!(kv.inner.changes.watches().watched_keys(), 0);
+    }
+
+    #[test]
+    fn test_subscribe_and_changes_since() {
+        let location = test_location();
+        let options = Options::new().subscriber_overflow(OverflowPolicy::Block);
+        let kv = KVStorage::new_with_options(&location, options).unwrap();
+
+        for key in 0..10 {
+            kv.write(100_000 + key, Some(key)).unwrap();
+        }
+
+        let receiver = kv.subscribe();
+        let subscriber = std::thread::spawn(move || receiver.take(15002).collect::<Vec<_>>());
+
+        for key in 0..15000 {
+            kv.write(key, Some(key * 2)).unwrap();
+        }
+        kv.delete_range(5, 10).unwrap();
+        kv.write(3, None).unwrap();
+
+        let received = subscriber.join().unwrap();
+        assert!(received.iter().map(|c| c.sequence).eq(11..=15012));
+        assert!(
+            received[..15000]
+                .iter()
+                .enumerate()
+                .all(|(i, c)| c.key == i as u64 && c.value == Some(i as u64 * 2))
+        );
+        assert_eq!(
+            (received[15000].key, received[15000].range_end),
+            (5, Some(10))
+        );
+        assert_eq!((received[15001].key, received[15001].value), (3, None));
+
+        // Every key was written once, so nothing was compacted away
+        assert_eq!(kv.changes_since(10).unwrap(), received);
+        assert_eq!(kv.changes_since(15011).unwrap(), received[15001..]);
+    }
+
+    #[derive(Default)]
+    struct RecordingListener(Mutex<Vec<String>>);
+
+    impl EventListener for RecordingListener {
+        fn on_flush_begin(&self, _info: &FlushInfo) {
+            self.0.lock().unwrap().push("flush_begin".to_owned());
+        }
+
+        fn on_flush_complete(&self, info: &FlushInfo) {
+            let table = info.table_id.unwrap();
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("flush_complete {table}"));
+        }
+
+        fn on_compaction_begin(&self, info: &CompactionInfo) {
+            let tables = &info.input_tables;
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("compaction_begin {tables:?}"));
+        }
+
+        fn on_compaction_complete(&self, info: &CompactionInfo) {
+            let (tables, output) = (&info.input_tables, info.output_table.unwrap());
+            let dropped = info.dropped_tombstones;
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("compaction_complete {tables:?} {output} {dropped}"));
+        }
+
+        fn on_background_error(&self, error: &Error) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("background_error {error:?}"));
+        }
+    }
+
+    #[test]
+    fn test_event_listener() {
+        let listener = Arc::new(RecordingListener::default());
+        // A single shard, so that every flush creates exactly one table
+        let options = Options::new()
+            .write_shards(1)
+            .compaction_policy(CompactionPolicy::SizeTiered {
+                ratio: 2.0,
+                min_merge: 2,
+            })
+            .event_listener(listener.clone());
+        let kv = KVStorage::new_in_memory_with_options(options).unwrap();
+
+        for key in 0..100 {
+            kv.write(key, Some(key)).unwrap();
+        }
+        kv.inner
+            .append_log
+            .flush(&kv.inner.sstables, &kv.inner.manifest)
+            .unwrap();
+        for key in 100..200 {
+            kv.write(key, Some(key)).unwrap();
+        }
+        kv.write(5, None).unwrap();
+        kv.inner
+            .append_log
+            .flush(&kv.inner.sstables, &kv.inner.manifest)
+            .unwrap();
+        let [new, old] = kv.inner.manifest.data().sstables[..] else {
+            panic!("expected two tables");
+        };
+
+        assert!(kv.wait_for_pending_compactions(COMPACTION_TIMEOUT).unwrap());
+
+        let [merged] = kv.inner.manifest.data().sstables[..] else {
+            panic!("expected a single table");
+        };
+        let events = listener.0.lock().unwrap().clone();
+        assert_eq!(
+            events,
+            [
+                "flush_begin".to_owned(),
+                format!("flush_complete {old}"),
+                "flush_begin".to_owned(),
+                format!("flush_complete {new}"),
+                format!("compaction_begin [{new}, {old}]"),
+                format!("compaction_complete [{new}, {old}] {merged} 1"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_seeded_env() {
+        let file_names = || {
+            let location = test_location();
+            let options = Options::new().write_shards(1);
+            let kv = KVStorage::new_with_env(&location, options, env::Env::seeded(7)).unwrap();
+            for key in 0..100 {
+                kv.write(key, Some(key)).unwrap();
+            }
+            kv.flush().unwrap();
+
+            let data = kv.inner.manifest.data();
+            (data.log_files.clone(), data.sstables.clone())
+        };
+
+        assert_eq!(file_names(), file_names());
+    }
+
+    #[test]
+    fn test_memtable_max_entries() {
+        #[derive(Default)]
+        struct Reasons(Mutex<Vec<FlushReason>>);
+
+        impl EventListener for Reasons {
+            fn on_flush_complete(&self, info: &FlushInfo) {
+                self.0.lock().unwrap().push(info.reason);
+            }
+        }
+
+        let location = test_location();
+        let listener = Arc::new(Reasons::default());
+        let options = Options::new()
+            .write_shards(1)
+            .memtable_max_entries(100)
+            .event_listener(listener.clone());
+        let kv = KVStorage::new_with_options(&location, options.clone()).unwrap();
+
+        // Far from filling the log file
+        for key in 0..1050 {
+            kv.write(key % 700, Some(key)).unwrap();
+        }
+        assert_eq!(
+            *listener.0.lock().unwrap(),
+            [FlushReason::MemtableLimit; 10]
+        );
+        assert_eq!(kv.inner.append_log.entry_count(), 50);
+        for key in 350..1050 {
+            assert_eq!(kv.read(&(key % 700)).unwrap(), Some(key));
+        }
+
+        kv.flush().unwrap();
+        assert_eq!(
+            listener.0.lock().unwrap().last(),
+            Some(&FlushReason::Manual)
+        );
+        drop(kv);
+        let kv = KVStorage::open_with_options(&location, options).unwrap();
+        for key in 350..1050 {
+            assert_eq!(kv.read(&(key % 700)).unwrap(), Some(key));
+        }
+    }
+
+    #[test]
+    fn test_flush_queue() {
+        // Slower than the writes filling the memtables
+        struct Throttled(Mutex<usize>);
+
+        impl EventListener for Throttled {
+            fn on_flush_begin(&self, _info: &FlushInfo) {
+                std::thread::sleep(Duration::from_millis(20));
+            }
+
+            fn on_flush_complete(&self, _info: &FlushInfo) {
+                *self.0.lock().unwrap() += 1;
+            }
+        }
+
+        let location = test_location();
+        let listener = Arc::new(Throttled(Mutex::new(0)));
+        let options = Options::new()
+            .write_shards(1)
+            .memtable_max_entries(10)
+            .max_immutable_memtables(3)
+            .compaction_policy(CompactionPolicy::SizeTiered {
+                ratio: 2.0,
+                min_merge: 100,
+            })
+            .event_listener(listener.clone());
+        let kv = KVStorage::new_with_options(&location, options.clone()).unwrap();
+
+        // Every memtable overwrites the keys of the previous one
+        let mut longest_queue = 0;
+        for round in 0..12 {
+            for key in 0..10 {
+                kv.write(key, Some(round * 100 + key)).unwrap();
+            }
+            for key in 0..10 {
+                assert_eq!(kv.read(&key).unwrap(), Some(round * 100 + key));
+            }
+            let queued = kv.inner.manifest.data().frozen_log_files(0).len();
+            longest_queue = longest_queue.max(queued);
+        }
+        assert!(longest_queue > 1);
+        assert!(longest_queue <= 3);
+        assert!(*listener.0.lock().unwrap() < 12);
+
+        kv.flush().unwrap();
+        assert!(kv.inner.manifest.data().frozen_log_files(0).is_empty());
+        let tables = kv.inner.sstables.lock().unwrap().clone();
+        assert_eq!(tables.len(), 12);
+        // Newest first, registered in the order the memtables were filled
+        for pair in tables.windows(2) {
+            assert!(pair[0].min_sequence() > pair[1].max_sequence());
+        }
+        for key in 0..10 {
+            assert_eq!(kv.read(&key).unwrap(), Some(1100 + key));
+        }
+
+        // Queued memtables are replayed in order after a crash
+        for round in 12..16 {
+            for key in 0..10 {
+                kv.write(key, Some(round * 100 + key)).unwrap();
+            }
+        }
+        drop(kv);
+        let kv = KVStorage::open_with_options(&location, options).unwrap();
+        for key in 0..10 {
+            assert_eq!(kv.read(&key).unwrap(), Some(1500 + key));
+        }
+        kv.flush().unwrap();
+        let tables = kv.inner.sstables.lock().unwrap().clone();
+        for pair in tables.windows(2) {
+            assert!(pair[0].min_sequence() > pair[1].max_sequence());
+        }
+        for key in 0..10 {
+            assert_eq!(kv.read(&key).unwrap(), Some(1500 + key));
+        }
+    }
+
+    #[test]
+    fn test_sstables_dir_removed() {
+        let location = test_location();
+        let listener = Arc::new(RecordingListener::default());
+        let options = Options::new()
+            .write_shards(1)
+            .event_listener(listener.clone());
+        let kv = KVStorage::new_with_options(&location, options.clone()).unwrap();
+        for key in 0..100 {
+            kv.write(key, Some(key)).unwrap();
+        }
+
+        // Gone, and a file is in the way of creating it again
+        let sstables_dir = Path::new(&location).join("db").join("sstables");
+        fs::remove_dir_all(&sstables_dir).unwrap();
+        fs::write(&sstables_dir, b"").unwrap();
+        for _ in 0..3 {
+            assert!(kv.is_healthy());
+            assert!(matches!(kv.flush(), Err(Error::StorageUnavailable)));
+        }
+        assert!(!kv.is_healthy());
+        assert!(matches!(
+            kv.write(100, Some(100)),
+            Err(Error::StorageUnavailable)
+        ));
+        assert!(
+            listener
+                .0
+                .lock()
+                .unwrap()
+                .contains(&"background_error StorageUnavailable".to_owned())
+        );
+        for key in 0..100 {
+            assert_eq!(kv.read(&key).unwrap(), Some(key));
+        }
+
+        fs::remove_file(&sstables_dir).unwrap();
+        fs::create_dir(&sstables_dir).unwrap();
+        kv.write(100, Some(100)).unwrap();
+        assert!(kv.is_healthy());
+        kv.flush().unwrap();
+        assert_eq!(kv.inner.sstables.lock().unwrap().len(), 2);
+        drop(kv);
+
+        let kv = KVStorage::open_with_options(&location, options).unwrap();
+        for key in 0..=100 {
+            assert_eq!(kv.read(&key).unwrap(), Some(key));
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_metrics() {
+        use metrics_util::debugging::DebuggingRecorder;
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        assert!(recorder.install().is_ok());
+
+        let location = test_location();
+        let options = Options::new().compaction_policy(CompactionPolicy::SizeTiered {
+            ratio: 2.0,
+            min_merge: 2,
+        });
+        let kv = KVStorage::new_with_options(&location, options).unwrap();
+        // Rotates the log at least once, then merges the resulting tables
+        for key in 0..30000 {
+            kv.write(key * 2, Some(key)).unwrap();
+        }
+        kv.flush_and_wait().unwrap();
+        // Odd keys are missing, so the bloom filter rejects most of them
+        for key in 0..200 {
+            kv.read(&key).unwrap();
+        }
+
+        let names: std::collections::HashSet<_> = snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .map(|(key, ..)| key.key().name().to_owned())
+            .collect();
+        for name in [
+            "kv_writes_total",
+            "kv_reads_total",
+            "kv_read_sstables_probed",
+            "kv_compaction_bytes_written",
+            "kv_flush_duration_seconds",
+            "kv_bloom_filter_useful_total",
+        ] {
+            assert!(names.contains(name), "missing {name}");
+        }
+    }
+
+    #[test]
+    fn test_sequence_across_rotation_and_compaction() {
+        let location = test_location();
+
+        let kv = KVStorage::new(&location).unwrap();
+        assert_eq!(kv.last_sequence(), 0);
+
+        // Every round overwrites the same keys and spans at least one rotation
+        for round in 0..5 {
+            for key in 0..15000 {
+                kv.write(key, Some(key * 10 + round)).unwrap();
+            }
+        }
+        assert_eq!(kv.last_sequence(), 75000);
+
+        kv.close().unwrap();
+        let kv = KVStorage::open(&location).unwrap();
+        assert_eq!(kv.last_sequence(), 75000);
+
+        kv.write(1, None).unwrap();
+        assert_eq!(kv.last_sequence(), 75001);
+        assert_eq!(kv.read(&1).unwrap(), None);
+        for key in 2..15000 {
+            assert_eq!(kv.read(&key).unwrap(), Some(key * 10 + 4));
+        }
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        let location = test_location();
+        let kv = KVStorage::new(&location).unwrap();
+
+        assert_eq!(kv.compare_and_swap(1, Some(1), Some(2)).unwrap(), Err(None));
+        assert_eq!(kv.compare_and_swap(1, None, Some(2)).unwrap(), Ok(()));
+        assert_eq!(kv.compare_and_swap(1, None, Some(3)).unwrap(), Err(Some(2)));
+        assert_eq!(kv.compare_and_swap(1, Some(2), None).unwrap(), Ok(()));
+        assert_eq!(kv.read(&1).unwrap(), None);
+
+        // Two threads racing on the same key, exactly one must win every round
+        let kv = Arc::new(kv);
+        for round in 0..100 {
+            let current = kv.read(&2).unwrap();
+            let barrier = Arc::new(std::sync::Barrier::new(2));
+            let handles: Vec<_> = (0..2)
+                .map(|thread| {
+                    let kv = kv.clone();
+                    let barrier = barrier.clone();
+                    std::thread::spawn(move || {
+                        barrier.wait();
+                        kv.compare_and_swap(2, current, Some(round * 2 + thread))
+                            .unwrap()
+                    })
+                })
+                .collect();
+
+            let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+            let winners = results.iter().filter(|r| r.is_ok()).count();
+            assert_eq!(winners, 1);
+        }
+    }
+
+    #[test]
+    fn test_concurrent_updates() {
+        const THREADS: u64 = 8;
+        const UPDATES: u64 = 500;
+
+        let location = test_location();
+        let kv = KVStorage::new(&location).unwrap();
+
+        let handles: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let kv = kv.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..UPDATES {
+                        kv.update(1, |v| Some(v.unwrap_or(0) + 1)).unwrap();
+                        // Toggle between present and deleted
+                        kv.update(2, |v| match v {
+                            Some(_) => None,
+                            None => Some(1),
+                        })
+                        .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(kv.read(&1).unwrap(), Some(THREADS * UPDATES));
+        // An even number of toggles
+        assert_eq!(kv.read(&2).unwrap(), None);
+        assert_eq!(kv.update(3, |_| None).unwrap(), None);
+    }
+
+    #[test]
+    fn test_concurrent_overwrites() {
+        const THREADS: u64 = 8;
+        const WRITES: u64 = 6000;
+
+        let location = test_location();
+        let kv = KVStorage::new_with_options(&location, Options::new().write_shards(1)).unwrap();
+
+        // Every 10th write of a thread, its last one included, is chained to the previous chained writes through the
+        // lock. The other writes race, but all of them complete before the last chained write starts
+        let last_chained = Arc::new(Mutex::new(None));
+        let handles: Vec<_> = (0..THREADS)
+            .map(|thread| {
+                let kv = kv.clone();
+                let last_chained = last_chained.clone();
+                std::thread::spawn(move || {
+                    for i in 0..WRITES {
+                        let value = thread * WRITES + i;
+                        if i % 10 == 9 {
+                            let mut last = last_chained.lock().unwrap();
+                            kv.write(0, Some(value)).unwrap();
+                            *last = Some(value);
+                        } else {
+                            kv.write(0, Some(value)).unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let expected = *last_chained.lock().unwrap();
+        // The log rotated along the way
+        assert!(!kv.inner.sstables.lock().unwrap().is_empty());
+        assert_eq!(kv.read(&0).unwrap(), expected);
+        drop(kv);
+
+        // Replayed from the log
+        let kv = KVStorage::open(&location).unwrap();
+        assert_eq!(kv.read(&0).unwrap(), expected);
+        kv.flush_and_wait().unwrap();
+        assert_eq!(kv.read(&0).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_write_batching() {
+        const THREADS: u64 = 8;
+        const WRITES: u64 = 2000;
+
+        let location = test_location();
+        let options = Options::new()
+            .write_shards(1)
+            .subscriber_overflow(OverflowPolicy::Block);
+        let kv = KVStorage::new_with_options(&location, options).unwrap();
+        let receiver = kv.subscribe();
+        let subscriber = std::thread::spawn(move || {
+            receiver
+                .take((THREADS * WRITES) as usize)
+                .collect::<Vec<_>>()
+        });
+
+        let handles: Vec<_> = (0..THREADS)
+            .map(|thread| {
+                let kv = kv.clone();
+                std::thread::spawn(move || {
+                    for i in 0..WRITES {
+                        kv.write(thread, Some(i)).unwrap();
+                        // Readable as soon as the write returned, whoever wrote the batch
+                        assert_eq!(kv.read(&thread).unwrap(), Some(i));
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        // Published in the order of the sequences, the writes of every thread in the order they were made
+        let received = subscriber.join().unwrap();
+        assert!(received.iter().map(|c| c.sequence).eq(1..=THREADS * WRITES));
+        for thread in 0..THREADS {
+            let values = received.iter().filter(|c| c.key == thread).map(|c| c.value);
+            assert!(values.eq((0..WRITES).map(Some)));
+        }
+        drop(kv);
+
+        let kv = KVStorage::open(&location).unwrap();
+        for thread in 0..THREADS {
+            assert_eq!(kv.read(&thread).unwrap(), Some(WRITES - 1));
+        }
+    }
+
+    #[test]
+    fn test_aligned_log_records() {
+        const BLOCK: u64 = 256;
+
+        let location = test_location();
+        let options = Options::new().write_shards(1).align_log_records(BLOCK);
+        let kv = KVStorage::new_with_options(&location, options).unwrap();
+
+        // Sequences of 1 to 2 bytes and deletions give records of several sizes, from concurrent writers too
+        let handles: Vec<_> = (0..4)
+            .map(|thread| {
+                let kv = kv.clone();
+                std::thread::spawn(move || {
+                    for i in 0..1000 {
+                        let key = thread * 10_000 + i;
+                        let value = (i % 7 != 0).then_some(i);
+                        kv.write(key, value).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let records = |kv: &KVStorage| {
+            let mut records: Vec<_> = kv.inner.append_log.dump().unwrap()[0]
+                .entries
+                .iter()
+                .map(|(offset, change)| {
+                    let entry = KVMemoryRepr::new(change.key, change.value, change.sequence);
+                    let len = serialization::serialize(&entry, RecordFormat::CURRENT)
+                        .unwrap()
+                        .len() as u64;
+                    (*offset, len)
+                })
+                .collect();
+            records.sort();
+            records
+        };
+        let written = records(&kv);
+        assert_eq!(written.len(), 4000);
+
+        let mut padded = 0;
+        for (&(offset, len), &(next, next_len)) in written.iter().zip(&written[1..]) {
+            assert_eq!(
+                offset / BLOCK,
+                (offset + len - 1) / BLOCK,
+                "record at {offset}"
+            );
+            // Only the records that didn't fit in the rest of their block were moved, by less than a block
+            let padding = next - offset - len;
+            if padding > 0 {
+                padded += 1;
+                assert_eq!((offset + len + padding) % BLOCK, 0);
+                assert!(padding <= next_len + 1, "{padding} bytes of padding");
+            }
+        }
+        assert!(padded > 50);
+        drop(kv);
+
+        // Replayed over the padding
+        let kv = KVStorage::open(&location).unwrap();
+        assert_eq!(records(&kv), written);
+        for thread in 0..4 {
+            for i in 0..1000 {
+                let value = (i % 7 != 0).then_some(i);
+                assert_eq!(kv.read(&(thread * 10_000 + i)).unwrap(), value);
+            }
+        }
+    }
+
+    #[test]
+    fn test_concurrent_increments() {
+        let location = test_location();
+        let kv = KVStorage::new(&location).unwrap();
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let kv = kv.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..10000 {
+                        kv.increment(1, 1).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(kv.read(&1).unwrap(), Some(80000));
+    }
+
+    #[test]
+    fn test_increment_bounds() {
+        let location = test_location();
+        let kv = KVStorage::new(&location).unwrap();
+
+        assert_eq!(kv.increment(1, 5).unwrap(), 5);
+        assert_eq!(kv.decrement(1, 2).unwrap(), 3);
+        assert!(matches!(kv.decrement(1, 4), Err(Error::Overflow)));
+        assert_eq!(kv.read(&1).unwrap(), Some(3));
+
+        let saturating = IncrementOptions {
+            default: 0,
+            saturating: true,
+        };
+        assert_eq!(kv.decrement_with(1, 4, &saturating).unwrap(), 0);
+        assert_eq!(
+            kv.increment_with(1, u64::MAX, &saturating).unwrap(),
+            u64::MAX
+        );
+        assert!(matches!(kv.increment(1, 1), Err(Error::Overflow)));
+
+        // Tombstones count as missing
+        kv.write(2, None).unwrap();
+        let with_default = IncrementOptions {
+            default: 100,
+            saturating: false,
+        };
+        assert_eq!(kv.increment_with(2, 1, &with_default).unwrap(), 101);
+    }
+
+    /// Clock moved by hand, so that expiration can be tested without sleeping
+    #[derive(Default)]
+    struct ManualClock(std::sync::atomic::AtomicU64);
+
+    impl ManualClock {
+        fn advance(&self, by: Duration) {
+            self.0.fetch_add(by.as_millis() as u64, Ordering::SeqCst);
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now_millis(&self) -> u64 {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    #[test]
+    fn test_ttl_expiration() {
+        let location = test_location();
+        let clock = Arc::new(ManualClock::default());
+        let options = Options::new().clock(clock.clone());
+
+        let kv = KVStorage::new_with_options(&location, options.clone()).unwrap();
+        kv.write(1, Some(1)).unwrap();
+        kv.write_with_ttl(1, 10, Duration::from_secs(10), &WriteOptions::default())
+            .unwrap();
+        kv.write_with_ttl(2, 20, Duration::from_secs(30), &WriteOptions::default())
+            .unwrap();
+        let snapshot = kv.snapshot().unwrap();
+
+        assert_eq!(kv.read(&1).unwrap(), Some(10));
+        clock.advance(Duration::from_secs(10));
+        // The expired entry shadows the older value instead of resurfacing it
+        assert_eq!(kv.multi_get(&[1, 2]).unwrap(), [None, Some(20)]);
+        assert_eq!(snapshot.read(&1).unwrap(), Some(10));
+
+        // Same once the entries are in an SSTable
+        kv.close().unwrap();
+        let kv = KVStorage::open_with_options(&location, options).unwrap();
+        assert_eq!(kv.read(&1).unwrap(), None);
+        assert_eq!(kv.read(&2).unwrap(), Some(20));
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(kv.read(&2).unwrap(), None);
+    }
+
+    #[test]
+    fn test_spill_values() {
+        let location = test_location();
+        let clock = Arc::new(ManualClock::default());
+        let options = Options::new()
+            .write_shards(2)
+            .spill_values(true)
+            .clock(clock.clone());
+
+        let kv = KVStorage::new_with_options(&location, options.clone()).unwrap();
+        let subscribed_at = kv.last_sequence();
+        for i in 0..100 {
+            kv.write(i % 10, Some(i)).unwrap();
+        }
+        kv.write(3, None).unwrap();
+        kv.delete_range(5, 7).unwrap();
+        kv.write(6, Some(600)).unwrap();
+        kv.write_with_ttl(8, 800, Duration::from_secs(10), &WriteOptions::default())
+            .unwrap();
+
+        let expected = [
+            Some(90),
+            Some(91),
+            Some(92),
+            None,
+            Some(94),
+            None,
+            Some(600),
+            Some(97),
+            Some(800),
+            Some(99),
+        ];
+        let keys: Vec<_> = (0..10).collect();
+        for (key, value) in expected.iter().enumerate() {
+            assert_eq!(kv.read(&(key as u64)).unwrap(), *value);
+        }
+        assert_eq!(kv.multi_get(&keys).unwrap(), expected);
+        let live: Vec<_> = kv.iter().unwrap().map(|(key, _)| key).collect();
+        assert_eq!(live, [0, 1, 2, 4, 6, 7, 8, 9]);
+        assert_eq!(kv.changes_since(subscribed_at).unwrap().len(), 104);
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(kv.read(&8).unwrap(), None);
+
+        // Only keys and offsets are kept for the point entries
+        let spilled_bytes = kv.stats().memtable_bytes;
+        let full = KVStorage::new_in_memory_with_options(Options::new().write_shards(2)).unwrap();
+        for i in 0..100 {
+            full.write(i % 10, Some(i)).unwrap();
+        }
+        assert!(spilled_bytes < full.stats().memtable_bytes);
+
+        // Replayed from the log files, then flushed from them
+        drop(kv);
+        let kv = KVStorage::open_with_options(&location, options).unwrap();
+        assert_eq!(kv.read(&6).unwrap(), Some(600));
+        assert_eq!(kv.read(&3).unwrap(), None);
+        kv.flush().unwrap();
+        assert_eq!(kv.stats().memtable_bytes, 0);
+        assert_eq!(kv.read(&6).unwrap(), Some(600));
+        assert_eq!(kv.read(&5).unwrap(), None);
+        assert_eq!(kv.read(&9).unwrap(), Some(99));
+    }
+
+    #[test]
+    fn test_disk_usage() {
+        let storage = Arc::new(MemStorage::new());
+        let options =
+            Options::new()
+                .write_shards(1)
+                .compaction_policy(CompactionPolicy::SizeTiered {
+                    ratio: 2.0,
+                    min_merge: 2,
+                });
+        let kv = KVStorage::create(storage.clone(), Path::new("db"), options).unwrap();
+        let wait_for_compaction = |kv: &KVStorage| {
+            assert!(kv.wait_for_pending_compactions(COMPACTION_TIMEOUT).unwrap());
+        };
+
+        for key in 0..100 {
+            kv.write(key, Some(key)).unwrap();
+        }
+        let usage = kv.disk_usage();
+        assert_eq!(usage.log_allocated_bytes, FILE_SIZE_BYTES);
+        assert!(usage.log_used_bytes > 0);
+        assert_eq!((usage.table_bytes, usage.dead_bytes), (0, 0));
+        assert_eq!(kv.refresh_from_disk().unwrap(), usage);
+
+        // The rotated log is kept for reuse
+        kv.inner
+            .append_log
+            .flush(&kv.inner.sstables, &kv.inner.manifest)
+            .unwrap();
+        let usage = kv.disk_usage();
+        assert_eq!(usage.log_allocated_bytes, 2 * FILE_SIZE_BYTES);
+        assert_eq!(usage.log_used_bytes, 0);
+        assert!(usage.table_bytes > 0);
+        assert_eq!(kv.refresh_from_disk().unwrap(), usage);
+
+        // The first table is overwritten in full by the second one
+        let first_table = usage.table_bytes;
+        for key in 0..100 {
+            kv.write(key, Some(key + 1)).unwrap();
+        }
+        kv.inner
+            .append_log
+            .flush(&kv.inner.sstables, &kv.inner.manifest)
+            .unwrap();
+        let usage = kv.disk_usage();
+        assert_eq!(usage.dead_bytes, first_table);
+        assert_eq!(kv.refresh_from_disk().unwrap(), usage);
+
+        // Replaced tables stay on disk while a snapshot reads them
+        let before_merge = usage.table_bytes;
+        let snapshot = kv.snapshot().unwrap();
+        wait_for_compaction(&kv);
+        let usage = kv.disk_usage();
+        assert_eq!(kv.inner.sstables.lock().unwrap().len(), 1);
+        assert_eq!(usage.pending_deletion_bytes, before_merge);
+        assert_eq!(usage.dead_bytes, 0);
+        drop(snapshot);
+        while kv.disk_usage().pending_deletion_bytes > 0 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        // Deleting everything leaves an empty store once merged
+        for key in 0..100 {
+            kv.write(key, None).unwrap();
+        }
+        kv.inner
+            .append_log
+            .flush(&kv.inner.sstables, &kv.inner.manifest)
+            .unwrap();
+        wait_for_compaction(&kv);
+        let usage = kv.disk_usage();
+        assert_eq!(usage.table_bytes, 0);
+        assert_eq!(kv.refresh_from_disk().unwrap(), usage);
+    }
+
+    #[test]
+    fn test_read_detailed() {
+        let options = Options::new()
+            .write_shards(1)
+            .cache_capacity(16)
+            .compaction_policy(CompactionPolicy::SizeTiered {
+                ratio: 2.0,
+                min_merge: 2,
+            });
+        let kv = KVStorage::new_in_memory_with_options(options).unwrap();
+        kv.write(1, Some(10)).unwrap();
+        kv.write(2, Some(20)).unwrap();
+        kv.write(2, None).unwrap();
+
+        let expected = [
+            ReadOutcome::Found(10),
+            ReadOutcome::Deleted,
+            ReadOutcome::NotFound,
+        ];
+        let outcomes = |kv: &KVStorage| -> Vec<_> {
+            [1, 2, 3]
+                .iter()
+                .map(|key| kv.read_detailed(key).unwrap().outcome)
+                .collect()
+        };
+        assert_eq!(outcomes(&kv), expected);
+
+        // From the table, then from the cache filled by the first pass
+        kv.inner
+            .append_log
+            .flush(&kv.inner.sstables, &kv.inner.manifest)
+            .unwrap();
+        assert_eq!(outcomes(&kv), expected);
+        assert_eq!(outcomes(&kv), expected);
+        assert_eq!(kv.read(&2).unwrap(), None);
+
+        // Merged into the oldest table, the tombstone is gone
+        kv.write(4, Some(40)).unwrap();
+        kv.flush_and_wait().unwrap();
+        assert_eq!(kv.inner.sstables.lock().unwrap().len(), 1);
+        assert_eq!(kv.read_detailed(&2).unwrap().outcome, ReadOutcome::NotFound);
+    }
+
+    #[test]
+    fn test_write_timestamps() {
+        let clock = Arc::new(ManualClock::default());
+        let options = Options::new()
+            .write_shards(1)
+            .cache_capacity(16)
+            .clock(clock.clone())
+            .write_timestamps(true)
+            .compaction_policy(CompactionPolicy::SizeTiered {
+                ratio: 2.0,
+                min_merge: 2,
+            });
+        let kv = KVStorage::new_in_memory_with_options(options).unwrap();
+        for (key, value) in [(1, 10), (2, 20), (1, 11)] {
+            clock.advance(Duration::from_secs(1));
+            kv.write(key, Some(value)).unwrap();
+        }
+        let written_at = |key| kv.read_detailed(&key).unwrap().written_at;
+        assert_eq!(written_at(1), Some(3000));
+        assert_eq!(written_at(2), Some(2000));
+        assert_eq!(written_at(3), None);
+
+        // Rotated into a table, read twice so that the second pass could come from the cache
+        kv.inner
+            .append_log
+            .flush(&kv.inner.sstables, &kv.inner.manifest)
+            .unwrap();
+        for _ in 0..2 {
+            assert_eq!(written_at(1), Some(3000));
+            assert_eq!(written_at(2), Some(2000));
+        }
+
+        clock.advance(Duration::from_secs(1));
+        kv.write(2, None).unwrap();
+        assert_eq!(
+            kv.read_detailed(&2).unwrap(),
+            ReadDetails {
+                outcome: ReadOutcome::Deleted,
+                written_at: Some(4000),
+            }
+        );
+        clock.advance(Duration::from_secs(1));
+        kv.write(3, Some(30)).unwrap();
+
+        // Merged, the newest record of every key keeps its time
+        kv.flush_and_wait().unwrap();
+        assert_eq!(kv.inner.sstables.lock().unwrap().len(), 1);
+        assert_eq!(written_at(1), Some(3000));
+        assert_eq!(written_at(2), None);
+        assert_eq!(written_at(3), Some(5000));
+        let dump = kv.dump(true).unwrap();
+        let times: Vec<_> = dump.tables[0]
+            .entries
+            .iter()
+            .flatten()
+            .map(|change| change.written_at)
+            .collect();
+        assert_eq!(times, [Some(3000), Some(5000)]);
+
+        // Off, nothing is stored
+        let kv = KVStorage::new_in_memory().unwrap();
+        kv.write(1, Some(1)).unwrap();
+        assert_eq!(kv.read_detailed(&1).unwrap().written_at, None);
+    }
+
+    #[test]
+    fn test_scan_page() {
+        let options =
+            Options::new()
+                .write_shards(1)
+                .compaction_policy(CompactionPolicy::SizeTiered {
+                    ratio: 100.0,
+                    min_merge: 2,
+                });
+        let kv = KVStorage::new_in_memory_with_options(options).unwrap();
+        for key in 0..30 {
+            kv.write(key, Some(key)).unwrap();
+        }
+        kv.flush().unwrap();
+        for key in (0..30).step_by(4) {
+            kv.write(key, None).unwrap();
+        }
+        kv.delete_range(10, 17).unwrap();
+        kv.write(12, Some(120)).unwrap();
+        kv.write(40, Some(40)).unwrap();
+
+        let pages = |kv: &KVStorage| {
+            let mut entries = Vec::new();
+            let mut start = Bound::Unbounded;
+            loop {
+                let (page, resume) = kv.scan_page(start, 3).unwrap();
+                assert!(page.len() == 3 || resume.is_none());
+                entries.extend(page);
+                match resume {
+                    Some(key) => start = Bound::Included(key),
+                    None => return entries,
+                }
+            }
+        };
+        let all: Vec<_> = kv.iter().unwrap().collect();
+        assert_eq!(all.len(), 19);
+        assert_eq!(pages(&kv), all);
+
+        // The resume key is the next live one, even for a limit of 0
+        assert_eq!(
+            kv.scan_page(Bound::Excluded(3), 0).unwrap(),
+            (vec![], Some(5))
+        );
+        assert_eq!(
+            kv.scan_page(Bound::Included(8), 2).unwrap(),
+            (vec![(9, 9), (12, 120)], Some(17))
+        );
+        assert_eq!(
+            kv.scan_page(Bound::Included(41), 3).unwrap(),
+            (vec![], None)
+        );
+
+        // Resuming from a key deleted since, and a merge between pages
+        let (page, resume) = kv.scan_page(Bound::Unbounded, 3).unwrap();
+        assert_eq!(page, [(1, 1), (2, 2), (3, 3)]);
+        kv.write(resume.unwrap(), None).unwrap();
+        kv.write(31, Some(31)).unwrap();
+        kv.flush_and_wait().unwrap();
+        assert_eq!(kv.inner.sstables.lock().unwrap().len(), 1);
+        let (page, _) = kv.scan_page(Bound::Included(resume.unwrap()), 3).unwrap();
+        assert_eq!(page, [(6, 6), (7, 7), (9, 9)]);
+        assert_eq!(pages(&kv), kv.iter().unwrap().collect::<Vec<_>>());
+
+        // Pages of a snapshot ignore later writes
+        let snapshot = kv.snapshot().unwrap();
+        kv.write(2, None).unwrap();
+        let (page, _) = snapshot.scan_page(Bound::Unbounded, 3).unwrap();
+        assert_eq!(page, [(1, 1), (2, 2), (3, 3)]);
+    }
+
+    #[test]
+    fn test_parallel_probes_newest_wins() {
+        let options = Options::new()
+            .write_shards(1)
+            .parallel_probe_threads(4)
+            .compaction_policy(CompactionPolicy::SizeTiered {
+                ratio: 2.0,
+                min_merge: usize::MAX,
+            });
+        let kv = KVStorage::new_in_memory_with_options(options).unwrap();
+
+        // Every key is in most tables, older tables' hits must lose against newer ones
+        let mut expected = HashMap::new();
+        for table in 0..8 {
+            for key in 0..50 {
+                if (key + table) % 7 == 0 {
+                    continue;
+                }
+                let value = (key % 5 != table % 5).then_some(table * 100 + key);
+                kv.write(key, value).unwrap();
+                expected.insert(key, value);
+            }
+            if table == 5 {
+                kv.delete_range(10, 20).unwrap();
+                for key in 10..20 {
+                    expected.insert(key, None);
+                }
+            }
+            kv.flush().unwrap();
+        }
+        assert_eq!(kv.inner.sstables.lock().unwrap().len(), 8);
+
+        for (key, value) in &expected {
+            let (read, trace) = kv.read_with_trace(key).unwrap();
+            assert_eq!(read, *value, "key {key}");
+            assert!(trace.bloom_maybes > 1);
+        }
+        assert_eq!(kv.read(&50).unwrap(), None);
+    }
+
+    #[test]
+    fn test_negative_cache_never_hides_writes() {
+        const THREADS: u64 = 4;
+        const KEYS: u64 = 2000;
+
+        let options = Options::new().negative_cache_slots(1024);
+        let kv = KVStorage::new_in_memory_with_options(options).unwrap();
+        kv.write(u64::MAX, Some(0)).unwrap();
+        kv.inner
+            .append_log
+            .flush(&kv.inner.sstables, &kv.inner.manifest)
+            .unwrap();
+
+        // Readers keep caching the keys as missing while the writers write them one by one
+        let stop = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let readers: Vec<_> = (0..2)
+            .map(|_| {
+                let kv = kv.clone();
+                let stop = stop.clone();
+                std::thread::spawn(move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        for key in 0..THREADS * KEYS {
+                            kv.read(&key).unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        let writers: Vec<_> = (0..THREADS)
+            .map(|thread| {
+                let kv = kv.clone();
+                std::thread::spawn(move || {
+                    for key in thread * KEYS..(thread + 1) * KEYS {
+                        assert_eq!(kv.read(&key).unwrap(), None);
+                        kv.write(key, Some(key)).unwrap();
+                        assert_eq!(kv.read(&key).unwrap(), Some(key));
+                        if key % 500 == 0 {
+                            kv.inner
+                                .append_log
+                                .flush(&kv.inner.sstables, &kv.inner.manifest)
+                                .unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        stop.store(true, Ordering::Relaxed);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        kv.inner
+            .append_log
+            .flush(&kv.inner.sstables, &kv.inner.manifest)
+            .unwrap();
+        for key in 0..THREADS * KEYS {
+            assert_eq!(kv.read(&key).unwrap(), Some(key));
+        }
+        let before = kv.stats().negative_cache_hits;
+        assert_eq!(kv.read(&(THREADS * KEYS)).unwrap(), None);
+        assert_eq!(kv.read(&(THREADS * KEYS)).unwrap(), None);
+        assert_eq!(kv.stats().negative_cache_hits, before + 1);
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_tracing_rotation_spans() {
+        use std::sync::atomic::AtomicU64;
+        use tracing::{
+            Event, Metadata, Subscriber,
+            field::{Field, Visit},
+            span::{Attributes, Id, Record},
+        };
+
+        /// Keeps the names of the spans and the fields recorded after their creation
+        #[derive(Default)]
+        struct Capture {
+            spans: Mutex<Vec<&'static str>>,
+            recorded: Mutex<Vec<String>>,
+            next_id: AtomicU64,
+        }
+
+        impl Visit for &Capture {
+            fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                let mut recorded = self.recorded.lock().unwrap();
+                recorded.push(format!("{}={:?}", field.name(), value));
+            }
+        }
+
+        impl Subscriber for Capture {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                self.spans.lock().unwrap().push(span.metadata().name());
+                Id::from_u64(self.next_id.fetch_add(1, Ordering::Relaxed) + 1)
+            }
+            fn record(&self, _: &Id, values: &Record<'_>) {
+                values.record(&mut &*self);
+            }
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+            fn event(&self, _: &Event<'_>) {}
+            fn enter(&self, _: &Id) {}
+            fn exit(&self, _: &Id) {}
+        }
+
+        let capture = Arc::new(Capture::default());
+        let kv = KVStorage::new_in_memory_with_options(Options::new().write_shards(1)).unwrap();
+        // The rotation runs in the write that doesn't fit in the log anymore, on this thread
+        tracing::subscriber::with_default(capture.clone(), || {
+            let mut key = 0;
+            while kv.inner.sstables.lock().unwrap().is_empty() {
+                kv.write(key, Some(key)).unwrap();
+                key += 1;
+            }
+        });
+
+        let spans = capture.spans.lock().unwrap();
+        for name in ["write", "rotation", "memtable_to_sstable"] {
+            assert!(spans.contains(&name), "missing span {name}");
+        }
+        let recorded = capture.recorded.lock().unwrap();
+        assert!(recorded.iter().any(|r| r == "rotated=true"));
+        assert!(recorded.iter().any(|r| r.starts_with("table_id=")));
+    }
+
+    #[test]
+    fn test_cloned_handles() {
+        let location = test_location();
+        let kv = KVStorage::new(&location).unwrap();
+
+        let writers: Vec<_> = (0..4)
+            .map(|thread| {
+                let kv = kv.clone();
+                std::thread::spawn(move || kv.write(thread, Some(thread)).unwrap())
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        // Closing needs the last handle
+        let other = kv.clone();
+        assert!(matches!(kv.close(), Err(Error::StoreInUse)));
+        assert_eq!(other.read(&3).unwrap(), Some(3));
+        other.close().unwrap();
+
+        let kv = KVStorage::open(&location).unwrap();
+        for key in 0..4 {
+            assert_eq!(kv.read(&key).unwrap(), Some(key));
+        }
+    }
+
+    #[test]
+    fn test_reclaimable_entries() {
+        let location = test_location();
+        let kv = KVStorage::new(&location).unwrap();
+        for key in 0..1000 {
+            kv.write(key, Some(key)).unwrap();
+        }
+        kv.flush().unwrap();
+        for key in 0..1000 {
+            kv.write(key, Some(key + 1)).unwrap();
+        }
+
+        let close = |estimate: u64| estimate.abs_diff(1000) < 50;
+        let stats = kv.stats();
+        assert!(close(stats.estimated_distinct_keys), "{stats:?}");
+        assert!(close(stats.reclaimable_entries), "{stats:?}");
+        assert_eq!(kv.health().reclaimable_entries, stats.reclaimable_entries);
+
+        // Persisted when the manifest is, the rest is replayed from the log
+        kv.flush().unwrap();
+        for key in 1000..2000 {
+            kv.write(key, Some(key)).unwrap();
+        }
+        kv.close().unwrap();
+        let kv = KVStorage::open(&location).unwrap();
+        let stats = kv.stats();
+        assert!(
+            stats.estimated_distinct_keys.abs_diff(2000) < 100,
+            "{stats:?}"
+        );
+        assert!(stats.reclaimable_entries.abs_diff(1000) < 100, "{stats:?}");
+    }
+
+    #[test]
+    fn test_sst_reader_reads_store_tables() {
+        let location = test_location();
+        let kv = KVStorage::new_with_options(&location, Options::new().write_shards(1)).unwrap();
+        for k in 0..1000 {
+            kv.write(k, Some(k * 10)).unwrap();
+        }
+        kv.write(7, None).unwrap();
+        kv.delete_range(100, 200).unwrap();
+        kv.flush().unwrap();
+
+        let table = kv.inner.table_view.load()[0].id();
+        let reader = sstable::SstReader::open(kv.inner.table_files.path(table)).unwrap();
+        let metadata = reader.metadata();
+        assert_eq!(metadata.entry_count, 1000);
+        assert_eq!(metadata.tombstone_count, 1);
+        assert_eq!(metadata.range_tombstones, vec![(100, 200)]);
+        assert_eq!(metadata.key_range, Some((0, 999)));
+
+        let entries: Vec<_> = reader.iter().collect::<Result<_, _>>().unwrap();
+        assert_eq!(entries.len(), 1000);
+        assert_eq!(entries[7], (7, None));
+        assert_eq!(entries[150], (150, Some(1500)));
+
+        assert_eq!(reader.get(&6).unwrap(), ReadOutcome::Found(60));
+        assert_eq!(reader.get(&7).unwrap(), ReadOutcome::Deleted);
+        assert_eq!(reader.get(&150).unwrap(), ReadOutcome::Deleted);
+        assert_eq!(reader.get(&1000).unwrap(), ReadOutcome::NotFound);
+    }
+
+    #[test]
+    #[cfg(not(feature = "mmap"))]
+    fn test_missing_table_file() {
+        for skip in [false, true] {
+            let location = test_location();
+            let options = || {
+                Options::new()
+                    .write_shards(1)
+                    .max_open_tables(1)
+                    .skip_missing_tables(skip)
+            };
+            let kv = KVStorage::new_with_options(&location, options()).unwrap();
+            for k in 0..200 {
+                kv.write(k, Some(k)).unwrap();
+            }
+            kv.flush().unwrap();
+            for k in 0..100 {
+                kv.write(k, Some(k + 1000)).unwrap();
+            }
+            kv.flush().unwrap();
+
+            // Reading the older table closes the file of the newer one, which is then deleted
+            assert_eq!(kv.read(&150).unwrap(), Some(150));
+            let newer = kv.inner.table_view.load()[0].id();
+            fs::remove_file(kv.inner.table_files.path(newer)).unwrap();
+
+            if !skip {
+                for _ in 0..2 {
+                    assert!(matches!(
+                        kv.read(&5),
+                        Err(Error::TableMissing { id, .. }) if id == newer
+                    ));
+                }
+                assert!(kv.multi_get(&[5, 150]).is_err());
+                assert!(
+                    kv.health()
+                        .background_error
+                        .unwrap()
+                        .contains("TableMissing")
+                );
+                continue;
+            }
+
+            assert_eq!(kv.read(&5).unwrap(), Some(5));
+            assert_eq!(kv.multi_get(&[6, 150]).unwrap(), vec![Some(6), Some(150)]);
+            assert_eq!(kv.inner.table_view.load().len(), 1);
+            assert!(
+                kv.health()
+                    .background_error
+                    .unwrap()
+                    .contains("TableMissing")
+            );
+
+            // Gone from the manifest too
+            drop(kv);
+            let kv = KVStorage::open_with_options(&location, options()).unwrap();
+            assert_eq!(kv.read(&5).unwrap(), Some(5));
+        }
     }
 }
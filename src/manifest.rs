@@ -0,0 +1,491 @@
+use crate::errors::Error;
+use crate::files::PositionedFile;
+use crate::functions;
+use crate::hll::HyperLogLog;
+use crate::serialization::SerializationError;
+use crate::storage::Storage;
+use bitcode::{Decode, Encode};
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+pub const MANIFEST_NAME: &str = "MANIFEST";
+pub const MANIFEST_TMP_NAME: &str = "MANIFEST.tmp";
+
+/// Persisted description of the live database state
+#[derive(Clone, Default, Encode, Decode)]
+pub struct ManifestData {
+    /// File names (inside `db/`) of the append logs currently receiving writes, one per write shard
+    pub log_files: Vec<String>,
+    /// SSTable ids, newest first
+    pub sstables: Vec<u64>,
+    /// Namespaces, each a database of its own in `db/<name>/`, in creation order
+    pub namespaces: Vec<String>,
+    /// Directory of the tables in `cold_sstables`, see [`crate::Options::cold_storage_dir`]
+    pub cold_storage_dir: Option<String>,
+    /// SSTable ids stored in `cold_storage_dir` rather than `db/sstables/`, in no particular order
+    pub cold_sstables: Vec<u64>,
+    /// Log files replaced by a rotation whose SSTable isn't registered yet, by write shard and oldest first. Their
+    /// entries are older than the ones of `log_files`, see [`crate::Options::max_immutable_memtables`]
+    pub frozen_log_files: Vec<Vec<String>>,
+    /// Name of the [`crate::KeyComparator`] ordering the keys, `None` for databases created before comparators, which
+    /// are ascending
+    pub comparator: Option<String>,
+    /// [`HyperLogLog`] of the keys written up to the last update, empty for databases created before it
+    pub distinct_keys: Vec<u8>,
+}
+
+impl ManifestData {
+    /// See [`ManifestData::frozen_log_files`]
+    pub fn frozen_log_files(&self, shard: usize) -> &[String] {
+        self.frozen_log_files.get(shard).map_or(&[], Vec::as_slice)
+    }
+
+    /// Queues `file` after the frozen logs of `shard`
+    pub fn push_frozen_log_file(&mut self, shard: usize, file: String) {
+        if self.frozen_log_files.len() <= shard {
+            self.frozen_log_files.resize(shard + 1, Vec::new());
+        }
+        self.frozen_log_files[shard].push(file);
+    }
+
+    /// Removes the oldest frozen log of `shard`, once its SSTable is registered
+    pub fn pop_frozen_log_file(&mut self, shard: usize) {
+        if let Some(files) = self.frozen_log_files.get_mut(shard)
+            && !files.is_empty()
+        {
+            files.remove(0);
+        }
+    }
+}
+
+/// Manifests written before the sketch of distinct keys
+#[derive(Decode)]
+struct ManifestDataV5 {
+    log_files: Vec<String>,
+    sstables: Vec<u64>,
+    namespaces: Vec<String>,
+    cold_storage_dir: Option<String>,
+    cold_sstables: Vec<u64>,
+    frozen_log_files: Vec<Vec<String>>,
+    comparator: Option<String>,
+}
+
+/// Manifests written before rotations could queue several log files
+#[derive(Decode)]
+struct ManifestDataV4 {
+    log_files: Vec<String>,
+    sstables: Vec<u64>,
+    namespaces: Vec<String>,
+    cold_storage_dir: Option<String>,
+    cold_sstables: Vec<u64>,
+    frozen_log_files: Vec<Option<String>>,
+    comparator: Option<String>,
+}
+
+/// Manifests written before the key comparator was recorded
+#[derive(Decode)]
+struct ManifestDataV3 {
+    log_files: Vec<String>,
+    sstables: Vec<u64>,
+    namespaces: Vec<String>,
+    cold_storage_dir: Option<String>,
+    cold_sstables: Vec<u64>,
+    frozen_log_files: Vec<Option<String>>,
+}
+
+/// Manifests written before rotations froze log files
+#[derive(Decode)]
+struct ManifestDataV2 {
+    log_files: Vec<String>,
+    sstables: Vec<u64>,
+    namespaces: Vec<String>,
+    cold_storage_dir: Option<String>,
+    cold_sstables: Vec<u64>,
+}
+
+/// Manifests written before cold storage existed
+#[derive(Decode)]
+struct ManifestDataV1 {
+    log_files: Vec<String>,
+    sstables: Vec<u64>,
+    namespaces: Vec<String>,
+}
+
+/// Manifests written before namespaces existed
+#[derive(Decode)]
+struct ManifestDataV0 {
+    log_files: Vec<String>,
+    sstables: Vec<u64>,
+}
+
+/// The manifest file, rewritten atomically on every change
+pub struct Manifest {
+    storage: Arc<dyn Storage>,
+    db_dir: PathBuf,
+    data: Mutex<ManifestData>,
+    /// Saved in [`ManifestData::distinct_keys`] by every update
+    distinct_keys: HyperLogLog,
+}
+
+impl Manifest {
+    pub fn create(
+        storage: &Arc<dyn Storage>,
+        db_dir: &Path,
+        data: ManifestData,
+    ) -> Result<Self, Error> {
+        write_manifest(&**storage, db_dir, &data)?;
+
+        Ok(Self::with_data(storage, db_dir, data))
+    }
+
+    pub fn load(storage: &Arc<dyn Storage>, db_dir: &Path) -> Result<Self, Error> {
+        let data = read_manifest(&**storage, db_dir)?;
+
+        Ok(Self::with_data(storage, db_dir, data))
+    }
+
+    fn with_data(storage: &Arc<dyn Storage>, db_dir: &Path, data: ManifestData) -> Self {
+        Self {
+            storage: storage.clone(),
+            db_dir: db_dir.to_owned(),
+            distinct_keys: HyperLogLog::from_bytes(&data.distinct_keys).unwrap_or_default(),
+            data: Mutex::new(data),
+        }
+    }
+
+    pub fn data(&self) -> ManifestData {
+        self.data.lock().expect("poisoned manifest lock").clone()
+    }
+
+    /// Keys written to the store, persisted with the next update. Starts empty for the manifests without one
+    pub fn distinct_keys(&self) -> &HyperLogLog {
+        &self.distinct_keys
+    }
+
+    /// Applies `change` and persists the result, along with [`Manifest::distinct_keys`].
+    ///
+    /// Callers changing the sstables list must hold the sstables lock so that updates land in order.
+    pub fn update(&self, change: impl FnOnce(&mut ManifestData)) -> Result<(), Error> {
+        let mut data = self.data.lock().expect("poisoned manifest lock");
+        let mut new_data = data.clone();
+        change(&mut new_data);
+        new_data.distinct_keys = self.distinct_keys.to_bytes();
+
+        write_manifest(&*self.storage, &self.db_dir, &new_data)?;
+        *data = new_data;
+
+        Ok(())
+    }
+}
+
+/// Reads the manifest in `db_dir` without keeping it
+pub fn read_manifest(storage: &dyn Storage, db_dir: &Path) -> Result<ManifestData, Error> {
+    let file = storage.open(&db_dir.join(MANIFEST_NAME), false)?;
+    let bytes = functions::read_file(&file, file.size()?)?;
+
+    decode_manifest(&bytes)
+}
+
+fn decode_manifest(bytes: &[u8]) -> Result<ManifestData, Error> {
+    let e = match bitcode::decode(bytes) {
+        Ok(data) => return Ok(data),
+        Err(e) => e,
+    };
+
+    if let Ok(data) = bitcode::decode::<ManifestDataV5>(bytes) {
+        return Ok(ManifestData {
+            log_files: data.log_files,
+            sstables: data.sstables,
+            namespaces: data.namespaces,
+            cold_storage_dir: data.cold_storage_dir,
+            cold_sstables: data.cold_sstables,
+            frozen_log_files: data.frozen_log_files,
+            comparator: data.comparator,
+            distinct_keys: Vec::new(),
+        });
+    }
+
+    if let Ok(data) = bitcode::decode::<ManifestDataV4>(bytes) {
+        return Ok(ManifestData {
+            log_files: data.log_files,
+            sstables: data.sstables,
+            namespaces: data.namespaces,
+            cold_storage_dir: data.cold_storage_dir,
+            cold_sstables: data.cold_sstables,
+            frozen_log_files: queued_log_files(data.frozen_log_files),
+            comparator: data.comparator,
+            distinct_keys: Vec::new(),
+        });
+    }
+
+    if let Ok(data) = bitcode::decode::<ManifestDataV3>(bytes) {
+        return Ok(ManifestData {
+            log_files: data.log_files,
+            sstables: data.sstables,
+            namespaces: data.namespaces,
+            cold_storage_dir: data.cold_storage_dir,
+            cold_sstables: data.cold_sstables,
+            frozen_log_files: queued_log_files(data.frozen_log_files),
+            ..Default::default()
+        });
+    }
+
+    if let Ok(data) = bitcode::decode::<ManifestDataV2>(bytes) {
+        return Ok(ManifestData {
+            log_files: data.log_files,
+            sstables: data.sstables,
+            namespaces: data.namespaces,
+            cold_storage_dir: data.cold_storage_dir,
+            cold_sstables: data.cold_sstables,
+            ..Default::default()
+        });
+    }
+
+    if let Ok(data) = bitcode::decode::<ManifestDataV1>(bytes) {
+        return Ok(ManifestData {
+            log_files: data.log_files,
+            sstables: data.sstables,
+            namespaces: data.namespaces,
+            ..Default::default()
+        });
+    }
+
+    let data: ManifestDataV0 = bitcode::decode(bytes)
+        .map_err(|_| Error::Serialization(SerializationError::DecodeFailed(e)))?;
+
+    Ok(ManifestData {
+        log_files: data.log_files,
+        sstables: data.sstables,
+        ..Default::default()
+    })
+}
+
+/// The single frozen log of every shard of the older manifests as a queue
+fn queued_log_files(frozen_log_files: Vec<Option<String>>) -> Vec<Vec<String>> {
+    frozen_log_files
+        .into_iter()
+        .map(|file| file.into_iter().collect())
+        .collect()
+}
+
+/// Writes to a temporary file and renames it over the old manifest, so a crash leaves either version intact
+fn write_manifest(storage: &dyn Storage, db_dir: &Path, data: &ManifestData) -> Result<(), Error> {
+    let tmp_path = db_dir.join(MANIFEST_TMP_NAME);
+
+    let file = storage.create(&tmp_path, 0)?;
+    file.write_all_at(&bitcode::encode(data), 0)?;
+    file.sync_all()?;
+
+    storage.rename(&tmp_path, &db_dir.join(MANIFEST_NAME))?;
+    storage.sync_dir(db_dir)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Encode)]
+    struct LegacyManifest {
+        log_files: Vec<String>,
+        sstables: Vec<u64>,
+    }
+
+    #[derive(Encode)]
+    struct ManifestWithoutColdStorage {
+        log_files: Vec<String>,
+        sstables: Vec<u64>,
+        namespaces: Vec<String>,
+    }
+
+    #[derive(Encode)]
+    struct ManifestWithoutFrozenLogs {
+        log_files: Vec<String>,
+        sstables: Vec<u64>,
+        namespaces: Vec<String>,
+        cold_storage_dir: Option<String>,
+        cold_sstables: Vec<u64>,
+    }
+
+    #[derive(Encode)]
+    struct ManifestWithoutComparator {
+        log_files: Vec<String>,
+        sstables: Vec<u64>,
+        namespaces: Vec<String>,
+        cold_storage_dir: Option<String>,
+        cold_sstables: Vec<u64>,
+        frozen_log_files: Vec<Option<String>>,
+    }
+
+    #[derive(Encode)]
+    struct ManifestWithSingleFrozenLogs {
+        log_files: Vec<String>,
+        sstables: Vec<u64>,
+        namespaces: Vec<String>,
+        cold_storage_dir: Option<String>,
+        cold_sstables: Vec<u64>,
+        frozen_log_files: Vec<Option<String>>,
+        comparator: Option<String>,
+    }
+
+    #[derive(Encode)]
+    struct ManifestWithoutDistinctKeys {
+        log_files: Vec<String>,
+        sstables: Vec<u64>,
+        namespaces: Vec<String>,
+        cold_storage_dir: Option<String>,
+        cold_sstables: Vec<u64>,
+        frozen_log_files: Vec<Vec<String>>,
+        comparator: Option<String>,
+    }
+
+    #[test]
+    fn test_decode_manifest_without_namespaces() {
+        let bytes = bitcode::encode(&LegacyManifest {
+            log_files: vec!["log_1".to_owned()],
+            sstables: vec![3, 2],
+        });
+        let data = decode_manifest(&bytes).unwrap();
+        assert_eq!(data.log_files, ["log_1"]);
+        assert_eq!(data.sstables, [3, 2]);
+        assert!(data.namespaces.is_empty());
+
+        let bytes = bitcode::encode(&ManifestData {
+            namespaces: vec!["users".to_owned()],
+            ..data
+        });
+        assert_eq!(decode_manifest(&bytes).unwrap().namespaces, ["users"]);
+
+        let bytes = bitcode::encode(&ManifestWithoutComparator {
+            log_files: vec!["log_1".to_owned()],
+            sstables: vec![3, 2],
+            namespaces: vec!["users".to_owned()],
+            cold_storage_dir: None,
+            cold_sstables: vec![],
+            frozen_log_files: vec![],
+        });
+        // Without its trailing empty list it reads as a manifest of the previous version
+        assert!(decode_manifest(&bytes[..bytes.len() - 1]).is_ok());
+        assert!(decode_manifest(&bytes[..bytes.len() / 2]).is_err());
+    }
+
+    #[test]
+    fn test_decode_manifest_without_cold_storage() {
+        let bytes = bitcode::encode(&ManifestWithoutColdStorage {
+            log_files: vec!["log_1".to_owned()],
+            sstables: vec![3, 2],
+            namespaces: vec!["users".to_owned()],
+        });
+        let data = decode_manifest(&bytes).unwrap();
+        assert_eq!(data.sstables, [3, 2]);
+        assert_eq!(data.namespaces, ["users"]);
+        assert_eq!(data.cold_storage_dir, None);
+        assert!(data.cold_sstables.is_empty());
+
+        let bytes = bitcode::encode(&ManifestData {
+            cold_storage_dir: Some("/cold".to_owned()),
+            cold_sstables: vec![2],
+            ..data
+        });
+        let data = decode_manifest(&bytes).unwrap();
+        assert_eq!(data.cold_storage_dir.as_deref(), Some("/cold"));
+        assert_eq!(data.cold_sstables, [2]);
+    }
+
+    #[test]
+    fn test_decode_manifest_without_frozen_logs() {
+        let bytes = bitcode::encode(&ManifestWithoutFrozenLogs {
+            log_files: vec!["log_1".to_owned(), "log_2".to_owned()],
+            sstables: vec![3, 2],
+            namespaces: vec![],
+            cold_storage_dir: Some("/cold".to_owned()),
+            cold_sstables: vec![2],
+        });
+        let mut data = decode_manifest(&bytes).unwrap();
+        assert_eq!(data.cold_sstables, [2]);
+        assert!(data.frozen_log_files(1).is_empty());
+
+        data.push_frozen_log_file(1, "log_0".to_owned());
+        data.push_frozen_log_file(1, "log_3".to_owned());
+        let mut data = decode_manifest(&bitcode::encode(&data)).unwrap();
+        assert!(data.frozen_log_files(0).is_empty());
+        assert_eq!(data.frozen_log_files(1), ["log_0", "log_3"]);
+
+        data.pop_frozen_log_file(1);
+        assert_eq!(data.frozen_log_files(1), ["log_3"]);
+    }
+
+    #[test]
+    fn test_decode_manifest_without_comparator() {
+        let bytes = bitcode::encode(&ManifestWithoutComparator {
+            log_files: vec!["log_1".to_owned()],
+            sstables: vec![3, 2],
+            namespaces: vec![],
+            cold_storage_dir: None,
+            cold_sstables: vec![],
+            frozen_log_files: vec![Some("log_0".to_owned())],
+        });
+        let data = decode_manifest(&bytes).unwrap();
+        assert_eq!(data.frozen_log_files(0), ["log_0"]);
+        assert_eq!(data.comparator, None);
+
+        let bytes = bitcode::encode(&ManifestData {
+            comparator: Some("descending".to_owned()),
+            ..data
+        });
+        assert_eq!(
+            decode_manifest(&bytes).unwrap().comparator.as_deref(),
+            Some("descending")
+        );
+    }
+
+    #[test]
+    fn test_decode_manifest_with_single_frozen_logs() {
+        let bytes = bitcode::encode(&ManifestWithSingleFrozenLogs {
+            log_files: vec!["log_1".to_owned(), "log_2".to_owned()],
+            sstables: vec![3],
+            namespaces: vec![],
+            cold_storage_dir: None,
+            cold_sstables: vec![],
+            frozen_log_files: vec![None, Some("log_0".to_owned())],
+            comparator: Some("ascending".to_owned()),
+        });
+        let data = decode_manifest(&bytes).unwrap();
+        assert!(data.frozen_log_files(0).is_empty());
+        assert_eq!(data.frozen_log_files(1), ["log_0"]);
+        assert_eq!(data.comparator.as_deref(), Some("ascending"));
+    }
+
+    #[test]
+    fn test_decode_manifest_without_distinct_keys() {
+        let bytes = bitcode::encode(&ManifestWithoutDistinctKeys {
+            log_files: vec!["log_1".to_owned()],
+            sstables: vec![3],
+            namespaces: vec![],
+            cold_storage_dir: None,
+            cold_sstables: vec![],
+            frozen_log_files: vec![vec!["log_0".to_owned(), "log_2".to_owned()]],
+            comparator: Some("ascending".to_owned()),
+        });
+        let data = decode_manifest(&bytes).unwrap();
+        assert_eq!(data.frozen_log_files(0), ["log_0", "log_2"]);
+        assert!(data.distinct_keys.is_empty());
+
+        let sketch = HyperLogLog::default();
+        sketch.add(&7);
+        let bytes = bitcode::encode(&ManifestData {
+            distinct_keys: sketch.to_bytes(),
+            ..data
+        });
+        let data = decode_manifest(&bytes).unwrap();
+        assert_eq!(
+            HyperLogLog::from_bytes(&data.distinct_keys)
+                .unwrap()
+                .estimate(),
+            1
+        );
+    }
+}
@@ -0,0 +1,54 @@
+use crate::{Error, KVStorage, Key, Value, append_log::LOG_FILE_PREFIX, repair::LOST_DIR};
+use std::marker::PhantomData;
+
+/// A keyspace of a [`KVStorage`], independent from the store itself and from the other namespaces.
+///
+/// Handles borrow the store they come from, see [`KVStorage::create_namespace`].
+pub struct Namespace<'a> {
+    name: String,
+    store: KVStorage,
+    _parent: PhantomData<&'a KVStorage>,
+}
+
+impl Namespace<'_> {
+    pub(crate) fn new(name: &str, store: KVStorage) -> Self {
+        Self {
+            name: name.to_owned(),
+            store,
+            _parent: PhantomData,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// See [`KVStorage::read`]
+    pub fn read(&self, key: &Key) -> Result<Option<Value>, Error> {
+        self.store.read(key)
+    }
+
+    /// See [`KVStorage::write`]
+    pub fn write(&self, key: Key, value: Option<Value>) -> Result<(), Error> {
+        self.store.write(key, value)
+    }
+
+    /// Same as writing `None`
+    pub fn delete(&self, key: Key) -> Result<(), Error> {
+        self.store.write(key, None)
+    }
+}
+
+/// Names become directories next to the files of the store, they can't clash with them
+pub fn check_name(name: &str) -> Result<(), Error> {
+    let valid_chars = name
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+    let reserved = name == "sstables" || name == LOST_DIR || name.starts_with(LOG_FILE_PREFIX);
+
+    if name.is_empty() || !valid_chars || reserved {
+        return Err(Error::InvalidNamespaceName);
+    }
+
+    Ok(())
+}
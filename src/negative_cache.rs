@@ -0,0 +1,134 @@
+use crate::Key;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Number of invalidation counters shared by all keys
+const VERSION_STRIPES: usize = 64;
+/// Marks a free slot, the key mixing to it is never cached
+const EMPTY: u64 = 0;
+
+/// Keys recently found in no table, so that reads of missing keys skip the tables. See
+/// [`crate::Options::negative_cache_slots`].
+///
+/// Every key has a single slot, picked by its hash and holding the whole (mixed) key: a slot taken by another key
+/// is a miss, never a wrong answer. Writes must call [`NegativeCache::invalidate`].
+pub struct NegativeCache {
+    slots: Box<[AtomicU64]>,
+    /// Bumped on every invalidation, see [`NegativeCache::ticket`]
+    versions: [AtomicU64; VERSION_STRIPES],
+}
+
+/// Taken before a lookup, lets [`NegativeCache::insert`] detect writes that happened meanwhile
+pub struct NegativeTicket(u64);
+
+impl NegativeCache {
+    /// `slots` of 0 disables the cache
+    pub fn new(slots: usize) -> Self {
+        Self {
+            slots: (0..slots).map(|_| AtomicU64::new(EMPTY)).collect(),
+            versions: std::array::from_fn(|_| AtomicU64::new(0)),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.slots.is_empty()
+    }
+
+    /// Must be called before looking `key` up anywhere, so that its absence isn't cached if it raced with a write
+    pub fn ticket(&self, key: &Key) -> NegativeTicket {
+        NegativeTicket(self.versions[stripe(key)].load(Ordering::SeqCst))
+    }
+
+    /// Whether `key` is known to be in no table
+    pub fn contains(&self, key: &Key) -> bool {
+        let mixed = mix(*key);
+        self.is_enabled() && mixed != EMPTY && self.slot(mixed).load(Ordering::SeqCst) == mixed
+    }
+
+    /// Records that no table has `key`, unless it was written since `ticket` was taken
+    pub fn insert(&self, key: &Key, ticket: NegativeTicket) {
+        let mixed = mix(*key);
+        if !self.is_enabled() || mixed == EMPTY {
+            return;
+        }
+
+        let version = &self.versions[stripe(key)];
+        if version.load(Ordering::SeqCst) != ticket.0 {
+            return;
+        }
+        let slot = self.slot(mixed);
+        slot.store(mixed, Ordering::SeqCst);
+
+        // A write between the check and the store may have cleared the slot before it was filled
+        if version.load(Ordering::SeqCst) != ticket.0 {
+            let _ = slot.compare_exchange(mixed, EMPTY, Ordering::SeqCst, Ordering::SeqCst);
+        }
+    }
+
+    /// Forgets that `key` is missing, to be called after every write
+    pub fn invalidate(&self, key: &Key) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        // Version first, so that an insert racing with the clear notices it
+        self.versions[stripe(key)].fetch_add(1, Ordering::SeqCst);
+        let mixed = mix(*key);
+        let _ = self
+            .slot(mixed)
+            .compare_exchange(mixed, EMPTY, Ordering::SeqCst, Ordering::SeqCst);
+    }
+
+    /// Forgets every key, for tables added without going through the writes
+    pub fn clear(&self) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        for version in &self.versions {
+            version.fetch_add(1, Ordering::SeqCst);
+        }
+        for slot in &self.slots {
+            slot.store(EMPTY, Ordering::SeqCst);
+        }
+    }
+
+    fn slot(&self, mixed: u64) -> &AtomicU64 {
+        &self.slots[(mixed % self.slots.len() as u64) as usize]
+    }
+}
+
+fn stripe(key: &Key) -> usize {
+    (*key % VERSION_STRIPES as u64) as usize
+}
+
+/// Spreads the keys over the slots. A bijection (splitmix64's finalizer), so that two keys never share a value
+fn mix(key: Key) -> u64 {
+    let mut x = key;
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_during_lookup() {
+        let cache = NegativeCache::new(16);
+
+        let ticket = cache.ticket(&5);
+        cache.insert(&5, ticket);
+        assert!(cache.contains(&5));
+        assert!(!cache.contains(&6));
+
+        // The key was written while the tables were being looked up
+        let ticket = cache.ticket(&7);
+        cache.invalidate(&7);
+        cache.insert(&7, ticket);
+        assert!(!cache.contains(&7));
+
+        cache.invalidate(&5);
+        assert!(!cache.contains(&5));
+    }
+}
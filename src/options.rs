@@ -0,0 +1,554 @@
+use crate::{
+    clock::{Clock, SystemClock},
+    compaction_filter::CompactionFilter,
+    comparator::{Ascending, KeyComparator},
+    env::{RandomSource, ThreadRandom},
+    events::EventListener,
+};
+use std::{path::PathBuf, sync::Arc, thread, time::Duration};
+
+/// Cap of the default [`Options::write_shards`]
+const MAX_DEFAULT_WRITE_SHARDS: usize = 8;
+/// Default of [`Options::block_size`]
+pub(crate) const DEFAULT_BLOCK_SIZE: usize = 4 * 1024;
+/// Default of [`Options::bloom_size`]
+const DEFAULT_BLOOM_FP_RATE: f64 = 0.001;
+const DEFAULT_BULK_LOAD_TABLE_SIZE: u64 = 8 * 1024 * 1024;
+const DEFAULT_QUOTA_HEADROOM: u64 = 1024 * 1024;
+const DEFAULT_MAX_COMPACTION_THREADS: usize = 4;
+
+/// Options of [`crate::KVStorage::increment_with`] and [`crate::KVStorage::decrement_with`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IncrementOptions {
+    /// Value assumed for missing (or deleted) keys
+    pub default: u64,
+    /// Clamp to `0`/`u64::MAX` instead of failing with [`crate::Error::Overflow`]
+    pub saturating: bool,
+}
+
+/// When a write returns, see [`WriteOptions`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// Once the record is written to the log file, it may still be in the OS cache. Durable anyway with
+    /// [`Options::sync_writes`]
+    #[default]
+    Buffered,
+    /// Once the log file is synced. The write is readable before that, fails with [`crate::Error::NotDurable`] if the
+    /// sync does
+    Synced,
+}
+
+/// Options of [`crate::KVStorage::write_with`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WriteOptions {
+    pub durability: Durability,
+}
+
+/// How the compactor picks the tables to merge
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompactionPolicy {
+    /// Merges at least `min_merge` consecutive tables whose sizes are within `ratio` of each other
+    SizeTiered { ratio: f64, min_merge: usize },
+}
+
+impl Default for CompactionPolicy {
+    fn default() -> Self {
+        CompactionPolicy::SizeTiered {
+            ratio: 2.0,
+            min_merge: 4,
+        }
+    }
+}
+
+/// How the data blocks of new SSTables are compressed, every table records its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+}
+
+/// How files on disk are read and written, see [`Options::io_backend`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IoBackend {
+    /// `pread`/`pwrite`
+    #[default]
+    Std,
+    /// io_uring, with a ring per thread. Needs the `uring` feature and Linux
+    Uring,
+}
+
+/// Whether [`crate::KVStorage::open_with_mode`] creates the database, mirroring [`std::fs::OpenOptions`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CreateMode {
+    /// Fails with [`crate::Error::DatabaseExists`] if there's one already, as [`crate::KVStorage::new`] does
+    CreateNew,
+    /// Fails with [`crate::Error::InvalidDbLocation`] if there's none, as [`crate::KVStorage::open`] does
+    OpenExisting,
+    OpenOrCreate,
+}
+
+/// Where the bloom filters of the tables come from when they're opened, see [`Options::bloom_recovery`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BloomRecovery {
+    /// Read from the table footer, without decoding the entries. Tables written before the filters were stored get
+    /// theirs rebuilt
+    #[default]
+    FromFooter,
+    /// Rebuilt out of the entries of every table
+    Rebuild,
+    /// No bloom filter at all, every lookup within a table key range reads a block. Saves their memory in stores
+    /// that are rarely read
+    Skip,
+}
+
+/// How large the bloom filters of new tables are, see [`Options::bloom_size`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BloomSize {
+    /// Sized for this rate of false positives, between 0 and 1
+    FalsePositiveRate(f64),
+    /// Bits of filter per point key of the table, about 1% of false positives at 10
+    BitsPerKey(u32),
+}
+
+impl Default for BloomSize {
+    fn default() -> Self {
+        BloomSize::FalsePositiveRate(DEFAULT_BLOOM_FP_RATE)
+    }
+}
+
+/// Which compaction outputs go to [`Options::cold_storage_dir`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColdStoragePolicy {
+    /// Merges including the oldest table
+    #[default]
+    OldestTier,
+    /// Tables of at least this many bytes
+    MinTableSize(u64),
+}
+
+/// What a write does when a subscriber's buffer is full, see [`crate::KVStorage::subscribe`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Discards the oldest buffered change, see [`crate::ChangeReceiver::dropped`]
+    #[default]
+    DropOldest,
+    /// Waits for the subscriber, stalling every write meanwhile
+    Block,
+}
+
+/// Configuration of a [`crate::KVStorage`], built with chained setters:
+///
+/// ```
+/// # use std::sync::Arc;
+/// # use key_value_store::{Options, SystemClock};
+/// let options = Options::new().clock(Arc::new(SystemClock));
+/// ```
+#[derive(Clone)]
+pub struct Options {
+    pub(crate) clock: Arc<dyn Clock>,
+    /// Only replaced through [`crate::KVStorage::new_with_env`]
+    pub(crate) random: Arc<dyn RandomSource>,
+    pub(crate) key_comparator: Arc<dyn KeyComparator>,
+    pub(crate) compaction_policy: CompactionPolicy,
+    pub(crate) compaction_filter: Option<Arc<dyn CompactionFilter>>,
+    pub(crate) event_listener: Option<Arc<dyn EventListener>>,
+    pub(crate) cache_capacity: usize,
+    pub(crate) subscriber_capacity: usize,
+    pub(crate) subscriber_overflow: OverflowPolicy,
+    pub(crate) write_shards: usize,
+    pub(crate) sync_writes: bool,
+    pub(crate) group_commit_delay: Duration,
+    pub(crate) write_batching: bool,
+    pub(crate) log_record_alignment: Option<u64>,
+    pub(crate) recycled_log_files: usize,
+    pub(crate) max_open_tables: usize,
+    pub(crate) skip_missing_tables: bool,
+    pub(crate) compression: Compression,
+    pub(crate) block_size: usize,
+    pub(crate) bloom_recovery: BloomRecovery,
+    pub(crate) bloom_size: BloomSize,
+    pub(crate) bloom_seed: Option<[u8; 32]>,
+    pub(crate) io_backend: IoBackend,
+    pub(crate) bulk_load_table_size: u64,
+    pub(crate) cold_storage_dir: Option<PathBuf>,
+    pub(crate) cold_storage_policy: ColdStoragePolicy,
+    pub(crate) max_db_size_bytes: Option<u64>,
+    pub(crate) quota_headroom_bytes: u64,
+    pub(crate) compaction_rate_limit: Option<u64>,
+    pub(crate) max_compaction_threads: usize,
+    pub(crate) compaction_tombstone_threshold: Option<f64>,
+    pub(crate) compaction_interval: Option<Duration>,
+    pub(crate) idle_compaction_after: Option<Duration>,
+    pub(crate) spill_values: bool,
+    pub(crate) memtable_max_entries: Option<usize>,
+    pub(crate) memtable_max_bytes: Option<u64>,
+    pub(crate) max_immutable_memtables: usize,
+    pub(crate) parallel_probe_threads: usize,
+    pub(crate) negative_cache_slots: usize,
+    pub(crate) write_timestamps: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            clock: Arc::new(SystemClock),
+            random: Arc::new(ThreadRandom),
+            key_comparator: Arc::new(Ascending),
+            compaction_policy: Default::default(),
+            compaction_filter: None,
+            event_listener: None,
+            cache_capacity: 0,
+            subscriber_capacity: 1024,
+            subscriber_overflow: Default::default(),
+            write_shards: thread::available_parallelism()
+                .map_or(1, |n| n.get())
+                .min(MAX_DEFAULT_WRITE_SHARDS),
+            sync_writes: false,
+            group_commit_delay: Duration::ZERO,
+            write_batching: true,
+            log_record_alignment: None,
+            recycled_log_files: 2,
+            max_open_tables: 256,
+            skip_missing_tables: false,
+            compression: Compression::None,
+            block_size: DEFAULT_BLOCK_SIZE,
+            bloom_recovery: Default::default(),
+            bloom_size: Default::default(),
+            bloom_seed: None,
+            io_backend: IoBackend::Std,
+            bulk_load_table_size: DEFAULT_BULK_LOAD_TABLE_SIZE,
+            cold_storage_dir: None,
+            cold_storage_policy: Default::default(),
+            max_db_size_bytes: None,
+            quota_headroom_bytes: DEFAULT_QUOTA_HEADROOM,
+            compaction_rate_limit: None,
+            max_compaction_threads: DEFAULT_MAX_COMPACTION_THREADS,
+            compaction_tombstone_threshold: None,
+            compaction_interval: None,
+            idle_compaction_after: None,
+            spill_values: false,
+            memtable_max_entries: None,
+            memtable_max_bytes: None,
+            max_immutable_memtables: 0,
+            parallel_probe_threads: 0,
+            negative_cache_slots: 0,
+            write_timestamps: false,
+        }
+    }
+}
+
+impl Options {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Time source for TTL expiration
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Order of the keys, see [`KeyComparator`]. Chosen when the database is created: opening it with another one
+    /// fails with [`crate::Error::ComparatorMismatch`]
+    pub fn key_comparator(mut self, key_comparator: Arc<dyn KeyComparator>) -> Self {
+        self.key_comparator = key_comparator;
+        self
+    }
+
+    pub fn compaction_policy(mut self, compaction_policy: CompactionPolicy) -> Self {
+        self.compaction_policy = compaction_policy;
+        self
+    }
+
+    /// Filter applied to the entries rewritten by compaction, see [`CompactionFilter`]
+    pub fn compaction_filter(mut self, compaction_filter: Arc<dyn CompactionFilter>) -> Self {
+        self.compaction_filter = Some(compaction_filter);
+        self
+    }
+
+    /// Notified of flushes and compactions, see [`EventListener`]
+    pub fn event_listener(mut self, event_listener: Arc<dyn EventListener>) -> Self {
+        self.event_listener = Some(event_listener);
+        self
+    }
+
+    /// Number of keys kept in the read cache, 0 (the default) disables it
+    pub fn cache_capacity(mut self, cache_capacity: usize) -> Self {
+        self.cache_capacity = cache_capacity;
+        self
+    }
+
+    /// Number of changes buffered for each subscriber, 1024 by default
+    pub fn subscriber_capacity(mut self, subscriber_capacity: usize) -> Self {
+        self.subscriber_capacity = subscriber_capacity;
+        self
+    }
+
+    /// Number of append logs receiving writes in parallel, defaults to the number of CPUs (up to 8).
+    ///
+    /// Only used when creating the database, reopening keeps the shards it was created with.
+    pub fn write_shards(mut self, write_shards: usize) -> Self {
+        self.write_shards = write_shards;
+        self
+    }
+
+    /// Syncs the append log before every write returns, concurrent writers share a single sync
+    pub fn sync_writes(mut self, sync_writes: bool) -> Self {
+        self.sync_writes = sync_writes;
+        self
+    }
+
+    /// Longest time a synchronous write waits for concurrent writers to join its sync (e.g. 1ms), zero by default.
+    ///
+    /// The wait ends early once no other write is in flight.
+    pub fn group_commit_delay(mut self, group_commit_delay: Duration) -> Self {
+        self.group_commit_delay = group_commit_delay;
+        self
+    }
+
+    /// Combines the writes of concurrent writers of a shard, on by default: one of them writes the entries queued by
+    /// the others along with its own, in a single log write and memtable lock.
+    ///
+    /// A write may then wait for the batch in progress before its own. Turning it off gives a lone writer a slightly
+    /// lower latency, at the cost of throughput under contention.
+    pub fn write_batching(mut self, write_batching: bool) -> Self {
+        self.write_batching = write_batching;
+        self
+    }
+
+    /// Pads the append log so that no record crosses a multiple of `block_size` bytes (e.g. 4 KiB, the page size),
+    /// off by default. On a device writing whole blocks atomically, a crash then never leaves half a record.
+    ///
+    /// A record that doesn't fit in the rest of its block starts the next one, the padding before it is never larger
+    /// than the record by more than a byte. Block sizes under 64 bytes are rounded up to it.
+    pub fn align_log_records(mut self, block_size: u64) -> Self {
+        self.log_record_alignment = Some(block_size);
+        self
+    }
+
+    /// Retired log files kept by every write shard to be reused by the next rotations, 2 by default.
+    ///
+    /// Reusing a file avoids creating, preallocating and deleting one on every rotation, 0 disables recycling.
+    pub fn recycled_log_files(mut self, recycled_log_files: usize) -> Self {
+        self.recycled_log_files = recycled_log_files;
+        self
+    }
+
+    /// Most SSTable files kept open at once, 256 by default. Other tables are opened again on their next read
+    pub fn max_open_tables(mut self, max_open_tables: usize) -> Self {
+        self.max_open_tables = max_open_tables;
+        self
+    }
+
+    /// What reads do with a table whose file was deleted from outside of the store, off by default: they fail with
+    /// [`crate::Error::TableMissing`] for every key the table may hold.
+    ///
+    /// When set, the table is dropped from the live ones (and the manifest) instead and the reads go on with the older
+    /// tables, which may return older values of its keys. Either way the listener is told, see
+    /// [`crate::EventListener::on_background_error`].
+    pub fn skip_missing_tables(mut self, skip_missing_tables: bool) -> Self {
+        self.skip_missing_tables = skip_missing_tables;
+        self
+    }
+
+    /// Compression of the tables written from now on, existing tables are rewritten by compaction
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Size (before compression) of the SSTable data blocks, 4 KiB by default. Every block has an index entry.
+    ///
+    /// A lookup decodes part of a single block: smaller blocks mean less work per read but a larger in-memory index.
+    pub fn block_size(mut self, block_size: usize) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    /// How the bloom filters of the tables are loaded, [`BloomRecovery::FromFooter`] by default. Tables are written
+    /// with their filter whatever the choice, [`Stats::bloom_filter_bytes`] tells the memory they take
+    ///
+    /// [`Stats::bloom_filter_bytes`]: crate::Stats::bloom_filter_bytes
+    pub fn bloom_recovery(mut self, bloom_recovery: BloomRecovery) -> Self {
+        self.bloom_recovery = bloom_recovery;
+        self
+    }
+
+    /// Size of the bloom filters of the tables written from now on, a false positive rate of 0.1% by default
+    pub fn bloom_size(mut self, bloom_size: BloomSize) -> Self {
+        self.bloom_size = bloom_size;
+        self
+    }
+
+    /// Seed of the hashes of the bloom filters of the tables written from now on. By default every table draws its
+    /// own, so that no set of keys chosen in advance defeats the filters of every table.
+    ///
+    /// The seed is stored in the table, reopening it with another one (or none) is fine.
+    pub fn bloom_seed(mut self, seed: [u8; 32]) -> Self {
+        self.bloom_seed = Some(seed);
+        self
+    }
+
+    /// I/O of the files on disk. [`IoBackend::Uring`] falls back to [`IoBackend::Std`] (with a warning) when the
+    /// crate is built without the `uring` feature or the kernel doesn't support io_uring.
+    pub fn io_backend(mut self, io_backend: IoBackend) -> Self {
+        self.io_backend = io_backend;
+        self
+    }
+
+    /// Size of the SSTables written by [`crate::KVStorage::bulk_load`], 8 MiB by default. A table is built in memory
+    /// before being written.
+    pub fn bulk_load_table_size(mut self, bulk_load_table_size: u64) -> Self {
+        self.bulk_load_table_size = bulk_load_table_size;
+        self
+    }
+
+    /// Directory receiving the tables written by compaction that match [`Options::cold_storage_policy`], e.g. on a
+    /// larger and slower disk. New tables stay in `db/sstables/`.
+    ///
+    /// Namespaces use a subdirectory named after them. Once the database has cold tables the directory can't change.
+    pub fn cold_storage_dir(mut self, cold_storage_dir: impl Into<PathBuf>) -> Self {
+        self.cold_storage_dir = Some(cold_storage_dir.into());
+        self
+    }
+
+    /// See [`ColdStoragePolicy`], [`ColdStoragePolicy::OldestTier`] by default
+    pub fn cold_storage_policy(mut self, cold_storage_policy: ColdStoragePolicy) -> Self {
+        self.cold_storage_policy = cold_storage_policy;
+        self
+    }
+
+    /// Size the store never grows past with writes, which then fail with [`crate::Error::QuotaExceeded`]. Unlimited
+    /// by default.
+    ///
+    /// The SSTables and the records in the current logs are counted, see [`crate::Stats::disk_bytes_used`]. Every
+    /// namespace has a budget of its own.
+    pub fn max_db_size_bytes(mut self, max_db_size_bytes: u64) -> Self {
+        self.max_db_size_bytes = Some(max_db_size_bytes);
+        self
+    }
+
+    /// Space allowed past [`Options::max_db_size_bytes`] for deletions and for the output of compactions, which
+    /// is written before their input is removed. 1 MiB by default
+    pub fn quota_headroom_bytes(mut self, quota_headroom_bytes: u64) -> Self {
+        self.quota_headroom_bytes = quota_headroom_bytes;
+        self
+    }
+
+    /// Bytes per second read and written by compaction, shared by all its merges. Unlimited by default.
+    ///
+    /// Leaves disk bandwidth to reads and writes while large merges run. Flushes aren't limited.
+    pub fn compaction_rate_limit(mut self, bytes_per_sec: u64) -> Self {
+        self.compaction_rate_limit = Some(bytes_per_sec);
+        self
+    }
+
+    /// Most merges running at once, each on its own thread. 4 by default
+    pub fn max_compaction_threads(mut self, max_compaction_threads: usize) -> Self {
+        self.max_compaction_threads = max_compaction_threads;
+        self
+    }
+
+    /// Share of tombstones (0 to 1) past which a group of similar tables is merged even when smaller than the
+    /// policy's `min_merge`. Disabled by default.
+    ///
+    /// Tables full of deletions are then rewritten sooner, dropping the tombstones once they reach the oldest table.
+    pub fn compaction_tombstone_threshold(mut self, threshold: f64) -> Self {
+        self.compaction_tombstone_threshold = Some(threshold);
+        self
+    }
+
+    /// Also checks for merges every `compaction_interval`, not only when tables are added. Disabled by default
+    pub fn compaction_interval(mut self, compaction_interval: Duration) -> Self {
+        self.compaction_interval = Some(compaction_interval);
+        self
+    }
+
+    /// Once no write happened for `idle_compaction_after`, merges all tables into one, dropping their tombstones.
+    /// Disabled by default.
+    ///
+    /// Checked every `idle_compaction_after` (or [`Options::compaction_interval`] when shorter).
+    pub fn idle_compaction_after(mut self, idle_compaction_after: Duration) -> Self {
+        self.idle_compaction_after = Some(idle_compaction_after);
+        self
+    }
+
+    /// Keeps only the keys of the in-memory log and where they are in the log file, reading values back from the
+    /// file when needed. Disabled by default.
+    ///
+    /// Lowers the memory used by the logs at the cost of a read from the log file for keys found there.
+    /// See [`crate::Stats::memtable_bytes`].
+    pub fn spill_values(mut self, spill_values: bool) -> Self {
+        self.spill_values = spill_values;
+        self
+    }
+
+    /// Rotates a write shard's log once its memtable holds that many entries (overwritten ones included), even if
+    /// the file has room left. Unlimited by default.
+    ///
+    /// Keeps the in-memory lookups and sorted inserts of the log short, see [`crate::FlushReason::MemtableLimit`]
+    pub fn memtable_max_entries(mut self, memtable_max_entries: usize) -> Self {
+        self.memtable_max_entries = Some(memtable_max_entries.max(1));
+        self
+    }
+
+    /// Rotates a write shard's log once its memtable takes that much memory, even if the file has room left.
+    /// Unlimited by default. See [`crate::Stats::memtable_bytes`]
+    pub fn memtable_max_bytes(mut self, memtable_max_bytes: u64) -> Self {
+        self.memtable_max_bytes = Some(memtable_max_bytes);
+        self
+    }
+
+    /// Filled memtables of a write shard waiting for the flush thread, 0 (the default) has the writer filling one flush
+    /// it before going on.
+    ///
+    /// Writes continue into a fresh memtable while a dedicated thread turns the queued ones into SSTables, oldest
+    /// first. A writer finding the queue full waits for the thread to make room. Reads look at the queued memtables,
+    /// newest first, before the tables
+    pub fn max_immutable_memtables(mut self, max_immutable_memtables: usize) -> Self {
+        self.max_immutable_memtables = max_immutable_memtables;
+        self
+    }
+
+    /// Threads looking up the SSTables of a read in parallel, 0 (the default) looks them up one after the other.
+    ///
+    /// Only used when more than one table may have the key according to its bloom filter. Tables older than the
+    /// answer are read too, which helps when compaction is behind and many tables answer "maybe".
+    pub fn parallel_probe_threads(mut self, parallel_probe_threads: usize) -> Self {
+        self.parallel_probe_threads = parallel_probe_threads;
+        self
+    }
+
+    /// Slots of the cache of keys found in no table, 0 (the default) disables it.
+    ///
+    /// Reads of such keys then skip the bloom filters and tables. Every key maps to a single slot, so a key may be
+    /// evicted by another one but is never reported missing once written.
+    pub fn negative_cache_slots(mut self, negative_cache_slots: usize) -> Self {
+        self.negative_cache_slots = negative_cache_slots;
+        self
+    }
+
+    /// Stores the clock time (in ms, from [`Options::clock`]) of every write in its record, off by default.
+    ///
+    /// The time is kept through flushes and merges, the entry surviving a merge keeping its own. It's reported as
+    /// `written_at` by [`crate::KVStorage::read_detailed`], in [`crate::Change`]s and by the dumps, to tell when a key
+    /// changed. Records grow by 6 bytes; those written with the option off are unchanged
+    pub fn write_timestamps(mut self, write_timestamps: bool) -> Self {
+        self.write_timestamps = write_timestamps;
+        self
+    }
+
+    /// Options of the namespace `name`, which keeps its cold tables apart
+    pub(crate) fn for_namespace(&self, name: &str) -> Self {
+        Self {
+            cold_storage_dir: self.cold_storage_dir.as_ref().map(|dir| dir.join(name)),
+            ..self.clone()
+        }
+    }
+
+    pub fn subscriber_overflow(mut self, subscriber_overflow: OverflowPolicy) -> Self {
+        self.subscriber_overflow = subscriber_overflow;
+        self
+    }
+}
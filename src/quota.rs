@@ -0,0 +1,124 @@
+use crate::{errors::Error, options::Options};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Bytes held by a store, checked against [`Options::max_db_size_bytes`].
+///
+/// Counts the SSTables and the records written to the current log files. The unused (preallocated) part of the
+/// logs, recycled logs and replaced files waiting for deletion aren't counted.
+///
+/// Also tracks the size of the log files on disk for [`crate::KVStorage::disk_usage`], outside of the quota.
+pub struct DiskQuota {
+    limit: Option<u64>,
+    /// See [`Options::quota_headroom_bytes`]
+    headroom: u64,
+    table_bytes: AtomicU64,
+    log_bytes: AtomicU64,
+    /// Preallocated size of the log files, recycled ones included
+    log_allocated: AtomicU64,
+}
+
+impl DiskQuota {
+    pub fn new(options: &Options) -> Self {
+        Self {
+            limit: options.max_db_size_bytes,
+            headroom: options.quota_headroom_bytes,
+            table_bytes: Default::default(),
+            log_bytes: Default::default(),
+            log_allocated: Default::default(),
+        }
+    }
+
+    pub fn used(&self) -> u64 {
+        self.table_bytes() + self.log_bytes()
+    }
+
+    pub fn table_bytes(&self) -> u64 {
+        self.table_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Records written to the current log files
+    pub fn log_bytes(&self) -> u64 {
+        self.log_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn log_allocated(&self) -> u64 {
+        self.log_allocated.load(Ordering::Relaxed)
+    }
+
+    /// Accounts for a log record of `bytes`, failing with [`Error::QuotaExceeded`] if it doesn't fit.
+    ///
+    /// Deletions can also use the headroom, they let compaction reclaim space.
+    pub fn try_add_log(&self, bytes: u64, deletion: bool) -> Result<(), Error> {
+        let Some(limit) = self.limit else {
+            self.add_log(bytes);
+            return Ok(());
+        };
+        let limit = if deletion {
+            limit.saturating_add(self.headroom)
+        } else {
+            limit
+        };
+
+        let table_bytes = self.table_bytes.load(Ordering::Relaxed);
+        self.log_bytes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |log_bytes| {
+                let used = table_bytes + log_bytes + bytes;
+                (used <= limit).then_some(log_bytes + bytes)
+            })
+            .map_err(|_| Error::QuotaExceeded)?;
+
+        Ok(())
+    }
+
+    pub fn add_log(&self, bytes: u64) {
+        self.log_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// The records (`bytes`) of a log left it, for a table or because the write failed
+    pub fn remove_log(&self, bytes: u64) {
+        self.log_bytes.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_table(&self, bytes: u64) {
+        self.table_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn remove_table(&self, bytes: u64) {
+        self.table_bytes.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    /// A log file of `bytes` was created or reopened
+    pub fn add_log_file(&self, bytes: u64) {
+        self.log_allocated.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// A log file of `bytes` was deleted
+    pub fn remove_log_file(&self, bytes: u64) {
+        // Saturating, a reconciliation may have counted less than the removed files
+        let _ =
+            self.log_allocated
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |allocated| {
+                    Some(allocated.saturating_sub(bytes))
+                });
+    }
+
+    /// Replaces the tracked sizes of the tables and log files with the ones found on disk
+    pub fn reconcile(&self, table_bytes: u64, log_allocated: u64) {
+        self.table_bytes.store(table_bytes, Ordering::Relaxed);
+        self.log_allocated.store(log_allocated, Ordering::Relaxed);
+    }
+
+    /// Fails with [`Error::QuotaExceeded`] unless `bytes` of new tables fit
+    pub fn check_tables(&self, bytes: u64) -> Result<(), Error> {
+        match self.limit {
+            Some(limit) if self.used() + bytes > limit => Err(Error::QuotaExceeded),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether a merge of `input_bytes` fits within the headroom, its output being at most as large as its input
+    pub fn allows_compaction(&self, input_bytes: u64) -> bool {
+        self.limit
+            .is_none_or(|limit| self.used() + input_bytes <= limit.saturating_add(self.headroom))
+    }
+}
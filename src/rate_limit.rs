@@ -0,0 +1,90 @@
+use std::{
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Token bucket shared by the merge threads, see [`crate::Options::compaction_rate_limit`].
+///
+/// Callers take the tokens of their I/O up front, going into debt if needed, and sleep until the debt is paid.
+/// Idle time accrues at most one second of tokens.
+pub struct RateLimiter {
+    bytes_per_sec: u64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    /// Negative when in debt
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec: bytes_per_sec.max(1),
+            bucket: Mutex::new(Bucket {
+                tokens: 0.0,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    /// Blocks until `bytes` of I/O fit in the rate
+    pub fn acquire(&self, bytes: u64) {
+        let rate = self.bytes_per_sec as f64;
+        let wait = {
+            let mut bucket = self.bucket.lock().expect("poisoned rate limiter");
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * rate).min(rate) - bytes as f64;
+            bucket.refilled = now;
+
+            (bucket.tokens < 0.0).then(|| Duration::from_secs_f64(-bucket.tokens / rate))
+        };
+
+        if let Some(wait) = wait {
+            thread::sleep(wait);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_throttles_copy() {
+        const RATE: u64 = 20 * 1024 * 1024;
+        const CHUNK: u64 = 64 * 1024;
+        const COPIED: u64 = 10 * 1024 * 1024;
+
+        let limiter = Arc::new(RateLimiter::new(RATE));
+        let source = vec![1u8; COPIED as usize];
+        let mut destination = Vec::with_capacity(source.len());
+
+        // Two threads sharing the limiter, each copying half
+        let start = Instant::now();
+        let other = {
+            let limiter = limiter.clone();
+            thread::spawn(move || {
+                for _ in 0..COPIED / 2 / CHUNK {
+                    limiter.acquire(CHUNK);
+                }
+            })
+        };
+        for chunk in source[..COPIED as usize / 2].chunks(CHUNK as usize) {
+            limiter.acquire(chunk.len() as u64);
+            destination.extend_from_slice(chunk);
+        }
+        other.join().unwrap();
+        let elapsed = start.elapsed().as_secs_f64();
+
+        let expected = COPIED as f64 / RATE as f64;
+        assert!(
+            elapsed > expected * 0.9 && elapsed < expected * 1.5,
+            "{elapsed}s instead of {expected}s"
+        );
+    }
+}
@@ -0,0 +1,215 @@
+use crate::{
+    append_log::{self, LOG_FILE_PREFIX},
+    comparator::{Ascending, KeyComparator, KeyOrder},
+    env::ThreadRandom,
+    errors::Error,
+    file_header::FileKind,
+    functions,
+    manifest::{Manifest, ManifestData},
+    sstables::{self, TableFiles, TableOptions},
+    storage::Storage,
+};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// Directory inside `db/` where [`crate::KVStorage::repair`] moves the files it couldn't fully read
+pub const LOST_DIR: &str = "lost";
+
+/// Result of [`crate::KVStorage::repair`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    /// Log and table files that were read
+    pub files: Vec<SalvagedFile>,
+    /// Files listed in the manifest that don't exist
+    pub missing: Vec<PathBuf>,
+    /// Id of the table holding everything salvaged, `None` if nothing was
+    pub table: Option<u64>,
+}
+
+impl RepairReport {
+    /// Files moved to `db/lost/`
+    pub fn quarantined(&self) -> impl Iterator<Item = &Path> {
+        self.files
+            .iter()
+            .filter_map(|file| file.quarantined.as_deref())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SalvagedFile {
+    /// Where the file was before the repair
+    pub path: PathBuf,
+    /// Records decoded from the file
+    pub records: u64,
+    /// Set when part of the file couldn't be read, the file was then moved there instead of being deleted
+    pub quarantined: Option<PathBuf>,
+}
+
+/// Rebuilds the database in `db_dir` out of what its files still hold, see [`crate::KVStorage::repair`]. The keys are
+/// ordered by `comparator`, which must be the one recorded in the manifest
+pub fn repair_database(
+    storage: &Arc<dyn Storage>,
+    db_dir: &Path,
+    comparator: &Arc<dyn KeyComparator>,
+) -> Result<RepairReport, Error> {
+    if !storage.is_dir(db_dir) {
+        return Err(Error::InvalidDbLocation);
+    }
+    let sstables_dir = db_dir.join("sstables");
+    storage.create_dir_all(&sstables_dir)?;
+    let files = TableFiles::new(storage.clone(), sstables_dir.clone(), 1)
+        .with_order(KeyOrder::new(comparator.clone()));
+
+    // Files outside of the manifest are left over by compactions, they can hold entries deleted since
+    let (log_paths, table_paths, namespaces, shards) = match Manifest::load(storage, db_dir) {
+        Ok(manifest) => {
+            let data = manifest.data();
+            let stored = data.comparator.as_deref().unwrap_or(Ascending.name());
+            if stored != comparator.name() {
+                return Err(Error::ComparatorMismatch {
+                    stored: stored.to_owned(),
+                    configured: comparator.name().to_owned(),
+                });
+            }
+            // Cold tables are salvaged from where they are, the rebuilt table goes to `db/sstables/`
+            let cold_files = TableFiles::new(storage.clone(), sstables_dir.clone(), 1)
+                .with_cold_dir(
+                    data.cold_storage_dir.as_ref().map(PathBuf::from),
+                    &data.cold_sstables,
+                );
+            (
+                data.log_files
+                    .iter()
+                    .chain(data.frozen_log_files.iter().flatten())
+                    .map(|name| db_dir.join(name))
+                    .collect(),
+                data.sstables
+                    .iter()
+                    .map(|id| cold_files.path(*id))
+                    .collect(),
+                data.namespaces,
+                // Frozen logs aren't shards of their own
+                data.log_files.len(),
+            )
+        }
+        Err(e) => {
+            log::warn!("manifest unreadable, salvaging every file: {e:?}");
+            let is_log = |name: &str| {
+                name.strip_prefix(LOG_FILE_PREFIX)
+                    .is_some_and(|suffix| suffix.parse::<u64>().is_ok())
+            };
+            let log_paths = list_matching(&**storage, db_dir, is_log)?;
+            let shards = log_paths.len();
+            (
+                log_paths,
+                list_matching(&**storage, &sstables_dir, |name| {
+                    name.parse::<u64>().is_ok()
+                })?,
+                Vec::new(),
+                shards,
+            )
+        }
+    };
+    let log_count = shards.max(1);
+
+    let mut report = RepairReport::default();
+    let mut entries = Vec::new();
+    let mut damaged_files = Vec::new();
+    let paths = log_paths
+        .into_iter()
+        .map(|path| (path, FileKind::Log))
+        .chain(table_paths.into_iter().map(|path| (path, FileKind::Table)));
+
+    for (path, kind) in paths {
+        let content = match storage.open(&path, false) {
+            Ok(file) => functions::read_file(&file, file.size()?)?,
+            Err(Error::IO(e)) if e.kind() == io::ErrorKind::NotFound => {
+                report.missing.push(path);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+
+        let (file_entries, damaged) = match kind {
+            FileKind::Log => append_log::salvage_log_file(&content),
+            FileKind::Table => sstables::salvage_table_file(&content),
+        };
+        if damaged {
+            log::warn!(
+                "file {path:?} is damaged, salvaged {} records",
+                file_entries.len()
+            );
+        }
+
+        report.files.push(SalvagedFile {
+            path,
+            records: file_entries.len() as u64,
+            quarantined: None,
+        });
+        damaged_files.push(damaged);
+        entries.extend(file_entries);
+    }
+
+    if !entries.is_empty() {
+        report.table = Some(sstables::rebuild_table(
+            &files,
+            entries,
+            TableOptions::default(),
+        )?);
+    }
+
+    let mut log_files = Vec::with_capacity(log_count);
+    for _ in 0..log_count {
+        let log_file = append_log::create_append_log_file(&**storage, db_dir, &ThreadRandom)?;
+        log_file.file.sync_all()?;
+        log_files.push(append_log::log_file_name(&log_file));
+    }
+    storage.sync_dir(db_dir)?;
+
+    Manifest::create(
+        storage,
+        db_dir,
+        ManifestData {
+            log_files,
+            sstables: report.table.into_iter().collect(),
+            namespaces,
+            comparator: Some(comparator.name().to_owned()),
+            ..Default::default()
+        },
+    )?;
+
+    // Everything salvaged is in the new table, the originals are only kept if they may hold more
+    let lost_dir = db_dir.join(LOST_DIR);
+    for (file, damaged) in report.files.iter_mut().zip(damaged_files) {
+        if !damaged {
+            storage.remove(&file.path)?;
+            continue;
+        }
+
+        storage.create_dir_all(&lost_dir)?;
+        let destination = lost_dir.join(file.path.file_name().unwrap_or_default());
+        storage.rename(&file.path, &destination)?;
+        file.quarantined = Some(destination);
+    }
+
+    Ok(report)
+}
+
+fn list_matching(
+    storage: &dyn Storage,
+    dir: &Path,
+    matches: impl Fn(&str) -> bool,
+) -> Result<Vec<PathBuf>, Error> {
+    Ok(storage
+        .list(dir)?
+        .into_iter()
+        .map(|file| file.path)
+        .filter(|path| {
+            path.file_name()
+                .is_some_and(|name| matches(&name.to_string_lossy()))
+        })
+        .collect())
+}
@@ -1,28 +1,153 @@
 use bitcode::{Decode, Encode};
+use std::cell::RefCell;
 
-use crate::{Key, Value, errors::Error};
+use crate::{Key, Value, comparator::KeyOrder, errors::Error, functions::ReadOutcome};
 
+/// Written before every [`RecordFormat::Framed`] record, bumped on incompatible changes of the record layout
+const RECORD_VERSION: u8 = 3;
+const RECORD_VERSION_BYTES: usize = 1;
 // 16mb
 const STRUCT_LEN_BYTES: usize = 3;
+const HEADER_BYTES: usize = RECORD_VERSION_BYTES + STRUCT_LEN_BYTES;
+/// Upper bound of a serialized record, header included
+pub const MAX_RECORD_BYTES: usize = 64;
+
+/// Kinds of [`RecordFormat::Fixed`] records, in the first byte. The high bit is set so that the zeros of empty space
+/// and most stray bytes aren't taken for a record
+const TAG_PUT: u8 = 0x81;
+const TAG_TOMBSTONE: u8 = 0x82;
+/// Followed by the number of bytes to skip (varint), see [`padding`]
+const TAG_PADDING: u8 = 0x83;
+/// Added to the tag when the record has an expiration
+const TAG_EXPIRES: u8 = 0x04;
+/// Added to the tag of range tombstones, followed by the end of the range
+const TAG_RANGE: u8 = 0x08;
+/// Added to the tag when the record has a write time, see [`crate::Options::write_timestamps`]
+const TAG_WRITTEN_AT: u8 = 0x10;
+/// Keys and values
+const FIXED_FIELD_BYTES: usize = 8;
+/// Of a varint holding any `u64`
+const MAX_VARINT_BYTES: usize = 10;
+
+/// How the records of a file are laid out, decided by the version in its header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordFormat {
+    /// `[record version (1)][length (3)][bitcode struct]`, able to hold values of any size
+    Framed,
+    /// `[tag (1)][key (8)][sequence (varint)]` followed by the value (8) of puts, the expiration (varint), the end
+    /// (8) of range tombstones and the write time (varint) when there are. Keys and values are little-endian, their
+    /// size is implied by the tag
+    Fixed,
+}
+
+impl RecordFormat {
+    /// Of the files written now
+    pub const CURRENT: Self = RecordFormat::Fixed;
+}
 
-#[derive(PartialEq, Eq, Encode, Decode)]
+thread_local! {
+    /// Reused between calls so that encoding and decoding don't allocate
+    static CODER: RefCell<bitcode::Buffer> = RefCell::new(bitcode::Buffer::new());
+}
+
+#[derive(Clone, PartialEq, Eq)]
 pub struct KVMemoryRepr {
     key: Key,
     /// Holds the value (or the tombstone)
     value: Option<Value>,
-    /// Used to distinguish from empty bytes. Should **ALWAYS** be true
+    /// Position of the write in the global order, the highest one is the most recent
+    sequence: u64,
+    /// Milliseconds since the UNIX epoch after which the entry counts as deleted
+    expires_at: Option<u64>,
+    /// Set for range tombstones, which delete every older entry in `[key, range_end)`
+    range_end: Option<Key>,
+    /// Milliseconds since the UNIX epoch when the entry was written, see [`crate::Options::write_timestamps`]. Not
+    /// stored by [`RecordFormat::Framed`] records
+    written_at: Option<u64>,
+}
+
+/// The bitcode struct of a [`RecordFormat::Framed`] record
+#[derive(Encode, Decode)]
+struct FramedRecord {
+    key: Key,
+    value: Option<Value>,
+    sequence: u64,
+    expires_at: Option<u64>,
+    range_end: Option<Key>,
+    /// Always true, told records from the zeros of empty space before the files had an end of data
     valid: bool,
 }
 
+impl From<&KVMemoryRepr> for FramedRecord {
+    fn from(entry: &KVMemoryRepr) -> Self {
+        FramedRecord {
+            key: entry.key,
+            value: entry.value,
+            sequence: entry.sequence,
+            expires_at: entry.expires_at,
+            range_end: entry.range_end,
+            valid: true,
+        }
+    }
+}
+
+impl From<FramedRecord> for KVMemoryRepr {
+    fn from(record: FramedRecord) -> Self {
+        KVMemoryRepr {
+            key: record.key,
+            value: record.value,
+            sequence: record.sequence,
+            expires_at: record.expires_at,
+            range_end: record.range_end,
+            written_at: None,
+        }
+    }
+}
+
+/// What starts at some offset of a file
+enum Record {
+    Entry(KVMemoryRepr),
+    /// Bytes holding no record, see [`padding`]
+    Padding,
+    /// A zero byte where a record would start: files are zeroed before they're written to, nothing follows
+    End,
+}
+
 impl KVMemoryRepr {
-    pub fn new(key: Key, value: Option<Value>) -> Self {
+    pub fn new(key: Key, value: Option<Value>, sequence: u64) -> Self {
         Self {
             key,
             value,
-            valid: true,
+            sequence,
+            expires_at: None,
+            range_end: None,
+            written_at: None,
+        }
+    }
+
+    /// Deletes the keys in `[start, end)` written before it
+    pub fn range_tombstone(start: Key, end: Key, sequence: u64) -> Self {
+        Self {
+            range_end: Some(end),
+            ..Self::new(start, None, sequence)
         }
     }
 
+    pub fn with_sequence(mut self, sequence: u64) -> Self {
+        self.sequence = sequence;
+        self
+    }
+
+    pub fn with_expiration(mut self, expires_at: Option<u64>) -> Self {
+        self.expires_at = expires_at;
+        self
+    }
+
+    pub fn with_written_at(mut self, written_at: Option<u64>) -> Self {
+        self.written_at = written_at;
+        self
+    }
+
     pub fn key(&self) -> &Key {
         &self.key
     }
@@ -30,6 +155,95 @@ impl KVMemoryRepr {
     pub fn value(&self) -> &Option<Value> {
         &self.value
     }
+
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    pub fn expires_at(&self) -> Option<u64> {
+        self.expires_at
+    }
+
+    pub fn written_at(&self) -> Option<u64> {
+        self.written_at
+    }
+
+    pub fn is_range_tombstone(&self) -> bool {
+        self.range_end.is_some()
+    }
+
+    /// Whether this is a range tombstone including `key`, in the order of `order`
+    pub(crate) fn covers(&self, key: &Key, order: &KeyOrder) -> bool {
+        self.range_end
+            .is_some_and(|end| order.in_range(&self.key, &end, key))
+    }
+
+    /// Bytes taken by the value, 0 for tombstones. Values are fixed-width for now
+    pub fn value_size(&self) -> u64 {
+        match self.value {
+            Some(_) => size_of::<Value>() as u64,
+            None => 0,
+        }
+    }
+
+    pub fn range_end(&self) -> Option<Key> {
+        self.range_end
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// Expired entries read as tombstones, so that they keep shadowing older values
+    pub fn read_outcome(&self, now: u64) -> ReadOutcome {
+        match self.value {
+            Some(value) if !self.is_expired(now) => ReadOutcome::Found(value),
+            _ => ReadOutcome::Deleted,
+        }
+    }
+
+    pub fn with_value(mut self, value: Value) -> Self {
+        self.value = Some(value);
+        self
+    }
+
+    /// Turns the entry into a plain tombstone
+    pub fn into_tombstone(self) -> Self {
+        Self {
+            value: None,
+            expires_at: None,
+            ..self
+        }
+    }
+}
+
+/// The most recent of the two entries
+pub fn newest<'a>(
+    a: Option<&'a KVMemoryRepr>,
+    b: Option<&'a KVMemoryRepr>,
+) -> Option<&'a KVMemoryRepr> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(if a.sequence >= b.sequence { a } else { b }),
+        (a, b) => a.or(b),
+    }
+}
+
+/// The most recent range tombstone in `ranges` covering `key`
+pub fn newest_covering<'a>(
+    ranges: impl IntoIterator<Item = &'a KVMemoryRepr>,
+    key: &Key,
+    order: &KeyOrder,
+) -> Option<&'a KVMemoryRepr> {
+    ranges
+        .into_iter()
+        .filter(|range| range.covers(key, order))
+        .max_by_key(|range| range.sequence)
+}
+
+/// Keeps a single copy of every range tombstone (one is written to each write shard), `entries` must be sorted by
+/// sequence
+pub fn dedup_range_copies(entries: &mut Vec<KVMemoryRepr>) {
+    entries.dedup_by(|a, b| a.is_range_tombstone() && a.sequence == b.sequence);
 }
 
 impl PartialOrd for KVMemoryRepr {
@@ -50,6 +264,19 @@ pub enum SerializationError {
     BufferTooSmall,
     InvalidLength,
     DecodeFailed(bitcode::Error),
+    UnsupportedRecordVersion(u8),
+    /// A fixed-width record starts with an unknown kind
+    UnknownRecordTag(u8),
+    /// Padding or the end of the data where a record was expected
+    NoRecord,
+    /// Something other than zeros after the end of the data, e.g. a record written past a slot whose write failed
+    DataAfterEnd,
+    UnsupportedCompression(u8),
+    /// A log or table file doesn't start with the magic bytes of its kind
+    InvalidFileHeader,
+    /// The footer or the block index of a table point outside of the file
+    InvalidTableLayout,
+    Decompression(lz4_flex::block::DecompressError),
 }
 
 impl From<bitcode::Error> for SerializationError {
@@ -58,100 +285,413 @@ impl From<bitcode::Error> for SerializationError {
     }
 }
 
-pub fn serialize(data: &KVMemoryRepr) -> Result<Vec<u8>, Error> {
-    let encoded_struct = bitcode::encode(data);
+pub fn serialize(data: &KVMemoryRepr, format: RecordFormat) -> Result<Vec<u8>, Error> {
+    if format == RecordFormat::Fixed {
+        let mut buffer = [0u8; MAX_RECORD_BYTES];
+        let len = encode_fixed(data, &mut buffer)?;
+        return Ok(buffer[..len].to_vec());
+    }
+
+    let encoded_struct = bitcode::encode(&FramedRecord::from(data));
 
-    let mut result = Vec::with_capacity(STRUCT_LEN_BYTES + encoded_struct.len());
-    result.resize(STRUCT_LEN_BYTES, 0);
+    let mut result = Vec::with_capacity(HEADER_BYTES + encoded_struct.len());
+    result.resize(HEADER_BYTES, 0);
 
+    result[0] = RECORD_VERSION;
     serialize_length(
         encoded_struct.len() as u64,
-        &mut result[0..STRUCT_LEN_BYTES],
+        &mut result[RECORD_VERSION_BYTES..HEADER_BYTES],
     )?;
     result.extend_from_slice(&encoded_struct);
 
     Ok(result)
 }
 
-/// Deserializes KV entries from a byte slice.
+/// Same as [`serialize`], writing into `out` instead of allocating. Returns the number of bytes written.
+pub fn serialize_into(
+    data: &KVMemoryRepr,
+    out: &mut [u8],
+    format: RecordFormat,
+) -> Result<usize, Error> {
+    if format == RecordFormat::Fixed {
+        return encode_fixed(data, out);
+    }
+
+    CODER.with_borrow_mut(|coder| {
+        let encoded_struct = coder.encode(&FramedRecord::from(data));
+        let total_len = HEADER_BYTES + encoded_struct.len();
+
+        if out.len() < total_len {
+            return Err(Error::Serialization(SerializationError::BufferTooSmall));
+        }
+
+        out[0] = RECORD_VERSION;
+        serialize_length(
+            encoded_struct.len() as u64,
+            &mut out[RECORD_VERSION_BYTES..HEADER_BYTES],
+        )?;
+        out[HEADER_BYTES..total_len].copy_from_slice(encoded_struct);
+
+        Ok(total_len)
+    })
+}
+
+/// Deserializes KV entries from a byte slice, up to the end of the data.
 ///
-/// Ignores the eventual empty part (all zeros).
+/// Fails when something other than zeros follows the end of the data.
 pub fn deserialize_entries_from_bytes(
     buffer: &[u8],
     file: &'static str,
+    format: RecordFormat,
 ) -> Result<Vec<KVMemoryRepr>, Error> {
-    let mut kv_entries = vec![];
+    let mut entries = Vec::new();
+    deserialize_entries_into(buffer, file, format, &mut entries)?;
+
+    Ok(entries)
+}
+
+/// Same as [`deserialize_entries_from_bytes`], appending the entries to `out` instead of allocating
+pub fn deserialize_entries_into(
+    buffer: &[u8],
+    file: &'static str,
+    format: RecordFormat,
+    out: &mut Vec<KVMemoryRepr>,
+) -> Result<(), Error> {
+    decode_entries(buffer, file, format, |_, entry| out.push(entry))?;
+
+    Ok(())
+}
+
+/// Same as [`deserialize_entries_from_bytes`], also returning the offset of each entry and the end of the data
+pub fn deserialize_entries_with_offsets(
+    buffer: &[u8],
+    file: &'static str,
+    format: RecordFormat,
+) -> Result<(Vec<(u64, KVMemoryRepr)>, u64), Error> {
+    let mut entries = Vec::new();
+    let end = decode_entries(buffer, file, format, |offset, entry| {
+        entries.push((offset, entry))
+    })?;
+
+    Ok((entries, end))
+}
+
+/// Passes every entry of `buffer` to `push` with its offset, returns the end of the data
+fn decode_entries(
+    buffer: &[u8],
+    file: &'static str,
+    format: RecordFormat,
+    mut push: impl FnMut(u64, KVMemoryRepr),
+) -> Result<u64, Error> {
     let mut remaining_slice = buffer;
 
     while !remaining_slice.is_empty() {
-        let p = deserialize(remaining_slice);
+        let offset = (buffer.len() - remaining_slice.len()) as u64;
 
-        if let Err(_) = &p
-            && remaining_slice.iter().any(|b| *b != 0)
-        {
-            eprintln!(
+        let p = decode_record(remaining_slice, format, true);
+        if p.is_err() {
+            log::error!(
                 "Error deserializing {file}, first 30 bytes: {:?}",
                 &remaining_slice[..remaining_slice.len().min(30)]
             );
         }
 
-        match p {
-            Ok((entry, unused)) => {
-                // Check if this is an actual struct or just empty space
-                // TODO: this could be a corrupted write
-                if entry.valid {
-                    kv_entries.push(entry);
-                }
-
+        match p? {
+            (Record::Entry(entry), unused) => {
+                push(offset, entry);
                 remaining_slice = unused;
             }
-            Err(e) => {
-                if remaining_slice.iter().all(|b| *b == 0) {
-                    // The file had an empty part, ignore it
-                    break;
-                } else {
-                    return Err(e);
+            (Record::Padding, unused) => remaining_slice = unused,
+            (Record::End, _) => {
+                if remaining_slice.iter().any(|b| *b != 0) {
+                    log::warn!("Data after the end of {file}, at offset {offset}");
+                    return Err(SerializationError::DataAfterEnd.into());
                 }
+                break;
+            }
+        }
+    }
+
+    Ok((buffer.len() - remaining_slice.len()) as u64)
+}
+
+/// Entries of `buffer` with their offset, up to the end of the data or the first record that can't be decoded.
+///
+/// For files another process may be writing to, where the last record can be incomplete.
+pub fn deserialize_prefix(buffer: &[u8], format: RecordFormat) -> Vec<(u64, KVMemoryRepr)> {
+    let mut entries = Vec::new();
+    let mut remaining = buffer;
+
+    while !remaining.is_empty() {
+        let offset = (buffer.len() - remaining.len()) as u64;
+        match decode_record(remaining, format, false) {
+            Ok((Record::Entry(entry), rest)) => {
+                entries.push((offset, entry));
+                remaining = rest;
             }
+            Ok((Record::Padding, rest)) => remaining = rest,
+            Ok((Record::End, _)) | Err(_) => break,
         }
     }
 
-    Ok(kv_entries)
+    entries
 }
 
-pub fn deserialize(bytes: &[u8]) -> Result<(KVMemoryRepr, &[u8]), Error> {
-    if bytes.len() < STRUCT_LEN_BYTES {
+/// Decodes every record found in `buffer`, skipping what can't be decoded instead of failing.
+///
+/// After a damaged span, decoding resumes at the next byte starting a plausible record. Returns the valid entries and
+/// the number of bytes skipped, zeros (empty space) and padding excluded.
+pub fn salvage_entries(buffer: &[u8], format: RecordFormat) -> (Vec<KVMemoryRepr>, u64) {
+    let mut entries = Vec::new();
+    let mut skipped = 0;
+    let mut position = 0;
+
+    while position < buffer.len() {
+        let remaining = &buffer[position..];
+
+        match decode_record(remaining, format, false) {
+            Ok((Record::End, _)) => position += 1,
+            Ok((Record::Padding, rest)) => position = buffer.len() - rest.len(),
+            Ok((Record::Entry(entry), rest))
+                if remaining.len() - rest.len() <= MAX_RECORD_BYTES =>
+            {
+                entries.push(entry);
+                position = buffer.len() - rest.len();
+            }
+            _ => {
+                skipped += 1;
+                position += 1;
+            }
+        }
+    }
+
+    (entries, skipped)
+}
+
+/// Decodes the record at the start of `bytes`, failing on padding and at the end of the data
+pub fn deserialize(bytes: &[u8], format: RecordFormat) -> Result<(KVMemoryRepr, &[u8]), Error> {
+    match decode_record(bytes, format, true)? {
+        (Record::Entry(entry), rest) => Ok((entry, rest)),
+        _ => Err(SerializationError::NoRecord.into()),
+    }
+}
+
+/// A record covering the `len` bytes from where it's written, that readers skip over: the end of a file abandoned by
+/// a rotation, the slot of a write that failed.
+///
+/// `None` when `len` is too short to hold one, or for framed records which have none. Readers then stop at the zeros
+/// left (or fail on garbage)
+pub fn padding(len: u64, format: RecordFormat) -> Option<Vec<u8>> {
+    if format == RecordFormat::Framed {
+        return None;
+    }
+
+    // The size of the skipped length counts in the padding, it's written on more bytes than needed when that's what
+    // makes the sizes add up
+    let varint_len = (1..=MAX_VARINT_BYTES as u64).find(|&varint_len| {
+        len.checked_sub(1 + varint_len)
+            .is_some_and(|skipped| skipped < 1 << (7 * varint_len).min(63) || varint_len == 10)
+    })?;
+    let skipped = len - 1 - varint_len;
+
+    let mut out = vec![TAG_PADDING];
+    for i in 0..varint_len {
+        let more = if i + 1 < varint_len { 0x80 } else { 0 };
+        out.push((skipped >> (7 * i)) as u8 & 0x7F | more);
+    }
+
+    Some(out)
+}
+
+/// `log_errors` prints the bytes that failed to decode
+fn decode_record(
+    bytes: &[u8],
+    format: RecordFormat,
+    log_errors: bool,
+) -> Result<(Record, &[u8]), Error> {
+    match bytes.first() {
+        Some(0) => return Ok((Record::End, bytes)),
+        None => return Err(Error::Serialization(SerializationError::BufferTooSmall)),
+        Some(_) => {}
+    }
+
+    if format == RecordFormat::Fixed {
+        return decode_fixed(bytes);
+    }
+
+    if bytes.len() < HEADER_BYTES {
         return Err(Error::Serialization(SerializationError::BufferTooSmall));
     }
 
-    let length_bytes: [u8; STRUCT_LEN_BYTES] = bytes[0..STRUCT_LEN_BYTES]
+    if bytes[0] != RECORD_VERSION {
+        return Err(Error::Serialization(
+            SerializationError::UnsupportedRecordVersion(bytes[0]),
+        ));
+    }
+
+    let length_bytes: [u8; STRUCT_LEN_BYTES] = bytes[RECORD_VERSION_BYTES..HEADER_BYTES]
         .try_into()
         .map_err(|_| Error::Serialization(SerializationError::BufferTooSmall))?;
 
     let struct_len = deserialize_length(&length_bytes) as usize;
 
     if struct_len == 0 {
-        return Err(Error::Serialization(SerializationError::BufferTooSmall));
+        return Err(Error::Serialization(SerializationError::InvalidLength));
     }
 
-    if bytes.len() < STRUCT_LEN_BYTES + struct_len {
+    if bytes.len() < HEADER_BYTES + struct_len {
         return Err(Error::Serialization(SerializationError::BufferTooSmall));
     }
 
-    let struct_bytes = &bytes[STRUCT_LEN_BYTES..STRUCT_LEN_BYTES + struct_len];
+    let struct_bytes = &bytes[HEADER_BYTES..HEADER_BYTES + struct_len];
+
+    let record: FramedRecord = CODER
+        .with_borrow_mut(|coder| coder.decode(struct_bytes))
+        .map_err(|e| {
+            if log_errors {
+                log::error!(
+                    "Decode error: len={}, bytes={:?}",
+                    struct_bytes.len(),
+                    struct_bytes
+                );
+            }
+            Error::Serialization(SerializationError::DecodeFailed(e))
+        })?;
 
-    let entry: KVMemoryRepr = bitcode::decode(struct_bytes).map_err(|e| {
-        eprintln!(
-            "Decode error: len={}, bytes={:?}",
-            struct_bytes.len(),
-            struct_bytes
-        );
-        Error::Serialization(SerializationError::DecodeFailed(e))
-    })?;
+    let remaining = &bytes[HEADER_BYTES + struct_len..];
+
+    Ok((Record::Entry(record.into()), remaining))
+}
+
+fn encode_fixed(data: &KVMemoryRepr, out: &mut [u8]) -> Result<usize, Error> {
+    let mut tag = match data.value {
+        Some(_) => TAG_PUT,
+        None => TAG_TOMBSTONE,
+    };
+    if data.expires_at.is_some() {
+        tag |= TAG_EXPIRES;
+    }
+    if data.range_end.is_some() {
+        tag |= TAG_RANGE;
+    }
+    if data.written_at.is_some() {
+        tag |= TAG_WRITTEN_AT;
+    }
 
-    let remaining = &bytes[STRUCT_LEN_BYTES + struct_len..];
+    let mut at = 0;
+    put_bytes(out, &mut at, &[tag])?;
+    put_bytes(out, &mut at, &data.key.to_le_bytes())?;
+    put_varint(out, &mut at, data.sequence)?;
+    if let Some(value) = data.value {
+        put_bytes(out, &mut at, &value.to_le_bytes())?;
+    }
+    if let Some(expires_at) = data.expires_at {
+        put_varint(out, &mut at, expires_at)?;
+    }
+    if let Some(range_end) = data.range_end {
+        put_bytes(out, &mut at, &range_end.to_le_bytes())?;
+    }
+    if let Some(written_at) = data.written_at {
+        put_varint(out, &mut at, written_at)?;
+    }
 
-    Ok((entry, remaining))
+    Ok(at)
+}
+
+fn decode_fixed(bytes: &[u8]) -> Result<(Record, &[u8]), Error> {
+    let (&tag, mut rest) = bytes
+        .split_first()
+        .ok_or(Error::Serialization(SerializationError::BufferTooSmall))?;
+    if tag == TAG_PADDING {
+        let skipped = take_varint(&mut rest)?;
+        let rest = usize::try_from(skipped)
+            .ok()
+            .and_then(|skipped| rest.get(skipped..))
+            .ok_or(Error::Serialization(SerializationError::BufferTooSmall))?;
+        return Ok((Record::Padding, rest));
+    }
+
+    let kind = tag & !(TAG_EXPIRES | TAG_RANGE | TAG_WRITTEN_AT);
+    let range = tag & TAG_RANGE != 0;
+    if !(kind == TAG_TOMBSTONE || kind == TAG_PUT && !range) {
+        return Err(Error::Serialization(SerializationError::UnknownRecordTag(
+            tag,
+        )));
+    }
+
+    let key = take_u64(&mut rest)?;
+    let sequence = take_varint(&mut rest)?;
+    let value = (kind == TAG_PUT).then(|| take_u64(&mut rest)).transpose()?;
+    let expires_at = (tag & TAG_EXPIRES != 0)
+        .then(|| take_varint(&mut rest))
+        .transpose()?;
+    let range_end = range.then(|| take_u64(&mut rest)).transpose()?;
+    let written_at = (tag & TAG_WRITTEN_AT != 0)
+        .then(|| take_varint(&mut rest))
+        .transpose()?;
+
+    let entry = KVMemoryRepr {
+        key,
+        value,
+        sequence,
+        expires_at,
+        range_end,
+        written_at,
+    };
+
+    Ok((Record::Entry(entry), rest))
+}
+
+/// Copies `bytes` to `out` at `at`, moving it past them
+fn put_bytes(out: &mut [u8], at: &mut usize, bytes: &[u8]) -> Result<(), Error> {
+    let end = *at + bytes.len();
+    out.get_mut(*at..end)
+        .ok_or(Error::Serialization(SerializationError::BufferTooSmall))?
+        .copy_from_slice(bytes);
+    *at = end;
+
+    Ok(())
+}
+
+/// LEB128: 7 bits per byte, the low ones first, the high bit set on all bytes but the last
+fn put_varint(out: &mut [u8], at: &mut usize, mut value: u64) -> Result<(), Error> {
+    let mut bytes = [0u8; MAX_VARINT_BYTES];
+    let mut len = 0;
+    while value >= 0x80 {
+        bytes[len] = value as u8 | 0x80;
+        value >>= 7;
+        len += 1;
+    }
+    bytes[len] = value as u8;
+
+    put_bytes(out, at, &bytes[..=len])
+}
+
+fn take_u64(bytes: &mut &[u8]) -> Result<u64, Error> {
+    let (value, rest) = bytes
+        .split_first_chunk::<FIXED_FIELD_BYTES>()
+        .ok_or(Error::Serialization(SerializationError::BufferTooSmall))?;
+    *bytes = rest;
+
+    Ok(u64::from_le_bytes(*value))
+}
+
+fn take_varint(bytes: &mut &[u8]) -> Result<u64, Error> {
+    let mut value = 0u64;
+    for (i, &byte) in bytes.iter().enumerate().take(MAX_VARINT_BYTES) {
+        let bits = (byte & 0x7F) as u64;
+        // The 10th byte holds the single highest bit
+        if i == MAX_VARINT_BYTES - 1 && bits > 1 {
+            break;
+        }
+        value |= bits << (7 * i);
+        if byte & 0x80 == 0 {
+            *bytes = &bytes[i + 1..];
+            return Ok(value);
+        }
+    }
+
+    Err(Error::Serialization(SerializationError::InvalidLength))
 }
 
 fn serialize_length(length: u64, bytes: &mut [u8]) -> Result<(), Error> {
@@ -181,3 +721,174 @@ fn deserialize_length(bytes: &[u8; STRUCT_LEN_BYTES]) -> u64 {
 
     length
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMATS: [RecordFormat; 2] = [RecordFormat::Framed, RecordFormat::Fixed];
+
+    #[test]
+    fn test_serialize_into_matches_serialize() {
+        let entries = [
+            KVMemoryRepr::new(0, None, 0),
+            KVMemoryRepr::new(u64::MAX, Some(u64::MAX), u64::MAX).with_expiration(Some(u64::MAX)),
+            KVMemoryRepr::range_tombstone(3, u64::MAX, 1).with_expiration(Some(2)),
+        ];
+
+        for (format, entry) in FORMATS
+            .into_iter()
+            .flat_map(|f| entries.clone().map(|e| (f, e)))
+        {
+            let mut buffer = [0u8; MAX_RECORD_BYTES];
+            let len = serialize_into(&entry, &mut buffer, format).unwrap();
+            assert_eq!(&buffer[..len], serialize(&entry, format).unwrap());
+
+            let (decoded, rest) = deserialize(&buffer, format).unwrap();
+            assert!(decoded == entry);
+            assert!(rest.iter().all(|b| *b == 0));
+        }
+
+        let entry = KVMemoryRepr::new(1, Some(1), 1);
+        for format in FORMATS {
+            assert!(serialize_into(&entry, &mut [0u8; HEADER_BYTES], format).is_err());
+        }
+    }
+
+    #[test]
+    fn test_fixed_records_are_smaller() {
+        // Full-width keys and values, a million writes in
+        let put = KVMemoryRepr::new(u64::MAX - 1, Some(u64::MAX - 2), 1_000_000);
+        let tombstone = KVMemoryRepr::new(u64::MAX - 1, None, 1_000_000);
+        let size = |entry: &KVMemoryRepr, format| serialize(entry, format).unwrap().len();
+
+        // Tag, key, sequence (3 bytes) and value
+        assert_eq!(size(&put, RecordFormat::Fixed), 20);
+        assert_eq!(size(&tombstone, RecordFormat::Fixed), 12);
+        assert_eq!(size(&put, RecordFormat::Framed), 31);
+        assert_eq!(size(&tombstone, RecordFormat::Framed), 22);
+
+        // Bytes the fixed format can't make sense of
+        for tag in [0, RECORD_VERSION, TAG_PUT | TAG_RANGE, 0xFF] {
+            let mut record = serialize(&put, RecordFormat::Fixed).unwrap();
+            record[0] = tag;
+            assert!(deserialize(&record, RecordFormat::Fixed).is_err());
+        }
+        let record = serialize(&put, RecordFormat::Fixed).unwrap();
+        assert!(deserialize(&record[..19], RecordFormat::Fixed).is_err());
+        // A sequence over 64 bits
+        let mut record = record;
+        record[9..19].fill(0xFF);
+        assert!(deserialize(&record, RecordFormat::Fixed).is_err());
+    }
+
+    #[test]
+    fn test_written_at() {
+        // Without a write time, the bytes written before there was one
+        let put = KVMemoryRepr::new(1, Some(2), 3);
+        assert_eq!(
+            serialize(&put, RecordFormat::Fixed).unwrap(),
+            [0x81, 1, 0, 0, 0, 0, 0, 0, 0, 3, 2, 0, 0, 0, 0, 0, 0, 0]
+        );
+
+        let entries = [
+            put.clone(),
+            KVMemoryRepr::new(1, None, 3),
+            put.clone().with_expiration(Some(u64::MAX)),
+            KVMemoryRepr::range_tombstone(3, 10, 1),
+        ];
+        let written_at = 1_760_000_000_000;
+        for entry in entries {
+            let stamped = entry.clone().with_written_at(Some(written_at));
+            let record = serialize(&stamped, RecordFormat::Fixed).unwrap();
+            // 41 bits of milliseconds
+            assert_eq!(
+                record.len(),
+                serialize(&entry, RecordFormat::Fixed).unwrap().len() + 6
+            );
+            let (decoded, rest) = deserialize(&record, RecordFormat::Fixed).unwrap();
+            assert!(decoded == stamped && rest.is_empty());
+
+            let record = serialize(&stamped, RecordFormat::Framed).unwrap();
+            let (decoded, _) = deserialize(&record, RecordFormat::Framed).unwrap();
+            assert!(decoded == entry);
+        }
+    }
+
+    #[test]
+    fn test_padding_covers_failed_writes() {
+        let record = |k| serialize(&KVMemoryRepr::new(k, Some(k), k), RecordFormat::Fixed).unwrap();
+        // The slot of key 1 reserved, its write failing
+        let gap = vec![0; record(1).len()];
+        let mut buffer = [record(0), gap.clone(), record(2)].concat();
+        let end = buffer.len() as u64;
+        buffer.resize(buffer.len() + 100, 0);
+
+        assert!(matches!(
+            deserialize_entries_from_bytes(&buffer, "test", RecordFormat::Fixed),
+            Err(Error::Serialization(SerializationError::DataAfterEnd))
+        ));
+
+        let gap_padding = padding(gap.len() as u64, RecordFormat::Fixed).unwrap();
+        let start = record(0).len();
+        buffer[start..start + gap_padding.len()].copy_from_slice(&gap_padding);
+        let (entries, data_end) =
+            deserialize_entries_with_offsets(&buffer, "test", RecordFormat::Fixed).unwrap();
+        let keys: Vec<_> = entries.iter().map(|(_, entry)| *entry.key()).collect();
+        assert_eq!(keys, [0, 2]);
+        assert_eq!(data_end, end);
+        assert_eq!(deserialize_prefix(&buffer, RecordFormat::Fixed).len(), 2);
+        let entries: Vec<_> = entries.into_iter().map(|(_, entry)| entry).collect();
+        assert!(salvage_entries(&buffer, RecordFormat::Fixed) == (entries, 0));
+
+        // Any gap from 2 bytes on, up to a whole log file
+        for len in (2..300).chain([crate::FILE_SIZE_BYTES]) {
+            let mut bytes = vec![0xFF; len as usize];
+            let gap_padding = padding(len, RecordFormat::Fixed).unwrap();
+            bytes[..gap_padding.len()].copy_from_slice(&gap_padding);
+            assert!(
+                deserialize_entries_from_bytes(&bytes, "test", RecordFormat::Fixed)
+                    .unwrap()
+                    .is_empty()
+            );
+        }
+        assert!(padding(1, RecordFormat::Fixed).is_none());
+        assert!(padding(100, RecordFormat::Framed).is_none());
+    }
+
+    #[test]
+    fn test_salvage_entries_resynchronizes() {
+        for format in FORMATS {
+            let records: Vec<_> = (0..100)
+                .map(|k| serialize(&KVMemoryRepr::new(k, Some(k), k), format).unwrap())
+                .collect();
+            let mut buffer = records.concat();
+            // Trailing empty space, as in log files
+            buffer.resize(buffer.len() + 100, 0);
+            assert!(
+                salvage_entries(&buffer, format)
+                    == (
+                        deserialize_entries_from_bytes(&buffer, "test", format).unwrap(),
+                        0
+                    )
+            );
+
+            // Garbage over the end of record 49 and the start of record 50
+            let damage_start = records[..50].iter().map(Vec::len).sum::<usize>() - 2;
+            buffer[damage_start..damage_start + 5].fill(0xff);
+            assert!(deserialize_entries_from_bytes(&buffer, "test", format).is_err());
+
+            let (entries, skipped) = salvage_entries(&buffer, format);
+            let keys: Vec<_> = entries.iter().map(|entry| *entry.key()).collect();
+            // Nothing tells a fixed-width record with a damaged value from a valid one
+            let damaged_49 = if format == RecordFormat::Fixed {
+                50
+            } else {
+                49
+            };
+            let expected: Vec<_> = (0..damaged_49).chain(51..100).collect();
+            assert_eq!(keys, expected);
+            assert!(skipped > 0);
+        }
+    }
+}
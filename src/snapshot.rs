@@ -0,0 +1,117 @@
+use crate::{
+    Key, Value,
+    comparator::KeyOrder,
+    errors::Error,
+    functions::ReadOutcome,
+    iter::KvIter,
+    serialization::{self, KVMemoryRepr},
+    sstables::{KeyLookup, SSTable},
+};
+use std::{collections::BTreeMap, ops::Bound, sync::Arc};
+
+/// Live entries returned by [`Snapshot::scan_page`], and the key the next page starts from
+pub type Page = (Vec<(Key, Value)>, Option<Key>);
+
+/// A frozen view of the database, unaffected by later writes, rotations and compactions.
+///
+/// The SSTables referenced by the snapshot are kept on disk for as long as it lives.
+pub struct Snapshot {
+    /// Latest entry of every key in the append log at the time of the snapshot
+    memtable: BTreeMap<Key, KVMemoryRepr>,
+    /// Range tombstones in the append log
+    memtable_ranges: Vec<KVMemoryRepr>,
+    /// Sorted list (newer at the beginning) of SSTables
+    sstables: Vec<Arc<SSTable>>,
+    /// Latest sequence number when the snapshot was taken, later entries aren't part of it
+    sequence: u64,
+    /// Time (in milliseconds) used to evaluate expiration, entries don't expire while the snapshot is alive
+    now: u64,
+    order: KeyOrder,
+}
+
+impl Snapshot {
+    pub(crate) fn new(
+        memtable: BTreeMap<Key, KVMemoryRepr>,
+        memtable_ranges: Vec<KVMemoryRepr>,
+        sstables: Vec<Arc<SSTable>>,
+        sequence: u64,
+        now: u64,
+        order: KeyOrder,
+    ) -> Self {
+        Self {
+            memtable,
+            memtable_ranges,
+            sstables,
+            sequence,
+            now,
+            order,
+        }
+    }
+
+    /// Latest sequence number when the snapshot was taken, see [`crate::KVStorage::last_sequence`]
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    pub fn read(&self, key: &Key) -> Result<Option<Value>, Error> {
+        let range = serialization::newest_covering(&self.memtable_ranges, key, &self.order);
+        if let Some(entry) = serialization::newest(self.memtable.get(key), range) {
+            return Ok(match entry.read_outcome(self.now) {
+                ReadOutcome::Found(value) => Some(value),
+                _ => None,
+            });
+        }
+
+        let mut lookup = KeyLookup::default();
+        for sstable in self.visible_tables() {
+            if lookup.shadows(sstable) {
+                continue;
+            }
+            if lookup.visit(sstable.find_entry(key)?) {
+                break;
+            }
+        }
+
+        Ok(
+            match lookup.finish().map(|entry| entry.read_outcome(self.now)) {
+                Some(ReadOutcome::Found(value)) => Some(value),
+                _ => None,
+            },
+        )
+    }
+
+    /// Iterates over the snapshot content in key order, see [`KvIter`]
+    pub fn iter(&self) -> KvIter {
+        KvIter::new(
+            self.memtable.clone(),
+            self.memtable_ranges.clone(),
+            self.visible_tables().cloned().collect(),
+            self.now,
+            self.order.clone(),
+        )
+    }
+
+    /// Up to `limit` live entries from `start` in key order, and the key the next page starts from: the first live key
+    /// after the page, to pass as `Bound::Included`. `None` once there's nothing left.
+    ///
+    /// Deleted, expired and overwritten entries don't count towards `limit`. With a `limit` of 0, the page is empty
+    /// and the resume key is the first live key from `start`
+    pub fn scan_page(&self, start: Bound<Key>, limit: usize) -> Result<Page, Error> {
+        let mut iter = self.iter();
+        iter.seek_bound(start);
+        let page: Vec<_> = iter.by_ref().take(limit).collect();
+        let resume = iter.next().map(|(key, _)| key);
+
+        match iter.into_error() {
+            Some(e) => Err(e),
+            None => Ok((page, resume)),
+        }
+    }
+
+    /// The tables holding entries written before the snapshot, newer first
+    fn visible_tables(&self) -> impl Iterator<Item = &Arc<SSTable>> {
+        self.sstables
+            .iter()
+            .filter(|sstable| sstable.min_sequence() <= self.sequence)
+    }
+}
@@ -0,0 +1,83 @@
+use crate::{
+    Key,
+    errors::Error,
+    sstables::SSTable,
+    stats::{self, VALUE_SIZE_BUCKETS},
+};
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+/// Returned by [`crate::KVStorage::space_report`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SpaceReport {
+    /// Tables scanned
+    pub tables: u64,
+    /// Entries stored in the tables, overwritten values and tombstones included
+    pub entries: u64,
+    /// The entries by value size, see [`VALUE_SIZE_BUCKETS`]
+    pub value_sizes: [u64; VALUE_SIZE_BUCKETS],
+    /// Largest stored values, largest first and by key among the same size. A key overwritten in several tables
+    /// can be there more than once
+    pub largest: Vec<LargeValue>,
+}
+
+/// A value of [`SpaceReport::largest`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LargeValue {
+    pub key: Key,
+    /// Bytes taken by the value
+    pub size: u64,
+    /// Table storing it
+    pub table: u64,
+}
+
+/// Scans `tables` a block at a time, keeping the `top_n` largest values. Checks `cancel` before every block and
+/// returns [`Error::Cancelled`] once it's set.
+pub fn scan_tables(
+    tables: &[Arc<SSTable>],
+    top_n: usize,
+    cancel: &AtomicBool,
+) -> Result<SpaceReport, Error> {
+    let mut report = SpaceReport::default();
+    // The smallest of the values kept on top, ties broken towards the smaller keys
+    let mut largest = BinaryHeap::with_capacity(top_n + 1);
+
+    for table in tables {
+        for block in 0..table.block_count() {
+            if cancel.load(Ordering::Relaxed) {
+                return Err(Error::Cancelled);
+            }
+
+            for entry in table.read_block(block)? {
+                if entry.is_range_tombstone() {
+                    continue;
+                }
+                let size = entry.value_size();
+                report.entries += 1;
+                report.value_sizes[stats::value_size_bucket(size)] += 1;
+
+                if top_n > 0 && entry.value().is_some() {
+                    largest.push(Reverse((size, Reverse(*entry.key()), table.id())));
+                    if largest.len() > top_n {
+                        largest.pop();
+                    }
+                }
+            }
+        }
+        report.tables += 1;
+    }
+
+    report.largest = largest
+        .into_sorted_vec()
+        .into_iter()
+        .map(|Reverse((size, Reverse(key), table))| LargeValue { key, size, table })
+        .collect();
+
+    Ok(report)
+}
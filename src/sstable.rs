@@ -0,0 +1,34 @@
+//! Single table files read and written outside of a store.
+//!
+//! [`SstReader`] opens a table file by path, whether it was written by a store (under `db/sstables/`) or by
+//! [`SstWriter`], which lays out its entries the way flushes do:
+//!
+//! ```no_run
+//! use key_value_store::{Options, ReadOutcome, sstable::{SstReader, SstWriter}};
+//!
+//! let mut writer = SstWriter::new("prices.sst", &Options::new(), 2)?;
+//! writer.add(1, Some(250))?;
+//! writer.add(2, None)?;
+//! writer.finish()?;
+//!
+//! let reader = SstReader::open("prices.sst")?;
+//! assert_eq!(reader.get(&1)?, ReadOutcome::Found(250));
+//! assert_eq!(reader.iter().collect::<Result<Vec<_>, _>>()?, vec![(1, Some(250)), (2, None)]);
+//! # Ok::<(), key_value_store::Error>(())
+//! ```
+//!
+//! # Format stability
+//!
+//! A table file starts with a 16 bytes header (magic `KVST`, format version, oldest reader version) and ends with a
+//! fixed-size footer locating the block index, the range tombstones and the optional sections. Both carry the format
+//! version, reported by [`TableMetadata::format_version`]:
+//!
+//! - a release reads the tables of every version from the oldest one it supports, currently 5, on;
+//! - tables of newer versions are read as long as their oldest reader version isn't above the supported one. Anything
+//!   else fails with [`crate::Error::UnsupportedVersion`] rather than being misread;
+//! - new optional data goes in sections older readers skip, without changing the version they need.
+//!
+//! Only the layout is stable: keys are stored in the order of the comparator the table was written with, which the
+//! file doesn't record, see [`SstReader::open_with_comparator`].
+
+pub use crate::sstables::{SstReader, SstWriter, TableMetadata};
@@ -0,0 +1,211 @@
+use super::{
+    BloomType, TableContent, TableSummary,
+    format::{self, BlockBuilder, BlockHandle, BloomParams, Footer},
+    new_bloom,
+};
+use crate::{
+    errors::Error,
+    options::{BloomSize, Compression, DEFAULT_BLOCK_SIZE, Options},
+    serialization::{self, KVMemoryRepr, RecordFormat},
+};
+
+/// How new tables are laid out, see [`Options::compression`], [`Options::block_size`], [`Options::bloom_size`] and
+/// [`Options::bloom_seed`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TableOptions {
+    pub compression: Compression,
+    pub block_size: usize,
+    pub bloom_size: BloomSize,
+    /// `None` for a random seed per table
+    pub bloom_seed: Option<[u8; 32]>,
+}
+
+impl Default for TableOptions {
+    fn default() -> Self {
+        Self {
+            compression: Compression::None,
+            block_size: DEFAULT_BLOCK_SIZE,
+            bloom_size: BloomSize::default(),
+            bloom_seed: None,
+        }
+    }
+}
+
+impl From<&Options> for TableOptions {
+    fn from(options: &Options) -> Self {
+        Self {
+            compression: options.compression,
+            block_size: options.block_size,
+            bloom_size: options.bloom_size,
+            bloom_seed: options.bloom_seed,
+        }
+    }
+}
+
+impl TableOptions {
+    /// How the bloom filter of a table of `points` keys is built, with a new random seed unless one is set
+    pub fn bloom_params(&self, points: usize) -> BloomParams {
+        let points = points.max(1);
+        let bitmap_bytes = match self.bloom_size {
+            BloomSize::FalsePositiveRate(rate) => BloomType::compute_bitmap_size(points, rate),
+            BloomSize::BitsPerKey(bits) => (points * bits as usize).div_ceil(8),
+        };
+
+        BloomParams {
+            seed: self.bloom_seed.unwrap_or_else(rand::random),
+            bitmap_bytes: bitmap_bytes.max(1) as u64,
+        }
+    }
+}
+
+/// Writes a table one entry at a time.
+///
+/// Point entries must be added in key order, range tombstones can come at any time.
+/// A block is closed as soon as it reaches the block size, so it never exceeds it by more than one record.
+pub struct TableBuilder {
+    options: TableOptions,
+    data: Vec<u8>,
+    /// Bytes handed out by [`TableBuilder::take_data`] before `data`, the offsets in the table account for them
+    taken: u64,
+    index: Vec<BlockHandle>,
+    block: BlockBuilder,
+    summary: TableSummary,
+    bloom_params: BloomParams,
+    /// [`RecordFormat::CURRENT`], except for tests writing the tables of older versions
+    record_format: RecordFormat,
+}
+
+impl TableBuilder {
+    /// `expected_points` sizes the bloom filter
+    pub fn new(options: TableOptions, expected_points: usize) -> Self {
+        let bloom_params = options.bloom_params(expected_points);
+
+        Self {
+            options,
+            data: Vec::new(),
+            taken: 0,
+            index: Vec::new(),
+            block: BlockBuilder::new(RecordFormat::CURRENT),
+            summary: TableSummary::new(Some(new_bloom(&bloom_params, expected_points))),
+            bloom_params,
+            record_format: RecordFormat::CURRENT,
+        }
+    }
+
+    /// Records in `format` instead of the current one, the version in the header and the footer is left to the test
+    #[cfg(test)]
+    pub fn with_record_format(mut self, format: RecordFormat) -> Self {
+        self.block = BlockBuilder::new(format);
+        self.record_format = format;
+        self
+    }
+
+    /// Builds a table out of `entries`, whose point entries must be sorted by key
+    pub fn from_entries(
+        entries: &[KVMemoryRepr],
+        options: TableOptions,
+    ) -> Result<TableContent, Error> {
+        let mut builder = Self::new(options, entries.len());
+        for entry in entries {
+            builder.add(entry)?;
+        }
+
+        builder.finish()
+    }
+
+    /// Records that a merge left `discarded_entries` of its input entries out of the table
+    pub fn with_discarded_entries(mut self, discarded_entries: u64) -> Self {
+        self.summary.discarded_entries = discarded_entries;
+        self
+    }
+
+    pub fn add(&mut self, entry: &KVMemoryRepr) -> Result<(), Error> {
+        self.summary.add(entry);
+        if entry.is_range_tombstone() {
+            return Ok(());
+        }
+
+        self.block.add(entry)?;
+        if self.block.size() >= self.options.block_size {
+            self.finish_block();
+        }
+
+        Ok(())
+    }
+
+    /// The table written so far, the blocks closed since the last call. The content returned by
+    /// [`TableBuilder::finish`] then only holds the rest of the table
+    pub fn take_data(&mut self) -> Vec<u8> {
+        self.taken += self.data.len() as u64;
+        std::mem::take(&mut self.data)
+    }
+
+    /// Offset in the table of the next byte written
+    fn offset(&self) -> u64 {
+        self.taken + self.data.len() as u64
+    }
+
+    /// Lays out the file: `[data blocks][range tombstones][block index][bloom and sequences sections][footer]`
+    pub fn finish(mut self) -> Result<TableContent, Error> {
+        self.finish_block();
+
+        let ranges_offset = self.offset();
+        for range in &self.summary.range_tombstones {
+            self.data
+                .extend_from_slice(&serialization::serialize(range, self.record_format)?);
+        }
+
+        let index_offset = self.offset();
+        for handle in &self.index {
+            handle.encode_into(&mut self.data);
+        }
+
+        let sections_offset = self.offset();
+        let bloom_filter = self
+            .summary
+            .bloom_filter
+            .as_ref()
+            .map(|bloom_filter| bloom_filter.to_bytes())
+            .unwrap_or_default();
+        self.summary.bloom_bytes = bloom_filter.len() as u64;
+        format::encode_sections_into(
+            &bloom_filter,
+            &self.bloom_params,
+            self.summary.sequences(),
+            &mut self.data,
+        );
+        Footer {
+            ranges_offset,
+            index_offset,
+            sections_offset,
+            entry_count: self.summary.entry_count,
+            tombstone_count: self.summary.tombstone_count,
+            discarded_entries: self.summary.discarded_entries,
+            compression: self.options.compression,
+            flags: 0,
+        }
+        .encode_into(&mut self.data);
+
+        Ok(TableContent {
+            index: self.index,
+            data: self.data,
+            compression: self.options.compression,
+            record_format: self.record_format,
+            summary: self.summary,
+        })
+    }
+
+    fn finish_block(&mut self) {
+        let Some((first_key, raw)) = self.block.finish() else {
+            return;
+        };
+
+        let stored = format::compress(self.options.compression, &raw);
+        self.index.push(BlockHandle {
+            first_key,
+            offset: self.offset(),
+            len: stored.len() as u32,
+        });
+        self.data.extend_from_slice(&stored);
+    }
+}
@@ -1,59 +1,457 @@
 use crate::{
-    cleanup::background_file_delete,
+    cleanup::Reaper,
+    compaction_filter::{CompactionFilter, FilterDecision},
+    comparator::KeyOrder,
     errors::Error,
-    functions::{self},
+    events::CompactionInfo,
+    instrumentation::{increment_counter, record, span},
+    manifest::Manifest,
+    options::{ColdStoragePolicy, Options},
+    quota::DiskQuota,
+    rate_limit::RateLimiter,
     serialization::{self, KVMemoryRepr},
-    sstables::{self, SSTable, entries_to_index_and_data},
+    sstables::{
+        self, SSTable, TableBuilder, TableFiles, TableList, TableOptions, TableState,
+        policy::{self, MergePolicy, PlannedCompaction, TableStats},
+    },
+    stats::{self, StatsCounters, VALUE_SIZE_BUCKETS},
+    workers::Workers,
 };
 use std::{
-    path::{Path, PathBuf},
-    sync::{Arc, Mutex, atomic::AtomicBool},
-    thread::spawn,
+    any::Any,
+    collections::VecDeque,
+    sync::{
+        Arc, Condvar, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    thread::{JoinHandle, sleep, spawn},
+    time::{Duration, Instant},
 };
 
-const MIN_TABLES_IN_MERGE: usize = 4;
-const MAX_TABLES_IN_MERGE: usize = 30;
+/// How long compaction stops after running out of disk space
+const DISK_FULL_PAUSE: Duration = Duration::from_secs(1);
+/// How often [`CompactorManager::wait_for_pending`] checks whether a pause ended
+const PAUSE_POLL: Duration = Duration::from_millis(10);
 
 pub struct CompactorManager {
-    sstables_dir: PathBuf,
+    context: Arc<CompactionContext>,
+    pub(crate) activity: Arc<Activity>,
+    /// Runs the compactions, shared with the other stores of the database
+    worker: Arc<CompactionWorker>,
+}
+
+/// The thread running the compactions of a store and of its namespaces, one at a time, along with their periodic
+/// checks (see [`CompactorManager::with_ticker`])
+pub struct CompactionWorker {
+    shared: Arc<WorkerShared>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+struct WorkerShared {
+    state: Mutex<WorkerState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct WorkerState {
+    /// Runs started by [`CompactorManager::signal_sstable_inserted`], oldest first
+    queue: VecDeque<(Arc<CompactionContext>, Running)>,
+    tickers: Vec<Ticker>,
+    stopping: bool,
+}
+
+/// Periodic checks of a store, see [`CompactorManager::with_ticker`]
+struct Ticker {
+    context: Arc<CompactionContext>,
+    activity: Arc<Activity>,
+    /// Clock times of the latest write of every log shard
+    last_writes: Vec<Arc<AtomicU64>>,
+    period: Duration,
+    next: Instant,
+}
+
+/// State shared with the compaction worker
+struct CompactionContext {
+    files: Arc<TableFiles>,
     /// Tables are sorted newest first (index 0 is the most recent table)
-    sstables: Arc<Mutex<Vec<Arc<SSTable>>>>,
-    currently_compacting: Arc<AtomicBool>,
+    sstables: Arc<Mutex<TableList>>,
+    manifest: Arc<Manifest>,
+    reaper: Arc<Reaper>,
+    options: Options,
+    policy: Box<dyn MergePolicy>,
+    stats: Arc<StatsCounters>,
+    quota: Arc<DiskQuota>,
+    /// See [`Options::compaction_rate_limit`]
+    limiter: Option<Arc<RateLimiter>>,
+    /// Set when the store is closing, checked by the worker between merges
+    shutdown: AtomicBool,
+    /// Set when a merge failed with [`Error::DiskFull`], no merge starts before then. Clock time in ms, see
+    /// [`Options::clock`]
+    paused_until: Mutex<Option<u64>>,
+}
+
+/// Compaction runs of a store in flight, started by a signal or the ticker (one at a time)
+#[derive(Default)]
+pub(crate) struct Activity {
+    running: Mutex<usize>,
+    idle: Condvar,
+}
+
+/// A compaction run in flight, ends when dropped (even by a panic)
+pub(crate) struct Running(Arc<Activity>);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let mut running = self.0.running.lock().expect("poisoned activity lock");
+        *running -= 1;
+        if *running == 0 {
+            self.0.idle.notify_all();
+        }
+    }
+}
+
+impl Activity {
+    /// Starts a run, unless one is already in flight
+    fn try_start(self: &Arc<Self>) -> Option<Running> {
+        let mut running = self.running.lock().expect("poisoned activity lock");
+        if *running > 0 {
+            return None;
+        }
+        *running += 1;
+
+        Some(Running(self.clone()))
+    }
+
+    #[cfg(test)]
+    pub fn is_running(&self) -> bool {
+        *self.running.lock().expect("poisoned activity lock") > 0
+    }
+
+    /// Blocks until no run is in flight, false if `deadline` passed first
+    fn wait_idle(&self, deadline: Option<Instant>) -> bool {
+        let mut running = self.running.lock().expect("poisoned activity lock");
+        while *running > 0 {
+            running = match deadline {
+                None => self.idle.wait(running).expect("poisoned activity lock"),
+                Some(deadline) => {
+                    let Some(left) = deadline.checked_duration_since(Instant::now()) else {
+                        return false;
+                    };
+                    self.idle
+                        .wait_timeout(running, left)
+                        .expect("poisoned activity lock")
+                        .0
+                }
+            };
+        }
+
+        true
+    }
+}
+
+impl CompactionWorker {
+    pub fn new() -> Self {
+        let shared = Arc::new(WorkerShared {
+            state: Default::default(),
+            changed: Condvar::new(),
+        });
+        let thread = {
+            let shared = shared.clone();
+            spawn(move || worker_loop(&shared))
+        };
+
+        Self {
+            shared,
+            thread: Mutex::new(Some(thread)),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, WorkerState> {
+        self.shared
+            .state
+            .lock()
+            .expect("poisoned compaction worker lock")
+    }
+
+    /// Joins the thread once the run in progress completes. The stores must have stopped their compactors first
+    pub fn stop(&self) {
+        self.state().stopping = true;
+        self.shared.changed.notify_all();
+
+        let thread = self
+            .thread
+            .lock()
+            .expect("poisoned compaction worker thread")
+            .take();
+        if let Some(thread) = thread
+            && thread.join().is_err()
+        {
+            log::error!("compaction worker panicked");
+        }
+    }
+}
+
+impl Drop for CompactionWorker {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Runs the queued compactions first, then the ticks that are due, and sleeps until the next one
+fn worker_loop(shared: &WorkerShared) {
+    let lock = || {
+        shared
+            .state
+            .lock()
+            .expect("poisoned compaction worker lock")
+    };
+
+    let mut state = lock();
+    while !state.stopping {
+        if let Some((context, running)) = state.queue.pop_front() {
+            drop(state);
+            run_compaction(&context, false);
+            drop(running);
+            state = lock();
+            continue;
+        }
+
+        let now = Instant::now();
+        if let Some(ticker) = state.tickers.iter_mut().find(|ticker| ticker.next <= now) {
+            ticker.next = now + ticker.period;
+            let context = ticker.context.clone();
+            let activity = ticker.activity.clone();
+            let last_writes = ticker.last_writes.clone();
+            drop(state);
+            tick(&context, &activity, &last_writes);
+            state = lock();
+            continue;
+        }
+
+        state = match state.tickers.iter().map(|ticker| ticker.next).min() {
+            None => shared
+                .changed
+                .wait(state)
+                .expect("poisoned compaction worker lock"),
+            Some(next) => {
+                shared
+                    .changed
+                    .wait_timeout(state, next - now)
+                    .expect("poisoned compaction worker lock")
+                    .0
+            }
+        };
+    }
+}
+
+/// Checks for merges, merging everything once the store has been idle for [`Options::idle_compaction_after`]
+fn tick(context: &CompactionContext, activity: &Arc<Activity>, last_writes: &[Arc<AtomicU64>]) {
+    let last_write = last_writes
+        .iter()
+        .map(|last_write| last_write.load(Ordering::Relaxed))
+        .max()
+        .unwrap_or(0);
+    let idle_for = context
+        .options
+        .clock
+        .now_millis()
+        .saturating_sub(last_write);
+    let idle = context
+        .options
+        .idle_compaction_after
+        .is_some_and(|after| idle_for >= after.as_millis() as u64);
+
+    if (context.options.compaction_interval.is_none() && !idle) || context.is_paused() {
+        return;
+    }
+    let Some(_running) = activity.try_start() else {
+        return;
+    };
+    run_compaction(context, idle);
 }
 
 impl CompactorManager {
-    pub fn new(sstables_dir: PathBuf, sstables: Arc<Mutex<Vec<Arc<SSTable>>>>) -> Self {
+    /// Compactions run on the worker of `workers`, which removes the replaced tables
+    pub fn new(
+        files: Arc<TableFiles>,
+        sstables: Arc<Mutex<TableList>>,
+        manifest: Arc<Manifest>,
+        options: Options,
+        stats: Arc<StatsCounters>,
+        quota: Arc<DiskQuota>,
+        workers: &Workers,
+    ) -> Self {
         Self {
-            sstables_dir,
-            sstables,
-            currently_compacting: Default::default(),
+            context: Arc::new(CompactionContext {
+                files,
+                sstables,
+                manifest,
+                reaper: workers.reaper.clone(),
+                policy: options
+                    .compaction_policy
+                    .build(options.compaction_tombstone_threshold),
+                limiter: options
+                    .compaction_rate_limit
+                    .map(|rate| Arc::new(RateLimiter::new(rate))),
+                options,
+                stats,
+                quota,
+                shutdown: Default::default(),
+                paused_until: Default::default(),
+            }),
+            activity: Default::default(),
+            worker: workers.compaction.clone(),
         }
     }
 
+    /// Has the worker check for merges every [`Options::compaction_interval`], and merge everything once no write
+    /// happened for [`Options::idle_compaction_after`].
+    ///
+    /// `last_writes` are the clock times of the latest write of every log shard.
+    pub fn with_ticker(self, last_writes: Vec<Arc<AtomicU64>>) -> Self {
+        let options = &self.context.options;
+        let Some(period) = [options.compaction_interval, options.idle_compaction_after]
+            .into_iter()
+            .flatten()
+            .min()
+        else {
+            return self;
+        };
+
+        self.worker.state().tickers.push(Ticker {
+            context: self.context.clone(),
+            activity: self.activity.clone(),
+            last_writes,
+            period,
+            next: Instant::now() + period,
+        });
+        self.worker.shared.changed.notify_all();
+
+        self
+    }
+
     pub fn signal_sstable_inserted(&self) {
-        let sstables_dir = self.sstables_dir.clone();
-        let sstables = self.sstables.clone();
-        let compacting = self.currently_compacting.clone();
+        // Held while queuing so that `stop` always sees the latest run
+        let mut state = self.worker.state();
 
-        if compacting.swap(true, std::sync::atomic::Ordering::SeqCst) {
-            return; // Already compacting
+        if self.context.shutdown.load(Ordering::SeqCst) || self.context.is_paused() {
+            return;
         }
 
-        spawn(move || {
-            if let Err(e) = handle_compaction_check_rec(&sstables_dir, &sstables) {
-                log::error!("Compaction check failed: {:?}", e)
+        let Some(running) = self.activity.try_start() else {
+            return; // Already compacting
+        };
+
+        state.queue.push_back((self.context.clone(), running));
+        self.worker.shared.changed.notify_all();
+    }
+
+    /// Blocks until no compaction runs, false if `timeout` expired first. Without a timeout, waits as long as it takes
+    pub fn wait_idle(&self, timeout: Option<Duration>) -> bool {
+        self.activity
+            .wait_idle(timeout.map(|timeout| Instant::now() + timeout))
+    }
+
+    /// Blocks until no compaction runs and the policy finds nothing to merge, starting the merges it finds.
+    ///
+    /// False if `timeout` expired first, e.g. because merges keep failing, or if the compactor was stopped.
+    pub fn wait_for_pending(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if !self.activity.wait_idle(Some(deadline)) {
+                return false;
             }
-            compacting.store(false, std::sync::atomic::Ordering::SeqCst);
-        });
+            if eligible_merges(&self.context, &self.context.current_state(), false).is_empty() {
+                return true;
+            }
+
+            let now = Instant::now();
+            if now >= deadline || self.context.shutdown.load(Ordering::SeqCst) {
+                return false;
+            }
+            if self.context.is_paused() {
+                sleep(PAUSE_POLL.min(deadline - now));
+            }
+            self.signal_sstable_inserted();
+        }
+    }
+
+    /// Tables in the merges the policy finds now, whether or not they fit in the quota headroom
+    pub fn backlog(&self) -> u64 {
+        self.plan()
+            .iter()
+            .map(|planned| planned.tables.len() as u64)
+            .sum()
+    }
+
+    /// The merges the policy finds among the current tables, without running them
+    pub fn plan(&self) -> Vec<PlannedCompaction> {
+        let state = self.context.current_state();
+        let stats: Vec<_> = state.iter().map(|t| TableStats::from(&**t)).collect();
+
+        policy::plan(&*self.context.policy, &stats)
+    }
+
+    /// Stops the compactor, returning once the running merge completes. The worker keeps going for the other stores
+    pub fn stop(&self) {
+        self.context.shutdown.store(true, Ordering::SeqCst);
+
+        let mut state = self.worker.state();
+        let ours = |context: &Arc<CompactionContext>| Arc::ptr_eq(context, &self.context);
+        state.tickers.retain(|ticker| !ours(&ticker.context));
+        // Dropping the queued run ends it
+        state.queue.retain(|(context, _)| !ours(context));
+        drop(state);
+
+        self.activity.wait_idle(None);
     }
 }
 
-fn handle_compaction_check_rec(
-    sstables_dir: &Path,
-    sstables: &Mutex<Vec<Arc<SSTable>>>,
-) -> Result<(), Error> {
-    loop {
-        let merged = handle_compaction_check(sstables_dir, sstables)?;
+impl CompactionContext {
+    fn current_state(&self) -> TableState {
+        self.sstables
+            .lock()
+            .expect("sstables lock poisoned")
+            .state()
+    }
+
+    fn is_paused(&self) -> bool {
+        let paused_until = self.paused_until.lock().expect("poisoned pause lock");
+        paused_until.is_some_and(|until| self.options.clock.now_millis() < until)
+    }
+}
+
+/// Merges until the policy finds nothing left, then everything into one table with `merge_all`.
+///
+/// Failures are logged, running out of disk space pauses compaction.
+fn run_compaction(context: &CompactionContext, merge_all: bool) {
+    // Retried on the next signal, a merge can't write its table meanwhile
+    if context.files.check_available().is_err() {
+        log::debug!("table storage unavailable, compaction skipped");
+        return;
+    }
+
+    let result = handle_compaction_check_rec(context).and_then(|()| {
+        if merge_all && !context.shutdown.load(Ordering::SeqCst) {
+            handle_compaction_check(context, true)?;
+        }
+        Ok(())
+    });
+
+    match result {
+        Ok(()) => {}
+        Err(Error::DiskFull) => {
+            log::warn!("disk full, compaction paused for {DISK_FULL_PAUSE:?}");
+            *context.paused_until.lock().expect("poisoned pause lock") =
+                Some(context.options.clock.now_millis() + DISK_FULL_PAUSE.as_millis() as u64);
+        }
+        Err(e) => log::error!("Compaction check failed: {:?}", e),
+    }
+}
+
+fn handle_compaction_check_rec(context: &CompactionContext) -> Result<(), Error> {
+    while !context.shutdown.load(Ordering::SeqCst) {
+        let merged = handle_compaction_check(context, false)?;
         if !merged {
             break;
         }
@@ -61,16 +459,20 @@ fn handle_compaction_check_rec(
     Ok(())
 }
 
-/// `sstables` must be sorted newest to oldest
+/// `sstables` must be sorted newest to oldest. With `merge_all` every table is merged, whatever the policy.
 ///
 /// Return whether a merge actually happened
-fn handle_compaction_check(
-    sstables_dir: &Path,
-    sstables: &Mutex<Vec<Arc<SSTable>>>,
-) -> Result<bool, Error> {
-    let current_state = { sstables.lock().expect("sstables lock poisoned").clone() };
+fn handle_compaction_check(context: &CompactionContext, merge_all: bool) -> Result<bool, Error> {
+    let sstables = &context.sstables;
+    let current_state = context.current_state();
+    let generation = current_state.generation();
+    // Entries expired at the start of the merge are dropped
+    let now = context.options.clock.now_millis();
 
-    let to_merge = find_sstables_to_merge(&current_state);
+    let to_merge: Vec<_> = eligible_merges(context, &current_state, merge_all)
+        .into_iter()
+        .take(context.options.max_compaction_threads.max(1))
+        .collect();
 
     for (start, end) in &to_merge {
         let sizes: Vec<u64> = current_state[*start..*end]
@@ -84,126 +486,322 @@ fn handle_compaction_check(
     let handles: Vec<_> = to_merge
         .iter()
         .map(|(start, end)| {
-            let sstables_dir = sstables_dir.to_path_buf();
+            let files = context.files.clone();
+            let table_options = TableOptions::from(&context.options);
+            let filter = context.options.compaction_filter.clone();
+            let cold_policy = context
+                .options
+                .cold_storage_dir
+                .as_ref()
+                .map(|_| context.options.cold_storage_policy);
+            let limiter = context.limiter.clone();
             let tables_to_merge: Vec<Arc<SSTable>> = current_state[*start..*end].to_vec();
 
             // Save tombstones if this range includes the end
             let save_tombstones = *end != current_state.len();
 
-            spawn(move || {
-                merge_sstables(&sstables_dir, tables_to_merge.as_slice(), save_tombstones)
-            })
+            let info = CompactionInfo {
+                input_tables: tables_to_merge.iter().map(|t| t.id).collect(),
+                input_bytes: tables_to_merge.iter().map(|t| t.file_size).sum(),
+                output_table: None,
+                output_bytes: 0,
+                dropped_tombstones: 0,
+                duration: Default::default(),
+            };
+            if let Some(listener) = &context.options.event_listener {
+                listener.on_compaction_begin(&info);
+            }
+
+            let output_id = files.new_table_id();
+            let started = Instant::now();
+            let handle = spawn(move || {
+                merge_sstables(
+                    &files,
+                    &tables_to_merge,
+                    table_options,
+                    save_tombstones,
+                    now,
+                    filter.as_deref(),
+                    MergeIo {
+                        output_id,
+                        cold_policy,
+                        limiter: limiter.as_deref(),
+                    },
+                )
+            });
+
+            (handle, info, started, output_id)
         })
         .collect();
 
-    // Join all threads and collect results
-    let merged_sstables: Vec<_> = handles
-        .into_iter()
-        .map(|handle| handle.join().expect("merge thread panicked"))
-        .collect::<Result<_, _>>()?;
+    // A failed (or panicked) merge leaves its input tables in place, the other merges still land
+    let mut failure = None;
+    let mut merged_sstables = Vec::new();
+    for ((handle, info, started, output_id), group) in handles.into_iter().zip(&to_merge) {
+        let result = handle
+            .join()
+            .unwrap_or_else(|panic| Err(Error::MergePanicked(panic_message(&*panic))));
+        match result {
+            Ok((sstable, counts)) => merged_sstables.push((sstable, counts, info, started, *group)),
+            Err(e) => {
+                log::error!("merge of tables {:?} failed: {e:?}", info.input_tables);
+                sstables::remove_unfinished_table(&context.files, output_id);
+                context
+                    .stats
+                    .compaction_failures
+                    .fetch_add(1, Ordering::Relaxed);
+                if let Some(listener) = &context.options.event_listener {
+                    listener.on_compaction_failed(&info, &e);
+                }
+                failure.get_or_insert(e);
+            }
+        }
+    }
 
     // Update the sstables list with all merged results
-    for (i, new_sstable) in merged_sstables.into_iter().enumerate() {
-        let new_sstable = Arc::new(new_sstable);
-        let (start, end) = to_merge[i];
+    for (new_sstable, counts, mut info, started, (start, end)) in merged_sstables {
+        let new_sstable = new_sstable.map(Arc::new);
+        context
+            .stats
+            .expired_entries_removed
+            .fetch_add(counts.expired, Ordering::Relaxed);
 
         let old_tables = current_state[start..end].to_vec();
         let old_ids: Vec<_> = old_tables.iter().map(|t| t.id).collect();
 
-        {
-            let mut locked_sstables = sstables.lock().expect("sstables lock poisoned");
-
-            // Check that all ids from the range still exist consecutively
-            let mut found_start = None;
-            for i in 0..locked_sstables.len() {
-                if i + old_ids.len() <= locked_sstables.len() {
-                    let consecutive_match =
-                        (0..old_ids.len()).all(|j| locked_sstables[i + j].id == old_ids[j]);
-
-                    if consecutive_match {
-                        found_start = Some(i);
-                        break;
-                    }
-                }
-            }
+        // The new list is built outside of the lock, then committed if no rotation changed the tables meanwhile
+        let mut current = sstables.lock().expect("sstables lock poisoned").state();
+        let committed = loop {
+            // Tables may have been added or replaced since planning, SSTables inserted during compaction are kept
+            let Some(new_state) =
+                current.with_run_replaced(generation, start, &old_ids, new_sstable.clone())
+            else {
+                break None;
+            };
 
-            if found_start.is_none() {
-                // TODO handle
-                panic!();
+            let locked_sstables = sstables.lock().expect("sstables lock poisoned");
+            if locked_sstables.generation() == current.generation() {
+                break Some((locked_sstables, new_state));
             }
+            current = locked_sstables.state();
+        };
+        let Some((mut locked_sstables, new_state)) = committed else {
+            log::warn!("tables {old_ids:?} changed during their merge, dropping its output");
+            if let Some(new_sstable) = new_sstable {
+                context.reaper.delete(new_sstable);
+            }
+            continue;
+        };
 
-            // Safe to merge and overwrite: remove the old sstables and insert the new one.
-            // SSTables inserted during compaction are kept
-            let new_state: Vec<Arc<SSTable>> = locked_sstables
+        // Reads load the published list, they don't wait for the manifest
+        let updated = context.manifest.update(|data| {
+            data.sstables = new_state.iter().map(|t| t.id).collect();
+            data.cold_storage_dir = context
+                .files
+                .cold_dir()
+                .map(|dir| dir.to_string_lossy().into_owned());
+            data.cold_sstables = new_state
                 .iter()
-                .flat_map(|sstable| {
-                    if old_ids.contains(&sstable.id) {
-                        if sstable.id == old_ids[0] {
-                            Some(new_sstable.clone())
-                        } else {
-                            None
-                        }
-                    } else {
-                        Some(sstable.clone())
-                    }
-                })
+                .map(|t| t.id)
+                .filter(|id| context.files.is_cold(*id))
                 .collect();
-
-            *locked_sstables = new_state;
+        });
+        if let Err(e) = updated {
+            // Neither the list nor the manifest changed, the inputs stay in place
+            drop(locked_sstables);
+            log::error!(
+                "failed to record the merge of tables {old_ids:?}, dropping its output: {e:?}"
+            );
+            if let Some(new_sstable) = new_sstable {
+                context.reaper.delete(new_sstable);
+            }
+            context
+                .stats
+                .compaction_failures
+                .fetch_add(1, Ordering::Relaxed);
+            if let Some(listener) = &context.options.event_listener {
+                listener.on_compaction_failed(&info, &e);
+            }
+            failure.get_or_insert(e);
+            continue;
         }
+        locked_sstables.set(new_state);
+        drop(locked_sstables);
+        context.files.remove_cold(&old_ids);
+        context.stats.add_value_sizes(&counts.value_sizes);
 
+        context
+            .quota
+            .add_table(new_sstable.as_ref().map_or(0, |t| t.file_size));
         for old_table in old_tables {
-            background_file_delete(old_table);
+            context.quota.remove_table(old_table.file_size);
+            context.reaper.delete(old_table);
+        }
+
+        if let Some(listener) = &context.options.event_listener {
+            info.output_table = new_sstable.as_ref().map(|t| t.id);
+            info.output_bytes = new_sstable.as_ref().map_or(0, |t| t.file_size);
+            info.dropped_tombstones = counts.dropped_tombstones;
+            info.duration = started.elapsed();
+            listener.on_compaction_complete(&info);
         }
     }
 
-    Ok(!to_merge.is_empty())
+    // Ends the pass, retrying the failed merge right away would likely fail again
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(!to_merge.is_empty()),
+    }
+}
+
+/// The message a merge thread panicked with
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    panic
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_owned())
+}
+
+/// Groups of `tables` (newest first) to merge, leaving out the ones that don't fit in the quota headroom. With
+/// `merge_all` every table is merged, whatever the policy
+fn eligible_merges(
+    context: &CompactionContext,
+    tables: &[Arc<SSTable>],
+    merge_all: bool,
+) -> Vec<(usize, usize)> {
+    let stats: Vec<_> = tables.iter().map(|t| TableStats::from(&**t)).collect();
+    let groups = if merge_all {
+        (stats.len() > 1)
+            .then_some((0, stats.len()))
+            .into_iter()
+            .collect()
+    } else {
+        context.policy.find_sstables_to_merge(&stats)
+    };
+
+    groups
+        .into_iter()
+        .filter(|(start, end)| {
+            let input_bytes = stats[*start..*end].iter().map(|t| t.size).sum();
+            let allowed = context.quota.allows_compaction(input_bytes);
+            if !allowed {
+                log::warn!(
+                    "skipping merge of {input_bytes} bytes, it doesn't fit in the quota headroom"
+                );
+            }
+            allowed
+        })
+        .collect()
+}
+
+/// What [`merge_sstable_contents`] removed
+#[derive(Debug, Default, PartialEq)]
+struct MergeCounts {
+    expired: u64,
+    /// Tombstones not carried over, expired entries included
+    dropped_tombstones: u64,
+    /// Entries of the output by value size, range tombstones left out
+    value_sizes: [u64; VALUE_SIZE_BUCKETS],
+}
+
+/// Where the output of [`merge_sstables`] goes and how fast the merge reads and writes
+#[derive(Default, Clone, Copy)]
+struct MergeIo<'a> {
+    /// Picked by the caller, so that it can remove the output of a merge that panicked
+    output_id: u64,
+    /// The output goes to the cold storage directory when it matches the policy
+    cold_policy: Option<ColdStoragePolicy>,
+    limiter: Option<&'a RateLimiter>,
 }
 
-/// Tables are expected newer first
+/// Tables are expected newer first. No table is created when nothing is left after the merge.
 fn merge_sstables(
-    sstables_dir: &Path,
+    files: &Arc<TableFiles>,
     tables: &[Arc<SSTable>],
+    table_options: TableOptions,
     save_tombstones: bool,
-) -> Result<SSTable, Error> {
+    now: u64,
+    filter: Option<&dyn CompactionFilter>,
+    io: MergeIo,
+) -> Result<(Option<SSTable>, MergeCounts), Error> {
+    let input_ids: Vec<u64> = tables.iter().map(|t| t.id).collect();
+    let span = span!("merge_sstables", input_ids = input_ids, output_id = io.output_id; entries_in, entries_out);
     let mut contents = Vec::with_capacity(tables.len());
     for table in tables {
-        let file_contents = functions::read_file(&table.file, table.file_size)?;
-        let entries = serialization::deserialize_entries_from_bytes(&file_contents, "sstable")?;
-        contents.push(entries);
+        if let Some(limiter) = io.limiter {
+            limiter.acquire(table.file_size);
+        }
+        contents.push(table.entries()?);
     }
 
-    let merged = merge_sstable_contents(contents, save_tombstones);
-
-    let (index, data, bloom_filter) = entries_to_index_and_data(&merged)?;
+    record!(
+        span,
+        entries_in = contents.iter().map(Vec::len).sum::<usize>()
+    );
+    let (merged, counts) =
+        merge_sstable_contents(contents, save_tombstones, now, filter, files.order());
+    record!(span, entries_out = merged.len());
+    if merged.is_empty() {
+        return Ok((None, counts));
+    }
 
-    let id: u64 = rand::random();
-    let (file, path, size) = sstables::create_sstable_file(id, sstables_dir, &data)?;
+    let input_entries: u64 = tables.iter().map(|t| t.entry_count()).sum();
+    let output_entries = merged.iter().filter(|e| !e.is_range_tombstone()).count() as u64;
+    let mut builder = TableBuilder::new(table_options, merged.len())
+        .with_discarded_entries(input_entries.saturating_sub(output_entries));
+    for entry in &merged {
+        builder.add(entry)?;
+    }
+    let table_content = builder.finish()?;
 
-    let sstable = SSTable {
-        id,
-        index,
-        file,
-        file_path: path,
-        file_size: size,
-        bloom_filter,
+    let id = io.output_id;
+    let cold = match io.cold_policy {
+        // The merge reaches the oldest table only when tombstones can go
+        Some(ColdStoragePolicy::OldestTier) => !save_tombstones,
+        Some(ColdStoragePolicy::MinTableSize(size)) => table_content.data.len() as u64 >= size,
+        None => false,
     };
+    if cold {
+        files.set_cold(id);
+    }
+    if let Some(limiter) = io.limiter {
+        limiter.acquire(table_content.data.len() as u64);
+    }
+    let (file, _, size) = sstables::create_sstable_file(files, id, &table_content.data)?;
 
-    Ok(sstable)
+    increment_counter!("kv_compaction_bytes_written", size);
+    let sstable = SSTable::new(id, files, file, size, table_content);
+
+    Ok((Some(sstable), counts))
 }
 
-/// `lists` are expected newest first;
-/// each list must be sorted by key
+/// Each list must be sorted by key in `order`, for duplicated keys the entry with the highest sequence wins.
+///
+/// Winners expired at `now` become tombstones (or are dropped with the tombstones).
+/// `filter` then runs on every remaining winner, removed entries are handled like expired ones.
+///
+/// Winners covered by a newer range tombstone are dropped, the range tombstones are kept with the other tombstones.
 fn merge_sstable_contents(
     lists: Vec<Vec<KVMemoryRepr>>,
     save_tombstones: bool,
-) -> Vec<KVMemoryRepr> {
+    now: u64,
+    filter: Option<&dyn CompactionFilter>,
+    order: &KeyOrder,
+) -> (Vec<KVMemoryRepr>, MergeCounts) {
     let mut result = Vec::new();
+    let mut counts = MergeCounts::default();
+    let mut ranges = Vec::new();
 
     // Convert each Vec into an iterator with an index
     let mut iters: Vec<_> = lists
         .into_iter()
-        .map(|v| v.into_iter().peekable())
+        .map(|v| {
+            let (list_ranges, points): (Vec<_>, Vec<_>) =
+                v.into_iter().partition(|kv| kv.is_range_tombstone());
+            ranges.extend(list_ranges);
+            points.into_iter().peekable()
+        })
         .collect();
 
     loop {
@@ -217,7 +815,7 @@ fn merge_sstable_contents(
                     None => {
                         min_key = Some(*key);
                     }
-                    Some(current_min) if *key < current_min => {
+                    Some(current_min) if order.lt(key, &current_min) => {
                         min_key = Some(*key);
                     }
                     _ => {}
@@ -240,80 +838,339 @@ fn merge_sstable_contents(
                 // Safety: we just peek'd
                 let kv = it.next().unwrap();
 
-                // Save the first (newest) value we encounter
-                if value_to_save.is_none() {
+                // Save the newest value we encounter
+                if value_to_save
+                    .as_ref()
+                    .is_none_or(|saved: &KVMemoryRepr| saved.sequence() < kv.sequence())
+                {
                     value_to_save = Some(kv);
                 }
             }
         }
 
+        let value_to_save = value_to_save.filter(|kv| {
+            serialization::newest_covering(&ranges, kv.key(), order)
+                .is_none_or(|range| range.sequence() < kv.sequence())
+        });
+
+        let value_to_save = value_to_save.map(|kv| {
+            if kv.value().is_some() && kv.is_expired(now) {
+                counts.expired += 1;
+                // Must keep shadowing older values in tables outside of this merge
+                kv.into_tombstone()
+            } else {
+                kv
+            }
+        });
+
+        let value_to_save = match (value_to_save, filter) {
+            (Some(kv), Some(filter)) => match filter.filter(kv.key(), kv.value()) {
+                FilterDecision::Keep => Some(kv),
+                FilterDecision::Remove => Some(kv.into_tombstone()),
+                FilterDecision::Replace(value) => Some(kv.with_value(value)),
+            },
+            (value_to_save, _) => value_to_save,
+        };
+
         // Save the value if appropriate
-        if let Some(kv) = value_to_save
-            && (save_tombstones || kv.value().is_some())
-        {
-            result.push(kv);
+        match value_to_save {
+            Some(kv) if save_tombstones || kv.value().is_some() => {
+                counts.value_sizes[stats::value_size_bucket(kv.value_size())] += 1;
+                result.push(kv);
+            }
+            Some(_) => counts.dropped_tombstones += 1,
+            None => {}
         }
     }
 
-    result
+    if save_tombstones {
+        result.extend(ranges);
+    } else {
+        counts.dropped_tombstones += ranges.len() as u64;
+    }
+
+    (result, counts)
 }
 
-/// Returns list of indexes of tables to merge in the form `[start, end)`
-///
-/// The ranges are non-overlapping.
-fn find_sstables_to_merge(sstables: &[Arc<SSTable>]) -> Vec<(usize, usize)> {
-    let mut result = Vec::new();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{comparator, functions::ReadOutcome, options::Compression, storage::DiskStorage};
 
-    let mut i = sstables.len();
+    fn values(entries: &[KVMemoryRepr]) -> Vec<(u64, Option<u64>)> {
+        entries.iter().map(|e| (*e.key(), *e.value())).collect()
+    }
 
-    while i > 0 {
-        // Start a new group from position i-1
-        let group_end = i;
-        let current_bucket = get_bucket(sstables[i - 1].file_size);
-        let mut group_start = i - 1;
+    #[test]
+    fn test_merge_keeps_highest_sequence() {
+        for (comparator, k) in comparator::test_orders() {
+            let newer = vec![
+                KVMemoryRepr::new(k(1), Some(10), 10),
+                KVMemoryRepr::new(k(2), None, 11),
+            ];
+            // The highest sequence wins regardless of the list order
+            let older = vec![
+                KVMemoryRepr::new(k(1), Some(1), 1),
+                KVMemoryRepr::new(k(2), Some(2), 2),
+                KVMemoryRepr::new(k(3), Some(3), 12),
+            ];
 
-        // Scan backwards while in the same bucket and under MAX_TABLES_IN_MERGE
-        while group_start > 0 && (group_end - group_start) < MAX_TABLES_IN_MERGE {
-            let prev_idx = group_start - 1;
-            let prev_bucket = get_bucket(sstables[prev_idx].file_size);
+            let order = KeyOrder::new(comparator);
+            let (merged, _) = merge_sstable_contents(vec![older, newer], true, 0, None, &order);
+            assert_eq!(
+                values(&merged),
+                [(k(1), Some(10)), (k(2), None), (k(3), Some(3))]
+            );
+            assert_eq!(
+                merged.iter().map(|e| e.sequence()).collect::<Vec<_>>(),
+                [10, 11, 12]
+            );
+        }
+    }
 
-            if prev_bucket == current_bucket {
-                group_start = prev_idx;
-            } else {
-                break;
+    #[test]
+    fn test_merge_drops_expired_entries() {
+        let newer = vec![
+            KVMemoryRepr::new(1, Some(10), 10).with_expiration(Some(100)),
+            KVMemoryRepr::new(2, Some(20), 11).with_expiration(Some(200)),
+        ];
+        let older = vec![
+            KVMemoryRepr::new(1, Some(1), 1),
+            KVMemoryRepr::new(3, Some(3), 3).with_expiration(Some(50)),
+        ];
+
+        // The expired entry still hides the older value when tombstones are kept
+        let (merged, counts) = merge_sstable_contents(
+            vec![newer.clone(), older.clone()],
+            true,
+            150,
+            None,
+            &KeyOrder::default(),
+        );
+        assert_eq!(values(&merged), [(1, None), (2, Some(20)), (3, None)]);
+        assert_eq!(counts.expired, 2);
+        assert_eq!(counts.dropped_tombstones, 0);
+
+        let (merged, counts) =
+            merge_sstable_contents(vec![newer, older], false, 150, None, &KeyOrder::default());
+        assert_eq!(values(&merged), [(2, Some(20))]);
+        let mut value_sizes = [0; VALUE_SIZE_BUCKETS];
+        // The single 8 bytes value left
+        value_sizes[4] = 1;
+        assert_eq!(
+            counts,
+            MergeCounts {
+                expired: 2,
+                dropped_tombstones: 2,
+                value_sizes,
             }
+        );
+    }
+
+    #[test]
+    fn test_merge_with_range_tombstones() {
+        for (comparator, k) in comparator::test_orders() {
+            let newer = vec![
+                KVMemoryRepr::new(k(5), Some(50), 12),
+                KVMemoryRepr::range_tombstone(k(2), k(6), 11),
+            ];
+            let older = vec![
+                KVMemoryRepr::new(k(1), Some(1), 1),
+                KVMemoryRepr::new(k(3), Some(3), 3),
+                KVMemoryRepr::new(k(6), Some(6), 6),
+            ];
+
+            // Covered entries are dropped, the range keeps shadowing the tables outside of the merge
+            let order = KeyOrder::new(comparator);
+            let (merged, _) =
+                merge_sstable_contents(vec![newer.clone(), older.clone()], true, 0, None, &order);
+            assert_eq!(
+                values(&merged),
+                [
+                    (k(1), Some(1)),
+                    (k(5), Some(50)),
+                    (k(6), Some(6)),
+                    (k(2), None)
+                ]
+            );
+            assert!(merged[3].is_range_tombstone());
+
+            let (merged, _) = merge_sstable_contents(vec![newer, older], false, 0, None, &order);
+            assert_eq!(
+                values(&merged),
+                [(k(1), Some(1)), (k(5), Some(50)), (k(6), Some(6))]
+            );
         }
+    }
 
-        // Check if this group meets the minimum size requirement
-        let group_size = group_end - group_start;
-        if group_size >= MIN_TABLES_IN_MERGE {
-            result.push((group_start, group_end));
+    struct DropOddKeys;
+
+    impl CompactionFilter for DropOddKeys {
+        fn filter(&self, key: &u64, value: &Option<u64>) -> FilterDecision {
+            match value {
+                _ if key % 2 == 1 => FilterDecision::Remove,
+                Some(value) if key.is_multiple_of(10) => FilterDecision::Replace(value + 1),
+                _ => FilterDecision::Keep,
+            }
         }
+    }
 
-        // Move to the next position
-        i = group_start;
+    #[test]
+    fn test_merge_with_compaction_filter() {
+        let dir = std::path::PathBuf::from(format!("./test-dbs/{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // Table 0 is the newest
+        let files = Arc::new(TableFiles::new(Arc::new(DiskStorage), dir.clone(), 2));
+        let tables: Vec<_> = (0..4)
+            .map(|id| {
+                let entries: Vec<_> = (0..100)
+                    .map(|k| KVMemoryRepr::new(k, Some(k * 100 + id), 100 * (4 - id) + k))
+                    .collect();
+                let data = TableBuilder::from_entries(&entries, TableOptions::default())
+                    .unwrap()
+                    .data;
+                sstables::create_sstable_file(&files, id, &data).unwrap();
+                Arc::new(SSTable::open(&files, id).unwrap())
+            })
+            .collect();
+
+        // Removed keys stay as tombstones, so they hide older tables outside of the merge
+        let (merged, _) = merge_sstables(
+            &files,
+            &tables[..2],
+            TableOptions::default(),
+            true,
+            0,
+            Some(&DropOddKeys),
+            MergeIo {
+                output_id: 100,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let merged = merged.unwrap();
+        assert!(matches!(merged.find(&3, 0).unwrap(), ReadOutcome::Deleted));
+        assert!(matches!(
+            merged.find(&4, 0).unwrap(),
+            ReadOutcome::Found(400)
+        ));
+        assert!(matches!(
+            merged.find(&20, 0).unwrap(),
+            ReadOutcome::Found(2001)
+        ));
+
+        let (merged, _) = merge_sstables(
+            &files,
+            &tables,
+            TableOptions::default(),
+            false,
+            0,
+            Some(&DropOddKeys),
+            MergeIo {
+                output_id: 100,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let merged = merged.unwrap();
+        assert!(matches!(merged.find(&3, 0).unwrap(), ReadOutcome::NotFound));
+        assert!(matches!(
+            merged.find(&4, 0).unwrap(),
+            ReadOutcome::Found(400)
+        ));
     }
 
-    result
-}
+    #[test]
+    fn test_merge_without_output() {
+        let dir = std::path::PathBuf::from(format!("./test-dbs/{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let files = Arc::new(TableFiles::new(Arc::new(DiskStorage), dir.clone(), 2));
 
-// Helper function to determine bucket for a table size
-fn get_bucket(size: u64) -> u32 {
-    if size == 0 {
-        return 0;
-    }
-    // Bucket boundaries: 0-10MB, 10-100MB, 100-1000MB, etc.
-    // 10MB = 10_000_000 bytes
-    let size_in_mb = size / 1_000_000;
-    if size_in_mb < 10 {
-        0
-    } else if size_in_mb < 100 {
-        1
-    } else if size_in_mb < 1000 {
-        2
-    } else if size_in_mb < 10000 {
-        3
-    } else {
-        4
+        // The newer table deletes every key of the older one
+        let tables: Vec<_> = [(0, None), (1, Some(7))]
+            .into_iter()
+            .map(|(id, value)| {
+                let entries: Vec<_> = (0..100)
+                    .map(|k| KVMemoryRepr::new(k, value, 100 * (2 - id) + k))
+                    .collect();
+                let data = TableBuilder::from_entries(&entries, TableOptions::default())
+                    .unwrap()
+                    .data;
+                sstables::create_sstable_file(&files, id, &data).unwrap();
+                Arc::new(SSTable::open(&files, id).unwrap())
+            })
+            .collect();
+
+        let (merged, counts) = merge_sstables(
+            &files,
+            &tables,
+            TableOptions::default(),
+            false,
+            0,
+            None,
+            MergeIo {
+                output_id: 100,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(merged.is_none());
+        assert_eq!(counts.dropped_tombstones, 100);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_merge_mixed_compression() {
+        let dir = std::path::PathBuf::from(format!("./test-dbs/{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let files = Arc::new(TableFiles::new(Arc::new(DiskStorage), dir.clone(), 4));
+
+        // The newer table is compressed and overwrites the even keys of the older one
+        let tables: Vec<_> = [(0, Compression::Lz4), (1, Compression::None)]
+            .into_iter()
+            .map(|(id, compression)| {
+                let entries: Vec<_> = (0..2000)
+                    .filter(|k| id == 1 || k % 2 == 0)
+                    .map(|k| KVMemoryRepr::new(k, Some(k * 10 + id), 10_000 * (2 - id) + k))
+                    .collect();
+                let options = TableOptions {
+                    compression,
+                    ..Default::default()
+                };
+                let data = TableBuilder::from_entries(&entries, options).unwrap().data;
+                sstables::create_sstable_file(&files, id, &data).unwrap();
+                Arc::new(SSTable::open(&files, id).unwrap())
+            })
+            .collect();
+
+        for compression in [Compression::None, Compression::Lz4] {
+            let (merged, _) = merge_sstables(
+                &files,
+                &tables,
+                TableOptions {
+                    compression,
+                    ..Default::default()
+                },
+                false,
+                0,
+                None,
+                MergeIo {
+                    output_id: rand::random(),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            let merged = merged.unwrap();
+            let reopened = SSTable::open(&files, merged.id()).unwrap();
+
+            for key in 0..2000 {
+                let expected = if key % 2 == 0 { key * 10 } else { key * 10 + 1 };
+                assert!(matches!(
+                    reopened.find(&key, 0).unwrap(),
+                    ReadOutcome::Found(value) if value == expected
+                ));
+            }
+        }
     }
 }
@@ -0,0 +1,642 @@
+use crate::{
+    Key,
+    comparator::KeyOrder,
+    errors::Error,
+    options::Compression,
+    serialization::{self, KVMemoryRepr, RecordFormat, SerializationError},
+    verify::Anomaly,
+};
+use std::borrow::Cow;
+
+/// Stored in the file header and the footer, bumped whenever the table layout changes
+pub const TABLE_FORMAT_VERSION: u8 = 6;
+/// Oldest version able to read the tables written now, bumped on changes older readers can't skip over. Newer tables
+/// are read as long as theirs isn't above [`TABLE_FORMAT_VERSION`]
+pub const TABLE_MIN_READER_VERSION: u8 = 6;
+/// Oldest version of the tables still read, with [`RecordFormat::Framed`] records
+pub const TABLE_OLDEST_VERSION: u8 = 5;
+/// First version of the tables with [`RecordFormat::Fixed`] records
+pub const TABLE_FIXED_RECORDS_VERSION: u8 = 6;
+/// Records between two restart points of a block
+const RESTART_INTERVAL: usize = 16;
+/// Restart offsets and the restart count are stored as `u32`
+const RESTART_BYTES: usize = 4;
+/// First key, offset and stored length
+const BLOCK_HANDLE_BYTES: usize = 8 + 8 + 4;
+/// Ranges, index and sections offsets, entry, tombstone and discarded entry counts, compression, flags, minimum
+/// reader version and format version
+pub const FOOTER_BYTES: usize = 8 + 8 + 8 + 8 + 8 + 8 + 1 + 1 + 1 + 1;
+/// Id and payload length of an optional section
+const SECTION_HEADER_BYTES: usize = 1 + 4;
+/// Section holding the lowest and highest sequence of the entries, see [`TableParts::sequences`]
+const SEQUENCES_SECTION: u8 = 1;
+/// Lowest and highest sequence
+const SEQUENCES_SECTION_BYTES: usize = 8 + 8;
+/// Section holding the bloom filter of the point keys, see [`TableParts::bloom_filter`]
+const BLOOM_SECTION: u8 = 2;
+/// Section holding how the bloom filter was built, see [`TableParts::bloom_params`]
+const BLOOM_PARAMS_SECTION: u8 = 3;
+/// Seed and bitmap size
+const BLOOM_PARAMS_SECTION_BYTES: usize = 32 + 8;
+
+/// Where a data block is stored in the table file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockHandle {
+    pub first_key: Key,
+    pub offset: u64,
+    /// Compressed size
+    pub len: u32,
+}
+
+/// Last bytes of every table file.
+///
+/// A table is laid out as `[data blocks][range tombstones][block index][optional sections][footer]`, only the data
+/// blocks are compressed.
+///
+/// Optional sections are each `[id (1)][length (4)][payload]`, readers skip the ones they don't know. Written are
+/// [`BLOOM_SECTION`], [`BLOOM_PARAMS_SECTION`] then [`SEQUENCES_SECTION`]. Data that older readers can't do without bumps
+/// [`TABLE_MIN_READER_VERSION`] instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Footer {
+    /// End of the data blocks, where the (uncompressed) range tombstones start
+    pub ranges_offset: u64,
+    pub index_offset: u64,
+    /// End of the block index, where the optional sections start
+    pub sections_offset: u64,
+    /// Point entries, tombstones included
+    pub entry_count: u64,
+    pub tombstone_count: u64,
+    /// Entries of the merged tables left out of this one, 0 for tables not written by compaction
+    pub discarded_entries: u64,
+    pub compression: Compression,
+    /// Optional features of the table, the ones a reader doesn't know are ignored. None is defined yet
+    pub flags: u8,
+}
+
+/// What the bloom filter of a table is built from, stored with it so that rebuilding it gives the same filter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BloomParams {
+    pub seed: [u8; 32],
+    pub bitmap_bytes: u64,
+}
+
+/// A table file split into its parts, the data blocks still compressed
+pub struct TableParts<'a> {
+    pub footer: Footer,
+    /// From the file header
+    pub record_format: RecordFormat,
+    pub index: Vec<BlockHandle>,
+    pub ranges: Vec<KVMemoryRepr>,
+    /// Lowest and highest sequence of the entries, `None` for the tables written without the sequences section
+    pub sequences: Option<(u64, u64)>,
+    /// Serialized bloom filter, `None` for the tables written without the bloom section
+    pub bloom_filter: Option<&'a [u8]>,
+    /// `None` for the tables written before the bloom filters had a seed
+    pub bloom_params: Option<BloomParams>,
+    pub data: &'a [u8],
+}
+
+impl BlockHandle {
+    pub fn encode_into(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.first_key.to_le_bytes());
+        out.extend_from_slice(&self.offset.to_le_bytes());
+        out.extend_from_slice(&self.len.to_le_bytes());
+    }
+}
+
+impl Footer {
+    pub fn encode_into(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.ranges_offset.to_le_bytes());
+        out.extend_from_slice(&self.index_offset.to_le_bytes());
+        out.extend_from_slice(&self.sections_offset.to_le_bytes());
+        out.extend_from_slice(&self.entry_count.to_le_bytes());
+        out.extend_from_slice(&self.tombstone_count.to_le_bytes());
+        out.extend_from_slice(&self.discarded_entries.to_le_bytes());
+        out.push(compression_to_byte(self.compression));
+        out.push(self.flags);
+        out.push(TABLE_MIN_READER_VERSION);
+        out.push(TABLE_FORMAT_VERSION);
+    }
+
+    /// The footer of a table written by a version from [`TABLE_OLDEST_VERSION`] on, not needing a newer reader.
+    ///
+    /// The version bytes are the last ones, so that any future footer still starts with the ones read here.
+    fn decode(bytes: &[u8; FOOTER_BYTES]) -> Result<Self, Error> {
+        let version = bytes[FOOTER_BYTES - 1];
+        let min_reader_version = bytes[FOOTER_BYTES - 2];
+        if version < TABLE_OLDEST_VERSION || min_reader_version > TABLE_FORMAT_VERSION {
+            return Err(Error::UnsupportedVersion {
+                found: version,
+                supported: TABLE_FORMAT_VERSION,
+            });
+        }
+
+        let read_u64 =
+            |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().expect("8 bytes"));
+
+        Ok(Footer {
+            ranges_offset: read_u64(0),
+            index_offset: read_u64(8),
+            sections_offset: read_u64(16),
+            entry_count: read_u64(24),
+            tombstone_count: read_u64(32),
+            discarded_entries: read_u64(40),
+            compression: compression_from_byte(bytes[48])?,
+            flags: bytes[49],
+        })
+    }
+}
+
+/// Reads the footer, the block index and the range tombstones of a whole table file, header excluded.
+///
+/// Offsets in the footer and the index are relative to the end of the header, whose version decides the `format` of
+/// its records.
+pub fn decode_table(data: &[u8], format: RecordFormat) -> Result<TableParts<'_>, Error> {
+    decode_parts(data, 0, format)
+}
+
+/// The footer of a table out of its last [`FOOTER_BYTES`] bytes, to tell where [`decode_table_tail`] starts
+pub fn decode_footer(bytes: &[u8]) -> Result<Footer, Error> {
+    Footer::decode(
+        bytes
+            .try_into()
+            .map_err(|_| SerializationError::InvalidTableLayout)?,
+    )
+}
+
+/// Same as [`decode_table`] out of the end of the table only, from the range tombstones on: `tail` starts at
+/// `footer.ranges_offset`. The parts have neither the data blocks, read apart, nor the bloom filter
+pub fn decode_table_tail(
+    tail: &[u8],
+    footer: &Footer,
+    format: RecordFormat,
+) -> Result<TableParts<'static>, Error> {
+    let parts = decode_parts(tail, footer.ranges_offset, format)?;
+
+    Ok(TableParts {
+        footer: parts.footer,
+        record_format: parts.record_format,
+        index: parts.index,
+        ranges: parts.ranges,
+        sequences: parts.sequences,
+        bloom_filter: None,
+        bloom_params: parts.bloom_params,
+        data: &[],
+    })
+}
+
+/// [`decode_table`] out of the part of a table starting at offset `base`, which must hold everything past the data
+/// blocks
+fn decode_parts(data: &[u8], base: u64, format: RecordFormat) -> Result<TableParts<'_>, Error> {
+    let footer_start = data
+        .len()
+        .checked_sub(FOOTER_BYTES)
+        .ok_or(SerializationError::InvalidTableLayout)?;
+    let footer = Footer::decode(data[footer_start..].try_into().expect("footer size"))?;
+    let part = |start: u64, end: u64| {
+        start
+            .checked_sub(base)
+            .zip(end.checked_sub(base))
+            .and_then(|(start, end)| data.get(start as usize..end as usize))
+            .ok_or(SerializationError::InvalidTableLayout)
+    };
+    let footer_offset = base + footer_start as u64;
+
+    let index_bytes = part(footer.index_offset, footer.sections_offset)?;
+    if index_bytes.len() % BLOCK_HANDLE_BYTES != 0 {
+        return Err(SerializationError::InvalidTableLayout.into());
+    }
+    let index = index_bytes
+        .chunks_exact(BLOCK_HANDLE_BYTES)
+        .map(|handle| BlockHandle {
+            first_key: u64::from_le_bytes(handle[0..8].try_into().expect("8 bytes")),
+            offset: u64::from_le_bytes(handle[8..16].try_into().expect("8 bytes")),
+            len: u32::from_le_bytes(handle[16..20].try_into().expect("4 bytes")),
+        })
+        .collect();
+
+    let ranges_bytes = part(footer.ranges_offset, footer.index_offset)?;
+    let ranges = serialization::deserialize_entries_from_bytes(ranges_bytes, "sstable", format)?;
+
+    let sections_bytes = part(footer.sections_offset, footer_offset)?;
+    let Sections {
+        sequences,
+        bloom_filter,
+        bloom_params,
+    } = decode_sections(sections_bytes)?;
+
+    Ok(TableParts {
+        footer,
+        record_format: format,
+        index,
+        ranges,
+        sequences,
+        bloom_filter,
+        bloom_params,
+        data,
+    })
+}
+
+/// Writes the sections following the block index: the serialized `bloom_filter` with its `bloom_params` and the
+/// `sequences` (lowest and highest) of the entries
+pub fn encode_sections_into(
+    bloom_filter: &[u8],
+    bloom_params: &BloomParams,
+    sequences: (u64, u64),
+    out: &mut Vec<u8>,
+) {
+    out.push(BLOOM_SECTION);
+    out.extend_from_slice(&(bloom_filter.len() as u32).to_le_bytes());
+    out.extend_from_slice(bloom_filter);
+
+    out.push(BLOOM_PARAMS_SECTION);
+    out.extend_from_slice(&(BLOOM_PARAMS_SECTION_BYTES as u32).to_le_bytes());
+    out.extend_from_slice(&bloom_params.seed);
+    out.extend_from_slice(&bloom_params.bitmap_bytes.to_le_bytes());
+
+    out.push(SEQUENCES_SECTION);
+    out.extend_from_slice(&(SEQUENCES_SECTION_BYTES as u32).to_le_bytes());
+    out.extend_from_slice(&sequences.0.to_le_bytes());
+    out.extend_from_slice(&sequences.1.to_le_bytes());
+}
+
+/// Content of the optional sections this version knows
+#[derive(Default)]
+struct Sections<'a> {
+    sequences: Option<(u64, u64)>,
+    bloom_filter: Option<&'a [u8]>,
+    bloom_params: Option<BloomParams>,
+}
+
+/// Goes over the optional sections of a table, skipping the ones it doesn't know. Fails when they don't add up to
+/// `bytes`
+fn decode_sections(mut bytes: &[u8]) -> Result<Sections<'_>, Error> {
+    let mut sections = Sections::default();
+    while !bytes.is_empty() {
+        let header = bytes
+            .get(..SECTION_HEADER_BYTES)
+            .ok_or(SerializationError::InvalidTableLayout)?;
+        let len = read_u32(&header[1..]) as usize;
+        let payload = bytes
+            .get(SECTION_HEADER_BYTES..SECTION_HEADER_BYTES + len)
+            .ok_or(SerializationError::InvalidTableLayout)?;
+
+        if header[0] == SEQUENCES_SECTION {
+            if len != SEQUENCES_SECTION_BYTES {
+                return Err(SerializationError::InvalidTableLayout.into());
+            }
+            let read_u64 =
+                |at: usize| u64::from_le_bytes(payload[at..at + 8].try_into().expect("8 bytes"));
+            sections.sequences = Some((read_u64(0), read_u64(8)));
+        } else if header[0] == BLOOM_SECTION {
+            sections.bloom_filter = Some(payload);
+        } else if header[0] == BLOOM_PARAMS_SECTION {
+            if len != BLOOM_PARAMS_SECTION_BYTES {
+                return Err(SerializationError::InvalidTableLayout.into());
+            }
+            sections.bloom_params = Some(BloomParams {
+                seed: payload[..32].try_into().expect("32 bytes"),
+                bitmap_bytes: u64::from_le_bytes(payload[32..].try_into().expect("8 bytes")),
+            });
+        }
+        bytes = &bytes[SECTION_HEADER_BYTES + len..];
+    }
+
+    Ok(sections)
+}
+
+impl TableParts<'_> {
+    /// Key of the last point entry, decoding only the last block
+    pub fn last_point_key(&self) -> Result<Option<Key>, Error> {
+        let Some(handle) = self.index.last() else {
+            return Ok(None);
+        };
+        let stored = self
+            .data
+            .get(handle.offset as usize..(handle.offset + handle.len as u64) as usize)
+            .ok_or(SerializationError::InvalidTableLayout)?;
+        let raw = decompress(self.footer.compression, stored)?;
+
+        Ok(Block::parse(&raw, self.record_format)?
+            .entries()?
+            .last()
+            .map(|entry| *entry.key()))
+    }
+
+    /// Every point entry, in key order
+    pub fn points(&self) -> Result<Vec<KVMemoryRepr>, Error> {
+        let mut points = Vec::new();
+        for handle in &self.index {
+            let stored = self
+                .data
+                .get(handle.offset as usize..(handle.offset + handle.len as u64) as usize)
+                .ok_or(SerializationError::InvalidTableLayout)?;
+            let raw = decompress(self.footer.compression, stored)?;
+            points.extend(Block::parse(&raw, self.record_format)?.entries()?);
+        }
+
+        Ok(points)
+    }
+}
+
+/// Decodes every block of a table (header excluded), collecting what doesn't match the layout written by
+/// [`super::TableBuilder`] instead of stopping at the first problem. Returns the point entries that could be decoded.
+///
+/// Fails only when the table can't be split into blocks.
+pub fn check_table(
+    data: &[u8],
+    format: RecordFormat,
+    order: &KeyOrder,
+    anomalies: &mut Vec<Anomaly>,
+) -> Result<Vec<KVMemoryRepr>, Error> {
+    let parts = decode_table(data, format)?;
+    let mut points: Vec<KVMemoryRepr> = Vec::new();
+    let mut expected_offset = 0;
+
+    for (block, handle) in parts.index.iter().enumerate() {
+        let end = handle.offset + handle.len as u64;
+        if handle.offset != expected_offset || end > parts.footer.ranges_offset {
+            anomalies.push(Anomaly::BlockOffset {
+                block,
+                offset: handle.offset,
+            });
+        }
+        expected_offset = end;
+
+        let Some(raw) = data
+            .get(handle.offset as usize..end as usize)
+            .and_then(|stored| decompress(parts.footer.compression, stored).ok())
+        else {
+            anomalies.push(Anomaly::CorruptBlock { block });
+            continue;
+        };
+        let Ok(parsed) = Block::parse(&raw, format) else {
+            anomalies.push(Anomaly::CorruptBlock { block });
+            continue;
+        };
+
+        let mut record_offsets = Vec::new();
+        let mut remaining = parsed.records;
+        while !remaining.is_empty() {
+            let offset = (parsed.records.len() - remaining.len()) as u64;
+            let Ok((entry, rest)) = serialization::deserialize(remaining, format) else {
+                anomalies.push(Anomaly::CorruptRecord { block, offset });
+                break;
+            };
+            remaining = rest;
+            record_offsets.push(offset);
+
+            if record_offsets.len() == 1 && *entry.key() != handle.first_key {
+                anomalies.push(Anomaly::IndexKey {
+                    block,
+                    indexed: handle.first_key,
+                    found: *entry.key(),
+                });
+            }
+            if let Some(previous) = points.last() {
+                if entry.key() == previous.key() {
+                    anomalies.push(Anomaly::DuplicateKey { key: *entry.key() });
+                } else if order.lt(entry.key(), previous.key()) {
+                    anomalies.push(Anomaly::UnsortedKey { key: *entry.key() });
+                }
+            }
+            points.push(entry);
+        }
+
+        for restart in parsed.restarts.chunks_exact(RESTART_BYTES) {
+            let offset = read_u32(restart) as u64;
+            if record_offsets.binary_search(&offset).is_err() {
+                anomalies.push(Anomaly::RestartOffset { block, offset });
+            }
+        }
+    }
+
+    Ok(points)
+}
+
+/// Every record that can be decoded from a table (header excluded), returning whether anything was skipped.
+///
+/// Blocks are located through the block index when it's readable. Otherwise, or for blocks that can't be parsed,
+/// uncompressed data is scanned for records.
+pub fn salvage_table(data: &[u8], format: RecordFormat) -> (Vec<KVMemoryRepr>, bool) {
+    let Ok(parts) = decode_table(data, format) else {
+        let (entries, _) = serialization::salvage_entries(data, format);
+        return (entries, true);
+    };
+
+    let mut entries = parts.ranges;
+    let mut damaged = false;
+    for handle in &parts.index {
+        let Some(stored) =
+            data.get(handle.offset as usize..(handle.offset + handle.len as u64) as usize)
+        else {
+            damaged = true;
+            continue;
+        };
+
+        let raw = decompress(parts.footer.compression, stored);
+        let block = raw.as_deref().map(|raw| Block::parse(raw, format));
+        let (block_entries, skipped) = match block {
+            Ok(Ok(block)) => serialization::salvage_entries(block.records, format),
+            _ if parts.footer.compression == Compression::None => {
+                // The restart points are scanned too, the records can still be found around them
+                (serialization::salvage_entries(stored, format).0, 1)
+            }
+            _ => (Vec::new(), 1),
+        };
+        damaged |= skipped > 0;
+        entries.extend(block_entries);
+    }
+
+    (entries, damaged)
+}
+
+/// Accumulates the records of a data block.
+///
+/// A block is laid out as `[records][restart offsets][restart count]`: every [`RESTART_INTERVAL`] records, the offset
+/// of the next record is kept so that lookups can binary search the block instead of decoding it whole.
+pub struct BlockBuilder {
+    buffer: Vec<u8>,
+    restarts: Vec<u32>,
+    count: usize,
+    first_key: Option<Key>,
+    format: RecordFormat,
+}
+
+impl BlockBuilder {
+    pub fn new(format: RecordFormat) -> Self {
+        Self {
+            buffer: Vec::new(),
+            restarts: Vec::new(),
+            count: 0,
+            first_key: None,
+            format,
+        }
+    }
+
+    pub fn add(&mut self, entry: &KVMemoryRepr) -> Result<(), Error> {
+        if self.count.is_multiple_of(RESTART_INTERVAL) {
+            self.restarts.push(self.buffer.len() as u32);
+        }
+        self.first_key.get_or_insert(*entry.key());
+        self.buffer
+            .extend_from_slice(&serialization::serialize(entry, self.format)?);
+        self.count += 1;
+
+        Ok(())
+    }
+
+    /// Size of the records added so far
+    pub fn size(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Returns the first key and the raw (uncompressed) block, leaving the builder empty
+    pub fn finish(&mut self) -> Option<(Key, Vec<u8>)> {
+        let first_key = self.first_key.take()?;
+
+        let mut raw = std::mem::take(&mut self.buffer);
+        for restart in &self.restarts {
+            raw.extend_from_slice(&restart.to_le_bytes());
+        }
+        raw.extend_from_slice(&(self.restarts.len() as u32).to_le_bytes());
+        self.restarts.clear();
+        self.count = 0;
+
+        Some((first_key, raw))
+    }
+}
+
+/// A raw (uncompressed) data block
+pub struct Block<'a> {
+    records: &'a [u8],
+    restarts: &'a [u8],
+    format: RecordFormat,
+}
+
+impl<'a> Block<'a> {
+    pub fn parse(raw: &'a [u8], format: RecordFormat) -> Result<Self, Error> {
+        if raw.is_empty() {
+            return Ok(Block {
+                records: raw,
+                restarts: raw,
+                format,
+            });
+        }
+
+        let count_start = raw
+            .len()
+            .checked_sub(RESTART_BYTES)
+            .ok_or(SerializationError::InvalidTableLayout)?;
+        let count = read_u32(&raw[count_start..]) as usize;
+        let restarts_start = count
+            .checked_mul(RESTART_BYTES)
+            .and_then(|size| count_start.checked_sub(size))
+            .ok_or(SerializationError::InvalidTableLayout)?;
+
+        Ok(Block {
+            records: &raw[..restarts_start],
+            restarts: &raw[restarts_start..count_start],
+            format,
+        })
+    }
+
+    pub fn entries(&self) -> Result<Vec<KVMemoryRepr>, Error> {
+        serialization::deserialize_entries_from_bytes(self.records, "sstable", self.format)
+    }
+
+    /// Same as [`Block::entries`], replacing the content of `out`
+    pub fn entries_into(&self, out: &mut Vec<KVMemoryRepr>) -> Result<(), Error> {
+        out.clear();
+        serialization::deserialize_entries_into(self.records, "sstable", self.format, out)
+    }
+
+    /// Binary searches the restart points, then decodes at most [`RESTART_INTERVAL`] records
+    pub fn find(&self, key: &Key, order: &KeyOrder) -> Result<Option<KVMemoryRepr>, Error> {
+        let restart_count = self.restarts.len() / RESTART_BYTES;
+        let restart = |i: usize| read_u32(&self.restarts[i * RESTART_BYTES..]) as usize;
+
+        // First restart whose key is above `key`
+        let (mut low, mut high) = (0, restart_count);
+        while low < high {
+            let middle = (low + high) / 2;
+            let records = self
+                .records
+                .get(restart(middle)..)
+                .ok_or(SerializationError::InvalidTableLayout)?;
+            let (entry, _) = serialization::deserialize(records, self.format)?;
+
+            if !order.lt(key, entry.key()) {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+
+        let Some(start) = low.checked_sub(1) else {
+            return Ok(None);
+        };
+        let records = self
+            .records
+            .get(restart(start)..)
+            .ok_or(SerializationError::InvalidTableLayout)?;
+
+        super::find_in_bytes(key, records, self.format, order)
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[..4].try_into().expect("4 bytes"))
+}
+
+pub fn compress(compression: Compression, raw: &[u8]) -> Cow<'_, [u8]> {
+    match compression {
+        Compression::None => Cow::Borrowed(raw),
+        Compression::Lz4 => Cow::Owned(lz4_flex::block::compress_prepend_size(raw)),
+    }
+}
+
+pub fn decompress(compression: Compression, stored: &[u8]) -> Result<Cow<'_, [u8]>, Error> {
+    match compression {
+        Compression::None => Ok(Cow::Borrowed(stored)),
+        Compression::Lz4 => lz4_flex::block::decompress_size_prepended(stored)
+            .map(Cow::Owned)
+            .map_err(|e| SerializationError::Decompression(e).into()),
+    }
+}
+
+/// Same as [`decompress`], writing the records of compressed blocks into `out` instead of a new buffer
+pub fn decompress_into<'a>(
+    compression: Compression,
+    stored: &'a [u8],
+    out: &'a mut Vec<u8>,
+) -> Result<&'a [u8], Error> {
+    match compression {
+        Compression::None => Ok(stored),
+        Compression::Lz4 => {
+            let (len, compressed) = lz4_flex::block::uncompressed_size(stored)
+                .map_err(SerializationError::Decompression)?;
+            out.clear();
+            out.resize(len, 0);
+            let len = lz4_flex::block::decompress_into(compressed, out)
+                .map_err(SerializationError::Decompression)?;
+
+            Ok(&out[..len])
+        }
+    }
+}
+
+fn compression_to_byte(compression: Compression) -> u8 {
+    match compression {
+        Compression::None => 0,
+        Compression::Lz4 => 1,
+    }
+}
+
+fn compression_from_byte(byte: u8) -> Result<Compression, Error> {
+    match byte {
+        0 => Ok(Compression::None),
+        1 => Ok(Compression::Lz4),
+        _ => Err(SerializationError::UnsupportedCompression(byte).into()),
+    }
+}
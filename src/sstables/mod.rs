@@ -1,64 +1,663 @@
+mod builder;
 pub mod compactor;
+mod format;
+pub mod policy;
+pub mod probe;
+mod scratch;
+mod sst_file;
+mod table_files;
+mod table_list;
 
-use crate::cleanup::CleanableFile;
-use crate::functions::FindResult;
-use crate::serialization::KVMemoryRepr;
-use crate::{FILE_SIZE_BYTES, serialization};
+use crate::changes::Change;
+use crate::cleanup::{self, CleanableFile};
+use crate::comparator::KeyOrder;
+use crate::debug::{BlockDump, TableDump};
+use crate::file_header::{self, FileHeader, FileKind, HEADER_BYTES};
+use crate::instrumentation::{increment_counter, record, span};
+use crate::options::{BloomRecovery, Compression};
+use crate::serialization::{self, SerializationError};
+use crate::serialization::{KVMemoryRepr, RecordFormat};
+use crate::stats::{BloomStats, RangeEstimate, ReadTrace};
+use crate::storage::{Handle, Storage};
+use crate::verify::{Anomaly, TableReport};
 use crate::{Key, errors::Error, functions};
 use bloomfilter::Bloom;
-use std::os::unix::fs::FileExt;
-use std::path::PathBuf;
-use std::{fs::File, path::Path};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
-const TABLE_TO_INDEX_RATIO: u64 = 128;
-const FP_RATE: f64 = 0.001;
+pub use builder::{TableBuilder, TableOptions};
+use format::{Block, BlockHandle, BloomParams, TableParts};
+pub(crate) use format::{
+    TABLE_FIXED_RECORDS_VERSION, TABLE_FORMAT_VERSION, TABLE_MIN_READER_VERSION,
+    TABLE_OLDEST_VERSION,
+};
+use scratch::with_scratch;
+pub use sst_file::{SstReader, SstWriter, TableMetadata};
+pub use table_files::TableFiles;
+pub use table_list::{TableList, TableState, TableView};
+
+pub const TMP_EXTENSION: &str = "tmp";
 
 type BloomType = Bloom<Key>;
 
+/// Empty bloom filter for `points` keys, built the same every time out of the same `params`
+fn new_bloom(params: &BloomParams, points: usize) -> BloomType {
+    Bloom::new_with_seed(
+        params.bitmap_bytes.max(1) as usize,
+        points.max(1),
+        &params.seed,
+    )
+    .expect("non-empty bloom filter")
+}
+
 /// A SSTable with in-memory index
 pub struct SSTable {
     id: u64,
-    /// Sorted list of (Key, offset) values
-    index: Index,
-    /// File containing sorted entries
-    file: File,
+    /// Data blocks, sorted by key
+    index: Vec<BlockHandle>,
+    compression: Compression,
+    /// Of the version that wrote the table
+    record_format: RecordFormat,
+    /// Opens the file containing the sorted entries on demand
+    files: Arc<TableFiles>,
     file_path: PathBuf,
     /// File size in bytes
     file_size: u64,
-    bloom_filter: BloomType,
+    /// `None` with [`BloomRecovery::Skip`]
+    bloom_filter: Option<BloomType>,
+    /// Size of the serialized `bloom_filter`, 0 without one
+    bloom_bytes: u64,
+    bloom_counters: BloomCounters,
+    /// Lowest and highest sequence number among the entries, both 0 for empty tables
+    min_sequence: u64,
+    max_sequence: u64,
+    /// Smallest and largest key, `None` for empty tables
+    key_range: Option<(Key, Key)>,
+    entry_count: u64,
+    tombstone_count: u64,
+    /// Entries of the merged tables that compaction left out of this one, overwritten or dropped
+    discarded_entries: u64,
+    /// Kept in memory since any of them can cover the key being read
+    range_tombstones: Vec<KVMemoryRepr>,
+    /// Whole file mapped in memory, reads fall back to `files` when mapping failed
+    #[cfg(feature = "mmap")]
+    map: Option<memmap2::Mmap>,
 }
 
 impl SSTable {
+    /// `file` is handed over to `files`, which may close it
+    fn new(
+        id: u64,
+        files: &Arc<TableFiles>,
+        file: Handle,
+        file_size: u64,
+        content: TableContent,
+    ) -> Self {
+        #[cfg(feature = "mmap")]
+        let map = map_file(&file);
+        files.insert(id, Arc::new(file));
+        let key_range = content.summary.key_range(files.order());
+        let (min_sequence, max_sequence) = content.summary.sequences();
+        let bloom_filter = match files.bloom_recovery() {
+            BloomRecovery::Skip => None,
+            _ => content.summary.bloom_filter,
+        };
+        let bloom_bytes = bloom_filter
+            .as_ref()
+            .map_or(0, |_| content.summary.bloom_bytes);
+
+        SSTable {
+            id,
+            index: content.index,
+            compression: content.compression,
+            record_format: content.record_format,
+            files: files.clone(),
+            file_path: files.path(id),
+            file_size,
+            bloom_filter,
+            bloom_bytes,
+            bloom_counters: Default::default(),
+            min_sequence,
+            max_sequence,
+            key_range,
+            entry_count: content.summary.entry_count,
+            tombstone_count: content.summary.tombstone_count,
+            discarded_entries: content.summary.discarded_entries,
+            range_tombstones: content.summary.range_tombstones,
+            #[cfg(feature = "mmap")]
+            map,
+        }
+    }
+
+    /// Opens an existing SSTable file, rebuilding its in-memory index. The bloom filter is loaded according to
+    /// [`TableFiles::bloom_recovery`]
+    pub fn open(files: &Arc<TableFiles>, id: u64) -> Result<Self, Error> {
+        let file = files.storage().open(&files.path(id), false)?;
+        let file_size = file.size()?;
+
+        let content = functions::read_file(&file, file_size)?;
+        let (data, record_format) = file_header::strip_header(FileKind::Table, &content)?;
+        let parts = format::decode_table(data, record_format)?;
+
+        let stored_bloom = match (files.bloom_recovery(), parts.bloom_filter) {
+            (BloomRecovery::FromFooter, Some(bytes)) => Some(
+                Bloom::from_bytes(bytes.to_vec())
+                    .map_err(|_| SerializationError::InvalidTableLayout)?,
+            ),
+            _ => None,
+        };
+        let summary = match (files.bloom_recovery(), stored_bloom, parts.sequences) {
+            (BloomRecovery::FromFooter, Some(bloom_filter), Some(sequences)) => {
+                let mut summary = TableSummary::from_parts(&parts, Some(bloom_filter), sequences)?;
+                summary.bloom_bytes = parts.bloom_filter.map_or(0, |bytes| bytes.len() as u64);
+                summary
+            }
+            (BloomRecovery::Skip, _, Some(sequences)) => {
+                TableSummary::from_parts(&parts, None, sequences)?
+            }
+            (recovery, _, _) => TableSummary::rebuild(&parts, recovery != BloomRecovery::Skip)?,
+        };
+        let table_content = TableContent {
+            index: parts.index,
+            data: Vec::new(),
+            compression: parts.footer.compression,
+            record_format,
+            summary,
+        };
+
+        Ok(SSTable::new(id, files, file, file_size, table_content))
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    pub fn max_sequence(&self) -> u64 {
+        self.max_sequence
+    }
+
+    /// Lowest sequence number among the entries, a snapshot taken before it can't see any of them
+    pub fn min_sequence(&self) -> u64 {
+        self.min_sequence
+    }
+
     pub fn file_path(&self) -> &Path {
         &self.file_path
     }
 
-    pub fn find(&self, key: &Key) -> Result<FindResult, Error> {
-        if !self.bloom_filter.check(key) {
-            return Ok(FindResult::None);
+    pub fn range_tombstones(&self) -> &[KVMemoryRepr] {
+        &self.range_tombstones
+    }
+
+    /// Number of entries, tombstones included (range tombstones excluded)
+    pub fn entry_count(&self) -> u64 {
+        self.entry_count
+    }
+
+    pub fn tombstone_count(&self) -> u64 {
+        self.tombstone_count
+    }
+
+    /// Smallest and largest key, range tombstones included. `None` for empty tables
+    pub fn key_range(&self) -> Option<(Key, Key)> {
+        self.key_range
+    }
+
+    /// Whether `key` is between the smallest and largest key of the table
+    pub fn in_key_range(&self, key: &Key) -> bool {
+        self.key_range
+            .is_some_and(|(min, max)| self.order().in_bounds(&min, &max, key))
+    }
+
+    /// Order of the keys in the table
+    pub fn order(&self) -> &KeyOrder {
+        self.files.order()
+    }
+
+    /// How useful the bloom filter was to the lookups since the table was opened
+    pub fn bloom_stats(&self) -> BloomStats {
+        BloomStats {
+            table: self.id,
+            checks: self.bloom_counters.checks.load(Ordering::Relaxed),
+            negatives: self.bloom_counters.negatives.load(Ordering::Relaxed),
+            false_positives: self.bloom_counters.false_positives.load(Ordering::Relaxed),
         }
+    }
 
-        let (range_start, range_end) = index_to_range(key, &self.index);
-        let range_end = range_end.unwrap_or(self.file_size);
+    /// Checks the bloom filter for `key`, counting the answer
+    /// Whether the bloom filter lets `key` through, without counting it in [`SSTable::bloom_stats`]. Always true
+    /// without a filter
+    pub fn may_contain(&self, key: &Key) -> bool {
+        self.bloom_filter
+            .as_ref()
+            .is_none_or(|bloom_filter| bloom_filter.check(key))
+    }
 
-        let size = range_end - range_start;
-        let mut buffer = vec![0u8; size as usize];
-        self.file.read_exact_at(&mut buffer, range_start)?;
+    /// Memory taken by the bloom filter, 0 without one
+    pub fn bloom_filter_bytes(&self) -> u64 {
+        self.bloom_bytes
+    }
+
+    fn bloom_check(&self, key: &Key) -> bool {
+        let Some(bloom_filter) = &self.bloom_filter else {
+            return true;
+        };
+        self.bloom_counters.checks.fetch_add(1, Ordering::Relaxed);
+        let maybe_present = bloom_filter.check(key);
+        if !maybe_present {
+            self.bloom_counters
+                .negatives
+                .fetch_add(1, Ordering::Relaxed);
+            increment_counter!("kv_bloom_filter_useful_total", 1);
+        }
+
+        maybe_present
+    }
+
+    /// A key let through by the bloom filter wasn't in the table
+    fn bloom_false_positive(&self) {
+        self.bloom_counters
+            .false_positives
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Forces the table content to disk
+    pub fn sync(&self) -> Result<(), Error> {
+        self.files.get(self.id, &self.file_path)?.sync_all()?;
+
+        Ok(())
+    }
+
+    /// Entries expired at `now` are reported as tombstones
+    #[cfg(test)]
+    pub fn find(&self, key: &Key, now: u64) -> Result<functions::ReadOutcome, Error> {
+        Ok(match self.find_entry(key)? {
+            Some(entry) => entry.read_outcome(now),
+            None => functions::ReadOutcome::NotFound,
+        })
+    }
+
+    /// Returns the entry stored for `key` or the range tombstone deleting it, whichever is newer
+    pub fn find_entry(&self, key: &Key) -> Result<Option<KVMemoryRepr>, Error> {
+        self.find_entry_traced(key, &mut ReadTrace::default())
+    }
 
-        let entries = serialization::deserialize_entries_from_bytes(&buffer, "sstable")?;
-        // TODO: test just a linear search as with small arrays it exploits cache locality or pipelining or whatever
-        let maybe_entry_index = entries.binary_search_by_key(key, |t| *t.key()).ok();
+    /// Same as [`SSTable::find_entry`], adding the bloom filter answer and the bytes read to `trace`
+    pub fn find_entry_traced(
+        &self,
+        key: &Key,
+        trace: &mut ReadTrace,
+    ) -> Result<Option<KVMemoryRepr>, Error> {
+        if !self.in_key_range(key) {
+            return Ok(None);
+        }
 
-        // it's important to distinguish between finding none and not finding anything
-        let result = match maybe_entry_index {
-            Some(i) => match *entries[i].value() {
-                Some(value) => FindResult::Found(value),
-                None => FindResult::Tombstone,
-            },
-            None => FindResult::None,
+        let point = if self.bloom_check(key) {
+            let block = self.block_of(key);
+            trace.bloom_maybes += 1;
+            trace.bytes_read += self.index.get(block).map_or(0, |handle| handle.len as u64);
+            let point = self.with_block_bytes(block, |bytes| {
+                Block::parse(bytes, self.record_format)?.find(key, self.order())
+            })?;
+            if point.is_none() {
+                self.bloom_false_positive();
+            }
+            point
+        } else {
+            None
         };
+        let range = serialization::newest_covering(&self.range_tombstones, key, self.order());
 
-        Ok(result)
+        Ok(serialization::newest(point.as_ref(), range).cloned())
+    }
+
+    /// Same as [`SSTable::find_entry`] for many keys, returning an entry for each of them (in the same order).
+    ///
+    /// Keys are visited in file order and keys sharing a block are served by a single read. Blocks are decoded into
+    /// the buffers of the thread, see [`with_scratch`].
+    pub fn find_entries(&self, keys: &[Key]) -> Result<Vec<Option<KVMemoryRepr>>, Error> {
+        let mut points: Vec<Option<KVMemoryRepr>> = vec![None; keys.len()];
+
+        let mut candidates: Vec<_> = keys
+            .iter()
+            .enumerate()
+            .filter(|(_, key)| self.in_key_range(key))
+            .filter(|(_, key)| self.bloom_check(key))
+            .map(|(i, key)| (self.block_of(key), i))
+            .collect();
+        candidates.sort_unstable();
+
+        with_scratch(|scratch| {
+            let mut current_block = None;
+            for (block, i) in candidates {
+                if current_block != Some(block) {
+                    let bytes =
+                        self.block_bytes(block, &mut scratch.stored, &mut scratch.decompressed)?;
+                    Block::parse(bytes, self.record_format)?.entries_into(&mut scratch.entries)?;
+                    current_block = Some(block);
+                }
+
+                points[i] = find_in_entries(&keys[i], &scratch.entries, self.order()).cloned();
+                if points[i].is_none() {
+                    self.bloom_false_positive();
+                }
+            }
+
+            Ok::<_, Error>(())
+        })?;
+
+        let results = keys
+            .iter()
+            .zip(points)
+            .map(|(key, point)| {
+                let range =
+                    serialization::newest_covering(&self.range_tombstones, key, self.order());
+                serialization::newest(point.as_ref(), range).cloned()
+            })
+            .collect();
+
+        Ok(results)
+    }
+
+    /// Every entry of the table, range tombstones last
+    pub fn entries(&self) -> Result<Vec<KVMemoryRepr>, Error> {
+        self.with_content(|content| {
+            let (data, record_format) = file_header::strip_header(FileKind::Table, content)?;
+            let parts = format::decode_table(data, record_format)?;
+            let mut entries = parts.points()?;
+            entries.extend(parts.ranges);
+
+            Ok(entries)
+        })
+    }
+
+    /// Describes the table and its block index, decoding every entry with `with_entries`
+    pub fn dump(&self, with_entries: bool) -> Result<TableDump, Error> {
+        let entries = if with_entries {
+            Some(self.entries()?.iter().map(Change::from).collect())
+        } else {
+            None
+        };
+
+        Ok(TableDump {
+            id: self.id,
+            path: self.file_path.clone(),
+            file_size: self.file_size,
+            entry_count: self.entry_count,
+            tombstone_count: self.tombstone_count,
+            discarded_entries: self.discarded_entries,
+            key_range: self.key_range,
+            blocks: self
+                .index
+                .iter()
+                .map(|handle| BlockDump {
+                    first_key: handle.first_key,
+                    offset: handle.offset,
+                    len: handle.len,
+                })
+                .collect(),
+            entries,
+        })
+    }
+
+    /// Decodes the whole file, checking its layout, the key order and the bloom filter
+    pub fn verify(&self) -> TableReport {
+        let mut anomalies = Vec::new();
+
+        let points = self
+            .with_content(|content| {
+                let (data, record_format) = file_header::strip_header(FileKind::Table, content)?;
+                format::check_table(data, record_format, self.order(), &mut anomalies)
+            })
+            .unwrap_or_else(|e| {
+                anomalies.push(Anomaly::Unreadable(format!("{e:?}")));
+                Vec::new()
+            });
+
+        if let Some(bloom_filter) = &self.bloom_filter {
+            for entry in &points {
+                if !bloom_filter.check(entry.key()) {
+                    anomalies.push(Anomaly::BloomMissingKey { key: *entry.key() });
+                }
+            }
+        }
+
+        TableReport {
+            id: self.id,
+            entry_count: points.len() as u64,
+            anomalies,
+        }
+    }
+
+    /// Runs `f` on the whole file, mapped or read into a buffer
+    fn with_content<T>(&self, f: impl FnOnce(&[u8]) -> Result<T, Error>) -> Result<T, Error> {
+        #[cfg(feature = "mmap")]
+        if let Some(map) = &self.map {
+            return f(map);
+        }
+
+        f(&functions::read_file(
+            &*self.files.get(self.id, &self.file_path)?,
+            self.file_size,
+        )?)
+    }
+
+    /// Share of the data blocks covering `start..end`, assuming keys are spread evenly within every block. No I/O.
+    ///
+    /// Spread is measured on the numeric value of the keys, a rough guess for comparators other than the built-in ones
+    pub fn approximate_range(&self, start: Key, end: Key) -> RangeEstimate {
+        let order = self.order();
+        let data_bytes: u64 = self.index.iter().map(|handle| handle.len as u64).sum();
+        let Some((_, max)) = self.key_range else {
+            return RangeEstimate::default();
+        };
+        if !order.lt(&start, &end) || data_bytes == 0 {
+            return RangeEstimate::default();
+        }
+
+        // Number of keys in `a..b`, as plain integers since the comparator gives no distance
+        let span = |a: Key, b: Key| {
+            if order.lt(&a, &b) {
+                a.abs_diff(b) as u128
+            } else {
+                0
+            }
+        };
+        let mut bytes = 0.0;
+        for (i, handle) in self.index.iter().enumerate().skip(self.block_of(&start)) {
+            if !order.lt(&handle.first_key, &end) {
+                break;
+            }
+
+            // Keys of the block are in `first_key..next.first_key`, or `first_key..=max` for the last one
+            let covered_start = order.max(start, handle.first_key);
+            let (block_len, covered_len) = match self.index.get(i + 1) {
+                Some(next) => (
+                    span(handle.first_key, next.first_key),
+                    span(covered_start, order.min(end, next.first_key)),
+                ),
+                None if order.lt(&max, &end) => (
+                    span(handle.first_key, max) + 1,
+                    span(covered_start, max) + u128::from(!order.lt(&max, &covered_start)),
+                ),
+                None => (span(handle.first_key, max) + 1, span(covered_start, end)),
+            };
+            let share = (covered_len as f64 / block_len.max(1) as f64).min(1.0);
+            bytes += handle.len as f64 * share;
+        }
+
+        RangeEstimate {
+            entries: (self.entry_count as f64 * bytes / data_bytes as f64).round() as u64,
+            bytes: bytes.round() as u64,
+        }
+    }
+
+    /// Number of data blocks, empty tables count as a single empty block
+    pub fn block_count(&self) -> usize {
+        self.index.len().max(1)
+    }
+
+    /// Block that contains `key`, if the table has it
+    pub fn block_of(&self, key: &Key) -> usize {
+        match self
+            .index
+            .binary_search_by(|handle| self.order().cmp(&handle.first_key, key))
+        {
+            Ok(i) => i,
+            Err(i) => i.saturating_sub(1),
+        }
+    }
+
+    pub fn read_block(&self, block: usize) -> Result<Vec<KVMemoryRepr>, Error> {
+        self.with_block_bytes(block, |bytes| {
+            Block::parse(bytes, self.record_format)?.entries()
+        })
+    }
+
+    /// Runs `f` on the decompressed records of `block`, read with the buffers of the thread, see [`with_scratch`]
+    fn with_block_bytes<T>(
+        &self,
+        block: usize,
+        f: impl FnOnce(&[u8]) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        with_scratch(|scratch| {
+            f(self.block_bytes(block, &mut scratch.stored, &mut scratch.decompressed)?)
+        })
+    }
+
+    /// The decompressed records of `block`, sliced from the mapped file or read into `stored`. Compressed blocks are
+    /// decompressed into `decompressed`
+    fn block_bytes<'a>(
+        &'a self,
+        block: usize,
+        stored: &'a mut Vec<u8>,
+        decompressed: &'a mut Vec<u8>,
+    ) -> Result<&'a [u8], Error> {
+        let Some(handle) = self.index.get(block) else {
+            return Ok(&[]);
+        };
+        let start = HEADER_BYTES + handle.offset;
+        let end = start + handle.len as u64;
+
+        #[cfg(feature = "mmap")]
+        if let Some(map) = &self.map {
+            let stored = &map[start as usize..end as usize];
+            return format::decompress_into(self.compression, stored, decompressed);
+        }
+
+        stored.clear();
+        stored.resize((end - start) as usize, 0);
+        let file = self.files.get(self.id, &self.file_path)?;
+        functions::read_data_at_offset(&file, stored, start)?;
+
+        format::decompress_into(self.compression, stored, decompressed)
+    }
+}
+
+/// `None` (reads then go through `pread`) if the file can't be mapped, e.g. because it's empty or in memory
+#[cfg(feature = "mmap")]
+fn map_file(file: &Handle) -> Option<memmap2::Mmap> {
+    let file = match file {
+        Handle::Disk(file) => file,
+        #[cfg(all(feature = "uring", target_os = "linux"))]
+        Handle::Uring(file) => file,
+        Handle::Memory(_) => return None,
+    };
+
+    // SAFETY: tables are never modified once written, and deleted only once no `SSTable` refers to them
+    match unsafe { memmap2::Mmap::map(file) } {
+        Ok(map) => Some(map),
+        Err(e) => {
+            log::debug!("failed to map sstable, falling back to pread: {e:?}");
+            None
+        }
+    }
+}
+
+/// Decodes the sorted records in `buffer` one at a time, stopping as soon as `key` is passed
+fn find_in_bytes(
+    key: &Key,
+    buffer: &[u8],
+    format: RecordFormat,
+    order: &KeyOrder,
+) -> Result<Option<KVMemoryRepr>, Error> {
+    let mut remaining = buffer;
+
+    while !remaining.is_empty() {
+        let (entry, rest) = serialization::deserialize(remaining, format)?;
+
+        if entry.key() == key {
+            return Ok(Some(entry));
+        }
+        if order.lt(key, entry.key()) {
+            break;
+        }
+
+        remaining = rest;
+    }
+
+    Ok(None)
+}
+
+fn find_in_entries<'a>(
+    key: &Key,
+    entries: &'a [KVMemoryRepr],
+    order: &KeyOrder,
+) -> Option<&'a KVMemoryRepr> {
+    // TODO: test just a linear search as with small arrays it exploits cache locality or pipelining or whatever
+    let maybe_entry_index = entries.binary_search_by(|t| order.cmp(t.key(), key)).ok();
+
+    // it's important to distinguish between finding none and not finding anything
+    maybe_entry_index.map(|i| &entries[i])
+}
+
+/// Newest entry of a key across SSTables visited newest first.
+///
+/// Every write shard keeps its own copy of a range tombstone, so the copy of another shard can sit in a newer table
+/// than a point written after the range: the lookup only ends at a point entry.
+#[derive(Default)]
+pub struct KeyLookup {
+    point: Option<KVMemoryRepr>,
+    range: Option<KVMemoryRepr>,
+}
+
+impl KeyLookup {
+    /// Feeds the entry found in the next table, returns whether older tables can be skipped
+    pub fn visit(&mut self, entry: Option<KVMemoryRepr>) -> bool {
+        match entry {
+            Some(range) if range.is_range_tombstone() => {
+                if self
+                    .range
+                    .as_ref()
+                    .is_none_or(|kept| kept.sequence() < range.sequence())
+                {
+                    self.range = Some(range);
+                }
+                false
+            }
+            Some(point) => {
+                self.point = Some(point);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether the range tombstone found so far is newer than every entry of `table`, which can then be skipped
+    pub fn shadows(&self, table: &SSTable) -> bool {
+        self.range
+            .as_ref()
+            .is_some_and(|range| range.sequence() > table.max_sequence())
+    }
+
+    pub fn finish(self) -> Option<KVMemoryRepr> {
+        serialization::newest(self.point.as_ref(), self.range.as_ref()).cloned()
     }
 }
 
@@ -66,23 +665,169 @@ impl CleanableFile for SSTable {
     fn path(&self) -> PathBuf {
         self.file_path().to_owned()
     }
+
+    fn size(&self) -> u64 {
+        self.file_size
+    }
 }
 
-type Index = Vec<(Key, u64)>;
+impl Drop for SSTable {
+    fn drop(&mut self) {
+        self.files.forget(self.id);
+    }
+}
 
-fn log_content_to_index_and_data(
-    log_file_content: &[u8],
-) -> Result<(Index, Vec<u8>, BloomType), Error> {
-    let mut log_file_entries =
-        serialization::deserialize_entries_from_bytes(log_file_content, "log_file")?;
+/// Serialized table with the in-memory structures describing it
+pub(crate) struct TableContent {
+    index: Vec<BlockHandle>,
+    /// Content of the table file, empty for tables read back from disk
+    data: Vec<u8>,
+    compression: Compression,
+    record_format: RecordFormat,
+    summary: TableSummary,
+}
 
-    // This is a STABLE sort (important)
-    log_file_entries.sort();
+/// Bloom filter answers, see [`SSTable::bloom_stats`]
+#[derive(Default)]
+struct BloomCounters {
+    checks: AtomicU64,
+    negatives: AtomicU64,
+    false_positives: AtomicU64,
+}
+
+/// What's kept in memory about the entries of a table
+struct TableSummary {
+    /// Of the point keys, `None` when it's not needed
+    bloom_filter: Option<BloomType>,
+    /// Size of the serialized `bloom_filter`
+    bloom_bytes: u64,
+    /// `u64::MAX` until an entry is added
+    min_sequence: u64,
+    max_sequence: u64,
+    /// First and last point entry, added in key order
+    point_range: Option<(Key, Key)>,
+    entry_count: u64,
+    tombstone_count: u64,
+    discarded_entries: u64,
+    range_tombstones: Vec<KVMemoryRepr>,
+}
+
+impl TableSummary {
+    fn new(bloom_filter: Option<BloomType>) -> Self {
+        Self {
+            bloom_filter,
+            bloom_bytes: 0,
+            min_sequence: u64::MAX,
+            max_sequence: 0,
+            point_range: None,
+            entry_count: 0,
+            tombstone_count: 0,
+            discarded_entries: 0,
+            range_tombstones: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, entry: &KVMemoryRepr) {
+        self.min_sequence = self.min_sequence.min(entry.sequence());
+        self.max_sequence = self.max_sequence.max(entry.sequence());
+
+        if entry.is_range_tombstone() {
+            self.range_tombstones.push(entry.clone());
+            return;
+        }
 
-    // Entries will be deduplicated and sorted
+        self.entry_count += 1;
+        if entry.value().is_none() {
+            self.tombstone_count += 1;
+        }
+        if let Some(bloom_filter) = &mut self.bloom_filter {
+            bloom_filter.set(entry.key());
+        }
+        let key = *entry.key();
+        self.point_range = Some(
+            self.point_range
+                .map_or((key, key), |(first, _)| (first, key)),
+        );
+    }
+
+    /// Summary of a table written with its sequences section, out of its footer and its last block
+    fn from_parts(
+        parts: &TableParts,
+        bloom_filter: Option<BloomType>,
+        (min_sequence, max_sequence): (u64, u64),
+    ) -> Result<Self, Error> {
+        let point_range = match (parts.index.first(), parts.last_point_key()?) {
+            (Some(first), Some(last)) => Some((first.first_key, last)),
+            _ => None,
+        };
+
+        Ok(Self {
+            bloom_filter,
+            bloom_bytes: 0,
+            min_sequence,
+            max_sequence,
+            point_range,
+            entry_count: parts.footer.entry_count,
+            tombstone_count: parts.footer.tombstone_count,
+            discarded_entries: parts.footer.discarded_entries,
+            range_tombstones: parts.ranges.clone(),
+        })
+    }
+
+    /// Summary of a table out of every entry, with a new bloom filter if `with_bloom`
+    fn rebuild(parts: &TableParts, with_bloom: bool) -> Result<Self, Error> {
+        let points = parts.points()?;
+
+        // Tables written before the parameters were stored get the default ones
+        let bloom_params = parts
+            .bloom_params
+            .unwrap_or_else(|| TableOptions::default().bloom_params(points.len()));
+        let bloom_filter = with_bloom.then(|| new_bloom(&bloom_params, points.len()));
+        let mut summary = TableSummary::new(bloom_filter);
+        for entry in points.iter().chain(&parts.ranges) {
+            summary.add(entry);
+        }
+        summary.discarded_entries = parts.footer.discarded_entries;
+        summary.bloom_bytes = summary
+            .bloom_filter
+            .as_ref()
+            .map_or(0, |bloom_filter| bloom_filter.to_bytes().len() as u64);
+
+        Ok(summary)
+    }
+
+    /// Lowest and highest sequence, both 0 when no entry was added
+    fn sequences(&self) -> (u64, u64) {
+        (self.min_sequence.min(self.max_sequence), self.max_sequence)
+    }
+
+    /// Smallest and largest key in `order`, range tombstones included
+    fn key_range(&self, order: &KeyOrder) -> Option<(Key, Key)> {
+        self.range_tombstones
+            .iter()
+            // Empty ranges don't cover any key
+            .filter_map(|range| order.bounds(*range.key(), range.range_end()?))
+            .chain(self.point_range)
+            .reduce(|(min, max), (first, last)| (order.min(min, first), order.max(max, last)))
+    }
+}
+
+/// Keeps the entry with the highest sequence of every key, sorted by key in `order`, followed by the range tombstones
+fn newest_entries(entries: Vec<KVMemoryRepr>, order: &KeyOrder) -> Vec<KVMemoryRepr> {
+    let (ranges, mut points): (Vec<_>, Vec<_>) = entries
+        .into_iter()
+        .partition(|entry| entry.is_range_tombstone());
+
+    points.sort_by(|a, b| {
+        order
+            .cmp(a.key(), b.key())
+            .then(a.sequence().cmp(&b.sequence()))
+    });
+
+    // Entries will be deduplicated and sorted, the highest sequence is the last one for each key
     let mut entries: Vec<KVMemoryRepr> = Vec::new();
 
-    for entry in log_file_entries.into_iter() {
+    for entry in points.into_iter() {
         if let Some(last) = entries.last_mut()
             && last.key() == entry.key()
         {
@@ -93,91 +838,777 @@ fn log_content_to_index_and_data(
         entries.push(entry);
     }
 
-    entries_to_index_and_data(&entries)
+    entries.extend(ranges);
+    entries
 }
 
-fn entries_to_index_and_data(
-    entries: &[KVMemoryRepr],
-) -> Result<(Index, Vec<u8>, BloomType), Error> {
-    let index_size = (FILE_SIZE_BYTES / TABLE_TO_INDEX_RATIO).max(1);
-    let index_interval = entries.len() / index_size as usize;
-    let mut index = Vec::new();
-    let mut sstable_data = Vec::new();
-    let mut total_offset = 0u64;
+/// Writes the file header and the table into a temporary file which is renamed once fully on disk.
+///
+/// A crash can therefore only leave behind `*.tmp` files, never a partial table.
+fn create_sstable_file(
+    files: &TableFiles,
+    id: u64,
+    sstable_data: &[u8],
+) -> Result<(Handle, PathBuf, u64), Error> {
+    let created = write_sstable_file(files, id, sstable_data);
+    files.record_write(&created);
 
-    let mut bloom_filter = Bloom::new_for_fp_rate(entries.len(), FP_RATE).unwrap();
+    created
+}
 
-    for (i, entry) in entries.iter().enumerate() {
-        let serialized = serialization::serialize(entry)?;
-        let entry_size = serialized.len() as u64;
+fn write_sstable_file(
+    files: &TableFiles,
+    id: u64,
+    sstable_data: &[u8],
+) -> Result<(Handle, PathBuf, u64), Error> {
+    let storage = files.storage();
+    let sstable_file_size = HEADER_BYTES + sstable_data.len() as u64;
+    let dir = files.ensure_dir_of(id)?;
+    let tmp_path = dir.join(format!("{id}.{TMP_EXTENSION}"));
+    let sstable_path = dir.join(id.to_string());
 
-        if index_interval > 0 && i % index_interval == 0 {
-            index.push((*entry.key(), total_offset));
-        }
+    let mut content = Vec::with_capacity(sstable_file_size as usize);
+    content.extend_from_slice(&FileHeader::new(FileKind::Table).encode());
+    content.extend_from_slice(sstable_data);
 
-        sstable_data.extend_from_slice(&serialized);
-        total_offset += entry_size;
+    let sstable_file = storage
+        .create(&tmp_path, sstable_file_size)
+        .map_err(|e| match e {
+            Error::IO(e) if e.kind() == io::ErrorKind::ReadOnlyFilesystem => {
+                log::error!("failed to create table file {tmp_path:?}: {e:?}");
+                Error::StorageUnavailable
+            }
+            e => e,
+        })?;
+    let written = functions::write_file(&sstable_file, &content, sstable_file_size)
+        .and_then(|_| Ok(sstable_file.sync_all()?))
+        .and_then(|_| storage.rename(&tmp_path, &sstable_path));
+    if let Err(e) = written {
+        // Not waiting for the next open, the disk may be full
+        cleanup::remove_file_logged(&**storage, &tmp_path);
+        return Err(e);
+    }
+    storage.sync_dir(dir)?;
 
-        bloom_filter.set(entry.key());
+    Ok((sstable_file, sstable_path, sstable_file_size))
+}
+
+/// Removes what writing table `id` may have left behind, e.g. when its writer panicked
+pub fn remove_unfinished_table(files: &TableFiles, id: u64) {
+    let dir = files.dir_of(id);
+    for path in [
+        dir.join(format!("{id}.{TMP_EXTENSION}")),
+        dir.join(id.to_string()),
+    ] {
+        match files.storage().remove(&path) {
+            Err(Error::IO(e)) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => log::error!("failed to remove file {path:?}: {e:?}"),
+            Ok(()) => {}
+        }
     }
+    files.forget(id);
+    files.remove_cold(&[id]);
+}
 
-    Ok((index, sstable_data, bloom_filter))
+/// Writes a table out of `entries`, in any order and from any number of tables and logs, keeping the newest entry
+/// of every key. Returns the id of the table.
+pub fn rebuild_table(
+    files: &TableFiles,
+    mut entries: Vec<KVMemoryRepr>,
+    options: TableOptions,
+) -> Result<u64, Error> {
+    // Every write shard had a copy of the range tombstones
+    entries.sort_by_key(|entry| entry.sequence());
+    serialization::dedup_range_copies(&mut entries);
+
+    let table_content =
+        TableBuilder::from_entries(&newest_entries(entries, files.order()), options)?;
+    let id = files.new_table_id();
+    create_sstable_file(files, id, &table_content.data)?;
+
+    Ok(id)
 }
 
-fn create_sstable_file(
-    id: u64,
-    sstables_dir: &Path,
-    sstable_data: &[u8],
-) -> Result<(File, PathBuf, u64), Error> {
-    let sstable_file_size = sstable_data.len() as u64;
-    let sstable_path = sstables_dir.join(format!("{id}"));
-    let sstable_file = functions::create_file(&sstable_path, sstable_file_size)?;
-    functions::write_file(&sstable_file, sstable_data, sstable_file_size)?;
+/// Everything that can be decoded from a (possibly damaged) table file, and whether anything was skipped
+pub fn salvage_table_file(content: &[u8]) -> (Vec<KVMemoryRepr>, bool) {
+    if let Ok(header) = FileHeader::decode(FileKind::Table, content) {
+        return format::salvage_table(&content[HEADER_BYTES as usize..], header.record_format());
+    }
 
-    Ok((sstable_file, sstable_path, sstable_file_size))
+    // Written by this version as far as we can tell
+    let (entries, _) = format::salvage_table(
+        content.get(HEADER_BYTES as usize..).unwrap_or_default(),
+        RecordFormat::CURRENT,
+    );
+    (entries, true)
 }
 
-pub fn log_file_to_sstable(sstables_dir: &Path, log_file: &File) -> Result<SSTable, Error> {
-    let log_file_content = functions::read_file(log_file, FILE_SIZE_BYTES)?;
-    let (index, sstable_data, bloom_filter) = log_content_to_index_and_data(&log_file_content)?;
+/// Deletes tables that were being written when the database crashed
+pub fn remove_tmp_files(storage: &dyn Storage, sstables_dir: &Path) -> Result<(), Error> {
+    for file in storage.list(sstables_dir)? {
+        let path = file.path;
+
+        if path.extension().is_some_and(|ext| ext == TMP_EXTENSION) {
+            log::warn!("removing partially written sstable {path:?}");
+            cleanup::remove_file_logged(storage, &path);
+        }
+    }
+
+    Ok(())
+}
 
-    let id: u64 = rand::random();
-    let (sstable_file, sstable_path, sstable_file_size) =
-        create_sstable_file(id, sstables_dir, &sstable_data)?;
+/// Writes a table out of `entries`, whose point entries must be sorted by key in the order of `files`
+pub fn write_table(
+    files: &Arc<TableFiles>,
+    entries: &[KVMemoryRepr],
+    options: TableOptions,
+) -> Result<SSTable, Error> {
+    let table_content = TableBuilder::from_entries(entries, options)?;
+    let id = files.new_table_id();
+    let (sstable_file, _, sstable_file_size) = create_sstable_file(files, id, &table_content.data)?;
 
-    Ok(SSTable {
+    Ok(SSTable::new(
         id,
-        index,
-        file: sstable_file,
-        file_path: sstable_path,
-        file_size: sstable_file_size,
-        bloom_filter,
-    })
-}
-
-fn index_to_range(key: &Key, index: &Index) -> (u64, Option<u64>) {
-    let mut start_offset = 0;
-    let mut end_offset = None;
-
-    // Binary search to find the appropriate range in the index
-    let pos = index.binary_search_by_key(&key, |(k, _)| k);
-
-    match pos {
-        Ok(idx) => {
-            // Exact match found
-            start_offset = index[idx].1;
-            end_offset = index.get(idx + 1).map(|(_, offset)| *offset);
-        }
-        Err(idx) => {
-            // Key would be inserted at idx
-            if idx > 0 {
-                start_offset = index[idx - 1].1;
+        files,
+        sstable_file,
+        sstable_file_size,
+        table_content,
+    ))
+}
+
+/// Writes the table of a rotated log out of its in-memory `entries`, keeping the entry with the highest sequence of
+/// every key whatever their order
+pub fn memtable_to_sstable(
+    files: &Arc<TableFiles>,
+    entries: Vec<KVMemoryRepr>,
+    options: TableOptions,
+) -> Result<SSTable, Error> {
+    let span = span!("memtable_to_sstable", entries = entries.len(); table_id);
+    let table = write_table(files, &newest_entries(entries, files.order()), options)?;
+    record!(span, table_id = table.id());
+
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cleanup::Reaper;
+    use crate::clock::{Clock, SystemClock};
+    use crate::functions::ReadOutcome;
+    use crate::options::BloomSize;
+    use crate::serialization::SerializationError;
+    use crate::storage::{DiskStorage, MemStorage};
+    use std::fs;
+    use std::sync::{Condvar, Mutex};
+
+    #[test]
+    fn test_create_sstable_file_writes_exact_data() {
+        let dir = PathBuf::from(format!("./test-dbs/{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let files = Arc::new(TableFiles::new(Arc::new(DiskStorage), dir.clone(), 1));
+
+        let entries: Vec<_> = (0..100)
+            .map(|k| KVMemoryRepr::new(k, (k % 3 != 0).then_some(k * 10), k))
+            .collect();
+        let data = TableBuilder::from_entries(&entries, TableOptions::default())
+            .unwrap()
+            .data;
+
+        let (_, path, size) = create_sstable_file(&files, 42, &data).unwrap();
+
+        assert_eq!(path, dir.join("42"));
+        let written = fs::read(&path).unwrap();
+        assert_eq!(size, HEADER_BYTES + data.len() as u64);
+        assert_eq!(written.len() as u64, size);
+        assert_eq!(&written[HEADER_BYTES as usize..], data);
+        assert!(!dir.join("42.tmp").exists());
+    }
+
+    #[test]
+    fn test_reaper_waits_for_release() {
+        let dir = PathBuf::from(format!("./test-dbs/{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let files = Arc::new(TableFiles::new(Arc::new(DiskStorage), dir.clone(), 1));
+
+        let entries: Vec<_> = (0..10).map(|k| KVMemoryRepr::new(k, Some(k), k)).collect();
+        let data = TableBuilder::from_entries(&entries, TableOptions::default())
+            .unwrap()
+            .data;
+        create_sstable_file(&files, 1, &data).unwrap();
+
+        let sstable = Arc::new(SSTable::open(&files, 1).unwrap());
+        let reader_copy = sstable.clone();
+
+        let clock = Arc::new(SteppedClock::default());
+        let reaper = Reaper::new(Arc::new(DiskStorage), clock.clone());
+        reaper.delete(sstable);
+
+        // Found in use, then again on the first retry
+        clock.wait_for_sleep(1);
+        assert!(dir.join("1").exists());
+        clock.step();
+        clock.wait_for_sleep(2);
+        assert!(dir.join("1").exists());
+        assert!(matches!(
+            reader_copy.find(&3, 0).unwrap(),
+            ReadOutcome::Found(3)
+        ));
+
+        drop(reader_copy);
+        clock.step();
+        reaper.stop();
+        assert!(!dir.join("1").exists());
+
+        // Still in use at the deadline, left for the orphan cleanup
+        create_sstable_file(&files, 2, &data).unwrap();
+        let sstable = Arc::new(SSTable::open(&files, 2).unwrap());
+        let reaper = Reaper::new(Arc::new(DiskStorage), Arc::new(SystemClock));
+        reaper.delete(sstable.clone());
+        assert_eq!(reaper.shutdown(std::time::Instant::now()), 1);
+        assert_eq!(reaper.shutdown(std::time::Instant::now()), 0);
+        assert!(dir.join("2").exists());
+    }
+
+    /// Clock whose sleeps only end when the test steps it, so that retries happen exactly when the test wants them
+    #[derive(Default)]
+    struct SteppedClock {
+        /// Sleeps started, and sleeps allowed to end
+        sleeps: Mutex<(u64, u64)>,
+        changed: Condvar,
+    }
+
+    impl SteppedClock {
+        fn wait_for_sleep(&self, count: u64) {
+            let sleeps = self.sleeps.lock().unwrap();
+            drop(
+                self.changed
+                    .wait_while(sleeps, |(started, _)| *started < count),
+            );
+        }
+
+        /// Ends the sleeps started so far
+        fn step(&self) {
+            let mut sleeps = self.sleeps.lock().unwrap();
+            sleeps.1 = sleeps.0;
+            self.changed.notify_all();
+        }
+    }
+
+    impl Clock for SteppedClock {
+        fn now_millis(&self) -> u64 {
+            0
+        }
+
+        fn sleep(&self, _duration: std::time::Duration) {
+            let mut sleeps = self.sleeps.lock().unwrap();
+            sleeps.0 += 1;
+            let ticket = sleeps.0;
+            self.changed.notify_all();
+            drop(
+                self.changed
+                    .wait_while(sleeps, |(_, allowed)| *allowed < ticket),
+            );
+        }
+    }
+
+    #[test]
+    fn test_flush_keeps_highest_sequence() {
+        let files = Arc::new(TableFiles::new(
+            Arc::new(MemStorage::new()),
+            PathBuf::from("sstables"),
+            1,
+        ));
+
+        // Out of sequence order, as concurrent writers can leave them
+        let entries = [(1, 10, 5), (2, 20, 1), (1, 11, 3), (2, 21, 2)]
+            .into_iter()
+            .map(|(k, v, seq)| KVMemoryRepr::new(k, Some(v), seq))
+            .collect();
+
+        let table = memtable_to_sstable(&files, entries, TableOptions::default()).unwrap();
+        let values: Vec<_> = table
+            .entries()
+            .unwrap()
+            .iter()
+            .map(|e| (*e.key(), *e.value()))
+            .collect();
+        assert_eq!(values, [(1, Some(10)), (2, Some(21))]);
+        assert_eq!(table.max_sequence(), 5);
+        assert_eq!(table.min_sequence(), 2);
+    }
+
+    #[test]
+    fn test_sequences_section() {
+        let files = Arc::new(TableFiles::new(
+            Arc::new(MemStorage::new()),
+            PathBuf::from("sstables"),
+            2,
+        ));
+
+        let entries: Vec<_> = (0..100)
+            .map(|i| KVMemoryRepr::new(i, Some(i), 1000 - i))
+            .collect();
+        let mut data = TableBuilder::from_entries(&entries, TableOptions::default())
+            .unwrap()
+            .data;
+        let parts = format::decode_table(&data, RecordFormat::CURRENT).unwrap();
+        assert_eq!(parts.sequences, Some((901, 1000)));
+        create_sstable_file(&files, 1, &data).unwrap();
+        let table = SSTable::open(&files, 1).unwrap();
+        assert_eq!((table.min_sequence(), table.max_sequence()), (901, 1000));
+
+        // Tables written before the section get the sequences out of their entries
+        let footer_start = data.len() - format::FOOTER_BYTES;
+        data.drain(footer_start - 21..footer_start);
+        assert_eq!(
+            format::decode_table(&data, RecordFormat::CURRENT)
+                .unwrap()
+                .sequences,
+            None
+        );
+        create_sstable_file(&files, 2, &data).unwrap();
+        let table = SSTable::open(&files, 2).unwrap();
+        assert_eq!((table.min_sequence(), table.max_sequence()), (901, 1000));
+    }
+
+    #[test]
+    fn test_bloom_seeds() {
+        let files = Arc::new(
+            TableFiles::new(Arc::new(MemStorage::new()), PathBuf::from("sstables"), 2)
+                .with_bloom_recovery(BloomRecovery::Rebuild),
+        );
+        let entries: Vec<_> = (0..1000)
+            .map(|k| KVMemoryRepr::new(k * 2, Some(k), k))
+            .collect();
+
+        let mut filters = Vec::new();
+        for (id, seed) in [(1, [1; 32]), (2, [2; 32])] {
+            let options = TableOptions {
+                bloom_size: BloomSize::BitsPerKey(10),
+                bloom_seed: Some(seed),
+                ..Default::default()
+            };
+            let content = TableBuilder::from_entries(&entries, options).unwrap();
+            let bloom_filter = content.summary.bloom_filter.as_ref().unwrap().to_bytes();
+            let parts = format::decode_table(&content.data, RecordFormat::CURRENT).unwrap();
+            assert_eq!(
+                parts.bloom_params,
+                Some(BloomParams {
+                    seed,
+                    bitmap_bytes: 1250
+                })
+            );
+            create_sstable_file(&files, id, &content.data).unwrap();
+
+            // Rebuilt out of the entries with the stored parameters, the filter is the one written
+            let table = SSTable::open(&files, id).unwrap();
+            assert_eq!(
+                table.bloom_filter.as_ref().unwrap().to_bytes(),
+                bloom_filter
+            );
+            for k in 0..1000 {
+                let entry = table.find_entry(&(k * 2)).unwrap().unwrap();
+                assert_eq!(*entry.value(), Some(k));
+                assert!(table.find_entry(&(k * 2 + 1)).unwrap().is_none());
+            }
+            filters.push(bloom_filter);
+        }
+        assert_ne!(filters[0], filters[1]);
+
+        // Without a seed, every table draws its own
+        let options = TableOptions::default();
+        assert_ne!(options.bloom_params(10).seed, options.bloom_params(10).seed);
+    }
+
+    #[test]
+    fn test_key_range_skips_tables() {
+        let dir = PathBuf::from(format!("./test-dbs/{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+
+        // Tables covering [0, 100), [100, 200), ...
+        let files = Arc::new(TableFiles::new(Arc::new(DiskStorage), dir.clone(), 4));
+        let tables: Vec<_> = (0..10)
+            .map(|id| {
+                let entries: Vec<_> = (id * 100..(id + 1) * 100)
+                    .map(|k| KVMemoryRepr::new(k, Some(k), k))
+                    .collect();
+                let data = TableBuilder::from_entries(&entries, TableOptions::default())
+                    .unwrap()
+                    .data;
+                create_sstable_file(&files, id, &data).unwrap();
+                SSTable::open(&files, id).unwrap()
+            })
+            .collect();
+
+        let candidates: Vec<_> = tables.iter().filter(|t| t.in_key_range(&550)).collect();
+        assert_eq!(candidates.len(), 1);
+        assert!(matches!(
+            candidates[0].find(&550, 0).unwrap(),
+            ReadOutcome::Found(550)
+        ));
+        assert!(tables.iter().all(|t| !t.in_key_range(&1000)));
+        assert!(matches!(
+            tables[0].find(&1000, 0).unwrap(),
+            ReadOutcome::NotFound
+        ));
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn test_mmap_matches_pread() {
+        let dir = PathBuf::from(format!("./test-dbs/{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut entries: Vec<_> = (0..5000)
+            .map(|k| KVMemoryRepr::new(k * 2, (k % 7 != 0).then_some(k), k))
+            .collect();
+        entries.push(KVMemoryRepr::range_tombstone(100, 200, 10_000));
+        let data = TableBuilder::from_entries(&entries, TableOptions::default())
+            .unwrap()
+            .data;
+        let files = Arc::new(TableFiles::new(Arc::new(DiskStorage), dir.clone(), 4));
+        create_sstable_file(&files, 1, &data).unwrap();
+
+        let mapped = SSTable::open(&files, 1).unwrap();
+        assert!(mapped.map.is_some());
+        let mut pread = SSTable::open(&files, 1).unwrap();
+        pread.map = None;
+
+        let keys: Vec<_> = (0..10_100).collect();
+        for key in &keys {
+            assert!(mapped.find_entry(key).unwrap() == pread.find_entry(key).unwrap());
+        }
+        assert!(mapped.find_entries(&keys).unwrap() == pread.find_entries(&keys).unwrap());
+        for block in 0..mapped.block_count() {
+            assert!(mapped.read_block(block).unwrap() == pread.read_block(block).unwrap());
+        }
+        assert!(mapped.entries().unwrap() == pread.entries().unwrap());
+    }
+
+    #[test]
+    fn test_compression_round_trip() {
+        let dir = PathBuf::from(format!("./test-dbs/{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let files = Arc::new(TableFiles::new(Arc::new(DiskStorage), dir.clone(), 4));
+
+        let mut entries: Vec<_> = (0..5000)
+            .map(|k| KVMemoryRepr::new(k * 2, (k % 7 != 0).then_some(k), k))
+            .collect();
+        entries.push(KVMemoryRepr::range_tombstone(100, 200, 10_000));
+
+        for (id, compression) in [(1, Compression::None), (2, Compression::Lz4)] {
+            let options = TableOptions {
+                compression,
+                ..Default::default()
+            };
+            let content = TableBuilder::from_entries(&entries, options).unwrap();
+            assert!(content.index.len() > 1);
+            create_sstable_file(&files, id, &content.data).unwrap();
+
+            let table = SSTable::open(&files, id).unwrap();
+            assert_eq!(table.compression, compression);
+            assert!(table.entries().unwrap() == entries);
+            for k in 0..5000 {
+                let found = table.find(&(k * 2), 0).unwrap();
+                if (50..100).contains(&k) || k % 7 == 0 {
+                    assert!(matches!(found, ReadOutcome::Deleted));
+                } else {
+                    assert!(matches!(found, ReadOutcome::Found(value) if value == k));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_keys_on_block_boundaries() {
+        let dir = PathBuf::from(format!("./test-dbs/{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let files = Arc::new(TableFiles::new(Arc::new(DiskStorage), dir.clone(), 1));
+
+        // Only multiples of 3, so that the keys around the first key of a block are missing
+        let entries: Vec<_> = (0..3000)
+            .map(|k| KVMemoryRepr::new(k * 3, Some(k), k))
+            .collect();
+        let content = TableBuilder::from_entries(&entries, TableOptions::default()).unwrap();
+        create_sstable_file(&files, 1, &content.data).unwrap();
+
+        let table = SSTable::open(&files, 1).unwrap();
+        assert!(table.index.len() > 2);
+        assert_eq!(table.index, content.index);
+
+        for handle in &table.index {
+            let first = handle.first_key;
+            assert!(
+                matches!(table.find(&first, 0).unwrap(), ReadOutcome::Found(v) if v == first / 3)
+            );
+            assert!(matches!(
+                table.find(&(first + 1), 0).unwrap(),
+                ReadOutcome::NotFound
+            ));
+            if first > 0 {
+                assert!(matches!(
+                    table.find(&(first - 1), 0).unwrap(),
+                    ReadOutcome::NotFound
+                ));
+                assert!(
+                    matches!(table.find(&(first - 3), 0).unwrap(), ReadOutcome::Found(v) if v == first / 3 - 1)
+                );
+            }
+        }
+        for key in 0..9003 {
+            let found = table.find(&key, 0).unwrap();
+            if key % 3 == 0 && key < 9000 {
+                assert!(matches!(found, ReadOutcome::Found(v) if v == key / 3));
+            } else {
+                assert!(matches!(found, ReadOutcome::NotFound));
             }
-            if idx < index.len() {
-                end_offset = Some(index[idx].1);
+        }
+    }
+
+    #[test]
+    fn test_block_size_bounds_lookups() {
+        for block_size in [1, 100, 4096] {
+            for len in [1, 10, 100, 10_000] {
+                let entries: Vec<_> = (0..len)
+                    .map(|k| KVMemoryRepr::new(k * 2, Some(k), k))
+                    .collect();
+                let options = TableOptions {
+                    block_size,
+                    ..Default::default()
+                };
+                let content = TableBuilder::from_entries(&entries, options).unwrap();
+
+                // The first entry is always indexed, so every key of the table has a block
+                assert_eq!(content.index[0].first_key, 0);
+
+                let parts = format::decode_table(&content.data, RecordFormat::CURRENT).unwrap();
+                for handle in &parts.index {
+                    let raw = &parts.data[handle.offset as usize..][..handle.len as usize];
+                    let records: usize = Block::parse(raw, RecordFormat::CURRENT)
+                        .unwrap()
+                        .entries()
+                        .unwrap()
+                        .iter()
+                        .map(|entry| {
+                            serialization::serialize(entry, RecordFormat::CURRENT)
+                                .unwrap()
+                                .len()
+                        })
+                        .sum();
+                    assert!(records < block_size + serialization::MAX_RECORD_BYTES);
+                }
             }
         }
     }
 
-    (start_offset, end_offset)
+    #[test]
+    fn test_unsupported_table_version() {
+        let dir = PathBuf::from(format!("./test-dbs/{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let files = Arc::new(TableFiles::new(Arc::new(DiskStorage), dir.clone(), 1));
+
+        let entries = [KVMemoryRepr::new(1, Some(1), 1)];
+        let data = TableBuilder::from_entries(&entries, TableOptions::default())
+            .unwrap()
+            .data;
+        let (_, path, _) = create_sstable_file(&files, 1, &data).unwrap();
+        assert!(SSTable::open(&files, 1).is_ok());
+
+        // Written by a newer version still readable by this one
+        let mut content = fs::read(&path).unwrap();
+        content[4] += 1;
+        *content.last_mut().unwrap() += 1;
+        fs::write(&path, &content).unwrap();
+        assert!(SSTable::open(&files, 1).is_ok());
+
+        // Needing a newer reader, in the file header
+        content[6] = TABLE_MIN_READER_VERSION + 1;
+        fs::write(&path, &content).unwrap();
+        assert!(matches!(
+            SSTable::open(&files, 1),
+            Err(Error::UnsupportedVersion { found, supported })
+                if found == TABLE_FORMAT_VERSION + 1 && supported == TABLE_FORMAT_VERSION
+        ));
+
+        // In the footer
+        let mut data = data;
+        let len = data.len();
+        data[len - 2] = TABLE_MIN_READER_VERSION + 1;
+        assert!(matches!(
+            format::decode_table(&data, RecordFormat::CURRENT),
+            Err(Error::UnsupportedVersion { .. })
+        ));
+
+        // Older than the oldest version still read
+        *data.last_mut().unwrap() = TABLE_OLDEST_VERSION - 1;
+        data[len - 2] = TABLE_OLDEST_VERSION - 1;
+        assert!(matches!(
+            format::decode_table(&data, RecordFormat::CURRENT),
+            Err(Error::UnsupportedVersion { .. })
+        ));
+
+        // Not a table at all
+        content[0] = 0;
+        fs::write(&path, &content).unwrap();
+        assert!(matches!(
+            SSTable::open(&files, 1),
+            Err(Error::Serialization(SerializationError::InvalidFileHeader))
+        ));
+    }
+
+    #[test]
+    fn test_read_tables_with_framed_records() {
+        let dir = PathBuf::from(format!("./test-dbs/{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let files = Arc::new(TableFiles::new(Arc::new(DiskStorage), dir.clone(), 1));
+
+        let mut entries: Vec<_> = (0..1000)
+            .map(|i| KVMemoryRepr::new(i, (i % 3 != 0).then_some(i * 2), i))
+            .collect();
+        entries.push(KVMemoryRepr::range_tombstone(500, 600, 2000));
+        let options = TableOptions {
+            block_size: 512,
+            ..Default::default()
+        };
+        let mut builder =
+            TableBuilder::new(options, entries.len()).with_record_format(RecordFormat::Framed);
+        for entry in &entries {
+            builder.add(entry).unwrap();
+        }
+        let mut data = builder.finish().unwrap().data;
+
+        // As written by the last version with framed records
+        let framed_version = TABLE_FIXED_RECORDS_VERSION - 1;
+        let len = data.len();
+        data[len - 2] = framed_version;
+        data[len - 1] = framed_version;
+        let (_, path, _) = create_sstable_file(&files, 1, &data).unwrap();
+        let mut content = fs::read(&path).unwrap();
+        content[4] = framed_version;
+        content[6] = framed_version;
+        fs::write(&path, &content).unwrap();
+
+        let table = SSTable::open(&files, 1).unwrap();
+        for i in 0..1000 {
+            let expected = match i {
+                500..600 => ReadOutcome::Deleted,
+                i if i % 3 == 0 => ReadOutcome::Deleted,
+                i => ReadOutcome::Found(i * 2),
+            };
+            assert_eq!(table.find(&i, 0).unwrap(), expected);
+        }
+        assert!(table.entries().unwrap() == entries);
+        assert!(table.verify().anomalies.is_empty());
+    }
+
+    #[test]
+    fn test_unknown_table_sections_are_skipped() {
+        let dir = PathBuf::from(format!("./test-dbs/{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let files = Arc::new(TableFiles::new(Arc::new(DiskStorage), dir.clone(), 1));
+
+        let entries: Vec<_> = (0..100)
+            .map(|i| KVMemoryRepr::new(i, Some(i * 2), i))
+            .collect();
+        let mut data = TableBuilder::from_entries(&entries, TableOptions::default())
+            .unwrap()
+            .data;
+
+        // A section and a flag from some future version, inserted before the footer
+        let footer_start = data.len() - format::FOOTER_BYTES;
+        let mut section = vec![200];
+        section.extend_from_slice(&3u32.to_le_bytes());
+        section.extend_from_slice(b"new");
+        data.splice(footer_start..footer_start, section.iter().copied());
+        let len = data.len();
+        data[len - 3] |= 0x80;
+        create_sstable_file(&files, 1, &data).unwrap();
+
+        let table = SSTable::open(&files, 1).unwrap();
+        for i in 0..100 {
+            assert!(matches!(table.find(&i, 0).unwrap(), ReadOutcome::Found(v) if v == i * 2));
+        }
+
+        // A section running into the footer
+        data[footer_start + 1] = 4;
+        assert!(matches!(
+            format::decode_table(&data, RecordFormat::CURRENT),
+            Err(Error::Serialization(SerializationError::InvalidTableLayout))
+        ));
+    }
+
+    #[test]
+    fn test_verify_flags_corruption() {
+        let dir = PathBuf::from(format!("./test-dbs/{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let files = Arc::new(TableFiles::new(Arc::new(DiskStorage), dir.clone(), 4));
+
+        let entries: Vec<_> = (0..1000)
+            .map(|k| KVMemoryRepr::new(k, Some(k), k))
+            .collect();
+        let content = TableBuilder::from_entries(&entries, TableOptions::default()).unwrap();
+        create_sstable_file(&files, 1, &content.data).unwrap();
+        let report = SSTable::open(&files, 1).unwrap().verify();
+        assert_eq!(report.entry_count, 1000);
+        assert!(report.anomalies.is_empty());
+
+        // Swapped entries, the builder doesn't check the order
+        let mut unsorted = entries.clone();
+        unsorted.swap(10, 11);
+        let data = TableBuilder::from_entries(&unsorted, TableOptions::default())
+            .unwrap()
+            .data;
+        create_sstable_file(&files, 2, &data).unwrap();
+        let report = SSTable::open(&files, 2).unwrap().verify();
+        assert_eq!(report.anomalies, [Anomaly::UnsortedKey { key: 10 }]);
+
+        // Second restart point of the first block moved inside a record
+        let mut data = content.data.clone();
+        let block_end = (content.index[0].offset + content.index[0].len as u64) as usize;
+        let restart_count = u32::from_le_bytes(data[block_end - 4..block_end].try_into().unwrap());
+        let second_restart = block_end - 4 - (restart_count as usize - 1) * 4;
+        let offset = u32::from_le_bytes(data[second_restart..][..4].try_into().unwrap()) + 1;
+        data[second_restart..][..4].copy_from_slice(&offset.to_le_bytes());
+        create_sstable_file(&files, 3, &data).unwrap();
+        let report = SSTable::open(&files, 3).unwrap().verify();
+        assert_eq!(
+            report.anomalies,
+            [Anomaly::RestartOffset {
+                block: 0,
+                offset: offset as u64
+            }]
+        );
+    }
+
+    #[test]
+    fn test_find_in_bytes() {
+        let find = |key: &Key, data: &[u8]| match find_in_bytes(
+            key,
+            data,
+            RecordFormat::CURRENT,
+            &KeyOrder::default(),
+        )
+        .unwrap()
+        {
+            Some(entry) => entry.read_outcome(0),
+            None => ReadOutcome::NotFound,
+        };
+
+        let data: Vec<u8> = [(10, Some(1)), (20, None), (30, Some(3))]
+            .into_iter()
+            .flat_map(|(k, v)| {
+                serialization::serialize(&KVMemoryRepr::new(k, v, k), RecordFormat::CURRENT)
+                    .unwrap()
+            })
+            .collect();
+
+        assert!(matches!(find(&10, &data), ReadOutcome::Found(1)));
+        assert!(matches!(find(&20, &data), ReadOutcome::Deleted));
+        assert!(matches!(find(&30, &data), ReadOutcome::Found(3)));
+        for absent in [5, 15, 35] {
+            assert!(matches!(find(&absent, &data), ReadOutcome::NotFound));
+        }
+        assert!(matches!(find(&10, &[]), ReadOutcome::NotFound));
+    }
 }
@@ -0,0 +1,409 @@
+use super::SSTable;
+use crate::{Key, options::CompactionPolicy};
+
+const MAX_TABLES_IN_MERGE: usize = 30;
+
+/// Decides which tables are merged together
+pub trait MergePolicy: Send + Sync {
+    /// `tables` are sorted newest first.
+    ///
+    /// Returns the non-overlapping ranges of tables to merge in the form `[start, end)`, the most worthwhile first.
+    fn find_sstables_to_merge(&self, tables: &[TableStats]) -> Vec<(usize, usize)>;
+
+    /// Why `tables[start..end]`, found by [`MergePolicy::find_sstables_to_merge`], is worth merging
+    fn explain(&self, tables: &[TableStats], (start, end): (usize, usize)) -> String {
+        format!("garbage {:.0}%", garbage_ratio(&tables[start..end]) * 100.0)
+    }
+}
+
+impl CompactionPolicy {
+    /// See [`crate::Options::compaction_tombstone_threshold`]
+    pub(crate) fn build(&self, tombstone_threshold: Option<f64>) -> Box<dyn MergePolicy> {
+        match *self {
+            CompactionPolicy::SizeTiered { ratio, min_merge } => Box::new(SizeTiered {
+                ratio,
+                min_merge,
+                tombstone_threshold,
+            }),
+        }
+    }
+}
+
+/// What the policies know about a table
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TableStats {
+    pub id: u64,
+    pub size: u64,
+    /// Point entries, tombstones included
+    pub entries: u64,
+    pub tombstones: u64,
+    /// Smallest and largest key by numeric value, whatever the comparator: the policies only estimate overlaps
+    pub key_range: Option<(Key, Key)>,
+}
+
+impl From<&SSTable> for TableStats {
+    fn from(table: &SSTable) -> Self {
+        Self {
+            id: table.id(),
+            size: table.file_size(),
+            entries: table.entry_count(),
+            tombstones: table.tombstone_count(),
+            key_range: table
+                .key_range()
+                .map(|(first, last)| (first.min(last), first.max(last))),
+        }
+    }
+}
+
+/// A merge the compaction policy would run now, see [`crate::KVStorage::compaction_plan`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlannedCompaction {
+    /// Ids of the merged tables, newest first
+    pub tables: Vec<u64>,
+    /// Sizes of `tables`, in the same order
+    pub table_sizes: Vec<u64>,
+    /// Size of the merged table, without the overwritten values and, when they're dropped, the tombstones. Keys are
+    /// assumed to be spread evenly over the key ranges
+    pub estimated_output_bytes: u64,
+    /// The merge reaches the oldest table, so no older value is left for the tombstones to hide
+    pub drops_tombstones: bool,
+    /// Why the policy picked the group, e.g. its size tier and estimated garbage
+    pub policy_explain: String,
+}
+
+/// The merges `policy` finds among `tables` (newest first), the most worthwhile first. Nothing is merged
+pub fn plan(policy: &dyn MergePolicy, tables: &[TableStats]) -> Vec<PlannedCompaction> {
+    policy
+        .find_sstables_to_merge(tables)
+        .into_iter()
+        .map(|(start, end)| {
+            let group = &tables[start..end];
+            let drops_tombstones = end == tables.len();
+            let input_bytes: u64 = group.iter().map(|table| table.size).sum();
+            let tombstone_bytes = if drops_tombstones {
+                group
+                    .iter()
+                    .map(|table| table.size * table.tombstones / table.entries.max(1))
+                    .sum()
+            } else {
+                0
+            };
+
+            PlannedCompaction {
+                tables: group.iter().map(|table| table.id).collect(),
+                table_sizes: group.iter().map(|table| table.size).collect(),
+                estimated_output_bytes: input_bytes
+                    .saturating_sub(dead_bytes(group))
+                    .saturating_sub(tombstone_bytes),
+                drops_tombstones,
+                policy_explain: policy.explain(tables, (start, end)),
+            }
+        })
+        .collect()
+}
+
+/// Estimated share of the entries of `tables` (newest first) that a merge removes: the tombstones, and the entries
+/// shadowed by newer tables, assuming keys are spread evenly over the key ranges
+pub fn garbage_ratio(tables: &[TableStats]) -> f64 {
+    let entries: u64 = tables.iter().map(|table| table.entries).sum();
+    if entries == 0 {
+        return 0.0;
+    }
+
+    let mut garbage = 0.0;
+    for (i, table) in tables.iter().enumerate() {
+        garbage += table.tombstones as f64
+            + (table.entries - table.tombstones) as f64 * shadowed(tables, i);
+    }
+
+    garbage / entries as f64
+}
+
+/// Estimated size of the values of `tables` (newest first) overwritten by newer tables, on the same assumption as
+/// [`garbage_ratio`]
+pub fn dead_bytes(tables: &[TableStats]) -> u64 {
+    let mut dead = 0.0;
+    for (i, table) in tables.iter().enumerate() {
+        let values = table.entries - table.tombstones;
+        dead +=
+            table.size as f64 * values as f64 / table.entries.max(1) as f64 * shadowed(tables, i);
+    }
+
+    dead as u64
+}
+
+/// Share of the key range of `tables[i]` also covered by a newer table
+fn shadowed(tables: &[TableStats], i: usize) -> f64 {
+    tables[..i]
+        .iter()
+        .map(|newer| overlap(tables[i].key_range, newer.key_range))
+        .fold(0.0, f64::max)
+}
+
+fn tombstone_ratio(tables: &[TableStats]) -> f64 {
+    let entries: u64 = tables.iter().map(|table| table.entries).sum();
+    let tombstones: u64 = tables.iter().map(|table| table.tombstones).sum();
+
+    tombstones as f64 / entries.max(1) as f64
+}
+
+/// Share of `range` covered by `other`
+fn overlap(range: Option<(Key, Key)>, other: Option<(Key, Key)>) -> f64 {
+    let (Some((start, end)), Some((other_start, other_end))) = (range, other) else {
+        return 0.0;
+    };
+    let (covered_start, covered_end) = (start.max(other_start), end.min(other_end));
+    if covered_start > covered_end {
+        return 0.0;
+    }
+
+    ((covered_end - covered_start) as f64 + 1.0) / ((end - start) as f64 + 1.0)
+}
+
+/// Merges runs of consecutive tables of similar size, so the result moves to the next size tier
+pub struct SizeTiered {
+    /// Largest allowed ratio between the biggest and the smallest table of a group
+    pub ratio: f64,
+    /// Smallest number of tables worth merging
+    pub min_merge: usize,
+    /// Share of tombstones past which two tables are already worth merging
+    pub tombstone_threshold: Option<f64>,
+}
+
+impl MergePolicy for SizeTiered {
+    fn find_sstables_to_merge(&self, tables: &[TableStats]) -> Vec<(usize, usize)> {
+        let sizes: Vec<_> = tables.iter().map(|table| table.size).collect();
+        let mut result = Vec::new();
+
+        let mut i = sizes.len();
+
+        while i > 0 {
+            // Start a new group from position i-1
+            let group_end = i;
+            let mut group_start = i - 1;
+            let mut min_size = sizes[group_start].max(1);
+            let mut max_size = min_size;
+
+            // Scan backwards while the sizes stay within the ratio and under MAX_TABLES_IN_MERGE
+            while group_start > 0 && (group_end - group_start) < MAX_TABLES_IN_MERGE {
+                let prev_size = sizes[group_start - 1].max(1);
+                let new_min = min_size.min(prev_size);
+                let new_max = max_size.max(prev_size);
+
+                if new_max as f64 > new_min as f64 * self.ratio {
+                    break;
+                }
+
+                group_start -= 1;
+                min_size = new_min;
+                max_size = new_max;
+            }
+
+            let group = &tables[group_start..group_end];
+            let tombstone_heavy = group.len() > 1
+                && self
+                    .tombstone_threshold
+                    .is_some_and(|threshold| tombstone_ratio(group) > threshold);
+            if group.len() >= self.min_merge || tombstone_heavy {
+                result.push((group_start, group_end));
+            }
+
+            // Move to the next position
+            i = group_start;
+        }
+
+        // Stable, groups with the same ratio stay oldest first
+        result.sort_by(|a, b| {
+            let ratio = |(start, end): &(usize, usize)| garbage_ratio(&tables[*start..*end]);
+            ratio(b).total_cmp(&ratio(a))
+        });
+
+        result
+    }
+
+    fn explain(&self, tables: &[TableStats], (start, end): (usize, usize)) -> String {
+        let group = &tables[start..end];
+        let min_size = group
+            .iter()
+            .map(|table| table.size.max(1))
+            .min()
+            .unwrap_or(1);
+        let max_size = group
+            .iter()
+            .map(|table| table.size.max(1))
+            .max()
+            .unwrap_or(1);
+
+        let reason = if group.len() >= self.min_merge {
+            format!("{} tables >= min_merge {}", group.len(), self.min_merge)
+        } else {
+            format!(
+                "tombstones {:.0}% > threshold {:.0}%",
+                tombstone_ratio(group) * 100.0,
+                self.tombstone_threshold.unwrap_or_default() * 100.0
+            )
+        };
+
+        format!(
+            "size tier {min_size}..={max_size} bytes (ratio {:.2} <= {}), {reason}, garbage {:.0}%",
+            max_size as f64 / min_size as f64,
+            self.ratio,
+            garbage_ratio(group) * 100.0
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sized(sizes: &[u64]) -> Vec<TableStats> {
+        sizes
+            .iter()
+            .map(|size| TableStats {
+                size: *size,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_size_tiered_groups_similar_sizes() {
+        let policy = SizeTiered {
+            ratio: 2.0,
+            min_merge: 4,
+            tombstone_threshold: None,
+        };
+
+        // Newest first: 4 fresh tables, then a bigger tier of 4 and an old huge table
+        let sizes = [10, 12, 11, 10, 50, 40, 45, 60, 1000];
+        assert_eq!(
+            policy.find_sstables_to_merge(&sized(&sizes)),
+            [(4, 8), (0, 4)]
+        );
+
+        // Not enough similar tables
+        assert!(
+            policy
+                .find_sstables_to_merge(&sized(&[10, 10, 10, 100]))
+                .is_empty()
+        );
+        assert!(policy.find_sstables_to_merge(&[]).is_empty());
+
+        // Groups are capped, the remaining tables are below `min_merge`
+        let sizes = vec![10; MAX_TABLES_IN_MERGE + 3];
+        assert_eq!(
+            policy.find_sstables_to_merge(&sized(&sizes)),
+            [(3, MAX_TABLES_IN_MERGE + 3)]
+        );
+    }
+
+    #[test]
+    fn test_garbage_heavy_group_first() {
+        let policy = SizeTiered {
+            ratio: 2.0,
+            min_merge: 4,
+            tombstone_threshold: Some(0.5),
+        };
+        let table = |start: Key, tombstones: u64| TableStats {
+            size: 1000,
+            entries: 100,
+            tombstones,
+            key_range: Some((start, start + 99)),
+            ..Default::default()
+        };
+        let disjoint = |start: Key| table(start, 0);
+        let overwritten = table(0, 0);
+
+        // Newest first: 4 tables overwriting the same keys, then a tier of 4 disjoint (bigger) tables
+        let mut tables = vec![overwritten; 4];
+        for i in 0..4 {
+            tables.push(TableStats {
+                size: 3000,
+                ..disjoint(i * 100)
+            });
+        }
+        assert_eq!(policy.find_sstables_to_merge(&tables), [(0, 4), (4, 8)]);
+        assert_eq!(garbage_ratio(&tables[4..]), 0.0);
+        assert_eq!(garbage_ratio(&tables[..4]), 0.75);
+
+        // Two tables are enough once most entries are tombstones
+        let tables = [table(0, 80), table(1000, 70), TableStats::default()];
+        assert_eq!(policy.find_sstables_to_merge(&tables), [(0, 2)]);
+        let tables = [table(0, 10), table(1000, 10)];
+        assert!(policy.find_sstables_to_merge(&tables).is_empty());
+    }
+
+    #[test]
+    fn test_plan() {
+        let policy = SizeTiered {
+            ratio: 2.0,
+            min_merge: 4,
+            tombstone_threshold: Some(0.5),
+        };
+        let with_ids = |tables: Vec<TableStats>| -> Vec<TableStats> {
+            tables
+                .into_iter()
+                .zip(1..)
+                .map(|(table, id)| TableStats { id, ..table })
+                .collect()
+        };
+
+        let tables = with_ids(sized(&[10, 12, 11, 10, 50, 40, 45, 60, 1000]));
+        let planned = plan(&policy, &tables);
+        assert_eq!(planned.len(), 2);
+        assert_eq!(
+            planned[0],
+            PlannedCompaction {
+                tables: vec![5, 6, 7, 8],
+                table_sizes: vec![50, 40, 45, 60],
+                estimated_output_bytes: 195,
+                drops_tombstones: false,
+                policy_explain:
+                    "size tier 40..=60 bytes (ratio 1.50 <= 2), 4 tables >= min_merge 4, garbage 0%"
+                        .to_owned(),
+            }
+        );
+        assert_eq!(planned[1].tables, [1, 2, 3, 4]);
+
+        // The same keys overwritten four times, only the newest values are left
+        let overwritten = TableStats {
+            size: 1000,
+            entries: 100,
+            tombstones: 0,
+            key_range: Some((0, 99)),
+            ..Default::default()
+        };
+        let mut tables = with_ids(vec![overwritten; 4]);
+        tables.push(TableStats {
+            id: 5,
+            size: 100_000,
+            ..Default::default()
+        });
+        let [planned] = &plan(&policy, &tables)[..] else {
+            panic!("expected a single merge");
+        };
+        assert_eq!(planned.estimated_output_bytes, 1000);
+        assert!(!planned.drops_tombstones);
+
+        // Down to the oldest table, the tombstones go too
+        let tombstones = |start: Key, tombstones: u64| TableStats {
+            size: 1000,
+            entries: 100,
+            tombstones,
+            key_range: Some((start, start + 99)),
+            ..Default::default()
+        };
+        let tables = with_ids(vec![tombstones(0, 80), tombstones(1000, 70)]);
+        let [planned] = &plan(&policy, &tables)[..] else {
+            panic!("expected a single merge");
+        };
+        assert_eq!(planned.tables, [1, 2]);
+        assert_eq!(planned.estimated_output_bytes, 500);
+        assert!(planned.drops_tombstones);
+        assert_eq!(
+            planned.policy_explain,
+            "size tier 1000..=1000 bytes (ratio 1.00 <= 2), tombstones 75% > threshold 50%, garbage 75%"
+        );
+    }
+}
@@ -0,0 +1,113 @@
+use super::SSTable;
+use crate::{Key, errors::Error, options::Options, serialization::KVMemoryRepr, stats::ReadTrace};
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        Arc, Mutex,
+        mpsc::{Receiver, Sender, channel},
+    },
+    thread::{self, JoinHandle},
+};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Entry found in a table along with what the lookup cost
+pub type Probe = Result<(Option<KVMemoryRepr>, ReadTrace), Error>;
+
+/// Threads looking a key up in many SSTables at once, see [`Options::parallel_probe_threads`]
+pub struct ProbePool {
+    sender: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl ProbePool {
+    pub fn new(threads: usize) -> Self {
+        let (sender, receiver) = channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..threads)
+            .map(|_| {
+                let receiver = receiver.clone();
+                thread::spawn(move || worker_loop(&receiver))
+            })
+            .collect();
+
+        Self {
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    /// `None` unless [`Options::parallel_probe_threads`] is set
+    pub fn from_options(options: &Options) -> Option<Self> {
+        (options.parallel_probe_threads > 0).then(|| Self::new(options.parallel_probe_threads))
+    }
+
+    /// Looks `key` up in every table of `tables`, returning the probes in the same order.
+    ///
+    /// The first table is probed by the calling thread, which also redoes the probes that panicked.
+    pub fn probe(&self, key: Key, tables: &[Arc<SSTable>]) -> Vec<Probe> {
+        let (results_sender, results) = channel();
+        if let Some(sender) = &self.sender {
+            for (i, table) in tables.iter().enumerate().skip(1) {
+                let table = table.clone();
+                let results_sender = results_sender.clone();
+                let job = Box::new(move || {
+                    // The reader may be gone after an earlier error
+                    let _ = results_sender.send((i, probe_table(&table, &key)));
+                });
+                if sender.send(job).is_err() {
+                    break;
+                }
+            }
+        }
+        drop(results_sender);
+
+        let mut probes: Vec<Option<Probe>> = tables.iter().map(|_| None).collect();
+        if let Some(first) = tables.first() {
+            probes[0] = Some(probe_table(first, &key));
+        }
+        // Ends once every job sent its result or was dropped
+        for (i, probe) in results {
+            probes[i] = Some(probe);
+        }
+
+        probes
+            .into_iter()
+            .zip(tables)
+            .map(|(probe, table)| probe.unwrap_or_else(|| probe_table(table, &key)))
+            .collect()
+    }
+}
+
+impl Drop for ProbePool {
+    fn drop(&mut self) {
+        // Disconnecting the channel stops the workers
+        self.sender.take();
+        for worker in self.workers.drain(..) {
+            if worker.join().is_err() {
+                log::error!("table probe thread panicked");
+            }
+        }
+    }
+}
+
+fn worker_loop(receiver: &Mutex<Receiver<Job>>) {
+    loop {
+        let job = receiver.lock().expect("poisoned probe queue").recv();
+        match job {
+            // The thread stays in the pool, the caller probes the table again and gets the panic
+            Ok(job) => {
+                let _ = panic::catch_unwind(AssertUnwindSafe(job));
+            }
+            Err(_) => return,
+        }
+    }
+}
+
+fn probe_table(table: &SSTable, key: &Key) -> Probe {
+    let mut trace = ReadTrace::default();
+    let entry = table.find_entry_traced(key, &mut trace)?;
+
+    Ok((entry, trace))
+}
@@ -0,0 +1,117 @@
+use crate::serialization::KVMemoryRepr;
+use std::cell::Cell;
+
+/// Above this capacity a buffer is released after use, so that a huge block doesn't pin its memory in every thread
+/// that once read it
+const MAX_KEPT_BYTES: usize = 1024 * 1024;
+/// Same as [`MAX_KEPT_BYTES`] for decoded entries
+const MAX_KEPT_ENTRIES: usize = 16 * 1024;
+
+thread_local! {
+    static SCRATCH: Cell<Scratch> = Cell::new(Scratch::default());
+}
+
+/// Buffers of the table lookups, reused by the next lookups of the same thread instead of allocated for each one.
+///
+/// They're cleared by their users, never shrunk below the bounds above.
+#[derive(Default)]
+pub struct Scratch {
+    /// Stored bytes of a block read with `pread`
+    pub stored: Vec<u8>,
+    /// The records of a compressed block
+    pub decompressed: Vec<u8>,
+    /// Entries of a block decoded whole
+    pub entries: Vec<KVMemoryRepr>,
+}
+
+/// Runs `f` with the buffers of the thread. They're taken out of it meanwhile: a lookup nested in `f` gets empty ones
+pub fn with_scratch<T>(f: impl FnOnce(&mut Scratch) -> T) -> T {
+    let mut scratch = SCRATCH.take();
+    let result = f(&mut scratch);
+
+    if scratch.stored.capacity() > MAX_KEPT_BYTES {
+        scratch.stored = Vec::new();
+    }
+    if scratch.decompressed.capacity() > MAX_KEPT_BYTES {
+        scratch.decompressed = Vec::new();
+    }
+    if scratch.entries.capacity() > MAX_KEPT_ENTRIES {
+        scratch.entries = Vec::new();
+    }
+    SCRATCH.set(scratch);
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        options::Compression,
+        sstables::{SSTable, TableFiles, TableOptions, memtable_to_sstable},
+        storage::MemStorage,
+    };
+    use std::{path::PathBuf, sync::Arc, thread};
+
+    /// Keys `0, 2, ..` with their half as value, in blocks of 4KB
+    fn table(compression: Compression) -> SSTable {
+        let files = Arc::new(TableFiles::new(
+            Arc::new(MemStorage::new()),
+            PathBuf::from("sstables"),
+            1,
+        ));
+        let entries = (0..10_000)
+            .map(|k| KVMemoryRepr::new(k * 2, Some(k), k))
+            .collect();
+        let options = TableOptions {
+            compression,
+            ..TableOptions::default()
+        };
+
+        memtable_to_sstable(&files, entries, options).unwrap()
+    }
+
+    #[test]
+    fn test_concurrent_lookups() {
+        let table = Arc::new(table(Compression::Lz4));
+
+        let readers: Vec<_> = (0..8)
+            .map(|reader| {
+                let table = table.clone();
+                thread::spawn(move || {
+                    for k in (reader..10_000).step_by(8) {
+                        let entry = table.find_entry(&(k * 2)).unwrap().unwrap();
+                        assert_eq!(*entry.value(), Some(k));
+                    }
+                    let keys: Vec<_> = (0..10_000).rev().step_by(3 + reader as usize).collect();
+                    let entries = table
+                        .find_entries(&keys.iter().map(|k| k * 2).collect::<Vec<_>>())
+                        .unwrap();
+                    for (k, entry) in keys.iter().zip(entries) {
+                        assert_eq!(*entry.unwrap().value(), Some(*k));
+                    }
+                })
+            })
+            .collect();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_reuse_and_release() {
+        with_scratch(|scratch| scratch.stored.resize(4096, 1));
+        with_scratch(|scratch| {
+            assert!(scratch.stored.capacity() >= 4096);
+            scratch.stored.clear();
+
+            // Nested, the buffers are out of the thread
+            with_scratch(|nested| assert_eq!(nested.stored.capacity(), 0));
+            scratch.decompressed.resize(MAX_KEPT_BYTES + 1, 0);
+        });
+        with_scratch(|scratch| {
+            assert!(scratch.stored.capacity() >= 4096);
+            assert_eq!(scratch.decompressed.capacity(), 0);
+        });
+    }
+}
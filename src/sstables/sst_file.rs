@@ -0,0 +1,371 @@
+use super::format::{self, Block, BlockHandle, FOOTER_BYTES};
+use super::{TMP_EXTENSION, TableBuilder};
+use crate::clock::{Clock, SystemClock};
+use crate::comparator::{Ascending, KeyComparator, KeyOrder};
+use crate::file_header::{FileHeader, FileKind, HEADER_BYTES};
+use crate::files::PositionedFile;
+use crate::functions::{self, ReadOutcome};
+use crate::options::{Compression, Options};
+use crate::serialization::{self, KVMemoryRepr, RecordFormat, SerializationError};
+use crate::storage::{DiskStorage, Handle, Storage};
+use crate::{Key, Value, cleanup, errors::Error};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// What a table file holds, read from its header and footer, see [`SstReader::metadata`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableMetadata {
+    /// Header included
+    pub file_size: u64,
+    /// Of the crate that wrote the file, see the [module](crate::sstable) documentation
+    pub format_version: u8,
+    pub compression: Compression,
+    /// Point entries, tombstones included
+    pub entry_count: u64,
+    pub tombstone_count: u64,
+    /// Deleted `start..end` ranges, whose keys [`SstReader::get`] reports as deleted
+    pub range_tombstones: Vec<(Key, Key)>,
+    pub block_count: usize,
+    /// First and last point key, `None` without point entries
+    pub key_range: Option<(Key, Key)>,
+    /// Lowest and highest sequence of the entries, `None` for tables written before they were stored
+    pub sequences: Option<(u64, u64)>,
+}
+
+/// A single table file read outside of any store, e.g. one written by [`SstWriter`] or copied out of a database.
+///
+/// [`SstReader::open`] only reads the end of the file, past the data blocks. Lookups and iterations then read the
+/// blocks they need one at a time, so that the memory used doesn't grow with the table.
+pub struct SstReader {
+    file: Handle,
+    record_format: RecordFormat,
+    index: Vec<BlockHandle>,
+    ranges: Vec<KVMemoryRepr>,
+    compression: Compression,
+    order: KeyOrder,
+    metadata: TableMetadata,
+}
+
+impl SstReader {
+    /// Reads the table at `path`, whose keys are in ascending order
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::open_with_comparator(path, Arc::new(Ascending))
+    }
+
+    /// Same as [`SstReader::open`] for a table written with `comparator`, see [`Options::key_comparator`]
+    pub fn open_with_comparator(
+        path: impl AsRef<Path>,
+        comparator: Arc<dyn KeyComparator>,
+    ) -> Result<Self, Error> {
+        let file = DiskStorage.open(path.as_ref(), false)?;
+        let file_size = file.size()?;
+        let read = |start: u64, end: u64| {
+            let mut buffer = vec![0u8; end.saturating_sub(start) as usize];
+            functions::read_data_at_offset(&file, &mut buffer, start).map(|()| buffer)
+        };
+
+        let header = FileHeader::decode(FileKind::Table, &read(0, HEADER_BYTES.min(file_size))?)?;
+        let record_format = header.record_format();
+        let footer_start = file_size
+            .checked_sub(FOOTER_BYTES as u64)
+            .filter(|start| *start >= HEADER_BYTES)
+            .ok_or(SerializationError::InvalidTableLayout)?;
+        let footer = format::decode_footer(&read(footer_start, file_size)?)?;
+        let tail_start = HEADER_BYTES + footer.ranges_offset;
+        if tail_start > footer_start {
+            return Err(SerializationError::InvalidTableLayout.into());
+        }
+        let parts =
+            format::decode_table_tail(&read(tail_start, file_size)?, &footer, record_format)?;
+
+        let mut reader = Self {
+            file,
+            record_format,
+            compression: footer.compression,
+            order: KeyOrder::new(comparator),
+            metadata: TableMetadata {
+                file_size,
+                format_version: header.version,
+                compression: footer.compression,
+                entry_count: footer.entry_count,
+                tombstone_count: footer.tombstone_count,
+                range_tombstones: parts
+                    .ranges
+                    .iter()
+                    .filter_map(|range| Some((*range.key(), range.range_end()?)))
+                    .collect(),
+                block_count: parts.index.len(),
+                key_range: None,
+                sequences: parts.sequences,
+            },
+            index: parts.index,
+            ranges: parts.ranges,
+        };
+
+        // Decoding only the last block
+        let first_key = reader.index.first().map(|handle| handle.first_key);
+        let last_key = match reader.index.last() {
+            Some(handle) => reader
+                .block_entries(handle)?
+                .last()
+                .map(|entry| *entry.key()),
+            None => None,
+        };
+        reader.metadata.key_range = first_key.zip(last_key);
+
+        Ok(reader)
+    }
+
+    pub fn metadata(&self) -> &TableMetadata {
+        &self.metadata
+    }
+
+    /// The point entries in key order, `None` for the deleted keys. Range tombstones aren't applied, they're listed
+    /// by [`SstReader::metadata`].
+    ///
+    /// Blocks are read as the iteration reaches them.
+    pub fn iter(&self) -> impl Iterator<Item = Result<(Key, Option<Value>), Error>> + '_ {
+        self.index.iter().flat_map(|handle| {
+            let entries = match self.block_entries(handle) {
+                Ok(entries) => entries.into_iter().map(Ok).collect(),
+                Err(e) => vec![Err(e)],
+            };
+            entries
+                .into_iter()
+                .map(|entry| entry.map(|entry| (*entry.key(), *entry.value())))
+        })
+    }
+
+    /// The newest entry of `key` in the table, range tombstones included. [`ReadOutcome::NotFound`] when the table
+    /// has none
+    pub fn get(&self, key: &Key) -> Result<ReadOutcome, Error> {
+        let block = match self
+            .index
+            .binary_search_by(|handle| self.order.cmp(&handle.first_key, key))
+        {
+            Ok(i) => Some(i),
+            Err(i) => i.checked_sub(1),
+        };
+        let point = match block {
+            Some(block) => {
+                let raw = self.block_bytes(&self.index[block])?;
+                Block::parse(&raw, self.record_format)?.find(key, &self.order)?
+            }
+            None => None,
+        };
+        let range = serialization::newest_covering(&self.ranges, key, &self.order);
+
+        Ok(
+            serialization::newest(point.as_ref(), range).map_or(ReadOutcome::NotFound, |entry| {
+                entry.read_outcome(SystemClock.now_millis())
+            }),
+        )
+    }
+
+    /// The decompressed records of the block at `handle`, read from the file
+    fn block_bytes(&self, handle: &BlockHandle) -> Result<Vec<u8>, Error> {
+        let start = HEADER_BYTES + handle.offset;
+        if start + handle.len as u64 > self.metadata.file_size {
+            return Err(SerializationError::InvalidTableLayout.into());
+        }
+        let mut stored = vec![0u8; handle.len as usize];
+        functions::read_data_at_offset(&self.file, &mut stored, start)?;
+
+        Ok(format::decompress(self.compression, &stored)?.into_owned())
+    }
+
+    fn block_entries(&self, handle: &BlockHandle) -> Result<Vec<KVMemoryRepr>, Error> {
+        Block::parse(&self.block_bytes(handle)?, self.record_format)?.entries()
+    }
+}
+
+/// Writes a single table file out of entries added in key order, read back by [`SstReader`] or by a store.
+///
+/// Blocks are written to a temporary file next to `path` as soon as they're full, only the block index and the
+/// bloom filter stay in memory. The file appears at its path once [`SstWriter::finish`] succeeds, and is removed if
+/// the writer is dropped before.
+pub struct SstWriter {
+    path: PathBuf,
+    file: TmpFile,
+    builder: TableBuilder,
+    /// Where the blocks taken from `builder` go next
+    offset: u64,
+    order: KeyOrder,
+    comparator: Arc<dyn KeyComparator>,
+    last_key: Option<Key>,
+    added: u64,
+}
+
+/// The file of an [`SstWriter`], removed unless it was moved to its final path
+struct TmpFile {
+    path: PathBuf,
+    /// `None` once moved
+    file: Option<Handle>,
+}
+
+impl SstWriter {
+    /// A table at `path` laid out with the compression, block size, bloom filter and comparator of `options`.
+    /// `expected_entries` sizes the bloom filter, the way flushes size it with the entries of the memtable
+    pub fn new(
+        path: impl AsRef<Path>,
+        options: &Options,
+        expected_entries: usize,
+    ) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(format!(".{TMP_EXTENSION}"));
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let file = TmpFile {
+            file: Some(DiskStorage.create(&tmp_path, 0)?),
+            path: tmp_path,
+        };
+        file.write(&FileHeader::new(FileKind::Table).encode(), 0)?;
+
+        Ok(Self {
+            path,
+            file,
+            builder: TableBuilder::new(options.into(), expected_entries),
+            offset: HEADER_BYTES,
+            order: KeyOrder::new(options.key_comparator.clone()),
+            comparator: options.key_comparator.clone(),
+            last_key: None,
+            added: 0,
+        })
+    }
+
+    /// Adds `key` with `value`, or a tombstone for `None`. Fails with [`Error::UnsortedBulkLoad`] unless `key` is
+    /// above the previous one
+    pub fn add(&mut self, key: Key, value: Option<Value>) -> Result<(), Error> {
+        if let Some(last) = &self.last_key
+            && !self.order.lt(last, &key)
+        {
+            return Err(Error::UnsortedBulkLoad {
+                position: self.added,
+            });
+        }
+        self.builder.add(&KVMemoryRepr::new(key, value, 0))?;
+        self.last_key = Some(key);
+        self.added += 1;
+
+        let data = self.builder.take_data();
+        self.file.write(&data, self.offset)?;
+        self.offset += data.len() as u64;
+
+        Ok(())
+    }
+
+    /// Writes and syncs the file, returning what [`SstReader::metadata`] reads back from it
+    pub fn finish(self) -> Result<TableMetadata, Error> {
+        let table_content = self.builder.finish()?;
+        self.file.write(&table_content.data, self.offset)?;
+        self.file.persist(&self.path)?;
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            DiskStorage.sync_dir(dir)?;
+        }
+
+        Ok(SstReader::open_with_comparator(&self.path, self.comparator)?.metadata)
+    }
+}
+
+impl TmpFile {
+    fn write(&self, data: &[u8], offset: u64) -> Result<(), Error> {
+        if let Some(file) = &self.file
+            && !data.is_empty()
+        {
+            file.write_all_at(data, offset)?;
+        }
+
+        Ok(())
+    }
+
+    /// Syncs the file and moves it to `path`
+    fn persist(mut self, path: &Path) -> Result<(), Error> {
+        let Some(file) = self.file.take() else {
+            return Ok(());
+        };
+        let persisted = file
+            .sync_all()
+            .map_err(Error::from)
+            .and_then(|_| DiskStorage.rename(&self.path, path));
+        if persisted.is_err() {
+            cleanup::remove_file_logged(&DiskStorage, &self.path);
+        }
+
+        persisted
+    }
+}
+
+impl Drop for TmpFile {
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            cleanup::remove_file_logged(&DiskStorage, &self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comparator::Descending;
+    use std::fs;
+
+    #[test]
+    fn test_writer_round_trip() {
+        let dir = PathBuf::from(format!("./test-dbs/{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let options = Options::new()
+            .compression(Compression::Lz4)
+            .key_comparator(Arc::new(Descending));
+        let path = dir.join("table.sst");
+
+        let tmp_path = dir.join("table.sst.tmp");
+
+        let mut writer = SstWriter::new(&path, &options, 5000).unwrap();
+        for k in (0..5000).rev() {
+            let value = (k % 3 != 0).then_some(k + 1);
+            writer.add(k, value).unwrap();
+        }
+        // Full blocks are on disk already
+        assert!(fs::metadata(&tmp_path).unwrap().len() > 4 * HEADER_BYTES);
+        assert!(matches!(
+            writer.add(5000, Some(0)),
+            Err(Error::UnsortedBulkLoad { position: 5000 })
+        ));
+        let metadata = writer.finish().unwrap();
+        assert_eq!(metadata.entry_count, 5000);
+        assert_eq!(metadata.tombstone_count, 1667);
+        assert_eq!(metadata.key_range, Some((4999, 0)));
+        assert!(metadata.block_count > 1);
+        assert!(!tmp_path.exists());
+
+        let reader = SstReader::open_with_comparator(&path, Arc::new(Descending)).unwrap();
+        assert_eq!(reader.metadata(), &metadata);
+        let entries: Vec<_> = reader.iter().collect::<Result<_, _>>().unwrap();
+        let expected: Vec<_> = (0..5000)
+            .rev()
+            .map(|k| (k, (k % 3 != 0).then_some(k + 1)))
+            .collect();
+        assert_eq!(entries, expected);
+        for k in (0..5000).step_by(7) {
+            let expected = if k % 3 == 0 {
+                ReadOutcome::Deleted
+            } else {
+                ReadOutcome::Found(k + 1)
+            };
+            assert_eq!(reader.get(&k).unwrap(), expected);
+        }
+        assert_eq!(reader.get(&5000).unwrap(), ReadOutcome::NotFound);
+
+        // Nothing left of an unfinished table
+        let mut writer = SstWriter::new(dir.join("unfinished.sst"), &options, 1).unwrap();
+        writer.add(1, Some(1)).unwrap();
+        drop(writer);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::write(dir.join("garbage.sst"), b"KVST").unwrap();
+        assert!(SstReader::open(dir.join("garbage.sst")).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
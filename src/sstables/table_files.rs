@@ -0,0 +1,351 @@
+use crate::{
+    comparator::KeyOrder,
+    env::{RandomSource, ThreadRandom},
+    errors::Error,
+    events::EventListener,
+    options::BloomRecovery,
+    storage::{Handle, Storage},
+};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex, MutexGuard,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+};
+
+/// Table writes failing in a row with [`Error::StorageUnavailable`] before writes are refused
+const UNAVAILABLE_AFTER_FAILURES: u64 = 3;
+
+/// The SSTables directories, with an LRU cache of the open table files.
+///
+/// Tables are in the SSTables directory, or in the cold storage directory once compaction placed them there.
+///
+/// Tables only keep their id, files are opened on demand and the least recently used ones are closed once more than
+/// `capacity` are open, so that the number of tables isn't bounded by the file descriptor limit.
+pub struct TableFiles {
+    storage: Arc<dyn Storage>,
+    dir: PathBuf,
+    /// See [`crate::Options::cold_storage_dir`]
+    cold_dir: Option<PathBuf>,
+    /// Tables stored in `cold_dir`
+    cold_tables: Mutex<HashSet<u64>>,
+    capacity: usize,
+    inner: Mutex<FilesInner>,
+    /// Table writes in a row that found no usable directory, see [`TableFiles::record_write`]
+    failed_writes: AtomicU64,
+    /// Set once `failed_writes` reaches [`UNAVAILABLE_AFTER_FAILURES`], see [`TableFiles::check_available`]
+    unavailable: AtomicBool,
+    /// Told when the tables can't be written anymore, or when a table file is missing
+    listener: Option<Arc<dyn EventListener>>,
+    /// Tables whose file was found missing, the listener is told once for each
+    missing: Mutex<HashSet<u64>>,
+    /// Draws the ids of new tables
+    random: Arc<dyn RandomSource>,
+    /// Order of the keys in the tables, and in the logs sharing the storage
+    order: KeyOrder,
+    /// See [`crate::Options::bloom_recovery`]
+    bloom_recovery: BloomRecovery,
+}
+
+struct FilesInner {
+    open: HashMap<u64, OpenFile>,
+    /// Table ids ordered by last use, the first one is closed first
+    by_use: BTreeMap<u64, u64>,
+    tick: u64,
+}
+
+struct OpenFile {
+    /// Readers keep their copy until they're done, closing only drops the cache's one
+    file: Arc<Handle>,
+    last_use: u64,
+}
+
+impl TableFiles {
+    /// At least one file is kept open
+    pub fn new(storage: Arc<dyn Storage>, dir: PathBuf, capacity: usize) -> Self {
+        Self {
+            storage,
+            dir,
+            cold_dir: None,
+            cold_tables: Default::default(),
+            capacity: capacity.max(1),
+            inner: Mutex::new(FilesInner {
+                open: HashMap::new(),
+                by_use: BTreeMap::new(),
+                tick: 0,
+            }),
+            failed_writes: Default::default(),
+            unavailable: Default::default(),
+            listener: None,
+            missing: Default::default(),
+            random: Arc::new(ThreadRandom),
+            order: KeyOrder::default(),
+            bloom_recovery: Default::default(),
+        }
+    }
+
+    /// Replaces the source of the ids of new tables, see [`TableFiles::new_table_id`]
+    pub fn with_random(mut self, random: Arc<dyn RandomSource>) -> Self {
+        self.random = random;
+        self
+    }
+
+    /// Orders the keys with `order` rather than ascending, see [`crate::Options::key_comparator`]
+    pub fn with_order(mut self, order: KeyOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    pub fn order(&self) -> &KeyOrder {
+        &self.order
+    }
+
+    /// Loads the bloom filters of the tables as `bloom_recovery` says, see [`crate::Options::bloom_recovery`]
+    pub fn with_bloom_recovery(mut self, bloom_recovery: BloomRecovery) -> Self {
+        self.bloom_recovery = bloom_recovery;
+        self
+    }
+
+    pub fn bloom_recovery(&self) -> BloomRecovery {
+        self.bloom_recovery
+    }
+
+    /// Id of a table about to be written
+    pub fn new_table_id(&self) -> u64 {
+        self.random.next_u64()
+    }
+
+    /// Adds the listener told about [`Error::StorageUnavailable`] and [`Error::TableMissing`], see [`EventListener::on_background_error`]
+    pub fn with_listener(mut self, listener: Option<Arc<dyn EventListener>>) -> Self {
+        self.listener = listener;
+        self
+    }
+
+    /// Adds the cold storage directory, which holds `cold_tables`
+    pub fn with_cold_dir(mut self, cold_dir: Option<PathBuf>, cold_tables: &[u64]) -> Self {
+        if cold_dir.is_some() {
+            self.cold_tables = Mutex::new(cold_tables.iter().copied().collect());
+        }
+        self.cold_dir = cold_dir;
+        self
+    }
+
+    /// Where the tables (and the rest of the database) are stored
+    pub fn storage(&self) -> &Arc<dyn Storage> {
+        &self.storage
+    }
+
+    pub fn cold_dir(&self) -> Option<&Path> {
+        self.cold_dir.as_deref()
+    }
+
+    /// Directory holding table `id`
+    pub fn dir_of(&self, id: u64) -> &Path {
+        match &self.cold_dir {
+            Some(cold_dir) if self.is_cold(id) => cold_dir,
+            _ => &self.dir,
+        }
+    }
+
+    /// Directory of table `id`, created again if it's gone (e.g. removed while the store runs).
+    ///
+    /// [`Error::StorageUnavailable`] when it can't be
+    pub fn ensure_dir_of(&self, id: u64) -> Result<&Path, Error> {
+        let dir = self.dir_of(id);
+        ensure_dir(&*self.storage, dir)?;
+
+        Ok(dir)
+    }
+
+    /// Counts the table writes failing with [`Error::StorageUnavailable`]. After [`UNAVAILABLE_AFTER_FAILURES`] in a
+    /// row the listener is told and writes fail fast until a table is written, or the directories are back
+    pub fn record_write<T>(&self, result: &Result<T, Error>) {
+        match result {
+            Ok(_) => {
+                self.failed_writes.store(0, Ordering::Relaxed);
+                if self.unavailable.swap(false, Ordering::Relaxed) {
+                    log::info!("tables are written again");
+                }
+            }
+            Err(e @ Error::StorageUnavailable) => {
+                let failures = self.failed_writes.fetch_add(1, Ordering::Relaxed) + 1;
+                if failures >= UNAVAILABLE_AFTER_FAILURES
+                    && !self.unavailable.swap(true, Ordering::Relaxed)
+                {
+                    log::error!("{failures} table writes failed in a row, refusing writes");
+                    if let Some(listener) = &self.listener {
+                        listener.on_background_error(e);
+                    }
+                }
+            }
+            Err(_) => {}
+        }
+    }
+
+    /// [`Error::StorageUnavailable`] once table writes kept failing, unless the directories can be used again.
+    ///
+    /// A single failure afterwards refuses writes again, it takes a table written to forget the previous ones
+    pub fn check_available(&self) -> Result<(), Error> {
+        if !self.unavailable.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        for dir in std::iter::once(&self.dir).chain(&self.cold_dir) {
+            ensure_dir(&*self.storage, dir)?;
+        }
+        if self.unavailable.swap(false, Ordering::Relaxed) {
+            log::info!("table directories are back, accepting writes");
+        }
+
+        Ok(())
+    }
+
+    /// Whether writes are refused, see [`TableFiles::check_available`]
+    pub fn is_unavailable(&self) -> bool {
+        self.unavailable.load(Ordering::Relaxed)
+    }
+
+    pub fn path(&self, id: u64) -> PathBuf {
+        self.dir_of(id).join(id.to_string())
+    }
+
+    pub fn is_cold(&self, id: u64) -> bool {
+        self.cold_lock().contains(&id)
+    }
+
+    /// Places table `id`, not written yet, in the cold storage directory. Ignored without one
+    pub fn set_cold(&self, id: u64) {
+        if self.cold_dir.is_some() {
+            self.cold_lock().insert(id);
+        }
+    }
+
+    /// Forgets the location of tables that were replaced, their [`crate::sstables::SSTable`] keeps its path
+    pub fn remove_cold(&self, ids: &[u64]) {
+        let mut cold_tables = self.cold_lock();
+        for id in ids {
+            cold_tables.remove(id);
+        }
+    }
+
+    /// The file of table `id` at `path`, opened if it's not in the cache. [`Error::TableMissing`] when it's gone
+    pub fn get(&self, id: u64, path: &Path) -> Result<Arc<Handle>, Error> {
+        if let Some(file) = self.touch(id) {
+            return Ok(file);
+        }
+
+        // Not under the lock, other tables can be served meanwhile
+        let file = match self.storage.open(path, false) {
+            Ok(file) => Arc::new(file),
+            Err(Error::IO(e)) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(self.missing(id, path));
+            }
+            Err(e) => return Err(e),
+        };
+
+        Ok(self.insert(id, file))
+    }
+
+    /// [`Error::TableMissing`] for table `id`, logged and told to the listener the first time
+    fn missing(&self, id: u64, path: &Path) -> Error {
+        let error = Error::TableMissing {
+            id,
+            path: path.to_path_buf(),
+        };
+        if self
+            .missing
+            .lock()
+            .expect("poisoned missing tables lock")
+            .insert(id)
+        {
+            log::error!("file of table {id} is gone from {path:?}, its keys can't be read");
+            if let Some(listener) = &self.listener {
+                listener.on_background_error(&error);
+            }
+        }
+
+        error
+    }
+
+    /// Adds the file of a table that was just written, saving a reopen on its first read
+    pub fn insert(&self, id: u64, file: Arc<Handle>) -> Arc<Handle> {
+        let mut inner = self.inner.lock().expect("poisoned table files lock");
+        inner.tick += 1;
+        let tick = inner.tick;
+
+        // Opened concurrently by another reader, keep a single copy
+        let file = match inner.open.remove(&id) {
+            Some(open) => {
+                inner.by_use.remove(&open.last_use);
+                open.file
+            }
+            None => file,
+        };
+        inner.open.insert(
+            id,
+            OpenFile {
+                file: file.clone(),
+                last_use: tick,
+            },
+        );
+        inner.by_use.insert(tick, id);
+
+        while inner.open.len() > self.capacity {
+            let (_, closed) = inner.by_use.pop_first().expect("open files are tracked");
+            inner.open.remove(&closed);
+        }
+
+        file
+    }
+
+    /// Closes the file of table `id`, to be called once the table is gone
+    pub fn forget(&self, id: u64) {
+        let mut inner = self.inner.lock().expect("poisoned table files lock");
+        if let Some(open) = inner.open.remove(&id) {
+            inner.by_use.remove(&open.last_use);
+        }
+    }
+
+    /// Number of files currently held by the cache
+    pub fn open_count(&self) -> usize {
+        self.inner
+            .lock()
+            .expect("poisoned table files lock")
+            .open
+            .len()
+    }
+
+    fn cold_lock(&self) -> MutexGuard<'_, HashSet<u64>> {
+        self.cold_tables.lock().expect("poisoned cold tables lock")
+    }
+
+    fn touch(&self, id: u64) -> Option<Arc<Handle>> {
+        let mut inner = self.inner.lock().expect("poisoned table files lock");
+        inner.tick += 1;
+        let tick = inner.tick;
+
+        let open = inner.open.get_mut(&id)?;
+        let old_use = std::mem::replace(&mut open.last_use, tick);
+        let file = open.file.clone();
+
+        inner.by_use.remove(&old_use);
+        inner.by_use.insert(tick, id);
+
+        Some(file)
+    }
+}
+
+fn ensure_dir(storage: &dyn Storage, dir: &Path) -> Result<(), Error> {
+    if storage.is_dir(dir) {
+        return Ok(());
+    }
+
+    log::warn!("table directory {dir:?} is gone, creating it again");
+    storage.create_dir_all(dir).map_err(|e| {
+        log::error!("failed to create table directory {dir:?}: {e:?}");
+        Error::StorageUnavailable
+    })
+}
@@ -0,0 +1,201 @@
+use super::SSTable;
+use std::{
+    ops::Deref,
+    sync::{Arc, Mutex},
+};
+
+/// The live SSTables, newest first, read through `Deref`.
+///
+/// Every change bumps the generation, so that a compaction can tell whether the tables it planned against moved
+/// before committing its output. See [`TableState::with_run_replaced`].
+///
+/// The tables are shared rather than copied, every change publishes the new ones to the [`TableView`] of the list.
+#[derive(Default)]
+pub struct TableList {
+    state: TableState,
+    view: TableView,
+}
+
+/// The tables of a [`TableList`] at a generation, cheap to clone
+#[derive(Default, Clone)]
+pub struct TableState {
+    tables: Arc<Vec<Arc<SSTable>>>,
+    generation: u64,
+}
+
+/// Latest list published by a [`TableList`], loaded without waiting for the lock held around its changes.
+///
+/// The lock of the list is held across manifest writes and merge commits, reads only load the view.
+#[derive(Default, Clone)]
+pub struct TableView(Arc<Mutex<Arc<Vec<Arc<SSTable>>>>>);
+
+impl TableView {
+    pub fn load(&self) -> Arc<Vec<Arc<SSTable>>> {
+        self.0.lock().expect("poisoned table view lock").clone()
+    }
+
+    fn publish(&self, tables: Arc<Vec<Arc<SSTable>>>) {
+        // The old list is dropped after the lock is released, it may hold the last references to tables
+        let _old = std::mem::replace(
+            &mut *self.0.lock().expect("poisoned table view lock"),
+            tables,
+        );
+    }
+}
+
+impl TableList {
+    pub fn new(tables: Vec<Arc<SSTable>>) -> Self {
+        let tables = Arc::new(tables);
+        let view = TableView(Arc::new(Mutex::new(tables.clone())));
+
+        Self {
+            state: TableState {
+                tables,
+                generation: 0,
+            },
+            view,
+        }
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.state.generation
+    }
+
+    /// The current tables and generation, to build the next list from outside of the lock
+    pub fn state(&self) -> TableState {
+        self.state.clone()
+    }
+
+    /// Where the changes of the list are published
+    pub fn view(&self) -> TableView {
+        self.view.clone()
+    }
+
+    /// Adds a table newer than all the others
+    pub fn push_newest(&mut self, table: Arc<SSTable>) {
+        let tables = std::iter::once(table)
+            .chain(self.state.tables.iter().cloned())
+            .collect();
+        self.set(tables);
+    }
+
+    pub fn set(&mut self, tables: Vec<Arc<SSTable>>) {
+        self.state.tables = Arc::new(tables);
+        self.state.generation += 1;
+        self.view.publish(self.state.tables.clone());
+    }
+}
+
+impl TableState {
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// The list with the tables `old` replaced by `new` (nothing if `None`), without changing it.
+    ///
+    /// `old` were adjacent at `start` in the list of `generation`. The replacement is only safe while they are still
+    /// all there and adjacent, tables may have been added or replaced around them since. Returns `None` otherwise.
+    pub fn with_run_replaced(
+        &self,
+        generation: u64,
+        start: usize,
+        old: &[u64],
+        new: Option<Arc<SSTable>>,
+    ) -> Option<Vec<Arc<SSTable>>> {
+        let start = if generation == self.generation {
+            start
+        } else {
+            let first = old.first()?;
+            self.tables.iter().position(|t| t.id == *first)?
+        };
+        let end = start + old.len();
+        if end > self.tables.len()
+            || !self.tables[start..end]
+                .iter()
+                .map(|t| t.id)
+                .eq(old.iter().copied())
+        {
+            return None;
+        }
+
+        Some(
+            self.tables[..start]
+                .iter()
+                .cloned()
+                .chain(new)
+                .chain(self.tables[end..].iter().cloned())
+                .collect(),
+        )
+    }
+}
+
+impl Deref for TableState {
+    type Target = Vec<Arc<SSTable>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.tables
+    }
+}
+
+impl Deref for TableList {
+    type Target = Vec<Arc<SSTable>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.state.tables
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        serialization::KVMemoryRepr,
+        sstables::{self, TableBuilder, TableFiles, TableOptions},
+        storage::MemStorage,
+    };
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_insert_between_plan_and_commit() {
+        let files = Arc::new(TableFiles::new(
+            Arc::new(MemStorage::new()),
+            PathBuf::from("sstables"),
+            8,
+        ));
+        let table = |id: u64| {
+            let entries = [KVMemoryRepr::new(id, Some(id), id)];
+            let data = TableBuilder::from_entries(&entries, TableOptions::default())
+                .unwrap()
+                .data;
+            sstables::create_sstable_file(&files, id, &data).unwrap();
+            Arc::new(SSTable::open(&files, id).unwrap())
+        };
+        let ids = |tables: &[Arc<SSTable>]| tables.iter().map(|t| t.id).collect::<Vec<_>>();
+
+        let mut list = TableList::new(vec![table(3), table(2), table(1)]);
+        // Planned: merging 2 and 1 into 10
+        let planned = list.generation();
+        let merged = table(10);
+
+        // A rotation added a newer table meanwhile, the inputs moved
+        list.push_newest(table(4));
+        let replaced = list
+            .state()
+            .with_run_replaced(planned, 1, &[2, 1], Some(merged.clone()))
+            .unwrap();
+        assert_eq!(ids(&replaced), [4, 3, 10]);
+        let view = list.view();
+        list.set(replaced);
+        // Published to the readers
+        assert_eq!(ids(&view.load()), [4, 3, 10]);
+
+        // Another merge replaced one of the inputs, nothing is committed
+        let planned = list.generation();
+        list.set(vec![table(4), table(11)]);
+        assert!(
+            list.state()
+                .with_run_replaced(planned, 1, &[3, 10], Some(table(12)))
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,226 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Buckets of the value size histograms, by powers of two: bucket `i` counts the sizes of `i` significant bits, so
+/// bucket 0 holds the tombstones, 1 the values of 1 byte, 2 the ones of 2 and 3 bytes and so on. The last bucket takes
+/// every larger size
+pub const VALUE_SIZE_BUCKETS: usize = 32;
+
+/// Bucket of a value of `size` bytes, see [`VALUE_SIZE_BUCKETS`]
+pub fn value_size_bucket(size: u64) -> usize {
+    ((u64::BITS - size.leading_zeros()) as usize).min(VALUE_SIZE_BUCKETS - 1)
+}
+
+/// Counters updated by the store while running
+#[derive(Default)]
+pub struct StatsCounters {
+    pub orphan_files_removed: AtomicU64,
+    pub orphan_bytes_removed: AtomicU64,
+    pub expired_entries_removed: AtomicU64,
+    pub sstables_skipped_by_key_range: AtomicU64,
+    pub cache_hits: AtomicU64,
+    pub cache_misses: AtomicU64,
+    pub negative_cache_hits: AtomicU64,
+    pub sstables_probed: AtomicU64,
+    pub bloom_filter_maybes: AtomicU64,
+    pub table_bytes_read: AtomicU64,
+    pub compaction_failures: AtomicU64,
+    pub value_sizes: [AtomicU64; VALUE_SIZE_BUCKETS],
+}
+
+impl StatsCounters {
+    pub fn snapshot(&self) -> Stats {
+        Stats {
+            orphan_files_removed: self.orphan_files_removed.load(Ordering::Relaxed),
+            orphan_bytes_removed: self.orphan_bytes_removed.load(Ordering::Relaxed),
+            expired_entries_removed: self.expired_entries_removed.load(Ordering::Relaxed),
+            sstables_skipped_by_key_range: self
+                .sstables_skipped_by_key_range
+                .load(Ordering::Relaxed),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            cache_misses: self.cache_misses.load(Ordering::Relaxed),
+            negative_cache_hits: self.negative_cache_hits.load(Ordering::Relaxed),
+            sstables_probed: self.sstables_probed.load(Ordering::Relaxed),
+            bloom_filter_maybes: self.bloom_filter_maybes.load(Ordering::Relaxed),
+            table_bytes_read: self.table_bytes_read.load(Ordering::Relaxed),
+            compaction_failures: self.compaction_failures.load(Ordering::Relaxed),
+            value_sizes: std::array::from_fn(|i| self.value_sizes[i].load(Ordering::Relaxed)),
+            open_table_files: 0,
+            disk_bytes_used: 0,
+            memtable_bytes: 0,
+            bloom_filter_bytes: 0,
+            estimated_distinct_keys: 0,
+            reclaimable_entries: 0,
+            bloom_filters: Vec::new(),
+        }
+    }
+
+    /// Adds the table lookups of a read
+    pub fn record_read(&self, trace: &ReadTrace) {
+        if trace.sstables_probed == 0 {
+            return;
+        }
+
+        self.sstables_probed
+            .fetch_add(trace.sstables_probed, Ordering::Relaxed);
+        self.bloom_filter_maybes
+            .fetch_add(trace.bloom_maybes, Ordering::Relaxed);
+        self.table_bytes_read
+            .fetch_add(trace.bytes_read, Ordering::Relaxed);
+    }
+
+    /// Counts a written value of `size` bytes
+    pub fn record_value_size(&self, size: u64) {
+        self.value_sizes[value_size_bucket(size)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Adds the value size histogram of a merge output
+    pub fn add_value_sizes(&self, value_sizes: &[u64; VALUE_SIZE_BUCKETS]) {
+        for (counter, count) in self.value_sizes.iter().zip(value_sizes) {
+            counter.fetch_add(*count, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Point-in-time copy of the store statistics
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Files deleted because they were no longer referenced (e.g. left behind by a crash)
+    pub orphan_files_removed: u64,
+    /// Total size of the files counted in `orphan_files_removed`
+    pub orphan_bytes_removed: u64,
+    /// Entries whose TTL ran out, garbage collected by compaction
+    pub expired_entries_removed: u64,
+    /// SSTable lookups avoided because the key was outside of the table key range
+    pub sstables_skipped_by_key_range: u64,
+    /// Reads answered by the read cache, reads served by the append log aren't counted
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    /// Reads answered by the cache of missing keys, see [`crate::Options::negative_cache_slots`]
+    pub negative_cache_hits: u64,
+    /// Tables looked up by reads, the ones skipped by key range aren't counted
+    pub sstables_probed: u64,
+    /// Table lookups that went past the bloom filter
+    pub bloom_filter_maybes: u64,
+    /// Size of the table blocks read to answer reads
+    pub table_bytes_read: u64,
+    /// Merges that failed or panicked, see [`crate::EventListener::on_compaction_failed`]
+    pub compaction_failures: u64,
+    /// Values written since the store was opened, and carried over by its merges, by size. See
+    /// [`VALUE_SIZE_BUCKETS`] and [`crate::KVStorage::space_report`] for the values stored
+    pub value_sizes: [u64; VALUE_SIZE_BUCKETS],
+    /// SSTable files currently open, see [`crate::Options::max_open_tables`]
+    pub open_table_files: u64,
+    /// Size of the tables and of the records in the current logs, see [`crate::Options::max_db_size_bytes`]
+    pub disk_bytes_used: u64,
+    /// Memory held by the in-memory logs, see [`crate::Options::spill_values`]
+    pub memtable_bytes: u64,
+    /// Memory held by the bloom filters of the live tables, see [`crate::Options::bloom_recovery`]
+    pub bloom_filter_bytes: u64,
+    /// Distinct keys written since the store was created, estimated within a few percent. Deleted keys still count
+    pub estimated_distinct_keys: u64,
+    /// Entries of the tables and logs beyond `estimated_distinct_keys`, about what merging every table would reclaim:
+    /// the overwritten values and the tombstones
+    pub reclaimable_entries: u64,
+    /// Bloom filter answers of every live table, newest first
+    pub bloom_filters: Vec<BloomStats>,
+}
+
+/// How a read was answered, see [`crate::KVStorage::read_with_trace`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReadTrace {
+    /// The append log had the key, written or deleted
+    pub memtable_hit: bool,
+    /// The read cache had the value, see [`crate::Options::cache_capacity`]
+    pub cache_hit: bool,
+    /// Tables looked up, newest first, until one had the key. The ones skipped by key range aren't counted
+    pub sstables_probed: u64,
+    /// Looked up tables whose bloom filter didn't rule the key out
+    pub bloom_maybes: u64,
+    /// Size of the table blocks read, from the file or its mapping
+    pub bytes_read: u64,
+    /// Table holding the entry that answered. `None` when the append log or the cache answered, or no table has
+    /// the key
+    pub table: Option<u64>,
+}
+
+impl ReadTrace {
+    /// Adds the cost of a table lookup made apart, see [`crate::Options::parallel_probe_threads`]
+    pub(crate) fn add_probe(&mut self, probe: &ReadTrace) {
+        self.bloom_maybes += probe.bloom_maybes;
+        self.bytes_read += probe.bytes_read;
+    }
+}
+
+/// Bloom filter answers of a table since it was opened, see [`Stats::bloom_filters`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BloomStats {
+    pub table: u64,
+    /// Lookups of keys within the table key range
+    pub checks: u64,
+    /// Lookups the filter ruled out, skipping the block read
+    pub negatives: u64,
+    /// Lookups the filter let through that then found nothing
+    pub false_positives: u64,
+}
+
+impl BloomStats {
+    /// Fraction of the keys missing from the table that the filter let through, 0 before any of them
+    pub fn false_positive_rate(&self) -> f64 {
+        let missing = self.negatives + self.false_positives;
+        if missing == 0 {
+            return 0.0;
+        }
+
+        self.false_positives as f64 / missing as f64
+    }
+}
+
+/// Disk space of a store, see [`crate::KVStorage::disk_usage`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiskUsage {
+    /// Size of the live SSTables
+    pub table_bytes: u64,
+    /// Size of the log files on disk, preallocated in full and recycled ones included
+    pub log_allocated_bytes: u64,
+    /// Records written to the current log files
+    pub log_used_bytes: u64,
+    /// Replaced SSTables waiting for their last reader before being deleted
+    pub pending_deletion_bytes: u64,
+    /// Estimated size of the values in the SSTables overwritten by newer tables, reclaimed by compaction
+    pub dead_bytes: u64,
+}
+
+impl DiskUsage {
+    /// Everything on disk
+    pub fn total(&self) -> u64 {
+        self.table_bytes + self.log_allocated_bytes + self.pending_deletion_bytes
+    }
+}
+
+/// Rough size of a key range, see [`crate::KVStorage::approximate_size`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RangeEstimate {
+    /// Stored entries, overwritten values and tombstones included
+    pub entries: u64,
+    /// Size of the table blocks and log records holding them
+    pub bytes: u64,
+}
+
+impl RangeEstimate {
+    pub(crate) fn add(&mut self, other: RangeEstimate) {
+        self.entries += other.entries;
+        self.bytes += other.bytes;
+    }
+}
+
+impl Stats {
+    /// Fraction of the cache lookups that were hits, 0 if the cache was never used
+    pub fn cache_hit_rate(&self) -> f64 {
+        let lookups = self.cache_hits + self.cache_misses;
+        if lookups == 0 {
+            return 0.0;
+        }
+
+        self.cache_hits as f64 / lookups as f64
+    }
+}
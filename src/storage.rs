@@ -0,0 +1,585 @@
+#[cfg(test)]
+use crate::failpoints::{self, Failpoints};
+use crate::{errors::Error, files::PositionedFile, options::IoBackend};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    time::SystemTime,
+};
+
+/// Where the files of a database live: the file system ([`DiskStorage`]) or memory ([`MemStorage`])
+pub trait Storage: Send + Sync {
+    /// Creates (or truncates) the file at `path`, sized to `len` zero bytes
+    fn create(&self, path: &Path, len: u64) -> Result<Handle, Error>;
+    /// Opens an existing file, read-only unless `writable`
+    fn open(&self, path: &Path, writable: bool) -> Result<Handle, Error>;
+    /// Replaces `to` if it exists
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), Error>;
+    fn remove(&self, path: &Path) -> Result<(), Error>;
+    /// Makes `to` share the content of `from`, copying it when that's not possible (e.g. across file systems)
+    fn link_or_copy(&self, from: &Path, to: &Path) -> Result<(), Error>;
+    /// Fails if `path` already exists
+    fn create_dir(&self, path: &Path) -> Result<(), Error>;
+    fn create_dir_all(&self, path: &Path) -> Result<(), Error>;
+    /// Fails unless the directory is empty
+    fn remove_dir(&self, path: &Path) -> Result<(), Error>;
+    fn is_dir(&self, path: &Path) -> bool;
+    /// Makes directory entry changes (creations, renames) durable
+    fn sync_dir(&self, path: &Path) -> Result<(), Error>;
+    /// Files directly in `dir`
+    fn list(&self, dir: &Path) -> Result<Vec<FileInfo>, Error>;
+}
+
+pub struct FileInfo {
+    pub path: PathBuf,
+    pub len: u64,
+    pub modified: SystemTime,
+}
+
+/// An open file of a [`Storage`]
+pub enum Handle {
+    Disk(File),
+    /// A file on disk read and written through io_uring
+    #[cfg(all(feature = "uring", target_os = "linux"))]
+    Uring(File),
+    Memory(Arc<MemFile>),
+}
+
+impl Handle {
+    /// Current size of the file in bytes
+    pub fn size(&self) -> Result<u64, Error> {
+        Ok(match self {
+            Handle::Disk(file) => file.metadata()?.len(),
+            #[cfg(all(feature = "uring", target_os = "linux"))]
+            Handle::Uring(file) => file.metadata()?.len(),
+            Handle::Memory(file) => file.read().bytes.len() as u64,
+        })
+    }
+
+    /// Fails when the failpoint `name` of a memory file is set, see [`crate::failpoints`]
+    #[cfg(test)]
+    pub fn hit_failpoint(&self, name: &str) -> io::Result<()> {
+        match self {
+            Handle::Memory(file) => file.failpoints.hit(name),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn sync_all(&self) -> io::Result<()> {
+        match self {
+            Handle::Disk(file) => file.sync_all(),
+            #[cfg(all(feature = "uring", target_os = "linux"))]
+            Handle::Uring(file) => file.sync_all(),
+            Handle::Memory(_) => Ok(()),
+        }
+    }
+
+    pub fn sync_data(&self) -> io::Result<()> {
+        match self {
+            Handle::Disk(file) => file.sync_data(),
+            #[cfg(all(feature = "uring", target_os = "linux"))]
+            Handle::Uring(file) => file.sync_data(),
+            #[cfg(test)]
+            Handle::Memory(file) => file.failpoints.hit(failpoints::SYNC_DATA),
+            #[cfg(not(test))]
+            Handle::Memory(_) => Ok(()),
+        }
+    }
+}
+
+impl PositionedFile for Handle {
+    fn read_exact_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<()> {
+        match self {
+            Handle::Disk(file) => file.read_exact_at(buffer, offset),
+            #[cfg(all(feature = "uring", target_os = "linux"))]
+            Handle::Uring(file) => crate::uring::read_exact_at(file, buffer, offset),
+            Handle::Memory(file) => {
+                let content = file.read();
+                let bytes = usize::try_from(offset)
+                    .ok()
+                    .and_then(|start| content.bytes.get(start..)?.get(..buffer.len()))
+                    .ok_or(io::ErrorKind::UnexpectedEof)?;
+                buffer.copy_from_slice(bytes);
+
+                Ok(())
+            }
+        }
+    }
+
+    fn write_all_at(&self, buffer: &[u8], offset: u64) -> io::Result<()> {
+        match self {
+            Handle::Disk(file) => file.write_all_at(buffer, offset),
+            #[cfg(all(feature = "uring", target_os = "linux"))]
+            Handle::Uring(file) => crate::uring::write_all_at(file, buffer, offset),
+            Handle::Memory(file) => {
+                let mut content = file.write();
+                let start = offset as usize;
+                let end = start + buffer.len();
+                if content.bytes.len() < end {
+                    content.bytes.resize(end, 0);
+                }
+                content.bytes[start..end].copy_from_slice(buffer);
+                content.modified = SystemTime::now();
+
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The storage of a database on disk, see [`crate::Options::io_backend`]
+pub fn disk_storage(backend: IoBackend) -> Arc<dyn Storage> {
+    match backend {
+        IoBackend::Std => Arc::new(DiskStorage),
+        #[cfg(all(feature = "uring", target_os = "linux"))]
+        IoBackend::Uring if crate::uring::is_supported() => Arc::new(UringStorage),
+        IoBackend::Uring => {
+            #[cfg(not(all(feature = "uring", target_os = "linux")))]
+            log::warn!("built without io_uring support, using the standard I/O path");
+            Arc::new(DiskStorage)
+        }
+    }
+}
+
+/// The file system, paths are used as they are
+pub struct DiskStorage;
+
+/// Files are renamed and removed while handles on them are still open (e.g. a table a reader got before its merge), so
+/// on Windows every handle lets others delete the file. That's the default of the standard library, set here so that
+/// it stays that way
+#[cfg(windows)]
+fn open_options() -> OpenOptions {
+    use std::os::windows::fs::OpenOptionsExt;
+
+    let mut options = OpenOptions::new();
+    // FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE
+    options.share_mode(0x1 | 0x2 | 0x4);
+
+    options
+}
+
+#[cfg(not(windows))]
+fn open_options() -> OpenOptions {
+    OpenOptions::new()
+}
+
+impl Storage for DiskStorage {
+    fn create(&self, path: &Path, len: u64) -> Result<Handle, Error> {
+        let file = open_options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        if let Err(e) = file.set_len(len) {
+            // No empty file left behind, e.g. when the disk is full
+            let _ = fs::remove_file(path);
+            return Err(e.into());
+        }
+
+        Ok(Handle::Disk(file))
+    }
+
+    fn open(&self, path: &Path, writable: bool) -> Result<Handle, Error> {
+        let file = open_options().read(true).write(writable).open(path)?;
+
+        Ok(Handle::Disk(file))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), Error> {
+        fs::rename(from, to)?;
+
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> Result<(), Error> {
+        fs::remove_file(path)?;
+
+        Ok(())
+    }
+
+    fn link_or_copy(&self, from: &Path, to: &Path) -> Result<(), Error> {
+        if fs::hard_link(from, to).is_err() {
+            fs::copy(from, to)?;
+        }
+
+        Ok(())
+    }
+
+    fn create_dir(&self, path: &Path) -> Result<(), Error> {
+        fs::create_dir(path)?;
+
+        Ok(())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<(), Error> {
+        fs::create_dir_all(path)?;
+
+        Ok(())
+    }
+
+    fn remove_dir(&self, path: &Path) -> Result<(), Error> {
+        fs::remove_dir(path)?;
+
+        Ok(())
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+
+    #[cfg(unix)]
+    fn sync_dir(&self, path: &Path) -> Result<(), Error> {
+        File::open(path)?.sync_all()?;
+
+        Ok(())
+    }
+
+    /// Directories can't be opened as files on Windows, where NTFS journals directory changes itself
+    #[cfg(not(unix))]
+    fn sync_dir(&self, _path: &Path) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn list(&self, dir: &Path) -> Result<Vec<FileInfo>, Error> {
+        let mut files = Vec::new();
+        for dir_entry in fs::read_dir(dir)? {
+            let dir_entry = dir_entry?;
+            let metadata = dir_entry.metadata()?;
+            if !metadata.is_file() {
+                continue;
+            }
+
+            files.push(FileInfo {
+                path: dir_entry.path(),
+                len: metadata.len(),
+                modified: metadata.modified()?,
+            });
+        }
+
+        Ok(files)
+    }
+}
+
+/// The file system like [`DiskStorage`], with file I/O going through io_uring
+#[cfg(all(feature = "uring", target_os = "linux"))]
+pub struct UringStorage;
+
+#[cfg(all(feature = "uring", target_os = "linux"))]
+impl UringStorage {
+    fn wrap(handle: Handle) -> Handle {
+        match handle {
+            Handle::Disk(file) => Handle::Uring(file),
+            handle => handle,
+        }
+    }
+}
+
+#[cfg(all(feature = "uring", target_os = "linux"))]
+impl Storage for UringStorage {
+    fn create(&self, path: &Path, len: u64) -> Result<Handle, Error> {
+        DiskStorage.create(path, len).map(Self::wrap)
+    }
+
+    fn open(&self, path: &Path, writable: bool) -> Result<Handle, Error> {
+        DiskStorage.open(path, writable).map(Self::wrap)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), Error> {
+        DiskStorage.rename(from, to)
+    }
+
+    fn remove(&self, path: &Path) -> Result<(), Error> {
+        DiskStorage.remove(path)
+    }
+
+    fn link_or_copy(&self, from: &Path, to: &Path) -> Result<(), Error> {
+        DiskStorage.link_or_copy(from, to)
+    }
+
+    fn create_dir(&self, path: &Path) -> Result<(), Error> {
+        DiskStorage.create_dir(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<(), Error> {
+        DiskStorage.create_dir_all(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> Result<(), Error> {
+        DiskStorage.remove_dir(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        DiskStorage.is_dir(path)
+    }
+
+    fn sync_dir(&self, path: &Path) -> Result<(), Error> {
+        DiskStorage.sync_dir(path)
+    }
+
+    fn list(&self, dir: &Path) -> Result<Vec<FileInfo>, Error> {
+        DiskStorage.list(dir)
+    }
+}
+
+/// Keeps every file in memory, nothing survives the storage being dropped.
+///
+/// Paths only need to be unique, they don't have to exist on disk. Syncs do nothing.
+#[derive(Default)]
+pub struct MemStorage {
+    inner: Mutex<MemInner>,
+    #[cfg(test)]
+    failpoints: Arc<Failpoints>,
+}
+
+#[derive(Default)]
+struct MemInner {
+    files: HashMap<PathBuf, Arc<MemFile>>,
+    dirs: HashSet<PathBuf>,
+}
+
+/// Content of a file of [`MemStorage`], shared by its handles (and by its links)
+pub struct MemFile {
+    content: RwLock<MemContent>,
+    /// Those of the storage that created the file
+    #[cfg(test)]
+    failpoints: Arc<Failpoints>,
+}
+
+struct MemContent {
+    bytes: Vec<u8>,
+    modified: SystemTime,
+}
+
+impl MemFile {
+    fn read(&self) -> RwLockReadGuard<'_, MemContent> {
+        self.content.read().expect("poisoned memory file")
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, MemContent> {
+        self.content.write().expect("poisoned memory file")
+    }
+}
+
+impl MemStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Failpoints of the files of this storage, see [`crate::failpoints`]
+    #[cfg(test)]
+    pub fn failpoints(&self) -> &Failpoints {
+        &self.failpoints
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MemInner> {
+        self.inner.lock().expect("poisoned memory storage")
+    }
+}
+
+fn not_found(path: &Path) -> Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{path:?} not found")).into()
+}
+
+impl Storage for MemStorage {
+    fn create(&self, path: &Path, len: u64) -> Result<Handle, Error> {
+        #[cfg(test)]
+        self.failpoints.hit(failpoints::CREATE_FILE)?;
+
+        let file = Arc::new(MemFile {
+            content: RwLock::new(MemContent {
+                bytes: vec![0; len as usize],
+                modified: SystemTime::now(),
+            }),
+            #[cfg(test)]
+            failpoints: self.failpoints.clone(),
+        });
+        self.lock().files.insert(path.to_owned(), file.clone());
+
+        Ok(Handle::Memory(file))
+    }
+
+    fn open(&self, path: &Path, _writable: bool) -> Result<Handle, Error> {
+        let file = self.lock().files.get(path).cloned();
+
+        file.map(Handle::Memory).ok_or_else(|| not_found(path))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), Error> {
+        let mut inner = self.lock();
+        let file = inner.files.remove(from).ok_or_else(|| not_found(from))?;
+        inner.files.insert(to.to_owned(), file);
+
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> Result<(), Error> {
+        // Open handles keep the content alive, as on Unix
+        self.lock()
+            .files
+            .remove(path)
+            .map(|_| ())
+            .ok_or_else(|| not_found(path))
+    }
+
+    fn link_or_copy(&self, from: &Path, to: &Path) -> Result<(), Error> {
+        let mut inner = self.lock();
+        let file = inner
+            .files
+            .get(from)
+            .cloned()
+            .ok_or_else(|| not_found(from))?;
+        inner.files.insert(to.to_owned(), file);
+
+        Ok(())
+    }
+
+    fn create_dir(&self, path: &Path) -> Result<(), Error> {
+        if !self.lock().dirs.insert(path.to_owned()) {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists).into());
+        }
+
+        Ok(())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<(), Error> {
+        self.lock()
+            .dirs
+            .extend(path.ancestors().map(Path::to_owned));
+
+        Ok(())
+    }
+
+    fn remove_dir(&self, path: &Path) -> Result<(), Error> {
+        let mut inner = self.lock();
+        let has_children = inner
+            .files
+            .keys()
+            .chain(&inner.dirs)
+            .any(|child| child.parent() == Some(path));
+        if has_children {
+            return Err(io::Error::from(io::ErrorKind::DirectoryNotEmpty).into());
+        }
+
+        if !inner.dirs.remove(path) {
+            return Err(not_found(path));
+        }
+
+        Ok(())
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.lock().dirs.contains(path)
+    }
+
+    fn sync_dir(&self, _path: &Path) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn list(&self, dir: &Path) -> Result<Vec<FileInfo>, Error> {
+        let inner = self.lock();
+        if !inner.dirs.contains(dir) {
+            return Err(not_found(dir));
+        }
+
+        Ok(inner
+            .files
+            .iter()
+            .filter(|(path, _)| path.parent() == Some(dir))
+            .map(|(path, file)| {
+                let content = file.read();
+                FileInfo {
+                    path: path.clone(),
+                    len: content.bytes.len() as u64,
+                    modified: content.modified,
+                }
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_storage(storage: &dyn Storage, dir: &Path) {
+        // Preallocated space reads as zeros
+        let file = storage.create(&dir.join("file"), 64).unwrap();
+        let mut buffer = [1u8; 64];
+        file.read_exact_at(&mut buffer, 0).unwrap();
+        assert_eq!(buffer, [0u8; 64]);
+
+        // Out of order writes, each landing at its own offset
+        file.write_all_at(b"world", 6).unwrap();
+        file.write_all_at(b"hello ", 0).unwrap();
+        let mut buffer = [0u8; 11];
+        file.read_exact_at(&mut buffer, 0).unwrap();
+        assert_eq!(&buffer, b"hello world");
+
+        // Past the end of the file
+        let mut buffer = [0u8; 8];
+        let error = file.read_exact_at(&mut buffer, 60).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+
+        // Writing past the end grows the file
+        file.write_all_at(b"end", 64).unwrap();
+        assert_eq!(file.size().unwrap(), 67);
+
+        storage
+            .rename(&dir.join("file"), &dir.join("renamed"))
+            .unwrap();
+        storage
+            .link_or_copy(&dir.join("renamed"), &dir.join("linked"))
+            .unwrap();
+        let mut names: Vec<_> = storage
+            .list(dir)
+            .unwrap()
+            .into_iter()
+            .map(|file| (file.path.file_name().unwrap().to_owned(), file.len))
+            .collect();
+        names.sort();
+        assert_eq!(names, [("linked".into(), 67), ("renamed".into(), 67)]);
+
+        let reopened = storage.open(&dir.join("linked"), false).unwrap();
+        let mut buffer = [0u8; 5];
+        reopened.read_exact_at(&mut buffer, 6).unwrap();
+        assert_eq!(&buffer, b"world");
+
+        storage.remove(&dir.join("renamed")).unwrap();
+        assert!(storage.open(&dir.join("renamed"), false).is_err());
+        assert!(storage.remove(&dir.join("renamed")).is_err());
+        assert!(storage.create_dir(dir).is_err());
+    }
+
+    #[test]
+    fn test_disk_storage() {
+        let dir = PathBuf::from(format!("./test-dbs/{}", rand::random::<u64>()));
+        DiskStorage.create_dir_all(&dir).unwrap();
+        assert!(DiskStorage.is_dir(&dir));
+
+        check_storage(&DiskStorage, &dir);
+    }
+
+    #[cfg(all(feature = "uring", target_os = "linux"))]
+    #[test]
+    fn test_uring_storage() {
+        let dir = PathBuf::from(format!("./test-dbs/{}", rand::random::<u64>()));
+        UringStorage.create_dir_all(&dir).unwrap();
+
+        check_storage(&UringStorage, &dir);
+    }
+
+    #[test]
+    fn test_mem_storage() {
+        let storage = MemStorage::new();
+        let dir = Path::new("db");
+        assert!(!storage.is_dir(dir));
+        storage.create_dir(dir).unwrap();
+
+        check_storage(&storage, dir);
+        assert!(!Path::new("db").exists());
+    }
+}
@@ -0,0 +1,111 @@
+use crate::files::PositionedFile;
+use io_uring::{IoUring, opcode, squeue, types};
+use std::{cell::RefCell, fs::File, io, os::fd::AsRawFd, sync::OnceLock};
+
+/// Size of the ring of every thread, operations are submitted one at a time
+const RING_ENTRIES: u32 = 4;
+
+thread_local! {
+    /// `None` when the thread couldn't get a ring, its I/O then goes through the standard path
+    static RING: Option<RefCell<IoUring>> = IoUring::new(RING_ENTRIES).ok().map(RefCell::new);
+}
+
+/// Whether the kernel supports io_uring, probed once
+pub fn is_supported() -> bool {
+    static SUPPORTED: OnceLock<bool> = OnceLock::new();
+
+    *SUPPORTED.get_or_init(|| match IoUring::new(RING_ENTRIES) {
+        Ok(_) => true,
+        Err(e) => {
+            log::warn!("io_uring is not available, using the standard I/O path: {e}");
+            false
+        }
+    })
+}
+
+pub fn read_exact_at(file: &File, mut buffer: &mut [u8], mut offset: u64) -> io::Result<()> {
+    RING.with(move |ring| {
+        let Some(ring) = ring else {
+            return file.read_exact_at(buffer, offset);
+        };
+        let mut ring = ring.borrow_mut();
+
+        while !buffer.is_empty() {
+            let len = buffer.len().min(u32::MAX as usize) as u32;
+            let entry = opcode::Read::new(types::Fd(file.as_raw_fd()), buffer.as_mut_ptr(), len)
+                .offset(offset)
+                .build();
+
+            // SAFETY: `buffer` outlives the operation, which is complete once `submit` returns
+            match unsafe { submit(&mut ring, &entry) } {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(read) => {
+                    buffer = &mut buffer[read..];
+                    offset += read as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    })
+}
+
+pub fn write_all_at(file: &File, mut buffer: &[u8], mut offset: u64) -> io::Result<()> {
+    RING.with(move |ring| {
+        let Some(ring) = ring else {
+            return file.write_all_at(buffer, offset);
+        };
+        let mut ring = ring.borrow_mut();
+
+        while !buffer.is_empty() {
+            let len = buffer.len().min(u32::MAX as usize) as u32;
+            let entry = opcode::Write::new(types::Fd(file.as_raw_fd()), buffer.as_ptr(), len)
+                .offset(offset)
+                .build();
+
+            // SAFETY: `buffer` outlives the operation, which is complete once `submit` returns
+            match unsafe { submit(&mut ring, &entry) } {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(written) => {
+                    buffer = &buffer[written..];
+                    offset += written as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    })
+}
+
+/// Submits `entry` and waits for its completion, returning the number of bytes transferred.
+///
+/// # Safety
+///
+/// The buffer of `entry` must stay valid until this returns.
+unsafe fn submit(ring: &mut IoUring, entry: &squeue::Entry) -> io::Result<usize> {
+    // SAFETY: forwarded to the caller, and the ring is empty since every call waits for its completion
+    unsafe { ring.submission().push(entry) }
+        .map_err(|_| io::Error::other("io_uring submission queue full"))?;
+
+    // Interrupted waits are retried, the operation must not outlive the buffer
+    loop {
+        match ring.submit_and_wait(1) {
+            Ok(_) => break,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    let completion = ring
+        .completion()
+        .next()
+        .ok_or_else(|| io::Error::other("io_uring completion missing"))?;
+    match completion.result() {
+        result if result < 0 => Err(io::Error::from_raw_os_error(-result)),
+        result => Ok(result as usize),
+    }
+}
@@ -0,0 +1,54 @@
+/// Result of [`crate::KVStorage::verify`], one report per SSTable
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    pub tables: Vec<TableReport>,
+}
+
+impl VerifyReport {
+    /// Whether no table has anomalies
+    pub fn is_ok(&self) -> bool {
+        self.tables.iter().all(|table| table.anomalies.is_empty())
+    }
+
+    /// Point entries across all tables, tombstones included
+    pub fn entry_count(&self) -> u64 {
+        self.tables.iter().map(|table| table.entry_count).sum()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableReport {
+    pub id: u64,
+    /// Point entries that could be decoded
+    pub entry_count: u64,
+    pub anomalies: Vec<Anomaly>,
+}
+
+/// Something in a table that doesn't match what the store writes.
+///
+/// Blocks are numbered as in the block index, record offsets are relative to the start of the decompressed block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Anomaly {
+    /// The header, footer, block index or range tombstones can't be decoded, nothing else was checked
+    Unreadable(String),
+    /// A block doesn't start where the previous one ends, or ends past the data blocks
+    BlockOffset { block: usize, offset: u64 },
+    /// A block can't be decompressed, or its restart points don't fit in it
+    CorruptBlock { block: usize },
+    /// The bytes at `offset` aren't a record, the rest of the block is skipped
+    CorruptRecord { block: usize, offset: u64 },
+    /// A restart point that isn't the start of a record
+    RestartOffset { block: usize, offset: u64 },
+    /// The first key of a block isn't the one in the block index
+    IndexKey {
+        block: usize,
+        indexed: u64,
+        found: u64,
+    },
+    /// A key below the one before it
+    UnsortedKey { key: u64 },
+    /// A key stored more than once
+    DuplicateKey { key: u64 },
+    /// The bloom filter rejects a key of the table, reads would miss it
+    BloomMissingKey { key: u64 },
+}
@@ -0,0 +1,169 @@
+use crate::{Key, Value, comparator::KeyOrder, serialization::KVMemoryRepr};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+/// Keys watched through [`crate::KVStorage::watch`], notified by the write path
+#[derive(Default)]
+pub struct Watches {
+    slots: Mutex<HashMap<Key, Vec<Arc<WatchSlot>>>>,
+}
+
+struct WatchSlot {
+    state: Mutex<WatchState>,
+    /// Notified on every change and when the store is dropped
+    changed: Condvar,
+}
+
+struct WatchState {
+    latest: Option<Value>,
+    /// Number of changes seen
+    version: u64,
+    /// The store is gone
+    closed: bool,
+}
+
+impl WatchSlot {
+    fn lock(&self) -> MutexGuard<'_, WatchState> {
+        self.state.lock().expect("poisoned watch lock")
+    }
+
+    fn set(&self, value: Option<Value>) {
+        let mut state = self.lock();
+        state.latest = value;
+        state.version += 1;
+        self.changed.notify_all();
+    }
+}
+
+impl Watches {
+    pub fn watch(self: &Arc<Self>, key: Key) -> WatchHandle {
+        let slot = Arc::new(WatchSlot {
+            state: Mutex::new(WatchState {
+                latest: None,
+                version: 0,
+                closed: false,
+            }),
+            changed: Condvar::new(),
+        });
+        self.lock().entry(key).or_default().push(slot.clone());
+
+        WatchHandle {
+            key,
+            slot,
+            watches: self.clone(),
+            seen: Mutex::new(0),
+        }
+    }
+
+    /// Passes the written `entry` to the watches of the keys it changes
+    pub fn notify(&self, entry: &KVMemoryRepr, order: &KeyOrder) {
+        let slots = self.lock();
+        if slots.is_empty() {
+            return;
+        }
+
+        if entry.is_range_tombstone() {
+            for slot in slots
+                .iter()
+                .filter(|(key, _)| entry.covers(key, order))
+                .flat_map(|(_, slots)| slots)
+            {
+                slot.set(None);
+            }
+        } else if let Some(slots) = slots.get(entry.key()) {
+            for slot in slots {
+                slot.set(*entry.value());
+            }
+        }
+    }
+
+    /// Wakes every waiter, the store is being dropped
+    pub fn close(&self) {
+        for slot in self.lock().values().flatten() {
+            slot.lock().closed = true;
+            slot.changed.notify_all();
+        }
+    }
+
+    #[cfg(test)]
+    pub fn watched_keys(&self) -> usize {
+        self.lock().len()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<Key, Vec<Arc<WatchSlot>>>> {
+        self.slots.lock().expect("poisoned watches lock")
+    }
+}
+
+/// Follows the value of a key, see [`crate::KVStorage::watch`].
+///
+/// Changes are coalesced: a waiter only gets the latest value, intermediate ones are skipped if it falls behind.
+pub struct WatchHandle {
+    key: Key,
+    slot: Arc<WatchSlot>,
+    watches: Arc<Watches>,
+    /// Version returned by the last [`WatchHandle::wait_for_change`]
+    seen: Mutex<u64>,
+}
+
+impl WatchHandle {
+    pub fn key(&self) -> Key {
+        self.key
+    }
+
+    /// Value of the key after the latest change, or when the watch started. `None` while the key is missing
+    pub fn latest(&self) -> Option<Value> {
+        self.slot.lock().latest
+    }
+
+    /// Waits for a change not returned yet and returns the value it left, `Some(None)` for deletions.
+    ///
+    /// `None` on timeout, or right away once the store is dropped.
+    pub fn wait_for_change(&self, timeout: Duration) -> Option<Option<Value>> {
+        let deadline = Instant::now() + timeout;
+        let mut seen = self.seen.lock().expect("poisoned watch lock");
+        let mut state = self.slot.lock();
+
+        loop {
+            if state.version > *seen {
+                *seen = state.version;
+                return Some(state.latest);
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if state.closed || remaining.is_zero() {
+                return None;
+            }
+            state = self
+                .slot
+                .changed
+                .wait_timeout(state, remaining)
+                .expect("poisoned watch lock")
+                .0;
+        }
+    }
+
+    /// Sets the value the watch starts from, unless a change already came in
+    pub(crate) fn init(&self, value: Option<Value>) {
+        let mut state = self.slot.lock();
+        if state.version == 0 {
+            state.latest = value;
+        }
+    }
+}
+
+impl Drop for WatchHandle {
+    fn drop(&mut self) {
+        let mut slots = self.watches.lock();
+
+        if let Some(key_slots) = slots.get_mut(&self.key) {
+            key_slots.retain(|slot| !Arc::ptr_eq(slot, &self.slot));
+            if key_slots.is_empty() {
+                slots.remove(&self.key);
+            }
+        }
+    }
+}
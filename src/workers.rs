@@ -0,0 +1,58 @@
+use crate::{
+    append_log::Flusher, cleanup::Reaper, options::Options, sstables::compactor::CompactionWorker,
+    sstables::probe::ProbePool, storage::Storage,
+};
+use std::sync::Arc;
+
+/// The background threads of a database, shared by the store and its namespaces.
+///
+/// The store owns them, the namespaces get a copy with [`Workers::for_namespace`] and must be stopped before the
+/// store stops the threads.
+pub struct Workers {
+    /// A namespace only gets its own view of the reaper, see [`Reaper::for_namespace`]
+    pub reaper: Arc<Reaper>,
+    pub compaction: Arc<CompactionWorker>,
+    /// Set with [`Options::max_immutable_memtables`]
+    pub flusher: Option<Arc<Flusher>>,
+    /// Set with [`Options::parallel_probe_threads`]
+    pub probe_pool: Option<Arc<ProbePool>>,
+    /// False for the copy of a namespace, which leaves the threads running
+    owned: bool,
+}
+
+impl Workers {
+    /// Starts the threads of a store, removing files from `storage`
+    pub fn new(storage: Arc<dyn Storage>, options: &Options) -> Self {
+        Self {
+            reaper: Arc::new(Reaper::new(storage, options.clock.clone())),
+            compaction: Arc::new(CompactionWorker::new()),
+            flusher: (options.max_immutable_memtables > 0)
+                .then(|| Arc::new(Flusher::start(options.clock.clone()))),
+            probe_pool: ProbePool::from_options(options).map(Arc::new),
+            owned: true,
+        }
+    }
+
+    /// The threads of this store, for one of its namespaces
+    pub fn for_namespace(&self) -> Self {
+        Self {
+            reaper: Arc::new(self.reaper.for_namespace()),
+            compaction: self.compaction.clone(),
+            flusher: self.flusher.clone(),
+            probe_pool: self.probe_pool.clone(),
+            owned: false,
+        }
+    }
+
+    /// Waits for the queued files to be removed. The store also stops the threads, once its compactor and flusher
+    /// stopped
+    pub fn stop(&self) {
+        self.reaper.stop();
+        if self.owned {
+            self.compaction.stop();
+            if let Some(flusher) = &self.flusher {
+                flusher.stop();
+            }
+        }
+    }
+}
@@ -0,0 +1,61 @@
+//! Table reads reuse the buffers of their thread instead of allocating. The allocator of this test binary counts the
+//! allocations of every thread, so it's kept out of the other tests.
+
+use key_value_store::{Compression, KVStorage, Options};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+};
+
+/// Counts the allocations of every thread, each in its own counter
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn allocations() -> u64 {
+    ALLOCATIONS.with(Cell::get)
+}
+
+/// Keys `0, 2, ..` with their half as value, in tables of 4KB blocks
+fn store(compression: Compression) -> KVStorage {
+    let options = Options::new().compression(compression).block_size(4096);
+    let kv = KVStorage::new_in_memory_with_options(options).unwrap();
+    for k in 0..10_000 {
+        kv.write(k * 2, Some(k)).unwrap();
+    }
+    kv.flush().unwrap();
+
+    kv
+}
+
+#[test]
+fn test_table_reads_dont_allocate() {
+    for compression in [Compression::None, Compression::Lz4] {
+        let kv = store(compression);
+        // Grows the buffers of the thread
+        kv.read(&0).unwrap();
+
+        let before = allocations();
+        for k in (0..10_000).step_by(7) {
+            assert_eq!(kv.read(&(k * 2)).unwrap(), Some(k));
+            assert_eq!(kv.read(&(k * 2 + 1)).unwrap(), None);
+        }
+        assert_eq!(allocations(), before, "{compression:?}");
+    }
+}